@@ -11,6 +11,7 @@
 #include "utils.h"
 #include "p2p_plugin.h"
 
+#include <algorithm>
 #include <atomic>
 #include <iostream>
 #include <vector>
@@ -37,16 +38,71 @@ struct optcastRequest
 
 struct serverHandler
 {
-  void *rcomm;
-  void *scomm;
+  std::vector<void *> rcomms; // one per channel
+  std::vector<void *> scomms;
+  int fd;                  // bootstrap socket, kept open for the lifetime of the comm
+  ncclDataType_t dataType; // data type the reduction server is configured with
+  ncclRedOp_t redOp;       // reduction operator of the server's communicator
+  uint64_t count;          // max element count per message the reduction server accepts
+  uint32_t nchunk;         // requests a message is split into for the reduction server
+  uint32_t nchannel;       // comm pairs the reduction server stripes its jobs over
 };
 
+// bootstrap protocol, see reduction_server/src/bootstrap.rs
+#define OPTCAST_MAGIC 0x5443504f // "OPCT"
+#define OPTCAST_VERSION 8
+#define OPTCAST_ABORT_MAGIC 0x54524241 // "ABRT"
+#define OPTCAST_STATUS_ACCEPT 0
+#define OPTCAST_MAX_REASON_SIZE 1024
+// FP8 data types of NCCL 2.24+, defined here to build with older headers
+#define OPTCAST_FLOAT8E4M3 10
+#define OPTCAST_FLOAT8E5M2 11
+// the reduction operators the reduction servers support: ncclSum, ncclProd, ncclMax, ncclMin and
+// ncclAvg
+#define OPTCAST_NUM_OPS 5
+
+struct __attribute__((packed)) optcastHello
+{
+  uint32_t magic;
+  uint32_t version;
+  uint64_t commId; // the ranks of a communicator share the same id
+  uint32_t dataType;
+  uint32_t redOp;
+  uint32_t nrank;
+  uint32_t rank; // the servers reduce the ranks in the order of their ids
+  uint32_t nchannel; // 0: any, the server tells its own
+  uint32_t nchunk;   // 0: any, the server tells its own
+  uint64_t count;
+};
+
+struct __attribute__((packed)) optcastReply
+{
+  uint32_t status;
+  uint32_t dataType;
+  uint32_t redOp;
+  uint64_t count;
+  uint32_t nchunk;
+  uint32_t nchannel;
+  uint32_t reasonSize;
+};
+
+// sent by the reduction server on the bootstrap socket when it tears down the communicator
+struct __attribute__((packed)) optcastAbort
+{
+  uint32_t magic;
+  uint32_t reasonSize;
+};
+
+// how many unfinished polls of a request between checks for an abort from the servers
+#define OPTCAST_ABORT_CHECK_INTERVAL 1024
+
 struct optcastComm
 {
   bool bypass;
   std::atomic<uint64_t> cursor;
   std::vector<serverHandler> handlers;
   int nsplit;
+  uint64_t polls;
 };
 
 struct optcastMr
@@ -67,24 +123,154 @@ struct optcastListenComm
   void *listenCommP2P;
 };
 
+struct optcastMemHandle;
+
 struct optcastCollComm
 {
   int rank;
   int nranks;
+  int dev;
+  char handle[NCCL_NET_HANDLE_MAXSIZE]; // the listen handle of rank 0, for the communicator ids
   void *recvComm;
   void *sendComm;
-  struct optcastComm *optcastComm;
+  // the reduction servers reduce a communicator with a single operator, so there is one per
+  // operator, connected by its first allreduce
+  struct optcastComm *optcastComms[OPTCAST_NUM_OPS];
+  std::vector<optcastMemHandle *> mhandles; // registered again with the communicators connected later
   struct optcastRequest *reqs;
+  size_t nslots; // requests of the servers each of reqs has room for
 };
 
 struct optcastMemHandle
 {
-  struct optcastMr *mr; // for optcast
+  struct optcastMr *mr[OPTCAST_NUM_OPS]; // for optcast, per operator
   void *ncclIbMr;
+  void *data;
+  int size;
   int type;
 };
 
-static ncclResult_t optcastConnect(int dev, const std::string &addr, int port, serverHandler *handler)
+static ncclResult_t optcastSendAll(int fd, const void *data, size_t size)
+{
+  size_t offset = 0;
+  while (offset < size)
+  {
+    ssize_t n = send(fd, (const char *)data + offset, size - offset, 0);
+    if (n <= 0)
+    {
+      if (n < 0 && errno == EINTR)
+        continue;
+      return ncclSystemError;
+    }
+    offset += n;
+  }
+  return ncclSuccess;
+}
+
+static ncclResult_t optcastRecvAll(int fd, void *data, size_t size)
+{
+  size_t offset = 0;
+  while (offset < size)
+  {
+    ssize_t n = recv(fd, (char *)data + offset, size - offset, 0);
+    if (n <= 0)
+    {
+      if (n < 0 && errno == EINTR)
+        continue;
+      return ncclSystemError;
+    }
+    offset += n;
+  }
+  return ncclSuccess;
+}
+
+// the element size of a data type the reduction server supports, 0 if unsupported
+static int optcastTypeSize(ncclDataType_t dataType)
+{
+  switch ((int)dataType)
+  {
+  case ncclInt8:
+  case ncclUint8:
+  case OPTCAST_FLOAT8E4M3:
+  case OPTCAST_FLOAT8E5M2:
+    return 1;
+  case ncclFloat16:
+  case 9: // ncclBfloat16, which only exists with cuda_bf16.h
+    return 2;
+  case ncclInt32:
+  case ncclUint32:
+  case ncclFloat32:
+    return 4;
+  case ncclInt64:
+  case ncclUint64:
+  case ncclFloat64:
+    return 8;
+  default:
+    return 0;
+  }
+}
+
+// the data type of the reduction servers, given with OPTCAST_DATA_TYPE as their --data-type.
+// NCCL asks for the supported types before it connects, so the servers can't tell us.
+// -1 if the name is unknown
+static int optcastDataType()
+{
+  static const std::pair<std::string, int> types[] = {
+      {"f32", ncclFloat32}, {"f16", ncclFloat16}, {"bf16", 9}, {"f64", ncclFloat64}, {"i8", ncclInt8}, {"u8", ncclUint8}, {"i32", ncclInt32}, {"u32", ncclUint32}, {"i64", ncclInt64}, {"u64", ncclUint64}, {"f8e4m3", OPTCAST_FLOAT8E4M3}, {"f8e5m2", OPTCAST_FLOAT8E5M2}};
+  char *s = getenv("OPTCAST_DATA_TYPE");
+  if (s == nullptr)
+  {
+    return ncclFloat32;
+  }
+  for (auto &t : types)
+  {
+    if (t.first == s)
+    {
+      return t.second;
+    }
+  }
+  return -1;
+}
+
+static ncclResult_t optcastHandshake(int fd, uint64_t commId, ncclRedOp_t redOp, int nranks, int rank, serverHandler *handler)
+{
+  int dataType = optcastDataType();
+  if (dataType < 0)
+  {
+    WARN("Optcast: unknown OPTCAST_DATA_TYPE %s", getenv("OPTCAST_DATA_TYPE"));
+    return ncclInvalidUsage;
+  }
+  optcastHello hello = {OPTCAST_MAGIC, OPTCAST_VERSION, commId, (uint32_t)dataType, (uint32_t)redOp, (uint32_t)nranks, (uint32_t)rank, 0, 0, 0};
+  NCCLCHECK(optcastSendAll(fd, &hello, sizeof(hello)));
+
+  optcastReply reply;
+  NCCLCHECK(optcastRecvAll(fd, &reply, sizeof(reply)));
+  if (reply.reasonSize > OPTCAST_MAX_REASON_SIZE)
+  {
+    WARN("Optcast: invalid handshake reply (reason size %u)", reply.reasonSize);
+    return ncclRemoteError;
+  }
+  std::string reason(reply.reasonSize, '\0');
+  NCCLCHECK(optcastRecvAll(fd, &reason[0], reply.reasonSize));
+  if (reply.status != OPTCAST_STATUS_ACCEPT)
+  {
+    WARN("Optcast: rejected by the reduction server: %s", reason.c_str());
+    return ncclInvalidUsage;
+  }
+  handler->dataType = (ncclDataType_t)reply.dataType;
+  handler->redOp = (ncclRedOp_t)reply.redOp;
+  handler->count = reply.count;
+  handler->nchunk = reply.nchunk;
+  handler->nchannel = reply.nchannel;
+  if (handler->nchunk == 0 || handler->nchannel == 0)
+  {
+    WARN("Optcast: invalid handshake reply (nchunk %u, nchannel %u)", reply.nchunk, reply.nchannel);
+    return ncclRemoteError;
+  }
+  return ncclSuccess;
+}
+
+static ncclResult_t optcastConnect(int dev, const std::string &addr, int port, uint64_t commId, ncclRedOp_t redOp, int nranks, int rank, serverHandler *handler)
 {
   // connected to addr:port
   int socket_fd;
@@ -117,53 +303,71 @@ static ncclResult_t optcastConnect(int dev, const std::string &addr, int port, s
     return ncclInternalError;
   }
 
-  // Receive the size of the incoming message
-  int msg_size;
-  if (recv(socket_fd, &msg_size, sizeof(msg_size), 0) < 0)
-  {
-    return ncclInternalError;
-  }
+  NCCLCHECK(optcastHandshake(socket_fd, commId, redOp, nranks, rank, handler));
 
-  // Receive the incoming message
-  std::vector<char> connect_handle(msg_size);
-  if (recv(socket_fd, connect_handle.data(), connect_handle.size(), 0) < 0)
+  // the channels are set up in order, the same way as the reduction server does
+  for (uint32_t c = 0; c < handler->nchannel; c++)
   {
-    return ncclInternalError;
-  }
-
-  std::vector<char> listen_handle(NCCL_NET_HANDLE_MAXSIZE);
-  void *lcomm;
-
-  NCCLCHECK(NCCL_PLUGIN_SYMBOL.listen(dev, listen_handle.data(), &lcomm));
+    // Receive the size of the incoming message
+    uint32_t msg_size;
+    NCCLCHECK(optcastRecvAll(socket_fd, &msg_size, sizeof(msg_size)));
+    if (msg_size > NCCL_NET_HANDLE_MAXSIZE)
+    {
+      WARN("Optcast: handle too large (%u bytes)", msg_size);
+      return ncclRemoteError;
+    }
 
-  msg_size = listen_handle.size();
-  send(socket_fd, &msg_size, sizeof(msg_size), 0);
-  send(socket_fd, listen_handle.data(), msg_size, 0);
+    // Receive the incoming message
+    std::vector<char> connect_handle(NCCL_NET_HANDLE_MAXSIZE);
+    NCCLCHECK(optcastRecvAll(socket_fd, connect_handle.data(), msg_size));
 
-  void *scomm = nullptr;
-  void *rcomm = nullptr;
-  while (scomm == nullptr || rcomm == nullptr)
-  {
-    if (scomm == nullptr)
+    // the part of the handle for the ranks on the same host, which the plugin doesn't use
+    NCCLCHECK(optcastRecvAll(socket_fd, &msg_size, sizeof(msg_size)));
+    if (msg_size > NCCL_NET_HANDLE_MAXSIZE)
     {
-      NCCLCHECK(NCCL_PLUGIN_SYMBOL.connect(dev, connect_handle.data(), &scomm));
+      WARN("Optcast: handle too large (%u bytes)", msg_size);
+      return ncclRemoteError;
     }
-    if (rcomm == nullptr)
+    std::vector<char> local_handle(msg_size);
+    NCCLCHECK(optcastRecvAll(socket_fd, local_handle.data(), msg_size));
+
+    std::vector<char> listen_handle(NCCL_NET_HANDLE_MAXSIZE);
+    void *lcomm;
+
+    NCCLCHECK(NCCL_PLUGIN_SYMBOL.listen(dev, listen_handle.data(), &lcomm));
+
+    msg_size = listen_handle.size();
+    NCCLCHECK(optcastSendAll(socket_fd, &msg_size, sizeof(msg_size)));
+    NCCLCHECK(optcastSendAll(socket_fd, listen_handle.data(), msg_size));
+    msg_size = 0;
+    NCCLCHECK(optcastSendAll(socket_fd, &msg_size, sizeof(msg_size)));
+
+    void *scomm = nullptr;
+    void *rcomm = nullptr;
+    while (scomm == nullptr || rcomm == nullptr)
     {
-      NCCLCHECK(NCCL_PLUGIN_SYMBOL.accept(lcomm, &rcomm));
+      if (scomm == nullptr)
+      {
+        NCCLCHECK(NCCL_PLUGIN_SYMBOL.connect(dev, connect_handle.data(), &scomm));
+      }
+      if (rcomm == nullptr)
+      {
+        NCCLCHECK(NCCL_PLUGIN_SYMBOL.accept(lcomm, &rcomm));
+      }
     }
-  }
-  INFO(NCCL_ALL, "connected to the reduction server: %s:%d", addr.c_str(), port);
+    handler->rcomms.push_back(rcomm);
+    handler->scomms.push_back(scomm);
 
-  handler->rcomm = rcomm;
-  handler->scomm = scomm;
+    NCCLCHECK(NCCL_PLUGIN_SYMBOL.closeListen(lcomm));
+  }
+  INFO(NCCL_ALL, "connected to the reduction server: %s:%d, nchannel: %u", addr.c_str(), port, handler->nchannel);
 
-  NCCLCHECK(NCCL_PLUGIN_SYMBOL.closeListen(lcomm));
+  handler->fd = socket_fd;
 
   return ncclSuccess;
 }
 
-static ncclResult_t optcastInit(int dev, int nranks, int rank, optcastComm **comm, optcastRequest *reqs, int nreqs)
+static ncclResult_t optcastInit(int dev, uint64_t commId, ncclRedOp_t redOp, int nranks, int rank, optcastComm **comm)
 {
   char *s = getenv("OPTCAST_REDUCTION_SERVERS");
   if (s == nullptr)
@@ -214,17 +418,10 @@ static ncclResult_t optcastInit(int dev, int nranks, int rank, optcastComm **com
     auto port = std::stoi(server.substr(pos + delimiter.length()));
 
     serverHandler handler;
-    NCCLCHECKGOTO(optcastConnect(dev, addr, port, &handler), ret, end);
+    NCCLCHECKGOTO(optcastConnect(dev, addr, port, commId, redOp, nranks, rank, &handler), ret, end);
     oComm->handlers.push_back(handler);
   }
 
-  for (int i = 0; i < nreqs; i++)
-  {
-    auto req = (optcastRequest *)reqs + i;
-    req->srequests = (void **)malloc(sizeof(void *) * oComm->handlers.size());
-    req->rrequests = (void **)malloc(sizeof(void *) * oComm->handlers.size());
-  }
-
   *comm = oComm;
   INFO(NCCL_ALL, "optcast_init done");
 
@@ -234,15 +431,16 @@ end:
   return ncclInternalError;
 }
 
-static ncclResult_t optcastAllreduce(optcastComm *oComm, optcastRequest *req, bool isHalf, void *sendData, void *recvData, void *sendMhandle, void *recvMhandle, int count)
+static ncclResult_t optcastAllreduce(optcastComm *oComm, optcastRequest *req, ncclDataType_t dataType, ncclRedOp_t redOp, void *sendData, void *recvData, void *sendMhandle, void *recvMhandle, int count)
 {
+  req->handler = oComm;
   if (oComm->bypass)
   {
     req->nreqs = 0;
     return ncclSuccess;
   }
   int tag = 0x69;
-  int size = isHalf ? count * 2 : count * 4;
+  int size = count * optcastTypeSize(dataType);
   int nsplit = oComm->nsplit;
   int nhandlers = oComm->handlers.size();
   auto idx = oComm->cursor.fetch_add(nsplit) % nhandlers;
@@ -252,41 +450,121 @@ static ncclResult_t optcastAllreduce(optcastComm *oComm, optcastRequest *req, bo
     WARN("size(%d) is not divisible by nsplit(%d)", size, nsplit);
     return ncclInvalidUsage;
   }
+  for (int i = 0; i < nsplit; i++)
+  {
+    auto &h = oComm->handlers[(idx + i) % nhandlers];
+    if (h.dataType != dataType)
+    {
+      WARN("Optcast: data type %d doesn't match the reduction server's %d", dataType, h.dataType);
+      return ncclInvalidUsage;
+    }
+    if (h.redOp != redOp)
+    {
+      WARN("Optcast: reduce operation %d doesn't match the reduction server's %d", redOp, h.redOp);
+      return ncclInvalidUsage;
+    }
+    // each channel of the server carries a part of the split
+    uint64_t pelems = ((uint64_t)(count / nsplit) + h.nchannel - 1) / h.nchannel;
+    if (pelems > h.count)
+    {
+      WARN("Optcast: count %d / nsplit %d / nchannel %u exceeds the reduction server's count %lu", count, nsplit, h.nchannel, h.count);
+      return ncclInvalidUsage;
+    }
+  }
   int csize = size / nsplit;
   auto sMr = (optcastMr *)sendMhandle;
   auto rMr = (optcastMr *)recvMhandle;
 
   TRACE(NCCL_ALL, "req(%p)/idx(%d) allreduce start", req, idx);
 
+  int typeSize = optcastTypeSize(dataType);
+  int n = 0;
   for (int i = 0; i < nsplit; i++)
   {
     auto &h = oComm->handlers[(idx + i) % nhandlers];
-    void *srequest = nullptr, *rrequest = nullptr;
-    while (srequest == nullptr || rrequest == nullptr)
+    // the split is striped over the h.nchannel channels, and each part is sent in h.nchunk
+    // requests, which the server reduces and sends back one by one. the last ones are empty when
+    // there are fewer elements than parts or chunks.
+    int celems = csize / typeSize;
+    int pelems = (celems + h.nchannel - 1) / h.nchannel;
+    for (uint32_t p = 0; p < h.nchannel; p++)
     {
-      if (srequest == nullptr)
+      int pbegin = std::min((int)p * pelems, celems);
+      int plen = std::min((int)(p + 1) * pelems, celems) - pbegin;
+      int chunk = (plen + h.nchunk - 1) / h.nchunk;
+      for (uint32_t c = 0; c < h.nchunk; c++)
       {
-        NCCLCHECK(NCCL_PLUGIN_SYMBOL.isend(h.scomm, (char *)sendData + i * csize, csize, tag, sMr->sMr, &srequest));
-      }
-      if (rrequest == nullptr)
-      {
-        void *r = (char *)recvData + i * csize;
-        NCCLCHECK(NCCL_PLUGIN_SYMBOL.irecv(h.rcomm, 1, &r, &csize, &tag, &rMr->rMr, &rrequest));
+        int offset = (pbegin + std::min((int)c * chunk, plen)) * typeSize;
+        int len = (pbegin + std::min((int)(c + 1) * chunk, plen)) * typeSize - offset;
+        void *srequest = nullptr, *rrequest = nullptr;
+        while (srequest == nullptr || rrequest == nullptr)
+        {
+          if (srequest == nullptr)
+          {
+            NCCLCHECK(NCCL_PLUGIN_SYMBOL.isend(h.scomms[p], (char *)sendData + i * csize + offset, len, tag, sMr->sMr, &srequest));
+          }
+          if (rrequest == nullptr)
+          {
+            void *r = (char *)recvData + i * csize + offset;
+            NCCLCHECK(NCCL_PLUGIN_SYMBOL.irecv(h.rcomms[p], 1, &r, &len, &tag, &rMr->rMr, &rrequest));
+          }
+        }
+        req->srequests[n] = srequest;
+        req->rrequests[n] = rrequest;
+        n++;
       }
     }
-    req->srequests[i] = srequest;
-    req->rrequests[i] = rrequest;
   }
 
-  TRACE(NCCL_ALL, "req(%p)/idx(%d) allreduce requested size: %d, csize: %d, nsplit: %d", req, idx, size, csize, nsplit);
+  TRACE(NCCL_ALL, "req(%p)/idx(%d) allreduce requested size: %d, csize: %d, nsplit: %d, nreqs: %d", req, idx, size, csize, nsplit, n);
 
-  req->nreqs = nsplit;
+  req->nreqs = n;
   req->idx = idx;
   return ncclSuccess;
 }
 
-static int optcastTest(optcastRequest *req)
+// returns an error if the reduction server aborted the communicator or closed the connection,
+// without blocking when there is nothing to read
+static ncclResult_t optcastCheckAbort(serverHandler &h)
 {
+  optcastAbort abort;
+  ssize_t n = recv(h.fd, &abort, sizeof(abort), MSG_PEEK | MSG_DONTWAIT);
+  if (n < 0 && (errno == EAGAIN || errno == EWOULDBLOCK || errno == EINTR))
+    return ncclSuccess;
+  if (n <= 0)
+  {
+    WARN("Optcast: lost the connection to the reduction server");
+    return ncclRemoteError;
+  }
+  if (n < (ssize_t)sizeof(abort))
+    return ncclSuccess; // the rest is still on the way
+  NCCLCHECK(optcastRecvAll(h.fd, &abort, sizeof(abort)));
+  if (abort.magic != OPTCAST_ABORT_MAGIC || abort.reasonSize > OPTCAST_MAX_REASON_SIZE)
+  {
+    WARN("Optcast: invalid message from the reduction server (magic 0x%08x)", abort.magic);
+    return ncclRemoteError;
+  }
+  std::string reason(abort.reasonSize, '\0');
+  NCCLCHECK(optcastRecvAll(h.fd, &reason[0], abort.reasonSize));
+  WARN("Optcast: the reduction server aborted the communicator: %s", reason.c_str());
+  return ncclRemoteError;
+}
+
+static ncclResult_t optcastPoll(optcastComm *oComm)
+{
+  if (++oComm->polls % OPTCAST_ABORT_CHECK_INTERVAL != 0)
+    return ncclSuccess;
+  for (auto &handler : oComm->handlers)
+  {
+    NCCLCHECK(optcastCheckAbort(handler));
+  }
+  return ncclSuccess;
+}
+
+static ncclResult_t optcastTest(optcastRequest *req, int *allDone)
+{
+  auto oComm = (optcastComm *)req->handler;
+  *allDone = 0;
   for (int i = 0; i < req->nreqs; i++)
   {
     int done = 0;
@@ -297,7 +575,7 @@ static int optcastTest(optcastRequest *req)
     NCCLCHECK(NCCL_PLUGIN_SYMBOL.test(req->srequests[i], &done, nullptr));
     if (done == 0)
     {
-      return -1;
+      return optcastPoll(oComm);
     }
     req->srequests[i] = nullptr;
     if (i == req->nreqs - 1)
@@ -315,12 +593,13 @@ static int optcastTest(optcastRequest *req)
     NCCLCHECK(NCCL_PLUGIN_SYMBOL.test(req->rrequests[i], &done, nullptr));
     if (done == 0)
     {
-      return -1;
+      return optcastPoll(oComm);
     }
     req->rrequests[i] = nullptr;
   }
   TRACE(NCCL_ALL, "req(%p)/idx(%d) recv done", req, req->idx);
-  return 0;
+  *allDone = 1;
+  return ncclSuccess;
 }
 
 static ncclResult_t optcastClose(void *comm)
@@ -328,8 +607,11 @@ static ncclResult_t optcastClose(void *comm)
   auto oComm = (optcastComm *)comm;
   for (auto &handler : oComm->handlers)
   {
-    NCCL_PLUGIN_SYMBOL.closeSend(handler.scomm);
-    NCCL_PLUGIN_SYMBOL.closeRecv(handler.rcomm);
+    for (auto scomm : handler.scomms)
+      NCCL_PLUGIN_SYMBOL.closeSend(scomm);
+    for (auto rcomm : handler.rcomms)
+      NCCL_PLUGIN_SYMBOL.closeRecv(rcomm);
+    close(handler.fd);
   }
   delete oComm;
   return ncclSuccess;
@@ -343,8 +625,10 @@ static ncclResult_t optcastRegMr(void *comm, void *data, int size, int type, opt
   // the first call of regMr will register the memory, the rest will just return the same mr from cache
   for (auto &handler : oComm->handlers)
   {
-    NCCLCHECK(NCCL_PLUGIN_SYMBOL.regMr(handler.rcomm, data, size, type, &mr->rMr));
-    NCCLCHECK(NCCL_PLUGIN_SYMBOL.regMr(handler.scomm, data, size, type, &mr->sMr));
+    for (auto rcomm : handler.rcomms)
+      NCCLCHECK(NCCL_PLUGIN_SYMBOL.regMr(rcomm, data, size, type, &mr->rMr));
+    for (auto scomm : handler.scomms)
+      NCCLCHECK(NCCL_PLUGIN_SYMBOL.regMr(scomm, data, size, type, &mr->sMr));
   }
   *mhandle = mr;
   return ncclSuccess;
@@ -355,13 +639,81 @@ static ncclResult_t optcastDeregMr(void *comm, optcastMr *mr)
   auto oComm = (optcastComm *)comm;
   for (auto &handler : oComm->handlers)
   {
-    NCCLCHECK(NCCL_PLUGIN_SYMBOL.deregMr(handler.rcomm, mr->rMr));
-    NCCLCHECK(NCCL_PLUGIN_SYMBOL.deregMr(handler.scomm, mr->sMr));
+    for (auto rcomm : handler.rcomms)
+      NCCLCHECK(NCCL_PLUGIN_SYMBOL.deregMr(rcomm, mr->rMr));
+    for (auto scomm : handler.scomms)
+      NCCLCHECK(NCCL_PLUGIN_SYMBOL.deregMr(scomm, mr->sMr));
   }
   delete mr;
   return ncclSuccess;
 }
 
+// the communicator id the reduction servers group the ranks with. every rank has the listen
+// handle of rank 0, which is unique while the communicator is being set up, and each reduction
+// operator gets a communicator of its own.
+static uint64_t optcastCommId(const void *handle, ncclRedOp_t redOp)
+{
+  // FNV-1a
+  uint64_t h = 0xcbf29ce484222325;
+  for (int i = 0; i < NCCL_NET_HANDLE_MAXSIZE; i++)
+  {
+    h ^= ((const unsigned char *)handle)[i];
+    h *= 0x100000001b3;
+  }
+  h ^= (unsigned char)redOp;
+  h *= 0x100000001b3;
+  return h;
+}
+
+// makes room in the requests for the requests of the servers of oComm: one per chunk of each
+// channel of each split of a message
+static ncclResult_t optcastReserveSlots(optcastCollComm *cComm, optcastComm *oComm)
+{
+  size_t nchunk = 1;
+  for (auto &handler : oComm->handlers)
+    nchunk = std::max(nchunk, (size_t)handler.nchunk * handler.nchannel);
+  size_t nslots = std::max((size_t)oComm->nsplit, oComm->handlers.size()) * nchunk;
+  if (nslots <= cComm->nslots)
+    return ncclSuccess;
+  for (int i = 0; i < MAX_REQUESTS; i++)
+  {
+    auto req = cComm->reqs + i;
+    // the requests in flight keep their contents
+    void **srequests = (void **)realloc(req->srequests, sizeof(void *) * nslots);
+    if (srequests == nullptr)
+      return ncclSystemError;
+    req->srequests = srequests;
+    void **rrequests = (void **)realloc(req->rrequests, sizeof(void *) * nslots);
+    if (rrequests == nullptr)
+      return ncclSystemError;
+    req->rrequests = rrequests;
+  }
+  cComm->nslots = nslots;
+  return ncclSuccess;
+}
+
+// the communicator of the reduction servers for redOp, connected by its first allreduce. the
+// memory registered so far is registered with it as well.
+static ncclResult_t optcastGetComm(optcastCollComm *cComm, ncclRedOp_t redOp, optcastComm **comm)
+{
+  if ((int)redOp < 0 || (int)redOp >= OPTCAST_NUM_OPS)
+  {
+    WARN("Optcast: unsupported reduce operation %d", redOp);
+    return ncclInvalidUsage;
+  }
+  if (cComm->optcastComms[redOp] == nullptr)
+  {
+    optcastComm *oComm;
+    NCCLCHECK(optcastInit(cComm->dev, optcastCommId(cComm->handle, redOp), redOp, cComm->nranks, cComm->rank, &oComm));
+    cComm->optcastComms[redOp] = oComm;
+    NCCLCHECK(optcastReserveSlots(cComm, oComm));
+    for (auto mh : cComm->mhandles)
+      NCCLCHECK(optcastRegMr(oComm, mh->data, mh->size, mh->type, &mh->mr[redOp]));
+  }
+  *comm = cComm->optcastComms[redOp];
+  return ncclSuccess;
+}
+
 static ncclResult_t ncclOptcastInit(ncclDebugLogger_t logFunction)
 {
   struct timeval tval;
@@ -407,9 +759,12 @@ static ncclResult_t ncclOptcastConnect(void *handles[], int nranks, int rank, vo
   struct optcastListenComm *lComm = (struct optcastListenComm *)listenComm;
   struct optcastCollComm *cComm;
 
-  NCCLCHECK(ncclIbMalloc((void **)&cComm, sizeof(struct optcastCollComm)));
+  cComm = new optcastCollComm();
   NCCLCHECK(ncclIbMalloc((void **)&cComm->reqs, sizeof(struct optcastRequest) * MAX_REQUESTS));
-  NCCLCHECK(optcastInit(lComm->dev, nranks, rank, &cComm->optcastComm, cComm->reqs, MAX_REQUESTS));
+  // the communicators of the reduction servers are connected by the first allreduce of each
+  // operator
+  memcpy(cComm->handle, handles[0], NCCL_NET_HANDLE_MAXSIZE);
+  cComm->dev = lComm->dev;
 
   cComm->nranks = nranks;
   cComm->rank = rank;
@@ -439,17 +794,8 @@ static ncclResult_t ncclOptcastConnect(void *handles[], int nranks, int rank, vo
 
 static ncclResult_t ncclOptcastReduceSupport(ncclDataType_t dataType, ncclRedOp_t redOp, int *supported)
 {
-  if (dataType != ncclFloat32 && dataType != ncclFloat16)
-  {
-    *supported = 0;
-    return ncclSuccess;
-  }
-  if (redOp != ncclSum)
-  {
-    *supported = 0;
-    return ncclSuccess;
-  }
-  *supported = 1;
+  // only the type the servers reduce, the others fall back to NCCL's own algorithms
+  *supported = (int)dataType == optcastDataType() && (int)redOp >= 0 && (int)redOp < OPTCAST_NUM_OPS;
   return ncclSuccess;
 }
 
@@ -466,9 +812,16 @@ static ncclResult_t ncclOptcastRegMr(void *collComm, void *data, int size, int t
   NCCLCHECK(ncclIbMalloc((void **)&mh, sizeof(struct optcastMemHandle)));
 
   mh->type = type;
+  mh->data = data;
+  mh->size = size;
 
-  NCCLCHECK((ncclResult_t)optcastRegMr(cComm->optcastComm, data, size, type, &mh->mr));
+  for (int op = 0; op < OPTCAST_NUM_OPS; op++)
+  {
+    if (cComm->optcastComms[op] != nullptr)
+      NCCLCHECK((ncclResult_t)optcastRegMr(cComm->optcastComms[op], data, size, type, &mh->mr[op]));
+  }
   NCCLCHECK(NCCL_PLUGIN_SYMBOL.regMr(cComm->recvComm, data, size, type, &mh->ncclIbMr));
+  cComm->mhandles.push_back(mh);
 
   *mhandle = mh;
   return ncclSuccess;
@@ -479,7 +832,12 @@ static ncclResult_t ncclOptcastDeregMr(void *collComm, void *mhandle)
   struct optcastCollComm *cComm = (struct optcastCollComm *)collComm;
   struct optcastMemHandle *mh = (struct optcastMemHandle *)mhandle;
 
-  NCCLCHECK((ncclResult_t)optcastDeregMr(cComm->optcastComm, mh->mr));
+  cComm->mhandles.erase(std::remove(cComm->mhandles.begin(), cComm->mhandles.end(), mh), cComm->mhandles.end());
+  for (int op = 0; op < OPTCAST_NUM_OPS; op++)
+  {
+    if (mh->mr[op] != nullptr)
+      NCCLCHECK((ncclResult_t)optcastDeregMr(cComm->optcastComms[op], mh->mr[op]));
+  }
   NCCLCHECK(NCCL_PLUGIN_SYMBOL.deregMr(cComm->recvComm, mh->ncclIbMr));
 
   free(mh);
@@ -512,21 +870,18 @@ static ncclResult_t ncclOptcastIallreduce(void *collComm, void *sendData, void *
   struct optcastMemHandle *sMh = (struct optcastMemHandle *)sendMhandle;
   struct optcastMemHandle *rMh = (struct optcastMemHandle *)recvMhandle;
 
-  if (dataType != ncclFloat32 && dataType != ncclFloat16)
+  if (optcastTypeSize(dataType) == 0)
   {
     WARN("Optcast: unsupported data type\n");
     return ncclInternalError;
   }
 
-  if (redOp != ncclSum)
-  {
-    WARN("Optcast: unsupported reduce operation\n");
-    return ncclInternalError;
-  }
+  optcastComm *oComm;
+  NCCLCHECK(optcastGetComm(cComm, redOp, &oComm));
 
   struct optcastRequest *req;
   NCCLCHECK(ncclOptcastGetRequest(cComm->reqs, &req));
-  NCCLCHECK(optcastAllreduce(cComm->optcastComm, req, dataType == ncclFloat16, sendData, recvData, sMh->mr, rMh->mr, count));
+  NCCLCHECK(optcastAllreduce(oComm, req, dataType, redOp, sendData, recvData, sMh->mr[redOp], rMh->mr[redOp], count));
 
   req->requestType = NCCL_OPTCAST_REQ_COLL;
   *request = req;
@@ -567,16 +922,12 @@ static ncclResult_t ncclOptcastTest(void *request, int *done, int *size)
     return ncclSuccess;
   }
 
-  if (optcastTest(req) == 0)
+  NCCLCHECK(optcastTest(req, done));
+  if (*done == 1)
   {
-    *done = 1;
     *size = req->size;
     req->used = 0;
   }
-  else
-  {
-    *done = 0;
-  }
 
   return ncclSuccess;
 }
@@ -587,9 +938,19 @@ static ncclResult_t ncclOptcastCloseColl(void *collComm)
 
   NCCLCHECK(NCCL_PLUGIN_SYMBOL.closeRecv(cComm->recvComm));
   NCCLCHECK(NCCL_PLUGIN_SYMBOL.closeSend(cComm->sendComm));
-  NCCLCHECK(optcastClose(cComm->optcastComm));
+  for (int op = 0; op < OPTCAST_NUM_OPS; op++)
+  {
+    if (cComm->optcastComms[op] != nullptr)
+      NCCLCHECK(optcastClose(cComm->optcastComms[op]));
+  }
+  for (int i = 0; i < MAX_REQUESTS; i++)
+  {
+    free(cComm->reqs[i].srequests);
+    free(cComm->reqs[i].rrequests);
+  }
+  free(cComm->reqs);
 
-  free(cComm);
+  delete cComm;
   return ncclSuccess;
 }
 
@@ -21,6 +21,4 @@ fn main() {
     bindings
         .write_to_file(out_file)
         .expect("Couldn't write bindings!");
-
-    println!("cargo:rustc-link-lib=nccl-net");
 }
@@ -0,0 +1,727 @@
+/*
+ * Copyright (c) 2024, the Optcast Authors. All rights reserved.
+ *
+ * See LICENSE for license information
+ */
+
+// Bootstrap protocol spoken over the TCP stream before the NCCL comms are set up.
+//
+// client -> server: Hello   (magic, version, comm_id, data_type, reduce_op, nrank, rank, nchannel,
+//                            nchunk, count)
+// server -> client: Reply   (status, data_type, reduce_op, max count, nchunk, nchannel, reason)
+// then, per channel:
+// server -> client: handle  (len, bytes of the network, local len, bytes for the same host)
+// client -> server: handle  (len, bytes of the network, local len, bytes for the same host)
+// the stream is then kept open for the lifetime of the communicator. when the communicator is
+// torn down because a rank failed, the server tells the other ranks:
+// server -> client: Abort   (magic, reason)
+//
+// All integers are little endian.
+
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+
+use nccl_net_sys as ffi;
+
+use crate::transport::Handle;
+use crate::utils::{Args, DataType, ReduceOp};
+
+pub(crate) const MAGIC: u32 = 0x5443_504f; // "OPCT"
+pub(crate) const VERSION: u32 = 8;
+const ABORT_MAGIC: u32 = 0x5452_4241; // "ABRT"
+
+pub(crate) const MAX_HANDLE_SIZE: usize = ffi::NCCL_NET_HANDLE_MAXSIZE as usize;
+const MAX_REASON_SIZE: usize = 1024;
+
+// wildcards for Hello::data_type and Hello::reduce_op, used by peers that don't know them
+// at connect time
+const ANY_DATA_TYPE: u32 = u32::MAX;
+const ANY_REDUCE_OP: u32 = u32::MAX;
+// Hello::rank of a peer that doesn't know its rank id
+const ANY_RANK: u32 = u32::MAX;
+
+const STATUS_ACCEPT: u32 = 0;
+const STATUS_REJECT: u32 = 1;
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    Io(std::io::Error),
+    InvalidMagic(u32),
+    UnsupportedVersion(u32),
+    UnknownDataType(u32),
+    UnknownReduceOp(u32),
+    InvalidStatus(u32),
+    InvalidAbort(u32),
+    HandleTooLarge(usize),
+    CountTooLarge {
+        server: usize,
+        client: usize,
+    },
+    RankOutOfRange {
+        rank: usize,
+        nrank: usize,
+    },
+    RankInUse(usize),
+    RankRequired,
+    CommunicatorFull(u64),
+    AvgWithUpstream,
+    ReasonTooLarge(usize),
+    Mismatch {
+        field: &'static str,
+        server: String,
+        client: String,
+    },
+    Rejected(String),
+    Aborted(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::InvalidMagic(v) => write!(f, "invalid magic: 0x{:08x}", v),
+            Error::UnsupportedVersion(v) => {
+                write!(
+                    f,
+                    "unsupported protocol version: {} (expected {})",
+                    v, VERSION
+                )
+            }
+            Error::UnknownDataType(v) => write!(f, "unknown data type: {}", v),
+            Error::UnknownReduceOp(v) => write!(f, "unknown reduce op: {}", v),
+            Error::InvalidStatus(v) => write!(f, "invalid reply status: {}", v),
+            Error::InvalidAbort(v) => write!(f, "invalid abort magic: 0x{:08x}", v),
+            Error::HandleTooLarge(v) => {
+                write!(f, "handle too large: {} bytes (max {})", v, MAX_HANDLE_SIZE)
+            }
+            Error::CountTooLarge { server, client } => write!(
+                f,
+                "count too large: client {}, server max {}",
+                client, server
+            ),
+            Error::RankOutOfRange { rank, nrank } => {
+                write!(f, "rank {} out of range (nrank {})", rank, nrank)
+            }
+            Error::RankInUse(v) => write!(f, "rank {} already connected", v),
+            Error::RankRequired => write!(f, "rank id is required in deterministic mode"),
+            Error::CommunicatorFull(v) => {
+                write!(
+                    f,
+                    "all the ranks of communicator 0x{:016x} are connected",
+                    v
+                )
+            }
+            Error::AvgWithUpstream => write!(f, "avg can't be used with an upstream server"),
+            Error::ReasonTooLarge(v) => write!(
+                f,
+                "reject reason too large: {} bytes (max {})",
+                v, MAX_REASON_SIZE
+            ),
+            Error::Mismatch {
+                field,
+                server,
+                client,
+            } => write!(
+                f,
+                "{} mismatch: server {}, client {}",
+                field, server, client
+            ),
+            Error::Rejected(reason) => write!(f, "rejected by server: {}", reason),
+            Error::Aborted(reason) => write!(f, "aborted by server: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32, Error> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64, Error> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Hello {
+    pub comm_id: u64,                // ranks with the same id are reduced together
+    pub data_type: Option<DataType>, // None: any
+    pub reduce_op: Option<ReduceOp>, // None: any
+    pub nrank: usize,                // 0: unspecified
+    pub rank: Option<usize>,         // None: unspecified
+    pub nchannel: usize, // comm pairs per server, 0: any
+    pub nchunk: usize,   // requests a message is split into, 0: any
+    pub count: usize,  // max elements per message, 0: unspecified
+}
+
+impl Hello {
+    pub(crate) fn from_args(args: &Args) -> Self {
+        Hello {
+            comm_id: args.comm_id,
+            data_type: Some(args.data_type),
+            reduce_op: Some(args.reduce_op),
+            nrank: args.nrank,
+            rank: args.rank,
+            nchannel: args.nchannel,
+            nchunk: args.nchunk,
+            count: args.count,
+        }
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        let data_type = self.data_type.map_or(ANY_DATA_TYPE, |v| v.to_nccl());
+        let reduce_op = self.reduce_op.map_or(ANY_REDUCE_OP, |v| v.to_nccl());
+        let rank = self.rank.map_or(ANY_RANK, |v| v as u32);
+        let mut buf = Vec::with_capacity(48);
+        buf.extend_from_slice(&MAGIC.to_le_bytes());
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&self.comm_id.to_le_bytes());
+        buf.extend_from_slice(&data_type.to_le_bytes());
+        buf.extend_from_slice(&reduce_op.to_le_bytes());
+        buf.extend_from_slice(&(self.nrank as u32).to_le_bytes());
+        buf.extend_from_slice(&rank.to_le_bytes());
+        buf.extend_from_slice(&(self.nchannel as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.nchunk as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.count as u64).to_le_bytes());
+        w.write_all(&buf)?;
+        Ok(())
+    }
+
+    pub(crate) fn read_from<R: Read>(r: &mut R) -> Result<Self, Error> {
+        let magic = read_u32(r)?;
+        if magic != MAGIC {
+            return Err(Error::InvalidMagic(magic));
+        }
+        let version = read_u32(r)?;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let comm_id = read_u64(r)?;
+        let data_type = match read_u32(r)? {
+            ANY_DATA_TYPE => None,
+            v => Some(DataType::from_nccl(v).ok_or(Error::UnknownDataType(v))?),
+        };
+        let reduce_op = match read_u32(r)? {
+            ANY_REDUCE_OP => None,
+            v => Some(ReduceOp::from_nccl(v).ok_or(Error::UnknownReduceOp(v))?),
+        };
+        let nrank = read_u32(r)? as usize;
+        let rank = match read_u32(r)? {
+            ANY_RANK => None,
+            v => Some(v as usize),
+        };
+        let nchannel = read_u32(r)? as usize;
+        let nchunk = read_u32(r)? as usize;
+        let count = read_u64(r)? as usize;
+        Ok(Hello {
+            comm_id,
+            data_type,
+            reduce_op,
+            nrank,
+            rank,
+            nchannel,
+            nchunk,
+            count,
+        })
+    }
+
+    // checks the configuration shared by all the communicators of the server. the reduce
+    // operator is chosen per communicator.
+    pub(crate) fn validate(&self, args: &Args) -> Result<(), Error> {
+        if let Some(data_type) = self.data_type {
+            check("data_type", args.data_type, data_type)?;
+        }
+        // an upstream server would average the averages of its children
+        if !args.upstream.is_empty() && self.reduce_op == Some(ReduceOp::Avg) {
+            return Err(Error::AvgWithUpstream);
+        }
+        // a peer that leaves it open opens as many channels as the server tells it
+        if self.nchannel != 0 {
+            check("nchannel", args.nchannel, self.nchannel)?;
+        }
+        // the chunks of a message are matched with the server buffers by their order
+        if self.nchunk != 0 {
+            check("nchunk", args.nchunk, self.nchunk)?;
+        }
+        // messages may be shorter than the server buffers
+        if self.count > args.count {
+            return Err(Error::CountTooLarge {
+                server: args.count,
+                client: self.count,
+            });
+        }
+        Ok(())
+    }
+
+    // checks that the peer can join its communicator of nrank ranks reduced with reduce_op.
+    // connected[i] tells whether rank i already has a connection.
+    pub(crate) fn validate_member(
+        &self,
+        nrank: usize,
+        reduce_op: ReduceOp,
+        connected: &[bool],
+        deterministic: bool,
+    ) -> Result<(), Error> {
+        if self.nrank != 0 {
+            check("nrank", nrank, self.nrank)?;
+        }
+        if let Some(v) = self.reduce_op {
+            check("reduce_op", reduce_op, v)?;
+        }
+        match self.rank {
+            Some(rank) if rank >= nrank => Err(Error::RankOutOfRange { rank, nrank }),
+            Some(rank) if connected.get(rank) == Some(&true) => Err(Error::RankInUse(rank)),
+            None if deterministic => Err(Error::RankRequired),
+            None if connected.iter().filter(|v| **v).count() >= nrank => {
+                Err(Error::CommunicatorFull(self.comm_id))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn check<T: PartialEq + Display>(field: &'static str, server: T, client: T) -> Result<(), Error> {
+    if server == client {
+        Ok(())
+    } else {
+        Err(Error::Mismatch {
+            field,
+            server: server.to_string(),
+            client: client.to_string(),
+        })
+    }
+}
+
+fn write_reply<W: Write>(
+    w: &mut W,
+    status: u32,
+    args: &Args,
+    reduce_op: ReduceOp,
+    reason: &str,
+) -> Result<(), Error> {
+    let reason = &reason.as_bytes()[..reason.len().min(MAX_REASON_SIZE)];
+    let mut buf = Vec::with_capacity(32 + reason.len());
+    buf.extend_from_slice(&status.to_le_bytes());
+    buf.extend_from_slice(&args.data_type.to_nccl().to_le_bytes());
+    buf.extend_from_slice(&reduce_op.to_nccl().to_le_bytes());
+    buf.extend_from_slice(&(args.count as u64).to_le_bytes());
+    buf.extend_from_slice(&(args.nchunk as u32).to_le_bytes());
+    buf.extend_from_slice(&(args.nchannel as u32).to_le_bytes());
+    buf.extend_from_slice(&(reason.len() as u32).to_le_bytes());
+    buf.extend_from_slice(reason);
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+// server side of the handshake. the Hello is checked against args and then by member, which
+// decides whether the peer can join its communicator. a peer that doesn't choose a reduce
+// operator gets --reduce-op. on a protocol error or a mismatch, a reject reply is sent back
+// before returning the error.
+pub(crate) fn accept<S: Read + Write>(
+    stream: &mut S,
+    args: &Args,
+    member: impl FnOnce(&Hello) -> Result<(), Error>,
+) -> Result<Hello, Error> {
+    let hello = read_hello(stream, args)?;
+    reply(stream, args, &hello, member(&hello))?;
+    Ok(hello)
+}
+
+// the first half of accept, which reads the Hello and checks it against args. the server reads
+// it without holding the communicators, which reply then decides about.
+pub(crate) fn read_hello<S: Read + Write>(stream: &mut S, args: &Args) -> Result<Hello, Error> {
+    let res = Hello::read_from(stream).and_then(|mut hello| {
+        hello.reduce_op.get_or_insert(args.reduce_op);
+        hello.validate(args)?;
+        Ok(hello)
+    });
+    match res {
+        Err(Error::Io(e)) => Err(Error::Io(e)),
+        Err(e) => Err(reject(stream, args, e)),
+        Ok(hello) => Ok(hello),
+    }
+}
+
+// the second half of accept, which tells the peer whether it joins its communicator
+pub(crate) fn reply<S: Write>(
+    stream: &mut S,
+    args: &Args,
+    hello: &Hello,
+    member: Result<(), Error>,
+) -> Result<(), Error> {
+    match member {
+        Ok(()) => {
+            let reduce_op = hello.reduce_op.unwrap_or(args.reduce_op);
+            write_reply(stream, STATUS_ACCEPT, args, reduce_op, "")
+        }
+        Err(e) => Err(reject(stream, args, e)),
+    }
+}
+
+fn reject<S: Write>(stream: &mut S, args: &Args, e: Error) -> Error {
+    // the peer may already be gone, the original error is more useful
+    let _ = write_reply(stream, STATUS_REJECT, args, args.reduce_op, &e.to_string());
+    e
+}
+
+// client side of the handshake
+pub(crate) fn connect<S: Read + Write>(stream: &mut S, hello: &Hello) -> Result<(), Error> {
+    hello.write_to(stream)?;
+    let status = read_u32(stream)?;
+    let _data_type = read_u32(stream)?;
+    let _reduce_op = read_u32(stream)?;
+    let _count = read_u64(stream)?;
+    let _nchunk = read_u32(stream)?;
+    let _nchannel = read_u32(stream)?;
+    let len = read_u32(stream)? as usize;
+    if len > MAX_REASON_SIZE {
+        return Err(Error::ReasonTooLarge(len));
+    }
+    let mut reason = vec![0u8; len];
+    stream.read_exact(&mut reason)?;
+    match status {
+        STATUS_ACCEPT => Ok(()),
+        STATUS_REJECT => Err(Error::Rejected(
+            String::from_utf8_lossy(&reason).into_owned(),
+        )),
+        v => Err(Error::InvalidStatus(v)),
+    }
+}
+
+// each part of a handle is at most MAX_HANDLE_SIZE bytes, which the plugin relies on
+pub(crate) fn send_handle<W: Write>(w: &mut W, handle: &Handle) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(8 + handle.net.len() + handle.local.len());
+    for part in [&handle.net, &handle.local] {
+        if part.len() > MAX_HANDLE_SIZE {
+            return Err(Error::HandleTooLarge(part.len()));
+        }
+        buf.extend_from_slice(&(part.len() as u32).to_le_bytes());
+        buf.extend_from_slice(part);
+    }
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+pub(crate) fn recv_handle<R: Read>(r: &mut R) -> Result<Handle, Error> {
+    let size = read_u32(r)?;
+    // a communicator torn down while the channels are set up is aborted in place of a handle
+    if size == ABORT_MAGIC {
+        return Err(Error::Aborted(read_reason(r)?));
+    }
+    let net = read_part(r, size as usize)?;
+    let size = read_u32(r)? as usize;
+    let local = read_part(r, size)?;
+    Ok(Handle { net, local })
+}
+
+fn read_part<R: Read>(r: &mut R, size: usize) -> Result<Vec<u8>, Error> {
+    if size > MAX_HANDLE_SIZE {
+        return Err(Error::HandleTooLarge(size));
+    }
+    let mut part = vec![0u8; size];
+    r.read_exact(&mut part)?;
+    Ok(part)
+}
+
+pub(crate) fn send_abort<W: Write>(w: &mut W, reason: &str) -> Result<(), Error> {
+    let reason = &reason.as_bytes()[..reason.len().min(MAX_REASON_SIZE)];
+    let mut buf = Vec::with_capacity(8 + reason.len());
+    buf.extend_from_slice(&ABORT_MAGIC.to_le_bytes());
+    buf.extend_from_slice(&(reason.len() as u32).to_le_bytes());
+    buf.extend_from_slice(reason);
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+// blocks until the server aborts the communicator and returns the reason
+pub(crate) fn recv_abort<R: Read>(r: &mut R) -> Result<String, Error> {
+    let magic = read_u32(r)?;
+    if magic != ABORT_MAGIC {
+        return Err(Error::InvalidAbort(magic));
+    }
+    read_reason(r)
+}
+
+fn read_reason<R: Read>(r: &mut R) -> Result<String, Error> {
+    let len = read_u32(r)? as usize;
+    if len > MAX_REASON_SIZE {
+        return Err(Error::ReasonTooLarge(len));
+    }
+    let mut reason = vec![0u8; len];
+    r.read_exact(&mut reason)?;
+    Ok(String::from_utf8_lossy(&reason).into_owned())
+}
+
+// test
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::net::{TcpListener, TcpStream};
+
+    fn server_args() -> Args {
+        Args::parse_from(["--verbose", "--nrank", "4", "--data-type", "f32"])
+    }
+
+    fn do_handshake(hello: Hello) -> (Result<Hello, Error>, Result<(), Error>) {
+        do_handshake_with(hello, server_args(), vec![])
+    }
+
+    fn do_handshake_with(
+        hello: Hello,
+        args: Args,
+        connected: Vec<bool>,
+    ) -> (Result<Hello, Error>, Result<(), Error>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            accept(&mut stream, &args, |hello| {
+                hello.validate_member(args.nrank, args.reduce_op, &connected, args.deterministic)
+            })
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let client = connect(&mut stream, &hello);
+        (server.join().unwrap(), client)
+    }
+
+    #[test]
+    fn test_hello_roundtrip() {
+        let hello = Hello {
+            comm_id: 0x0123_4567_89ab_cdef,
+            data_type: Some(DataType::BF16),
+            reduce_op: Some(ReduceOp::Avg),
+            nrank: 8,
+            rank: Some(5),
+            nchannel: 2,
+            nchunk: 4,
+            count: 1 << 20,
+        };
+        let mut buf = vec![];
+        hello.write_to(&mut buf).unwrap();
+        assert_eq!(Hello::read_from(&mut buf.as_slice()).unwrap(), hello);
+    }
+
+    #[test]
+    fn test_handshake_accept() {
+        let hello = Hello {
+            comm_id: 0,
+            data_type: None,
+            reduce_op: None,
+            nrank: 0,
+            rank: None,
+            nchannel: 1,
+            nchunk: 0,
+            count: 0,
+        };
+        let (server, client) = do_handshake(hello.clone());
+        // the server's reduce operator is the default
+        let reduce_op = Some(server_args().reduce_op);
+        assert_eq!(server.unwrap(), Hello { reduce_op, ..hello });
+        client.unwrap();
+    }
+
+    #[test]
+    fn test_handshake_reject() {
+        let mut hello = Hello::from_args(&server_args());
+        hello.data_type = Some(DataType::F16);
+        let (server, client) = do_handshake(hello);
+        assert!(matches!(
+            server,
+            Err(Error::Mismatch {
+                field: "data_type",
+                ..
+            })
+        ));
+        match client {
+            Err(Error::Rejected(reason)) => assert!(reason.contains("data_type"), "{}", reason),
+            v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_handshake_reduce_op() {
+        // any operator can start a communicator, whose ranks must then all use it
+        let args = server_args();
+        let mut hello = Hello::from_args(&args);
+        hello.reduce_op = Some(ReduceOp::Max);
+        hello.validate(&args).unwrap();
+        hello.validate_member(4, ReduceOp::Max, &[], false).unwrap();
+        assert!(matches!(
+            hello.validate_member(4, ReduceOp::Sum, &[], false),
+            Err(Error::Mismatch {
+                field: "reduce_op",
+                ..
+            })
+        ));
+        let (server, client) = do_handshake(hello.clone());
+        assert!(matches!(
+            server,
+            Err(Error::Mismatch {
+                field: "reduce_op",
+                ..
+            })
+        ));
+        assert!(matches!(client, Err(Error::Rejected(_))));
+
+        // an upstream server can't average the averages of its children
+        let args = Args::parse_from(["--verbose", "--upstream", "localhost:8918"]);
+        hello.reduce_op = Some(ReduceOp::Avg);
+        assert!(matches!(hello.validate(&args), Err(Error::AvgWithUpstream)));
+    }
+
+    #[test]
+    fn test_handshake_count() {
+        let mut hello = Hello::from_args(&server_args());
+        hello.count /= 3;
+        let (server, client) = do_handshake(hello.clone());
+        assert_eq!(server.unwrap(), hello);
+        client.unwrap();
+
+        hello.count = server_args().count + 1;
+        let (server, client) = do_handshake(hello);
+        assert!(matches!(server, Err(Error::CountTooLarge { .. })));
+        assert!(matches!(client, Err(Error::Rejected(_))));
+    }
+
+    #[test]
+    fn test_handshake_nchunk() {
+        let mut hello = Hello::from_args(&server_args());
+        hello.nchunk = 0;
+        let (server, client) = do_handshake(hello.clone());
+        assert_eq!(server.unwrap(), hello);
+        client.unwrap();
+
+        hello.nchunk = 4;
+        let (server, client) = do_handshake(hello);
+        assert!(matches!(
+            server,
+            Err(Error::Mismatch {
+                field: "nchunk",
+                ..
+            })
+        ));
+        assert!(matches!(client, Err(Error::Rejected(_))));
+    }
+
+    #[test]
+    fn test_handshake_nchannel() {
+        // the plugin opens as many channels as the server tells it
+        let args = Args::parse_from(["--verbose", "--nrank", "4", "--nchannel", "2"]);
+        let mut hello = Hello::from_args(&args);
+        hello.nchannel = 0;
+        let (server, client) = do_handshake_with(hello.clone(), args.clone(), vec![]);
+        assert_eq!(server.unwrap(), hello);
+        client.unwrap();
+        let mut buf = vec![];
+        write_reply(&mut buf, STATUS_ACCEPT, &args, args.reduce_op, "").unwrap();
+        assert_eq!(buf[24..28], 2u32.to_le_bytes());
+
+        hello.nchannel = 1;
+        let (server, client) = do_handshake_with(hello, args, vec![]);
+        assert!(matches!(
+            server,
+            Err(Error::Mismatch {
+                field: "nchannel",
+                ..
+            })
+        ));
+        assert!(matches!(client, Err(Error::Rejected(_))));
+    }
+
+    #[test]
+    fn test_handshake_rank() {
+        let mut hello = Hello::from_args(&server_args());
+        hello.rank = Some(2);
+        let (server, client) = do_handshake_with(hello.clone(), server_args(), vec![true; 2]);
+        assert_eq!(server.unwrap(), hello);
+        client.unwrap();
+
+        let (server, client) = do_handshake_with(hello.clone(), server_args(), vec![true; 3]);
+        assert!(matches!(server, Err(Error::RankInUse(2))));
+        assert!(matches!(client, Err(Error::Rejected(_))));
+
+        hello.rank = Some(4);
+        let (server, _) = do_handshake(hello.clone());
+        assert!(matches!(
+            server,
+            Err(Error::RankOutOfRange { rank: 4, nrank: 4 })
+        ));
+
+        hello.rank = None;
+        let (server, client) = do_handshake_with(hello, server_args(), vec![true; 4]);
+        assert!(matches!(server, Err(Error::CommunicatorFull(0))));
+        assert!(matches!(client, Err(Error::Rejected(_))));
+    }
+
+    #[test]
+    fn test_handshake_deterministic() {
+        let args = Args::parse_from(["--verbose", "--deterministic", "--nrank", "4"]);
+        let mut hello = Hello::from_args(&args);
+        let (server, client) = do_handshake_with(hello.clone(), args.clone(), vec![]);
+        assert!(matches!(server, Err(Error::RankRequired)));
+        assert!(matches!(client, Err(Error::Rejected(_))));
+
+        hello.rank = Some(0);
+        let (server, client) = do_handshake_with(hello, args, vec![]);
+        server.unwrap();
+        client.unwrap();
+    }
+
+    #[test]
+    fn test_abort() {
+        let mut buf = vec![];
+        send_abort(&mut buf, "rank 3 disconnected").unwrap();
+        assert_eq!(
+            recv_abort(&mut buf.as_slice()).unwrap(),
+            "rank 3 disconnected"
+        );
+        // a closed stream is not an abort
+        assert!(matches!(recv_abort(&mut [].as_slice()), Err(Error::Io(_))));
+        // the abort may come before the handles of the channels
+        assert!(matches!(
+            recv_handle(&mut buf.as_slice()),
+            Err(Error::Aborted(reason)) if reason == "rank 3 disconnected"
+        ));
+    }
+
+    #[test]
+    fn test_handle_roundtrip() {
+        let handle = Handle {
+            net: vec![7u8; MAX_HANDLE_SIZE],
+            local: b"OSHM".to_vec(),
+        };
+        let mut buf = vec![];
+        send_handle(&mut buf, &handle).unwrap();
+        assert_eq!(recv_handle(&mut buf.as_slice()).unwrap(), handle);
+    }
+
+    #[test]
+    fn test_handle_too_large() {
+        let mut buf = vec![];
+        buf.extend_from_slice(&((MAX_HANDLE_SIZE + 1) as u32).to_le_bytes());
+        assert!(matches!(
+            recv_handle(&mut buf.as_slice()),
+            Err(Error::HandleTooLarge(_))
+        ));
+        // each part has the limit of its own
+        let handle = Handle {
+            net: vec![0u8; MAX_HANDLE_SIZE],
+            local: vec![0u8; MAX_HANDLE_SIZE + 1],
+        };
+        assert!(matches!(
+            send_handle(&mut vec![], &handle),
+            Err(Error::HandleTooLarge(_))
+        ));
+    }
+}
@@ -5,25 +5,35 @@
  */
 
 use std::hint;
-use std::io::{Read, Write};
+use std::io::Read;
 use std::net::{TcpListener, TcpStream};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
-use half::{bf16, f16};
-use log::{info, trace};
+use log::{info, trace, warn};
 
+use crate::bootstrap;
 use crate::utils::*;
 
-use crate::nccl_net;
-use crate::nccl_net::{Comm, Request};
+use crate::transport::Transport;
 
 use crate::partitioned_vec::PartitionedVec;
 
-fn do_client<T: Float>(args: &Args, comms: Vec<(Comm, Comm)>) {
+// `aborted` is set with the reason once a server aborts the communicator
+fn do_client<T: Element, N: Transport>(
+    net: &N,
+    args: &Args,
+    comms: Vec<(N::Comm, N::Comm)>,
+    aborted: &OnceLock<String>,
+) -> Result<(), String> {
     let size = args.count * std::mem::size_of::<T>();
     let initial: T = T::from_f32(2.0).unwrap();
 
     let tag = 0x69;
+    // a message is sent as nchunk requests of at most chunk elements, in order
+    let chunk = args.count.div_ceil(args.nchunk);
+    let chunks = (0..args.nchunk)
+        .map(|c| (c * chunk).min(args.count)..((c + 1) * chunk).min(args.count))
+        .collect::<Vec<_>>();
 
     let mut reqs = (0..args.nreq)
         .map(|i| {
@@ -42,15 +52,13 @@ fn do_client<T: Float>(args: &Args, comms: Vec<(Comm, Comm)>) {
                 .iter()
                 .enumerate()
                 .map(|(i, (scomm, rcomm))| {
-                    let s_mhandle =
-                        nccl_net::reg_mr(scomm, &mut sbuf.parts[i].lock().unwrap()).unwrap();
-                    let r_mhandle =
-                        nccl_net::reg_mr(rcomm, &mut rbuf.parts[i].lock().unwrap()).unwrap();
+                    let s_mhandle = net.reg_mr(scomm, &sbuf.parts[i].lock().unwrap()).unwrap();
+                    let r_mhandle = net.reg_mr(rcomm, &rbuf.parts[i].lock().unwrap()).unwrap();
                     (s_mhandle, r_mhandle)
                 })
                 .collect::<Vec<_>>();
 
-            (i, None, sbuf, rbuf, mhs)
+            (i, None, Deadline::from_secs(0), sbuf, rbuf, mhs)
         })
         .collect::<Vec<_>>();
 
@@ -67,16 +75,28 @@ fn do_client<T: Float>(args: &Args, comms: Vec<(Comm, Comm)>) {
             hint::spin_loop();
         }
 
-        for (i, req, sbuf, rbuf, mhs) in reqs.iter_mut() {
+        // a server aborts once the results it sent are done on its side, so the requests get
+        // one more test before giving up in case they are in
+        let abort = aborted.get().cloned();
+
+        // the socket plugin exchanges the size of a message when its request is first tested, so
+        // the requests of a comm are tested in the order they were posted, and only once the
+        // earlier ones are done. the slots are then posted and done round robin, starting over
+        // from the oldest one.
+        let (newer, older) = reqs.split_at_mut(finished % args.nreq);
+        // per comm, whether an earlier send and receive are still in flight
+        let mut busy = vec![(false, false); comms.len()];
+        for (i, req, deadline, sbuf, rbuf, mhs) in older.iter_mut().chain(newer.iter_mut()) {
             if req.is_none() && reqed < args.try_count {
                 *req = Some(
                     comms
                         .iter()
                         .enumerate()
-                        .map(|(j, (scomm, rcomm))| {
+                        .flat_map(|v| chunks.iter().map(move |range| (v, range.clone())))
+                        .map(|((j, (scomm, rcomm)), range)| {
                             let (s_mhandle, r_mhandle) = &mhs[j];
-                            let mut srequest: Option<Request> = None;
-                            let mut rrequest: Option<Request> = None;
+                            let mut srequest: Option<N::Request> = None;
+                            let mut rrequest: Option<N::Request> = None;
 
                             loop {
                                 if cfg!(no_spinloop) {
@@ -85,26 +105,32 @@ fn do_client<T: Float>(args: &Args, comms: Vec<(Comm, Comm)>) {
                                     hint::spin_loop();
                                 }
 
+                                if let Some(reason) = aborted.get() {
+                                    return Err(reason.clone());
+                                }
+
                                 if srequest.is_none() {
-                                    srequest = nccl_net::isend(
-                                        scomm,
-                                        s_mhandle,
-                                        &sbuf.parts[j].lock().unwrap(),
-                                        tag,
-                                    )
-                                    .unwrap();
+                                    srequest = net
+                                        .isend(
+                                            scomm,
+                                            s_mhandle,
+                                            &sbuf.parts[j].lock().unwrap()[range.clone()],
+                                            tag,
+                                        )
+                                        .unwrap();
                                     if srequest.is_some() {
                                         trace!("send  : idx: {}, j: {} start", i, j);
                                     }
                                 }
                                 if rrequest.is_none() {
-                                    rrequest = nccl_net::irecv(
-                                        rcomm,
-                                        r_mhandle,
-                                        &mut rbuf.parts[j].lock().unwrap(),
-                                        tag,
-                                    )
-                                    .unwrap();
+                                    rrequest = net
+                                        .irecv(
+                                            rcomm,
+                                            r_mhandle,
+                                            &mut rbuf.parts[j].lock().unwrap()[range.clone()],
+                                            tag,
+                                        )
+                                        .unwrap();
                                     if srequest.is_some() {
                                         trace!("recv : idx: {}, j: {} start", i, j);
                                     }
@@ -113,30 +139,34 @@ fn do_client<T: Float>(args: &Args, comms: Vec<(Comm, Comm)>) {
                                     break;
                                 }
                             }
-                            (srequest, rrequest)
+                            Ok((srequest, rrequest))
                         })
-                        .collect::<Vec<_>>(),
+                        .collect::<Result<Vec<_>, String>>()?,
                 );
+                *deadline = Deadline::from_secs(args.recv_timeout);
                 reqed += 1;
             }
 
             if req.is_some() {
                 let mut all_done = true;
-                for (j, (srequest, rrequest)) in req.as_mut().unwrap().iter_mut().enumerate() {
-                    if srequest.is_some() {
-                        let (send_done, _) = nccl_net::test(&srequest.as_ref().unwrap()).unwrap();
+                for (k, (srequest, rrequest)) in req.as_mut().unwrap().iter_mut().enumerate() {
+                    let j = k / args.nchunk;
+                    if srequest.is_some() && !busy[j].0 {
+                        let (send_done, _) = net.test(srequest.as_ref().unwrap()).unwrap();
                         if send_done {
                             trace!("send  : idx: {}, j: {} done", i, j);
                             *srequest = None;
                         }
                     }
-                    if rrequest.is_some() {
-                        let (recv_done, _) = nccl_net::test(&rrequest.as_ref().unwrap()).unwrap();
+                    if rrequest.is_some() && !busy[j].1 {
+                        let (recv_done, _) = net.test(rrequest.as_ref().unwrap()).unwrap();
                         if recv_done {
                             trace!("recv : idx: {}, j: {} done", i, j);
                             *rrequest = None;
                         }
                     }
+                    busy[j].0 |= srequest.is_some();
+                    busy[j].1 |= rrequest.is_some();
                     if srequest.is_some() || rrequest.is_some() {
                         all_done = false
                     }
@@ -144,6 +174,24 @@ fn do_client<T: Float>(args: &Args, comms: Vec<(Comm, Comm)>) {
                 if all_done {
                     finished += 1;
                     *req = None;
+                } else if let Some(elapsed) = deadline.expired() {
+                    let channels = |recv: bool| {
+                        req.as_ref()
+                            .unwrap()
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, v)| if recv { v.1.is_some() } else { v.0.is_some() })
+                            .map(|(k, _)| k / args.nchunk)
+                            .collect::<std::collections::BTreeSet<_>>()
+                    };
+                    let report = format!(
+                        "request({}) stalled for {:?}, channels sending: {:?}, channels receiving: {:?}",
+                        i,
+                        elapsed,
+                        channels(false),
+                        channels(true)
+                    );
+                    timed_out(args.timeout_action, report)?;
                 }
             }
         }
@@ -151,58 +199,102 @@ fn do_client<T: Float>(args: &Args, comms: Vec<(Comm, Comm)>) {
         if finished == args.try_count {
             break;
         }
+
+        if let Some(reason) = abort {
+            return Err(reason);
+        }
     }
 
     // stop timer
     let elapsed = start.elapsed();
     print_stat(&args, &elapsed);
+    Ok(())
 }
 
-pub(crate) fn client(args: Args) {
-    let (streams, comms): (Vec<TcpStream>, Vec<Vec<(Comm, Comm)>>) = args
+// the server only writes to the bootstrap stream to abort the communicator, so a thread per
+// server blocks on it and records why the allreduce can't complete
+fn watch_abort(addr: &str, stream: &TcpStream, aborted: &Arc<OnceLock<String>>) {
+    let mut stream = stream.try_clone().unwrap();
+    let aborted = Arc::clone(aborted);
+    let addr = addr.to_string();
+    std::thread::spawn(move || {
+        let reason = match bootstrap::recv_abort(&mut stream) {
+            Ok(reason) => format!("{} aborted the communicator: {}", addr, reason),
+            Err(e) => format!("lost the connection to {}: {}", addr, e),
+        };
+        let _ = aborted.set(reason);
+    });
+}
+
+pub(crate) fn client<N: Transport>(net: N, args: Args) {
+    let aborted = Arc::new(OnceLock::new());
+    let rails = rails(&net, &args);
+
+    let (streams, comms): (Vec<TcpStream>, Vec<Vec<(N::Comm, N::Comm)>>) = args
         .address
         .split(',')
-        .map(|addr| {
+        .enumerate()
+        .map(|(i, addr)| {
             info!("connecting to {}", addr);
+            let mut deadline = Deadline::from_secs(args.connect_timeout);
             let mut stream = loop {
                 let res = TcpStream::connect(&addr);
                 if res.is_ok() {
                     break res.unwrap();
                 }
+                if let Some(elapsed) = deadline.expired() {
+                    let report = format!("failed to connect to {} for {:?}", addr, elapsed);
+                    timed_out(args.timeout_action, report).unwrap_or_else(|e| panic!("{}", e));
+                }
                 // sleep 1s
                 std::thread::sleep(std::time::Duration::from_secs(1));
             };
 
+            bootstrap::connect(&mut stream, &bootstrap::Hello::from_args(&args))
+                .unwrap_or_else(|e| panic!("handshake with {} failed: {}", addr, e));
+
+            // the channels to the servers are striped across the rails
             let comms = (0..args.nchannel)
-                .map(|_| {
-                    let mut buffer = [0u8; 4];
-                    stream.read(buffer.as_mut()).unwrap();
-                    let size = u32::from_le_bytes(buffer);
-                    let mut handle = vec![0u8; size as usize];
-                    stream.read(handle.as_mut()).unwrap();
+                .map(|c| {
+                    let dev = rails[(i * args.nchannel + c) % rails.len()];
+                    let handle = match bootstrap::recv_handle(&mut stream) {
+                        Ok(handle) => handle,
+                        Err(bootstrap::Error::Aborted(reason)) => panic!(
+                            "allreduce aborted: {} aborted the communicator: {}",
+                            addr, reason
+                        ),
+                        Err(e) => panic!("failed to receive a handle from {}: {}", addr, e),
+                    };
                     info!("received handle: {:?}", handle);
 
-                    let (lcomm, lhandle) = nccl_net::listen().unwrap();
+                    let (lcomm, lhandle) = net.listen(dev).unwrap();
 
-                    // send size of handle
-                    let size = lhandle.len() as u32;
-                    stream.write_all(&size.to_le_bytes()).unwrap();
-                    // send handle
-                    stream.write_all(&lhandle).unwrap();
+                    bootstrap::send_handle(&mut stream, &lhandle).unwrap();
 
-                    let mut scomm: Option<Comm> = None;
-                    let mut rcomm: Option<Comm> = None;
+                    let mut scomm: Option<N::Comm> = None;
+                    let mut rcomm: Option<N::Comm> = None;
 
                     loop {
                         if scomm.is_none() {
-                            scomm = nccl_net::connect(handle.as_slice()).unwrap();
+                            scomm = net.connect(dev, &handle).unwrap();
                         }
                         if rcomm.is_none() {
-                            rcomm = nccl_net::accept(&lcomm).unwrap();
+                            rcomm = net.accept(&lcomm).unwrap();
                         }
                         if scomm.is_some() && rcomm.is_some() {
                             break;
                         }
+                        if let Some(elapsed) = deadline.expired() {
+                            let report = format!(
+                                "comms with {} not set up for {:?}, send: {}, recv: {}",
+                                addr,
+                                elapsed,
+                                scomm.is_some(),
+                                rcomm.is_some()
+                            );
+                            timed_out(args.timeout_action, report)
+                                .unwrap_or_else(|e| panic!("{}", e));
+                        }
                     }
 
                     let scomm = scomm.unwrap();
@@ -210,6 +302,8 @@ pub(crate) fn client(args: Args) {
                     (scomm, rcomm)
                 })
                 .collect::<Vec<_>>();
+            // the handles of the channels come through the stream until then
+            watch_abort(addr, &stream, &aborted);
             (stream, comms) // return stream to keep the socket open until we finish
         })
         .unzip();
@@ -223,54 +317,66 @@ pub(crate) fn client(args: Args) {
     let hs = comms
         .into_iter()
         .map(|comm| {
+            let net = net.clone();
             let args = Arc::clone(&args);
+            let aborted = Arc::clone(&aborted);
             std::thread::spawn(move || {
-                if args.data_type == DataType::F32 {
-                    do_client::<f32>(args.as_ref(), comm);
-                } else if args.data_type == DataType::F16 {
-                    do_client::<f16>(args.as_ref(), comm);
-                } else if args.data_type == DataType::BF16 {
-                    do_client::<bf16>(args.as_ref(), comm);
-                }
+                with_data_type!(
+                    args.data_type,
+                    do_client::<N>(&net, args.as_ref(), comm, aborted.as_ref())
+                )
             })
         })
         .collect::<Vec<_>>();
-    hs.into_iter().for_each(|h| h.join().unwrap());
-    drop(streams);
+    let res = hs
+        .into_iter()
+        .map(|h| h.join().unwrap())
+        .collect::<Result<Vec<_>, String>>();
+    // wake up the watchers
+    for stream in streams {
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+    }
+    if let Err(reason) = res {
+        panic!("allreduce aborted: {}", reason);
+    }
 }
 
-pub(crate) fn bench(args: Args) {
+pub(crate) fn bench<N: Transport>(net: N, args: Args) {
     let listener =
         TcpListener::bind(format!("{}:{}", args.address, args.port)).expect("failed to bind");
 
-    let (streams, comms): (Vec<TcpStream>, Vec<Vec<(Comm, Comm)>>) = (0..args.nrank)
-        .map(|_| {
-            let (mut stream, _) = listener.accept().unwrap();
+    let rails = rails(&net, &args);
+
+    let (streams, comms): (Vec<TcpStream>, Vec<Vec<(N::Comm, N::Comm)>>) = (0..args.nrank)
+        .map(|i| {
+            let mut stream = loop {
+                let (mut stream, addr) = listener.accept().unwrap();
+                let res = bootstrap::accept(&mut stream, &args, |hello| {
+                    hello.validate_member(args.nrank, args.reduce_op, &[], false)
+                });
+                match res {
+                    Ok(_) => break stream,
+                    Err(e) => warn!("handshake with {} failed: {}", addr, e),
+                }
+            };
             let comms = (0..args.nchannel)
-                .map(|_| {
-                    let (lcomm, handle) = nccl_net::listen().unwrap();
-                    // send size of handle
-                    let size = handle.len() as u32;
-                    stream.write_all(&size.to_le_bytes()).unwrap();
-                    // send handle
-                    stream.write_all(&handle).unwrap();
-
-                    let mut buffer = [0u8; 4];
-                    stream.read(buffer.as_mut()).unwrap();
-                    let size = u32::from_le_bytes(buffer);
-                    let mut handle = vec![0u8; size as usize];
-                    stream.read(handle.as_mut()).unwrap();
+                .map(|c| {
+                    let dev = rails[(i * args.nchannel + c) % rails.len()];
+                    let (lcomm, handle) = net.listen(dev).unwrap();
+                    bootstrap::send_handle(&mut stream, &handle).unwrap();
+
+                    let handle = bootstrap::recv_handle(&mut stream).unwrap();
                     info!("received handle: {:?}", handle);
 
-                    let mut scomm: Option<Comm> = None;
-                    let mut rcomm: Option<Comm> = None;
+                    let mut scomm: Option<N::Comm> = None;
+                    let mut rcomm: Option<N::Comm> = None;
 
                     loop {
                         if scomm.is_none() {
-                            scomm = nccl_net::connect(handle.as_slice()).unwrap();
+                            scomm = net.connect(dev, &handle).unwrap();
                         }
                         if rcomm.is_none() {
-                            rcomm = nccl_net::accept(&lcomm).unwrap();
+                            rcomm = net.accept(&lcomm).unwrap();
                         }
                         if scomm.is_some() && rcomm.is_some() {
                             break;
@@ -295,63 +401,90 @@ pub(crate) fn bench(args: Args) {
     let hs = comms
         .into_iter()
         .map(|comm| {
+            let net = net.clone();
             let args = Arc::clone(&args);
             std::thread::spawn(move || {
-                if args.data_type == DataType::F32 {
-                    do_client::<f32>(args.as_ref(), comm);
-                } else if args.data_type == DataType::F16 {
-                    do_client::<f16>(args.as_ref(), comm);
-                } else if args.data_type == DataType::BF16 {
-                    do_client::<bf16>(args.as_ref(), comm);
-                }
+                with_data_type!(
+                    args.data_type,
+                    do_client::<N>(&net, args.as_ref(), comm, &OnceLock::new())
+                )
+                .unwrap();
             })
         })
         .collect::<Vec<_>>();
     hs.into_iter().for_each(|h| h.join().unwrap());
-    drop(streams);
+    // a client takes a closed stream for an abort, so it closes its own first once it has its
+    // last results
+    for mut stream in streams {
+        let _ = stream.read(&mut [0u8; 1]);
+    }
 }
 
 // test
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::utils::tests::initialize;
+    use crate::transport::loopback::Loopback;
+    use crate::transport::uring::Uring;
+    use crate::utils::tests::{free_port, init_logger};
     use clap::Parser;
 
     fn do_bench(dt: &str) {
-        initialize();
+        do_bench_with(dt, &[]);
+    }
+
+    fn do_bench_with(dt: &str, opts: &[&str]) {
+        do_bench_on(Loopback::default(), dt, opts);
+    }
+
+    fn do_bench_on<N: Transport>(net: N, dt: &str, opts: &[&str]) {
+        init_logger();
+        let port = free_port();
+        let opts = opts.iter().map(|v| v.to_string()).collect::<Vec<_>>();
         let b = {
+            let net = net.clone();
             let dt = dt.to_string();
+            let opts = opts.clone();
             std::thread::spawn(move || {
+                let port = port.to_string();
                 let count = format!("{}", 1024 * 1024);
-                let args = Args::parse_from([
-                    "--bench",
-                    "--address",
-                    "127.0.0.1",
-                    "--port",
-                    "8080",
-                    "--count",
-                    &count,
-                    "--data-type",
-                    &dt,
-                ]);
-                bench(args);
+                let args = Args::parse_from(
+                    [
+                        "--bench",
+                        "--address",
+                        "127.0.0.1",
+                        "--port",
+                        &port,
+                        "--count",
+                        &count,
+                        "--data-type",
+                        &dt,
+                    ]
+                    .into_iter()
+                    .chain(opts.iter().map(|v| v.as_str())),
+                );
+                bench(net, args);
             })
         };
         let c = {
             let dt = dt.to_string();
             std::thread::spawn(move || {
+                let address = format!("127.0.0.1:{}", port);
                 let count = format!("{}", 1024 * 1024);
-                let args = Args::parse_from([
-                    "--client",
-                    "--address",
-                    "127.0.0.1:8080",
-                    "--count",
-                    &count,
-                    "--data-type",
-                    &dt,
-                ]);
-                client(args);
+                let args = Args::parse_from(
+                    [
+                        "--client",
+                        "--address",
+                        &address,
+                        "--count",
+                        &count,
+                        "--data-type",
+                        &dt,
+                    ]
+                    .into_iter()
+                    .chain(opts.iter().map(|v| v.as_str())),
+                );
+                client(net, args);
             })
         };
         b.join().unwrap();
@@ -372,4 +505,15 @@ mod tests {
     fn test_bench_bf16() {
         do_bench("bf16");
     }
+
+    #[test]
+    fn test_bench_multi_channel() {
+        do_bench_with("f32", &["--nchannel", "2"]);
+    }
+
+    #[test]
+    fn test_bench_uring() {
+        let net = Uring::new("lo").unwrap();
+        do_bench_on(net, "f32", &["--nchannel", "2", "--nreq", "4"]);
+    }
 }
@@ -12,17 +12,26 @@
 use clap::Parser;
 
 mod nccl_net;
+mod bootstrap;
 mod utils;
 mod partitioned_vec;
 mod client;
 mod server;
 mod ring;
 mod reduce;
+mod ready;
+mod transport;
+mod pool;
 
-use utils::Args;
+use utils::{Args, Net};
 use server::server;
 use client::{client, bench};
 use ring::ring;
+use nccl_net::NcclNet;
+use transport::Transport;
+use transport::shm::Shm;
+use transport::tcp::{self, Tcp};
+use transport::uring::Uring;
 
 fn main() {
     let mut builder = env_logger::Builder::from_default_env();
@@ -30,21 +39,44 @@ fn main() {
         .target(env_logger::Target::Stdout)
         .format_timestamp_nanos()
         .init();
-    nccl_net::init();
-
     let args = Args::parse();
+    let shm = !args.no_shm;
+
+    match args.net {
+        Net::Plugin => {
+            nccl_net::load(&args.net_plugin).unwrap_or_else(|e| panic!("{}", e));
+            nccl_net::init().expect("failed to initialize the net plugin");
+            let name = format!("{}, version: v{}", nccl_net::name(), nccl_net::version() as u32);
+            run(Shm::new(NcclNet, shm), &name, args);
+        }
+        Net::Tcp => {
+            let net = Tcp::new(tcp::Options::from_args(&args)).unwrap_or_else(|e| panic!("{}", e));
+            run(Shm::new(net, shm), "tcp", args);
+        }
+        Net::Uring => {
+            let net = Uring::new(&args.socket_ifname).unwrap_or_else(|e| panic!("{}", e));
+            run(Shm::new(net, shm), "uring", args);
+        }
+    }
+}
+
+fn run<N: Transport>(net: N, name: &str, args: Args) {
+    if args.list_devices {
+        utils::print_devices(&net, name);
+        return;
+    }
 
     if args.client {
-        client(args);
+        client(net, args);
         return;
     } else if args.bench {
-        bench(args);
+        bench(net, args);
         return;
     } else if args.ring_rank > 0 {
-        ring(args);
+        ring(net, args);
         return;
     } else {
-        server(args);
+        server(net, args);
         return;
     }
 }
@@ -6,16 +6,22 @@
 
 use std::io::{BufWriter, Write};
 
-use log::{log, log_enabled};
+use log::{error, info, log, log_enabled};
 
 use nccl_net_sys as ffi;
 
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
 use std::ptr::NonNull;
+use std::sync::OnceLock;
 
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::future::Future;
 
+use crate::transport::{Handle, Properties, Transport};
+use crate::utils::{DataType, ReduceOp};
+
 unsafe extern "C" fn logfn(
     level: ffi::ncclDebugLogLevel::Type,
     _: ::std::os::raw::c_ulong,
@@ -125,34 +131,262 @@ unsafe extern "C" fn logfn(
     );
 }
 
-#[derive(Debug)]
+// the most buffers of a grouped receive or flush
+pub(crate) const MAX_RECVS: usize = 8;
+
+// errors of the plugin: the ncclResult_t codes other than ncclSuccess, the entry points the
+// plugin leaves unset, and the failures to load it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Error {
+    UnhandledCuda,
+    System,
+    Internal,
+    InvalidArgument,
+    InvalidUsage,
+    Remote,
+    InProgress,
+    Unknown(ffi::ncclResult_t::Type),
+    Unsupported(&'static str),
+    Load(String),
+}
+
+impl Error {
+    fn check(ret: ffi::ncclResult_t::Type) -> Result<(), Error> {
+        match ret {
+            ffi::ncclResult_t::ncclSuccess => Ok(()),
+            ffi::ncclResult_t::ncclUnhandledCudaError => Err(Error::UnhandledCuda),
+            ffi::ncclResult_t::ncclSystemError => Err(Error::System),
+            ffi::ncclResult_t::ncclInternalError => Err(Error::Internal),
+            ffi::ncclResult_t::ncclInvalidArgument => Err(Error::InvalidArgument),
+            ffi::ncclResult_t::ncclInvalidUsage => Err(Error::InvalidUsage),
+            ffi::ncclResult_t::ncclRemoteError => Err(Error::Remote),
+            ffi::ncclResult_t::ncclInProgress => Err(Error::InProgress),
+            v => Err(Error::Unknown(v)),
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::UnhandledCuda => write!(f, "unhandled CUDA error"),
+            Error::System => write!(f, "system error"),
+            Error::Internal => write!(f, "internal error"),
+            Error::InvalidArgument => write!(f, "invalid argument"),
+            Error::InvalidUsage => write!(f, "invalid usage"),
+            Error::Remote => write!(f, "remote error"),
+            Error::InProgress => write!(f, "operation in progress"),
+            Error::Unknown(v) => write!(f, "unknown error: {}", v),
+            Error::Unsupported(name) => write!(f, "{} is not supported by the plugin", name),
+            Error::Load(msg) => write!(f, "failed to load the plugin: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// calls an entry point of a plugin table
+macro_rules! call {
+    ($table:expr, $f:ident($($arg:expr),* $(,)?)) => {
+        match $table.$f {
+            Some(f) => Error::check(unsafe { f($($arg),*) }),
+            None => Err(Error::Unsupported(stringify!($f))),
+        }
+    };
+}
+
+// versions of the net table, which differ in the signatures of a few entry points
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Version {
+    V6 = 6,
+    V7 = 7,
+    V8 = 8,
+}
+
+// the net table of the plugin. the plugin fills it in init, so it is read through the pointer at
+// each call.
+#[derive(Debug, Clone, Copy)]
+enum Net {
+    V6(*const ffi::ncclNet_v6_t),
+    V7(*const ffi::ncclNet_v7_t),
+    V8(*const ffi::ncclNet_v8_t),
+}
+
+// the tables of the loaded plugin library, which is never unloaded
+struct Plugin {
+    library: String,
+    net: Net,
+    collnet: *const ffi::ncclCollNet_v6_t,
+}
+
+unsafe impl Send for Plugin {}
+unsafe impl Sync for Plugin {}
+
+static PLUGIN: OnceLock<Plugin> = OnceLock::new();
+
+// the file name of a plugin library. like NCCL_NET_PLUGIN of NCCL, a bare name such as "ofi"
+// stands for libnccl-net-ofi.so.
+fn plugin_library(name: &str) -> String {
+    if name.is_empty() {
+        "libnccl-net.so".to_string()
+    } else if name.contains('/') || name.contains(".so") {
+        name.to_string()
+    } else {
+        format!("libnccl-net-{}.so", name)
+    }
+}
+
+fn dlerror() -> String {
+    let msg = unsafe { libc::dlerror() };
+    if msg.is_null() {
+        "unknown error".to_string()
+    } else {
+        c_string(msg)
+    }
+}
+
+// looks up a table the plugin may not export
+fn lookup<T>(handle: *mut c_void, name: &CStr) -> Option<*const T> {
+    let ptr = unsafe { libc::dlsym(handle, name.as_ptr()) };
+    (!ptr.is_null()).then_some(ptr as *const T)
+}
+
+// opens the plugin library and picks the newest net table it exports
+fn open(library: &str) -> Result<Plugin, Error> {
+    let path = CString::new(library).map_err(|_| Error::InvalidArgument)?;
+    let handle = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        return Err(Error::Load(dlerror()));
+    }
+    let net = if let Some(table) = lookup(handle, c"ncclNetPlugin_v8") {
+        Net::V8(table)
+    } else if let Some(table) = lookup(handle, c"ncclNetPlugin_v7") {
+        Net::V7(table)
+    } else if let Some(table) = lookup(handle, c"ncclNetPlugin_v6") {
+        Net::V6(table)
+    } else {
+        unsafe { libc::dlclose(handle) };
+        return Err(Error::Load(format!(
+            "{}: no ncclNetPlugin_v8, v7 or v6 symbol",
+            library
+        )));
+    };
+    // plugins without CollNet get a table with every entry point unset
+    let collnet = lookup(handle, c"ncclCollNetPlugin_v6")
+        .unwrap_or_else(|| Box::leak(Box::<ffi::ncclCollNet_v6_t>::default()) as *const _);
+    Ok(Plugin {
+        library: library.to_string(),
+        net,
+        collnet,
+    })
+}
+
+// loads the plugin named on the command line, or else by NCCL_NET_PLUGIN, or else
+// libnccl-net.so. loading the same library again is a no-op.
+pub(crate) fn load(name: &str) -> Result<(), Error> {
+    let library = if name.is_empty() {
+        plugin_library(&std::env::var("NCCL_NET_PLUGIN").unwrap_or_default())
+    } else {
+        plugin_library(name)
+    };
+    let loaded = |plugin: &Plugin| {
+        if plugin.library == library {
+            Ok(())
+        } else {
+            Err(Error::InvalidUsage)
+        }
+    };
+    if let Some(plugin) = PLUGIN.get() {
+        return loaded(plugin);
+    }
+    let plugin = open(&library)?;
+    match PLUGIN.set(plugin) {
+        Ok(()) => Ok(()),
+        Err(_) => loaded(PLUGIN.get().unwrap()),
+    }
+}
+
+fn plugin() -> &'static Plugin {
+    PLUGIN.get().expect("the net plugin is not loaded")
+}
+
+fn net() -> Net {
+    plugin().net
+}
+
+// calls an entry point of the net table whose signature is the same in all versions
+macro_rules! call_net {
+    ($f:ident($($arg:expr),* $(,)?)) => {
+        match net() {
+            Net::V6(table) => call!(unsafe { &*table }, $f($($arg),*)),
+            Net::V7(table) => call!(unsafe { &*table }, $f($($arg),*)),
+            Net::V8(table) => call!(unsafe { &*table }, $f($($arg),*)),
+        }
+    };
+}
+
+fn collnet() -> &'static ffi::ncclCollNet_v6_t {
+    unsafe { &*plugin().collnet }
+}
+
+// sizes are passed to the plugin as c_int
+fn c_size(len: usize) -> Result<c_int, Error> {
+    c_int::try_from(len).map_err(|_| Error::InvalidArgument)
+}
+
+// strings of the plugin, which may be left unset
+fn c_string(v: *const c_char) -> String {
+    if v.is_null() {
+        String::new()
+    } else {
+        unsafe { std::ffi::CStr::from_ptr(v) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+#[allow(dead_code)] // the collective comms are only created through the coll module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum CommType {
     Listen,
     Send,
     Recv,
+    CollListen,
+    Coll,
 }
 
 #[derive(Debug)]
 pub(crate) struct Comm {
-    ptr: NonNull<std::ffi::c_void>,
+    ptr: NonNull<c_void>,
     r#type: CommType,
 }
 
+impl Comm {
+    fn new(ptr: *mut c_void, r#type: CommType) -> Option<Comm> {
+        NonNull::new(ptr).map(|ptr| Comm { ptr, r#type })
+    }
+
+    fn expect(&self, r#type: CommType) -> Result<*mut c_void, Error> {
+        if self.r#type != r#type {
+            return Err(Error::InvalidUsage);
+        }
+        Ok(self.ptr.as_ptr())
+    }
+}
+
+// a comm that fails to close is leaked, the plugin logs the details
 impl Drop for Comm {
     fn drop(&mut self) {
+        let ptr = self.ptr.as_ptr();
         let ret = match self.r#type {
-            CommType::Listen => unsafe {
-                ffi::ncclNetPlugin_v6.closeListen.unwrap()(self.ptr.as_ptr())
-            },
-            CommType::Send => unsafe {
-                ffi::ncclNetPlugin_v6.closeSend.unwrap()(self.ptr.as_ptr())
-            },
-            CommType::Recv => unsafe {
-                ffi::ncclNetPlugin_v6.closeRecv.unwrap()(self.ptr.as_ptr())
-            },
+            CommType::Listen => call_net!(closeListen(ptr)),
+            CommType::Send => call_net!(closeSend(ptr)),
+            CommType::Recv => call_net!(closeRecv(ptr)),
+            CommType::CollListen => call!(collnet(), closeListen(ptr)),
+            CommType::Coll => call!(collnet(), closeColl(ptr)),
         };
-        if ret != ffi::ncclResult_t::ncclSuccess {
-            panic!("failed to close comm: {:?}", ret);
+        if let Err(e) = ret {
+            error!("failed to close {:?} comm: {}", self.r#type, e);
         }
     }
 }
@@ -161,16 +395,19 @@ unsafe impl Send for Comm {}
 
 #[derive(Debug)]
 pub(crate) struct MemoryHandle<'a> {
-    ptr: *mut std::ffi::c_void,
+    ptr: *mut c_void,
     comm: &'a Comm,
 }
 
 impl Drop for MemoryHandle<'_> {
     fn drop(&mut self) {
-        let ret =
-            unsafe { ffi::ncclNetPlugin_v6.deregMr.unwrap()(self.comm.ptr.as_ptr(), self.ptr) };
-        if ret != ffi::ncclResult_t::ncclSuccess {
-            panic!("failed to dereg_mr: {:?}", ret);
+        let comm = self.comm.ptr.as_ptr();
+        let ret = match self.comm.r#type {
+            CommType::Coll => call!(collnet(), deregMr(comm, self.ptr)),
+            _ => call_net!(deregMr(comm, self.ptr)),
+        };
+        if let Err(e) = ret {
+            error!("failed to dereg_mr: {}", e);
         }
     }
 }
@@ -179,7 +416,15 @@ unsafe impl Send for MemoryHandle<'_> {}
 
 #[derive(Debug)]
 pub(crate) struct Request {
-    ptr: NonNull<std::ffi::c_void>,
+    ptr: NonNull<c_void>,
+    n: usize,   // buffers of the request
+    coll: bool, // tested through the CollNet table
+}
+
+impl Request {
+    fn new(ptr: *mut c_void, n: usize, coll: bool) -> Option<Request> {
+        NonNull::new(ptr).map(|ptr| Request { ptr, n, coll })
+    }
 }
 
 unsafe impl Send for Request {}
@@ -198,96 +443,212 @@ impl Future for Request {
     }
 }
 
-pub(crate) fn init() {
-    unsafe {
-        ffi::ncclNetPlugin_v6.init.unwrap()(Some(logfn));
+// the buffers of a grouped receive or flush, laid out for the plugin
+struct Group {
+    n: usize,
+    data: [*mut c_void; MAX_RECVS],
+    sizes: [c_int; MAX_RECVS],
+    mhandles: [*mut c_void; MAX_RECVS],
+}
+
+impl Group {
+    fn new<T>(mhandles: &[&MemoryHandle], data: &mut [&mut [T]]) -> Result<Group, Error> {
+        let n = data.len();
+        if n == 0 || n > MAX_RECVS || mhandles.len() != n {
+            return Err(Error::InvalidArgument);
+        }
+        let mut group = Group {
+            n,
+            data: [std::ptr::null_mut(); MAX_RECVS],
+            sizes: [0; MAX_RECVS],
+            mhandles: [std::ptr::null_mut(); MAX_RECVS],
+        };
+        for (i, (buf, mh)) in data.iter_mut().zip(mhandles).enumerate() {
+            group.data[i] = buf.as_mut_ptr() as *mut c_void;
+            group.sizes[i] = c_size(std::mem::size_of_val(*buf))?;
+            group.mhandles[i] = mh.ptr;
+        }
+        Ok(group)
+    }
+}
+
+// the properties of all versions share the fields of v6
+macro_rules! impl_from_properties {
+    ($t:ty, |$props:ident| $reg_is_global:expr) => {
+        impl From<&$t> for Properties {
+            fn from($props: &$t) -> Self {
+                Properties {
+                    name: c_string($props.name),
+                    pci_path: c_string($props.pciPath),
+                    guid: $props.guid,
+                    ptr_support: $props.ptrSupport,
+                    reg_is_global: $reg_is_global,
+                    speed: $props.speed,
+                    port: $props.port,
+                    latency: $props.latency,
+                    max_comms: $props.maxComms,
+                    max_recvs: $props.maxRecvs,
+                }
+            }
+        }
+    };
+}
+
+impl_from_properties!(ffi::ncclNetProperties_v6_t, |props| false);
+impl_from_properties!(ffi::ncclNetProperties_v7_t, |props| false);
+impl_from_properties!(ffi::ncclNetProperties_v8_t, |props| props.regIsGlobal != 0);
+
+pub(crate) fn init() -> Result<(), Error> {
+    call_net!(init(Some(logfn)))?;
+    info!("net: {}, version: v{}", name(), version() as u32);
+    Ok(())
+}
+
+// the version of the net table in use, the newest the plugin exports
+pub(crate) fn version() -> Version {
+    match net() {
+        Net::V6(_) => Version::V6,
+        Net::V7(_) => Version::V7,
+        Net::V8(_) => Version::V8,
     }
 }
 
-pub(crate) fn listen() -> Result<(Comm, Vec<u8>), ffi::ncclResult_t::Type> {
+pub(crate) fn name() -> String {
+    match net() {
+        Net::V6(table) => c_string(unsafe { (*table).name }),
+        Net::V7(table) => c_string(unsafe { (*table).name }),
+        Net::V8(table) => c_string(unsafe { (*table).name }),
+    }
+}
+
+pub(crate) fn devices() -> Result<usize, Error> {
+    let mut ndev = 0;
+    call_net!(devices(&mut ndev))?;
+    Ok(ndev as usize)
+}
+
+pub(crate) fn get_properties(dev: usize) -> Result<Properties, Error> {
+    let dev = dev as c_int;
+    match net() {
+        Net::V6(table) => {
+            let mut props = ffi::ncclNetProperties_v6_t::default();
+            call!(unsafe { &*table }, getProperties(dev, &mut props))?;
+            Ok(Properties::from(&props))
+        }
+        Net::V7(table) => {
+            let mut props = ffi::ncclNetProperties_v7_t::default();
+            call!(unsafe { &*table }, getProperties(dev, &mut props))?;
+            Ok(Properties::from(&props))
+        }
+        Net::V8(table) => {
+            let mut props = ffi::ncclNetProperties_v8_t::default();
+            call!(unsafe { &*table }, getProperties(dev, &mut props))?;
+            Ok(Properties::from(&props))
+        }
+    }
+}
+
+pub(crate) fn listen(dev: usize) -> Result<(Comm, Vec<u8>), Error> {
     let mut handle = [0u8; ffi::NCCL_NET_HANDLE_MAXSIZE as usize];
     let mut lcomm = std::ptr::null_mut();
-    let lcomm_ptr = &mut lcomm;
-    let ret = unsafe {
-        ffi::ncclNetPlugin_v6.listen.unwrap()(
-            0,
-            handle.as_mut_ptr() as *mut std::ffi::c_void,
-            lcomm_ptr,
-        )
-    };
-    if ret != ffi::ncclResult_t::ncclSuccess {
-        return Err(ret);
-    }
-    let handle = handle.to_vec();
-    Ok((
-        Comm {
-            ptr: unsafe { NonNull::new_unchecked(lcomm) },
-            r#type: CommType::Listen,
-        },
-        handle,
-    ))
+    call_net!(listen(
+        dev as c_int,
+        handle.as_mut_ptr() as *mut c_void,
+        &mut lcomm
+    ))?;
+    let lcomm = Comm::new(lcomm, CommType::Listen).ok_or(Error::Internal)?;
+    Ok((lcomm, handle.to_vec()))
 }
 
-pub(crate) fn connect(handle: &[u8]) -> Result<Option<Comm>, ffi::ncclResult_t::Type> {
+// the device handles of v7 and later are only set by plugins offloading to the GPU. the server
+// works on host memory and ignores them.
+pub(crate) fn connect(dev: usize, handle: &[u8]) -> Result<Option<Comm>, Error> {
+    let dev = dev as c_int;
     let mut scomm = std::ptr::null_mut();
-    let scomm_ptr = &mut scomm;
-    let handle_ptr: *mut std::ffi::c_void = handle.as_ptr() as *mut std::ffi::c_void;
-    let ret = unsafe { ffi::ncclNetPlugin_v6.connect.unwrap()(0, handle_ptr, scomm_ptr) };
-    if ret != ffi::ncclResult_t::ncclSuccess {
-        return Err(ret);
-    }
-    Ok(if scomm.is_null() {
-        None
-    } else {
-        Some(Comm {
-            ptr: unsafe { NonNull::new_unchecked(scomm) },
-            r#type: CommType::Send,
-        })
-    })
+    let mut dev_comm = std::ptr::null_mut();
+    let handle_ptr = handle.as_ptr() as *mut c_void;
+    match net() {
+        Net::V6(table) => call!(unsafe { &*table }, connect(dev, handle_ptr, &mut scomm))?,
+        Net::V7(table) => call!(
+            unsafe { &*table },
+            connect(dev, handle_ptr, &mut scomm, &mut dev_comm)
+        )?,
+        Net::V8(table) => call!(
+            unsafe { &*table },
+            connect(dev, handle_ptr, &mut scomm, &mut dev_comm)
+        )?,
+    }
+    Ok(Comm::new(scomm, CommType::Send))
 }
 
-pub(crate) fn accept(comm: &Comm) -> Result<Option<Comm>, ffi::ncclResult_t::Type> {
+pub(crate) fn accept(comm: &Comm) -> Result<Option<Comm>, Error> {
+    let lcomm = comm.expect(CommType::Listen)?;
     let mut rcomm = std::ptr::null_mut();
-    let rcomm_ptr = &mut rcomm;
-    let ret = unsafe { ffi::ncclNetPlugin_v6.accept.unwrap()(comm.ptr.as_ptr(), rcomm_ptr) };
-    if ret != ffi::ncclResult_t::ncclSuccess {
-        return Err(ret);
+    let mut dev_comm = std::ptr::null_mut();
+    match net() {
+        Net::V6(table) => call!(unsafe { &*table }, accept(lcomm, &mut rcomm))?,
+        Net::V7(table) => call!(unsafe { &*table }, accept(lcomm, &mut rcomm, &mut dev_comm))?,
+        Net::V8(table) => call!(unsafe { &*table }, accept(lcomm, &mut rcomm, &mut dev_comm))?,
     }
-    Ok(if rcomm.is_null() {
-        None
-    } else {
-        Some(Comm {
-            ptr: unsafe { NonNull::new_unchecked(rcomm) },
-            r#type: CommType::Recv,
-        })
-    })
+    Ok(Comm::new(rcomm, CommType::Recv))
+}
+
+// registers host memory with a send, recv or collective comm
+pub(crate) fn reg_mr<'a, T>(comm: &'a Comm, data: &[T]) -> Result<MemoryHandle<'a>, Error> {
+    let mut mh = std::ptr::null_mut();
+    let ptr = data.as_ptr() as *mut c_void;
+    let size = std::mem::size_of_val(data);
+    let ptr_type = ffi::NCCL_PTR_HOST as c_int;
+    let cptr = comm.ptr.as_ptr();
+    match (comm.r#type, net()) {
+        // the size is a size_t since v8
+        (CommType::Send | CommType::Recv, Net::V8(table)) => call!(
+            unsafe { &*table },
+            regMr(cptr, ptr, size, ptr_type, &mut mh)
+        )?,
+        (CommType::Send | CommType::Recv, Net::V7(table)) => call!(
+            unsafe { &*table },
+            regMr(cptr, ptr, c_size(size)?, ptr_type, &mut mh)
+        )?,
+        (CommType::Send | CommType::Recv, Net::V6(table)) => call!(
+            unsafe { &*table },
+            regMr(cptr, ptr, c_size(size)?, ptr_type, &mut mh)
+        )?,
+        (CommType::Coll, _) => call!(
+            collnet(),
+            regMr(cptr, ptr, c_size(size)?, ptr_type, &mut mh)
+        )?,
+        (CommType::Listen | CommType::CollListen, _) => return Err(Error::InvalidUsage),
+    }
+    Ok(MemoryHandle { ptr: mh, comm })
 }
 
-pub(crate) fn reg_mr<'a, T>(
+// registers memory exported as a DMA-BUF, like GPU memory, which has no slice to borrow.
+// safety: data must point to size bytes of the buffer at offset of fd, which stay mapped as
+// long as the handle lives.
+#[allow(dead_code)]
+pub(crate) unsafe fn reg_mr_dma_buf<'a>(
     comm: &'a Comm,
-    data: &[T],
-) -> Result<MemoryHandle<'a>, ffi::ncclResult_t::Type> {
+    data: *mut c_void,
+    size: usize,
+    ptr_type: u32,
+    offset: u64,
+    fd: std::os::fd::RawFd,
+) -> Result<MemoryHandle<'a>, Error> {
     let mut mh = std::ptr::null_mut();
-    let mh_ptr = &mut mh;
-    let ptr = data.as_ptr() as *mut ::std::os::raw::c_void;
-    let len = (data.len() * std::mem::size_of::<T>()) as ::std::os::raw::c_int;
-
-    let ret = unsafe {
-        ffi::ncclNetPlugin_v6.regMr.unwrap()(
-            comm.ptr.as_ptr(),
-            ptr,
-            len,
-            ffi::NCCL_PTR_HOST as ::std::os::raw::c_int,
-            mh_ptr,
-        )
-    };
-    if ret != ffi::ncclResult_t::ncclSuccess {
-        return Err(ret);
+    let ptr_type = ptr_type as c_int;
+    let ptr = comm.ptr.as_ptr();
+    match comm.r#type {
+        CommType::Send | CommType::Recv => {
+            call_net!(regMrDmaBuf(ptr, data, size, ptr_type, offset, fd, &mut mh))?
+        }
+        CommType::Coll => call!(
+            collnet(),
+            regMrDmaBuf(ptr, data, size, ptr_type, offset, fd, &mut mh)
+        )?,
+        CommType::Listen | CommType::CollListen => return Err(Error::InvalidUsage),
     }
-    Ok(MemoryHandle {
-        ptr: mh,
-        comm: comm,
-    })
+    Ok(MemoryHandle { ptr: mh, comm })
 }
 
 pub(crate) fn isend<T>(
@@ -295,29 +656,18 @@ pub(crate) fn isend<T>(
     mhandle: &MemoryHandle,
     data: &[T],
     tag: i32,
-) -> Result<Option<Request>, ffi::ncclResult_t::Type> {
+) -> Result<Option<Request>, Error> {
+    let scomm = comm.expect(CommType::Send)?;
     let mut request = std::ptr::null_mut();
-    let request_ptr = &mut request;
-    let ret = unsafe {
-        ffi::ncclNetPlugin_v6.isend.unwrap()(
-            comm.ptr.as_ptr(),
-            data.as_ptr() as *mut ::std::os::raw::c_void,
-            (data.len() * std::mem::size_of::<T>()) as ::std::os::raw::c_int,
-            tag,
-            mhandle.ptr,
-            request_ptr,
-        )
-    };
-    if ret != ffi::ncclResult_t::ncclSuccess {
-        return Err(ret);
-    }
-    Ok(if request.is_null() {
-        None
-    } else {
-        Some(Request {
-            ptr: unsafe { NonNull::new_unchecked(request) },
-        })
-    })
+    call_net!(isend(
+        scomm,
+        data.as_ptr() as *mut c_void,
+        c_size(std::mem::size_of_val(data))?,
+        tag,
+        mhandle.ptr,
+        &mut request,
+    ))?;
+    Ok(Request::new(request, 1, false))
 }
 
 pub(crate) fn irecv<T>(
@@ -325,45 +675,412 @@ pub(crate) fn irecv<T>(
     mhandle: &MemoryHandle,
     data: &mut [T],
     tag: i32,
-) -> Result<Option<Request>, ffi::ncclResult_t::Type> {
-    let mut request = std::ptr::null_mut();
-    let request_ptr = &mut request;
-    let data_ptr = &mut data.as_mut_ptr() as *mut _;
-    let len_ptr =
-        &mut ((data.len() * std::mem::size_of::<T>()) as i32) as *mut ::std::os::raw::c_int;
-    let mhandle_ptr = &mhandle.ptr as *const _ as *mut _;
-    let ret = unsafe {
-        ffi::ncclNetPlugin_v6.irecv.unwrap()(
-            comm.ptr.as_ptr(),
-            1,
-            data_ptr as *mut *mut ::std::os::raw::c_void,
-            len_ptr,
-            &tag as *const _ as *mut ::std::os::raw::c_int,
-            mhandle_ptr,
-            request_ptr,
-        )
-    };
-    if ret != ffi::ncclResult_t::ncclSuccess {
-        return Err(ret);
+) -> Result<Option<Request>, Error> {
+    irecv_grouped(comm, &[mhandle], &mut [data], &[tag])
+}
+
+// receives up to MAX_RECVS messages with a single request, matched by their tags
+pub(crate) fn irecv_grouped<T>(
+    comm: &Comm,
+    mhandles: &[&MemoryHandle],
+    data: &mut [&mut [T]],
+    tags: &[i32],
+) -> Result<Option<Request>, Error> {
+    let rcomm = comm.expect(CommType::Recv)?;
+    let mut group = Group::new(mhandles, data)?;
+    if tags.len() != group.n {
+        return Err(Error::InvalidArgument);
     }
-    Ok(if request.is_null() {
-        None
-    } else {
-        Some(Request {
-            ptr: unsafe { NonNull::new_unchecked(request) },
-        })
-    })
+    let mut tags = {
+        let mut v = [0; MAX_RECVS];
+        v[..group.n].copy_from_slice(tags);
+        v
+    };
+    let mut request = std::ptr::null_mut();
+    call_net!(irecv(
+        rcomm,
+        group.n as c_int,
+        group.data.as_mut_ptr(),
+        group.sizes.as_mut_ptr(),
+        tags.as_mut_ptr(),
+        group.mhandles.as_mut_ptr(),
+        &mut request,
+    ))?;
+    Ok(Request::new(request, group.n, false))
+}
+
+// makes the data received into CUDA memory visible to the GPU. None when the plugin has
+// nothing to flush.
+#[allow(dead_code)]
+pub(crate) fn iflush<T>(
+    comm: &Comm,
+    mhandles: &[&MemoryHandle],
+    data: &mut [&mut [T]],
+) -> Result<Option<Request>, Error> {
+    let rcomm = comm.expect(CommType::Recv)?;
+    let mut group = Group::new(mhandles, data)?;
+    let mut request = std::ptr::null_mut();
+    call_net!(iflush(
+        rcomm,
+        group.n as c_int,
+        group.data.as_mut_ptr(),
+        group.sizes.as_mut_ptr(),
+        group.mhandles.as_mut_ptr(),
+        &mut request,
+    ))?;
+    Ok(Request::new(request, group.n, false))
 }
 
-pub(crate) fn test(request: &Request) -> Result<(bool, usize), ffi::ncclResult_t::Type> {
+// returns whether the request is done and the bytes transferred by each of its buffers
+pub(crate) fn test_grouped(request: &Request) -> Result<(bool, Vec<usize>), Error> {
     let mut done = 0;
-    let done_ptr = &mut done;
-    let mut sizes = 0;
-    let sizes_ptr = &mut sizes;
-    let ret =
-        unsafe { ffi::ncclNetPlugin_v6.test.unwrap()(request.ptr.as_ptr(), done_ptr, sizes_ptr) };
-    if ret != ffi::ncclResult_t::ncclSuccess {
-        return Err(ret);
-    }
-    Ok(((done != 0), sizes as usize))
+    let mut sizes: [c_int; MAX_RECVS] = [0; MAX_RECVS];
+    let ptr = request.ptr.as_ptr();
+    if request.coll {
+        call!(collnet(), test(ptr, &mut done, sizes.as_mut_ptr()))?;
+    } else {
+        call_net!(test(ptr, &mut done, sizes.as_mut_ptr()))?;
+    }
+    let sizes = sizes[..request.n].iter().map(|v| *v as usize).collect();
+    Ok((done != 0, sizes))
+}
+
+// returns whether the request is done and the bytes it transferred
+pub(crate) fn test(request: &Request) -> Result<(bool, usize), Error> {
+    let (done, sizes) = test_grouped(request)?;
+    Ok((done, sizes.iter().sum()))
+}
+
+// the transport of the loaded plugin
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct NcclNet;
+
+impl Transport for NcclNet {
+    type Error = Error;
+    type Comm = Comm;
+    type MemoryHandle<'a> = MemoryHandle<'a>;
+    type Request = Request;
+
+    fn devices(&self) -> Result<usize, Error> {
+        devices()
+    }
+
+    fn get_properties(&self, dev: usize) -> Result<Properties, Error> {
+        get_properties(dev)
+    }
+
+    fn listen(&self, dev: usize) -> Result<(Comm, Handle), Error> {
+        listen(dev).map(|(comm, handle)| (comm, Handle::from(handle)))
+    }
+
+    fn connect(&self, dev: usize, handle: &Handle) -> Result<Option<Comm>, Error> {
+        connect(dev, &handle.net)
+    }
+
+    fn accept(&self, comm: &Comm) -> Result<Option<Comm>, Error> {
+        accept(comm)
+    }
+
+    fn reg_mr<'a, T>(&self, comm: &'a Comm, data: &[T]) -> Result<MemoryHandle<'a>, Error> {
+        reg_mr(comm, data)
+    }
+
+    fn isend<T>(
+        &self,
+        comm: &Comm,
+        mhandle: &MemoryHandle,
+        data: &[T],
+        tag: i32,
+    ) -> Result<Option<Request>, Error> {
+        isend(comm, mhandle, data, tag)
+    }
+
+    fn irecv<T>(
+        &self,
+        comm: &Comm,
+        mhandle: &MemoryHandle,
+        data: &mut [T],
+        tag: i32,
+    ) -> Result<Option<Request>, Error> {
+        irecv(comm, mhandle, data, tag)
+    }
+
+    fn test(&self, request: &Request) -> Result<(bool, usize), Error> {
+        test(request)
+    }
+}
+
+// the CollNet table of the plugin, through which NCCL offloads the allreduce to the servers
+pub(crate) mod coll {
+    #![allow(dead_code)]
+
+    use super::*;
+
+    pub(crate) fn init() -> Result<(), Error> {
+        call!(collnet(), init(Some(logfn)))
+    }
+
+    pub(crate) fn name() -> String {
+        c_string(collnet().name)
+    }
+
+    // devices capable of collective operations
+    pub(crate) fn devices() -> Result<usize, Error> {
+        let mut ndev = 0;
+        call!(collnet(), devices(&mut ndev))?;
+        Ok(ndev as usize)
+    }
+
+    pub(crate) fn get_properties(dev: usize) -> Result<Properties, Error> {
+        let mut props = ffi::ncclNetProperties_v6_t::default();
+        call!(collnet(), getProperties(dev as c_int, &mut props))?;
+        Ok(Properties::from(&props))
+    }
+
+    pub(crate) fn listen(dev: usize) -> Result<(Comm, Vec<u8>), Error> {
+        let mut handle = [0u8; ffi::NCCL_NET_HANDLE_MAXSIZE as usize];
+        let mut lcomm = std::ptr::null_mut();
+        call!(
+            collnet(),
+            listen(dev as c_int, handle.as_mut_ptr() as *mut c_void, &mut lcomm)
+        )?;
+        let lcomm = Comm::new(lcomm, CommType::CollListen).ok_or(Error::Internal)?;
+        Ok((lcomm, handle.to_vec()))
+    }
+
+    // joins the collective group of the ranks whose listen handles are given, in rank order
+    pub(crate) fn connect(
+        handles: &[Vec<u8>],
+        rank: usize,
+        lcomm: &Comm,
+    ) -> Result<Option<Comm>, Error> {
+        let lcomm = lcomm.expect(CommType::CollListen)?;
+        if rank >= handles.len() {
+            return Err(Error::InvalidArgument);
+        }
+        let mut handles = handles
+            .iter()
+            .map(|v| v.as_ptr() as *mut c_void)
+            .collect::<Vec<_>>();
+        let mut comm = std::ptr::null_mut();
+        call!(
+            collnet(),
+            connect(
+                handles.as_mut_ptr(),
+                handles.len() as c_int,
+                rank as c_int,
+                lcomm,
+                &mut comm,
+            )
+        )?;
+        Ok(Comm::new(comm, CommType::Coll))
+    }
+
+    pub(crate) fn reduce_support(data_type: DataType, reduce_op: ReduceOp) -> Result<bool, Error> {
+        let mut supported = 0;
+        call!(
+            collnet(),
+            reduceSupport(data_type.to_nccl(), reduce_op.to_nccl(), &mut supported)
+        )?;
+        Ok(supported != 0)
+    }
+
+    pub(crate) fn iallreduce<T>(
+        comm: &Comm,
+        send: &[T],
+        recv: &mut [T],
+        data_type: DataType,
+        reduce_op: ReduceOp,
+        send_mhandle: &MemoryHandle,
+        recv_mhandle: &MemoryHandle,
+    ) -> Result<Option<Request>, Error> {
+        let ccomm = comm.expect(CommType::Coll)?;
+        if send.len() != recv.len() || std::mem::size_of::<T>() != data_type.size() {
+            return Err(Error::InvalidArgument);
+        }
+        let mut request = std::ptr::null_mut();
+        call!(
+            collnet(),
+            iallreduce(
+                ccomm,
+                send.as_ptr() as *mut c_void,
+                recv.as_mut_ptr() as *mut c_void,
+                c_size(send.len())?,
+                data_type.to_nccl(),
+                reduce_op.to_nccl(),
+                send_mhandle.ptr,
+                recv_mhandle.ptr,
+                &mut request,
+            )
+        )?;
+        Ok(Request::new(request, 1, true))
+    }
+
+    pub(crate) fn iflush<T>(
+        comm: &Comm,
+        mhandle: &MemoryHandle,
+        data: &mut [T],
+    ) -> Result<Option<Request>, Error> {
+        let ccomm = comm.expect(CommType::Coll)?;
+        let mut request = std::ptr::null_mut();
+        call!(
+            collnet(),
+            iflush(
+                ccomm,
+                data.as_mut_ptr() as *mut c_void,
+                c_size(std::mem::size_of_val(data))?,
+                mhandle.ptr,
+                &mut request,
+            )
+        )?;
+        Ok(Request::new(request, 1, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::tests::initialize;
+
+    // a listen comm and a connected pair of send and recv comms on device 0
+    fn connect_pair() -> (Comm, Comm, Comm) {
+        let (lcomm, handle) = listen(0).unwrap();
+        let mut scomm = None;
+        let mut rcomm = None;
+        while scomm.is_none() || rcomm.is_none() {
+            if scomm.is_none() {
+                scomm = connect(0, &handle).unwrap();
+            }
+            if rcomm.is_none() {
+                rcomm = accept(&lcomm).unwrap();
+            }
+        }
+        (lcomm, scomm.unwrap(), rcomm.unwrap())
+    }
+
+    #[test]
+    fn test_check() {
+        assert_eq!(Error::check(ffi::ncclResult_t::ncclSuccess), Ok(()));
+        assert_eq!(
+            Error::check(ffi::ncclResult_t::ncclRemoteError),
+            Err(Error::Remote)
+        );
+        assert_eq!(Error::check(100), Err(Error::Unknown(100)));
+        assert_eq!(c_size(1 << 31), Err(Error::InvalidArgument));
+    }
+
+    #[test]
+    fn test_version() {
+        // the newest table the plugin exports is used
+        load("").unwrap();
+        let path = CString::new(plugin().library.as_str()).unwrap();
+        let handle = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        assert!(!handle.is_null());
+        let v8 = lookup::<c_void>(handle, c"ncclNetPlugin_v8").is_some();
+        let v7 = lookup::<c_void>(handle, c"ncclNetPlugin_v7").is_some();
+        let expected = match (v8, v7) {
+            (true, _) => Version::V8,
+            (false, true) => Version::V7,
+            (false, false) => Version::V6,
+        };
+        assert_eq!(version(), expected);
+        assert!(lookup::<c_void>(handle, c"ncclNetPlugin_v0").is_none());
+
+        let props = ffi::ncclNetProperties_v8_t {
+            ptrSupport: ffi::NCCL_PTR_HOST as i32,
+            regIsGlobal: 1,
+            speed: 100000,
+            maxRecvs: 8,
+            ..Default::default()
+        };
+        let props = Properties::from(&props);
+        assert_eq!(props.name, "");
+        assert!(props.reg_is_global);
+        assert_eq!(props.speed, 100000);
+        assert_eq!(props.max_recvs, 8);
+    }
+
+    #[test]
+    fn test_load() {
+        assert_eq!(plugin_library(""), "libnccl-net.so");
+        assert_eq!(plugin_library("ofi"), "libnccl-net-ofi.so");
+        assert_eq!(plugin_library("libnccl-net-ofi.so"), "libnccl-net-ofi.so");
+        assert_eq!(
+            plugin_library("/opt/lib/plugin.so.1"),
+            "/opt/lib/plugin.so.1"
+        );
+
+        // missing libraries and libraries without a net table are reported with their name
+        match open("/nonexistent/libnccl-net.so") {
+            Err(Error::Load(msg)) => assert!(msg.contains("/nonexistent/libnccl-net.so")),
+            _ => panic!("loaded a missing plugin"),
+        }
+        match open("libc.so.6") {
+            Err(Error::Load(msg)) => assert!(msg.contains("libc.so.6: no ncclNetPlugin")),
+            _ => panic!("loaded a library without a net table"),
+        }
+    }
+
+    #[test]
+    fn test_get_properties() {
+        initialize();
+        let ndev = devices().unwrap();
+        assert!(ndev > 0);
+        for dev in 0..ndev {
+            let props = get_properties(dev).unwrap();
+            assert!(!props.name.is_empty());
+            assert_ne!(props.ptr_support & ffi::NCCL_PTR_HOST as i32, 0);
+        }
+    }
+
+    #[test]
+    fn test_send_recv() {
+        initialize();
+        let (lcomm, scomm, rcomm) = connect_pair();
+
+        // the comms only accept the operations of their kind
+        let mut buf = vec![0u8; 1024];
+        assert!(matches!(accept(&scomm), Err(Error::InvalidUsage)));
+        assert!(matches!(reg_mr(&lcomm, &buf), Err(Error::InvalidUsage)));
+
+        let data = (0..1024).map(|v| v as u8).collect::<Vec<_>>();
+        let smh = reg_mr(&scomm, &data).unwrap();
+        let rmh = reg_mr(&rcomm, &buf).unwrap();
+        assert!(matches!(
+            isend(&rcomm, &rmh, &data, 0),
+            Err(Error::InvalidUsage)
+        ));
+
+        // grouped receives are limited to MAX_RECVS buffers with a tag each
+        let mut empty: [&mut [u8]; 0] = [];
+        assert!(matches!(
+            irecv_grouped(&rcomm, &[], &mut empty, &[]),
+            Err(Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            irecv_grouped(&rcomm, &[&rmh], &mut [&mut buf[..]], &[0, 1]),
+            Err(Error::InvalidArgument)
+        ));
+
+        let mut sreq = None;
+        let mut rreq = None;
+        while sreq.is_none() || rreq.is_none() {
+            if sreq.is_none() {
+                sreq = isend(&scomm, &smh, &data[..100], 0x69).unwrap();
+            }
+            if rreq.is_none() {
+                rreq = irecv_grouped(&rcomm, &[&rmh], &mut [&mut buf[..]], &[0x69]).unwrap();
+            }
+        }
+        let (sreq, rreq) = (sreq.unwrap(), rreq.unwrap());
+        while !test(&sreq).unwrap().0 {}
+        let sizes = loop {
+            let (done, sizes) = test_grouped(&rreq).unwrap();
+            if done {
+                break sizes;
+            }
+        };
+        assert_eq!(sizes, vec![100]);
+        assert_eq!(buf[..100], data[..100]);
+    }
 }
@@ -0,0 +1,201 @@
+/*
+ * Copyright (c) 2024, the Optcast Authors. All rights reserved.
+ *
+ * See LICENSE for license information
+ */
+
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+
+use log::warn;
+
+use crate::utils::Backoff;
+
+// a unit of work that a pool thread drives along with the other tasks of the thread. poll makes
+// whatever progress it can without blocking and returns None once the task is over, otherwise
+// whether it made any progress.
+pub(crate) trait Task {
+    fn poll(&mut self) -> Option<bool>;
+
+    // called with the message of a panic of poll, before the task is dropped
+    fn panicked(&mut self, _message: &str) {}
+}
+
+// tasks are made on the thread that polls them, so that they may keep what can't be sent to
+// another thread, like the memory registrations of their comms
+type Spawn = Box<dyn FnOnce() -> Box<dyn Task> + Send>;
+
+// a fixed set of threads shared by all the communicators of a server. the tasks are assigned to
+// the threads round robin, so that consecutive tasks, like the ones of a communicator, land on
+// different threads. a thread polls its tasks in turn and backs off when none of them
+// progresses.
+pub(crate) struct Pool {
+    chs: Vec<Sender<Spawn>>,
+    threads: Vec<std::thread::JoinHandle<()>>,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    pub(crate) fn new(name: &str, nthreads: usize) -> Self {
+        assert!(nthreads > 0, "a pool needs at least one thread");
+        let (chs, threads) = (0..nthreads)
+            .map(|i| {
+                let (tx, rx) = std::sync::mpsc::channel();
+                let name = format!("{}({})", name, i);
+                let h = std::thread::Builder::new()
+                    .name(name.clone())
+                    .spawn(move || run(&name, rx))
+                    .unwrap();
+                (tx, h)
+            })
+            .unzip();
+        Pool {
+            chs,
+            threads,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.chs.len()
+    }
+
+    pub(crate) fn spawn<F, T>(&self, f: F)
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Task + 'static,
+    {
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.chs.len();
+        self.chs[i]
+            .send(Box::new(move || Box::new(f()) as Box<dyn Task>))
+            .unwrap();
+    }
+}
+
+// the threads exit once their tasks are over
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.chs.clear();
+        for h in self.threads.drain(..) {
+            let _ = h.join();
+        }
+    }
+}
+
+fn run(name: &str, rx: Receiver<Spawn>) {
+    let mut tasks: Vec<Box<dyn Task>> = vec![];
+    let mut backoff = Backoff::new();
+    loop {
+        // an idle thread sleeps until it gets a task
+        let spawn = if tasks.is_empty() {
+            match rx.recv() {
+                Ok(v) => Some(v),
+                Err(_) => return,
+            }
+        } else {
+            rx.try_recv().ok()
+        };
+        if let Some(spawn) = spawn {
+            tasks.push(spawn());
+            backoff.reset();
+        }
+
+        // a task that panics is told and dropped, the other tasks of the thread go on
+        let mut progressed = false;
+        tasks.retain_mut(
+            |task| match std::panic::catch_unwind(AssertUnwindSafe(|| task.poll())) {
+                Ok(Some(v)) => {
+                    progressed |= v;
+                    true
+                }
+                Ok(None) => false,
+                Err(e) => {
+                    let message = e
+                        .downcast_ref::<&str>()
+                        .map(|v| v.to_string())
+                        .or_else(|| e.downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                    warn!("{}: task panicked: {}", name, message);
+                    task.panicked(&message);
+                    false
+                }
+            },
+        );
+
+        if progressed {
+            backoff.reset();
+        } else {
+            backoff.idle();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    struct Count {
+        n: usize,
+        panic: bool,
+        threads: Arc<std::sync::Mutex<Vec<String>>>,
+        done: Sender<()>,
+        panics: Sender<String>,
+    }
+
+    impl Task for Count {
+        fn poll(&mut self) -> Option<bool> {
+            if self.n == 0 {
+                let name = std::thread::current().name().unwrap().to_string();
+                self.threads.lock().unwrap().push(name);
+                let _ = self.done.send(());
+                return None;
+            }
+            self.n -= 1;
+            if self.panic && self.n == 1 {
+                panic!("count");
+            }
+            Some(true)
+        }
+
+        fn panicked(&mut self, message: &str) {
+            let _ = self.panics.send(message.to_string());
+        }
+    }
+
+    #[test]
+    fn test_pool() {
+        let pool = Pool::new("test", 3);
+        assert_eq!(pool.len(), 3);
+        let threads = Arc::new(std::sync::Mutex::new(vec![]));
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (panics_tx, panics) = std::sync::mpsc::channel();
+        // the task of 5 panics halfway, the others share the threads with it
+        for n in [0, 1, 2, 100, 5, 1000] {
+            let threads = Arc::clone(&threads);
+            let done = tx.clone();
+            let panics = panics_tx.clone();
+            let panic = n == 5;
+            pool.spawn(move || Count {
+                n,
+                panic,
+                threads,
+                done,
+                panics,
+            });
+        }
+        drop(tx);
+        drop(panics_tx);
+        assert_eq!(rx.iter().count(), 5);
+        assert_eq!(panics.iter().collect::<Vec<_>>(), ["count"]);
+        drop(pool);
+
+        let mut threads = threads.lock().unwrap().clone();
+        threads.sort();
+        assert_eq!(
+            threads,
+            ["test(0)", "test(0)", "test(1)", "test(2)", "test(2)"]
+        );
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ * Copyright (c) 2024, the Optcast Authors. All rights reserved.
+ *
+ * See LICENSE for license information
+ */
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const BITS: usize = u64::BITS as usize;
+
+// readiness flags of a buffer, one per thread that works on it. the threads set their own flag
+// when they are done with the buffer and the owner of the buffer waits for all of them, then
+// clears them to hand the buffer back. unlike a single word, it works for any number of threads.
+pub(crate) struct ReadySet {
+    words: Vec<AtomicU64>,
+    len: usize,
+}
+
+impl ReadySet {
+    pub(crate) fn new(len: usize, set: bool) -> Self {
+        let v = ReadySet {
+            words: (0..len.div_ceil(BITS)).map(|_| AtomicU64::new(0)).collect(),
+            len,
+        };
+        if set {
+            v.set_all();
+        }
+        v
+    }
+
+    // the bits of word i that are in use
+    fn mask(&self, i: usize) -> u64 {
+        let n = (self.len - i * BITS).min(BITS);
+        if n == BITS {
+            u64::MAX
+        } else {
+            (1 << n) - 1
+        }
+    }
+
+    pub(crate) fn set(&self, i: usize) {
+        assert!(i < self.len);
+        self.words[i / BITS].fetch_or(1 << (i % BITS), Ordering::Release);
+    }
+
+    pub(crate) fn is_set(&self, i: usize) -> bool {
+        assert!(i < self.len);
+        self.words[i / BITS].load(Ordering::Acquire) & (1 << (i % BITS)) != 0
+    }
+
+    pub(crate) fn all_set(&self) -> bool {
+        self.words
+            .iter()
+            .enumerate()
+            .all(|(i, w)| w.load(Ordering::Acquire) == self.mask(i))
+    }
+
+    pub(crate) fn none_set(&self) -> bool {
+        self.words.iter().all(|w| w.load(Ordering::Acquire) == 0)
+    }
+
+    pub(crate) fn set_all(&self) {
+        for (i, w) in self.words.iter().enumerate() {
+            w.store(self.mask(i), Ordering::Release);
+        }
+    }
+
+    // the threads only set their flag again after they see it cleared, so clearing the words
+    // one by one doesn't lose any of them
+    pub(crate) fn clear_all(&self) {
+        for w in self.words.iter() {
+            w.store(0, Ordering::Release);
+        }
+    }
+
+    // the threads that haven't set their flag, for diagnostics
+    pub(crate) fn unset(&self) -> Vec<usize> {
+        (0..self.len).filter(|&i| !self.is_set(i)).collect()
+    }
+}
+
+// test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ready_set() {
+        for len in [1, 2, 63, 64, 65, 128, 200] {
+            let v = ReadySet::new(len, false);
+            assert!(v.none_set());
+            assert!(!v.all_set());
+            for i in 0..len {
+                assert!(!v.all_set());
+                v.set(i);
+                assert!(v.is_set(i));
+            }
+            assert!(v.all_set());
+            assert!(v.unset().is_empty());
+            v.clear_all();
+            assert!(v.none_set());
+            assert_eq!(v.unset(), (0..len).collect::<Vec<_>>());
+
+            let v = ReadySet::new(len, true);
+            assert!(v.all_set());
+            v.clear_all();
+            v.set(len - 1);
+            assert!(!v.none_set());
+            assert_eq!(v.unset(), (0..len - 1).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_ready_set_threads() {
+        let n = 130;
+        let v = std::sync::Arc::new(ReadySet::new(n, false));
+        let hs = (0..n)
+            .map(|i| {
+                let v = std::sync::Arc::clone(&v);
+                std::thread::spawn(move || v.set(i))
+            })
+            .collect::<Vec<_>>();
+        hs.into_iter().for_each(|h| h.join().unwrap());
+        assert!(v.all_set());
+    }
+}
@@ -4,21 +4,24 @@
  * See LICENSE for license information
  */
 
+use std::simd::prelude::*;
+use std::simd::SimdElement;
+
 use aligned_box::AlignedBox;
+use float8::{F8E4M3, F8E5M2};
+use half::slice::HalfFloatSliceExt;
 use half::{f16, bf16};
 
-use crate::utils::{alignment, Float};
+use crate::utils::{alignment, Element, ReduceOp};
 
 #[cfg(all(target_arch = "aarch64", target_feature = "fp16"))]
 mod aarch64;
 
-#[cfg(not(all(target_arch = "aarch64", target_feature = "fp16")))]
-use half::slice::HalfFloatSliceExt;
-
 #[allow(dead_code)]
 pub(crate) struct WorkingMemory {
     recv_bufs: Vec<AlignedBox<[f32]>>,
     send_buf: AlignedBox<[f32]>,
+    pub(crate) accumulate_f64: bool, // accumulate f32 elements in f64
 }
 
 #[allow(dead_code)]
@@ -31,117 +34,482 @@ impl WorkingMemory {
         Self {
             recv_bufs,
             send_buf,
+            accumulate_f64: false,
         }
     }
 }
 
+// recv_bufs are folded one after another in their order, and every element goes through the
+// same arithmetic whatever its position is. the result is a fixed function of the order of
+// recv_bufs, bit for bit, independent of how the buffers are split into chunks.
 pub(crate) trait Reduce<T> {
     fn reduce(
         &mut self,
         recv_bufs: &Vec<&[T]>,
+        op: ReduceOp,
         work_mem: Option<&mut WorkingMemory>,
     ) -> Result<(), ()>;
 }
 
-impl<T: Float> Reduce<T> for [T] {
-    default fn reduce(&mut self, _: &Vec<&[T]>, _: Option<&mut WorkingMemory>) -> Result<(), ()> {
+impl<T: Element> Reduce<T> for [T] {
+    default fn reduce(&mut self, _: &Vec<&[T]>, _: ReduceOp, _: Option<&mut WorkingMemory>) -> Result<(), ()> {
         Err(())
     }
 }
 
+// calls $f with the SIMD and the scalar form of the operator on the floating point type $t
+macro_rules! with_op {
+    ($op:expr, $t:ty, $f:expr, $($arg:expr),*) => {
+        match $op {
+            ReduceOp::Sum | ReduceOp::Avg => $f($($arg,)* |a, b| a + b, |a, b| a + b),
+            ReduceOp::Prod => $f($($arg,)* |a, b| a * b, |a, b| a * b),
+            ReduceOp::Max => $f($($arg,)* |a, b| a.simd_max(b), <$t>::max),
+            ReduceOp::Min => $f($($arg,)* |a, b| a.simd_min(b), <$t>::min),
+        }
+    };
+}
+
 impl Reduce<f16> for [f16] {
-    #[allow(unused_variables)]
     fn reduce(
         &mut self,
         recv_bufs: &Vec<&[f16]>,
+        op: ReduceOp,
         work_mem: Option<&mut WorkingMemory>,
     ) -> Result<(), ()> {
+        check_len(self, recv_bufs)?;
         cfg_if::cfg_if! {
             if #[cfg(all(
                 target_arch = "aarch64",
                 target_feature = "fp16"
             ))] {
-                for (i, recv) in recv_bufs.iter().enumerate() {
-                    if i == 0 {
-                        self.copy_from_slice(recv);
-                    } else {
-                        unsafe {aarch64::add_assign_f16_aligned_slice(self, recv);}
+                // a f16 sum easily overflows before the division, so Avg takes the f32 path below
+                if op != ReduceOp::Avg {
+                    let (simd_op, scalar_op): (unsafe fn(&mut [f16], &[f16]), fn(f16, f16) -> f16) = match op {
+                        ReduceOp::Sum | ReduceOp::Avg => (aarch64::add_assign_f16_aligned_slice, |a, b| a + b),
+                        ReduceOp::Prod => (aarch64::mul_assign_f16_aligned_slice, |a, b| a * b),
+                        ReduceOp::Max => (aarch64::max_assign_f16_aligned_slice, f16::max),
+                        ReduceOp::Min => (aarch64::min_assign_f16_aligned_slice, f16::min),
+                    };
+                    // the vector unit handles 8 elements at a time, the rest is done one by one
+                    let n = self.len() - self.len() % 8;
+                    for (i, recv) in recv_bufs.iter().enumerate() {
+                        if i == 0 {
+                            self.copy_from_slice(recv);
+                        } else {
+                            unsafe { simd_op(&mut self[..n], &recv[..n]); }
+                            for j in n..self.len() {
+                                self[j] = scalar_op(self[j], recv[j]);
+                            }
+                        }
                     }
+                    return Ok(());
                 }
             }
-            else {
-                let work_mem = work_mem.unwrap();
-                for (i, recv) in recv_bufs.iter().enumerate() {
-                    recv.convert_to_f32_slice(&mut work_mem.recv_bufs[i].as_mut());
-                }
-                work_mem.send_buf.reduce(
-                    &work_mem
-                        .recv_bufs
-                        .iter()
-                        .map(|v| {
-                            let slice_ref: &[f32] = &**v;
-                            slice_ref
-                        })
-                        .collect(),
-                    None,
-                )?;
-                self.as_mut()
-                    .convert_from_f32_slice(&work_mem.send_buf.as_ref());
-                }
         }
-        Ok(())
+        reduce_in_f32(
+            self,
+            recv_bufs,
+            op,
+            work_mem,
+            |src, dst| src.convert_to_f32_slice(dst),
+            |src, dst| dst.convert_from_f32_slice(src),
+        )
     }
 }
 
+// calls $f with the SIMD form of the operator
+macro_rules! with_simd_op {
+    ($op:expr, $f:expr, $($arg:expr),*) => {
+        match $op {
+            ReduceOp::Sum | ReduceOp::Avg => $f($($arg,)* |a, b| a + b),
+            ReduceOp::Prod => $f($($arg,)* |a, b| a * b),
+            ReduceOp::Max => $f($($arg,)* |a, b| a.simd_max(b)),
+            ReduceOp::Min => $f($($arg,)* |a, b| a.simd_min(b)),
+        }
+    };
+}
+
+// FP8 has only 2 or 3 mantissa bits, so the reduction is done in f32 and rounded once.
+// results out of range saturate to the max finite value.
+macro_rules! impl_reduce_f8 {
+    ($t:ty) => {
+        impl Reduce<$t> for [$t] {
+            fn reduce(
+                &mut self,
+                recv_bufs: &Vec<&[$t]>,
+                op: ReduceOp,
+                work_mem: Option<&mut WorkingMemory>,
+            ) -> Result<(), ()> {
+                check_len(self, recv_bufs)?;
+                reduce_in_f32(
+                    self,
+                    recv_bufs,
+                    op,
+                    work_mem,
+                    |src, dst| dst.iter_mut().zip(src).for_each(|(d, s)| *d = s.to_f32()),
+                    |src, dst| dst.iter_mut().zip(src).for_each(|(d, s)| *d = <$t>::from_f32(*s)),
+                )
+            }
+        }
+    };
+}
+
+impl_reduce_f8!(F8E4M3);
+impl_reduce_f8!(F8E5M2);
+
 // impl<T: Float + std::simd::SimdElement> Reduce<T> for AlignedBox<[T]> can't compile
 // error: cannot specialize on trait `SimdElement`
 // --> src/main.rs:139:17
 // |
 // 139 | impl<T: Float + std::simd::SimdElement> Reduce<T> for AlignedBox<[T]> {
-impl Reduce<f32> for [f32] {
-    fn reduce(&mut self, recv_bufs: &Vec<&[f32]>, _: Option<&mut WorkingMemory>) -> Result<(), ()> {
-        let (_, send, _) = self.as_simd_mut::<4>();
-        for (i, recv) in recv_bufs.iter().enumerate() {
-            let (_, recv, _) = recv.as_ref().as_simd::<4>();
-            if i == 0 {
-                send.copy_from_slice(&recv.as_ref());
-            } else {
-                for j in 0..send.len() {
-                    send[j] += recv[j];
+macro_rules! impl_reduce_float {
+    ($t:ty, $lanes:literal) => {
+        impl Reduce<$t> for [$t] {
+            fn reduce(&mut self, recv_bufs: &Vec<&[$t]>, op: ReduceOp, _: Option<&mut WorkingMemory>) -> Result<(), ()> {
+                check_len(self, recv_bufs)?;
+                with_op!(op, $t, reduce_simd::<$t, $lanes>, self, recv_bufs);
+                if op == ReduceOp::Avg {
+                    let n = recv_bufs.len() as $t;
+                    self.iter_mut().for_each(|v| *v /= n);
                 }
+                Ok(())
             }
         }
+    };
+}
+
+impl_reduce_float!(f64, 2);
+
+impl Reduce<f32> for [f32] {
+    fn reduce(&mut self, recv_bufs: &Vec<&[f32]>, op: ReduceOp, work_mem: Option<&mut WorkingMemory>) -> Result<(), ()> {
+        check_len(self, recv_bufs)?;
+        // max and min are exact, there is nothing to gain from a wider accumulator
+        let wide = work_mem.is_some_and(|m| m.accumulate_f64) && !matches!(op, ReduceOp::Max | ReduceOp::Min);
+        if wide {
+            let n = if op == ReduceOp::Avg { recv_bufs.len() } else { 1 } as f64;
+            with_simd_op!(
+                op,
+                reduce_widened::<f32, f64, 4>,
+                self,
+                recv_bufs,
+                |v| v.cast(),
+                |v| (v / Simd::splat(n)).cast()
+            );
+            return Ok(());
+        }
+        with_op!(op, f32, reduce_simd::<f32, 4>, self, recv_bufs);
+        if op == ReduceOp::Avg {
+            let n = recv_bufs.len() as f32;
+            self.iter_mut().for_each(|v| *v /= n);
+        }
         Ok(())
     }
 }
 
-impl Reduce<bf16> for [bf16] {
-    fn reduce(&mut self, recv_bufs: &Vec<&[bf16]>, _: Option<&mut WorkingMemory>) -> Result<(), ()> {
-        for (i, recv) in recv_bufs.iter().enumerate() {
-            if i == 0 {
-                self.copy_from_slice(&recv.as_ref());
-            } else {
-                for j in 0..self.len() {
-                    self[j] += recv[j];
+// Sum and Prod wrap around on overflow. Avg is accumulated in i128 and truncated toward zero.
+macro_rules! impl_reduce_int {
+    ($t:ty, $lanes:literal) => {
+        impl Reduce<$t> for [$t] {
+            fn reduce(&mut self, recv_bufs: &Vec<&[$t]>, op: ReduceOp, _: Option<&mut WorkingMemory>) -> Result<(), ()> {
+                check_len(self, recv_bufs)?;
+                match op {
+                    ReduceOp::Sum => reduce_simd::<$t, $lanes>(self, recv_bufs, |a, b| a + b, <$t>::wrapping_add),
+                    ReduceOp::Prod => reduce_simd::<$t, $lanes>(self, recv_bufs, |a, b| a * b, <$t>::wrapping_mul),
+                    ReduceOp::Max => reduce_simd::<$t, $lanes>(self, recv_bufs, |a, b| a.simd_max(b), <$t>::max),
+                    ReduceOp::Min => reduce_simd::<$t, $lanes>(self, recv_bufs, |a, b| a.simd_min(b), <$t>::min),
+                    ReduceOp::Avg => {
+                        let n = recv_bufs.len() as i128;
+                        for j in 0..self.len() {
+                            let sum = recv_bufs.iter().map(|recv| recv[j] as i128).sum::<i128>();
+                            self[j] = (sum / n) as $t;
+                        }
+                    }
                 }
+                Ok(())
             }
         }
+    };
+}
+
+impl_reduce_int!(i8, 16);
+impl_reduce_int!(u8, 16);
+impl_reduce_int!(i32, 4);
+impl_reduce_int!(u32, 4);
+impl_reduce_int!(i64, 2);
+impl_reduce_int!(u64, 2);
+
+impl Reduce<bf16> for [bf16] {
+    fn reduce(&mut self, recv_bufs: &Vec<&[bf16]>, op: ReduceOp, _: Option<&mut WorkingMemory>) -> Result<(), ()> {
+        check_len(self, recv_bufs)?;
+        let recv_bufs = recv_bufs
+            .iter()
+            .map(|v| v.reinterpret_cast())
+            .collect::<Vec<_>>();
+        // accumulated in f32 and rounded to bf16 once, after the division for Avg
+        let n = if op == ReduceOp::Avg { recv_bufs.len() } else { 1 } as f32;
+        with_simd_op!(
+            op,
+            reduce_widened::<u16, f32, 8>,
+            self.reinterpret_cast_mut(),
+            &recv_bufs,
+            widen_bf16,
+            |v| narrow_bf16(v / Simd::splat(n))
+        );
         Ok(())
     }
 }
 
+// widens the elements into the working memory, reduces them there and narrows the result back
+fn reduce_in_f32<T>(
+    send_buf: &mut [T],
+    recv_bufs: &Vec<&[T]>,
+    op: ReduceOp,
+    work_mem: Option<&mut WorkingMemory>,
+    widen: impl Fn(&[T], &mut [f32]),
+    narrow: impl Fn(&[f32], &mut [T]),
+) -> Result<(), ()> {
+    let len = send_buf.len();
+    let work_mem = work_mem.ok_or(())?;
+    for (i, recv) in recv_bufs.iter().enumerate() {
+        widen(recv, &mut work_mem.recv_bufs[i][..len]);
+    }
+    work_mem.send_buf[..len].reduce(
+        &work_mem
+            .recv_bufs
+            .iter()
+            .take(recv_bufs.len())
+            .map(|v| &v[..len])
+            .collect(),
+        op,
+        None,
+    )?;
+    narrow(&work_mem.send_buf[..len], send_buf);
+    Ok(())
+}
+
+// all the buffers must have the same length
+fn check_len<T>(send_buf: &[T], recv_bufs: &Vec<&[T]>) -> Result<(), ()> {
+    if recv_bufs.iter().any(|recv| recv.len() != send_buf.len()) {
+        return Err(());
+    }
+    Ok(())
+}
+
+fn reduce_scalar<T: Copy>(send_buf: &mut [T], recv_bufs: &Vec<&[T]>, op: impl Fn(T, T) -> T) {
+    for (i, recv) in recv_bufs.iter().enumerate() {
+        if i == 0 {
+            send_buf.copy_from_slice(recv);
+        } else {
+            for j in 0..send_buf.len() {
+                send_buf[j] = op(send_buf[j], recv[j]);
+            }
+        }
+    }
+}
+
+// simd_op is applied to the lane aligned body and op to the unaligned head and tail.
+// the SIMD body can only be used when all the buffers share the same alignment.
+fn reduce_simd<T, const N: usize>(
+    send_buf: &mut [T],
+    recv_bufs: &Vec<&[T]>,
+    simd_op: impl Fn(Simd<T, N>, Simd<T, N>) -> Simd<T, N>,
+    op: impl Fn(T, T) -> T,
+) where
+    T: SimdElement,
+{
+    let offset = send_buf.as_simd::<N>().0.len();
+    if recv_bufs.iter().any(|recv| recv.as_simd::<N>().0.len() != offset) {
+        return reduce_scalar(send_buf, recv_bufs, op);
+    }
+    let (send_head, send, send_tail) = send_buf.as_simd_mut::<N>();
+    for (i, recv) in recv_bufs.iter().enumerate() {
+        let (recv_head, recv, recv_tail) = recv.as_simd::<N>();
+        if i == 0 {
+            send_head.copy_from_slice(recv_head);
+            send.copy_from_slice(recv);
+            send_tail.copy_from_slice(recv_tail);
+        } else {
+            for j in 0..send_head.len() {
+                send_head[j] = op(send_head[j], recv_head[j]);
+            }
+            for j in 0..send.len() {
+                send[j] = simd_op(send[j], recv[j]);
+            }
+            for j in 0..send_tail.len() {
+                send_tail[j] = op(send_tail[j], recv_tail[j]);
+            }
+        }
+    }
+}
+
+// bf16 is the upper half of a f32, widening is just a shift
+fn widen_bf16(v: Simd<u16, 8>) -> Simd<f32, 8> {
+    Simd::from_bits(v.cast::<u32>() << Simd::splat(16))
+}
+
+// rounds to nearest even the same way as bf16::from_f32, NaNs are kept quiet
+fn narrow_bf16(v: Simd<f32, 8>) -> Simd<u16, 8> {
+    let bits = v.to_bits();
+    let lsb = (bits >> Simd::splat(16)) & Simd::splat(1);
+    let rounded = (bits + Simd::splat(0x7fff) + lsb) >> Simd::splat(16);
+    let nan = (bits >> Simd::splat(16)) | Simd::splat(0x0040);
+    v.is_nan().select(nan, rounded).cast::<u16>()
+}
+
+// every element is accumulated in the wider type W over all the recv_bufs and narrowed once.
+// the head and the tail are padded to a full vector, so that all the elements go through the
+// same arithmetic.
+fn reduce_widened<T, W, const N: usize>(
+    send_buf: &mut [T],
+    recv_bufs: &[&[T]],
+    widen: impl Fn(Simd<T, N>) -> Simd<W, N>,
+    narrow: impl Fn(Simd<W, N>) -> Simd<T, N>,
+    simd_op: impl Fn(Simd<W, N>, Simd<W, N>) -> Simd<W, N>,
+) where
+    T: SimdElement + Default,
+    W: SimdElement,
+{
+    for start in (0..send_buf.len()).step_by(N) {
+        let end = (start + N).min(send_buf.len());
+        let mut acc = widen(Simd::load_or_default(&recv_bufs[0][start..end]));
+        for recv in &recv_bufs[1..] {
+            acc = simd_op(acc, widen(Simd::load_or_default(&recv[start..end])));
+        }
+        send_buf[start..end].copy_from_slice(&narrow(acc).as_array()[..end - start]);
+    }
+}
+
+// accumulate-on-arrival folds the recv buffers into a wider accumulator one at a time, as each
+// of them arrives, and narrows the result once at the end. the accumulators are the ones the
+// batch reductions use, so folding in the same order gives the same result bit for bit.
+pub(crate) trait Accumulate: Copy {
+    type Acc: Copy + Default + Send;
+    fn widen(self) -> Self::Acc;
+    fn narrow(acc: Self::Acc) -> Self;
+    fn fold_sum(a: Self::Acc, b: Self::Acc) -> Self::Acc;
+    fn fold_prod(a: Self::Acc, b: Self::Acc) -> Self::Acc;
+    fn fold_max(a: Self::Acc, b: Self::Acc) -> Self::Acc;
+    fn fold_min(a: Self::Acc, b: Self::Acc) -> Self::Acc;
+    fn average(acc: Self::Acc, n: usize) -> Self::Acc;
+    // rounds the result of a fold to the precision the batch reduction accumulates in, which
+    // for f32 depends on wide, --accumulate-f64
+    fn round(acc: Self::Acc, _wide: bool) -> Self::Acc {
+        acc
+    }
+}
+
+macro_rules! impl_accumulate_float {
+    ($t:ty, $acc:ty, $widen:expr, $narrow:expr) => {
+        impl_accumulate_float!($t, $acc, $widen, $narrow, |v: $acc, _| v);
+    };
+    ($t:ty, $acc:ty, $widen:expr, $narrow:expr, $round:expr) => {
+        impl Accumulate for $t {
+            type Acc = $acc;
+            fn widen(self) -> $acc { $widen(self) }
+            fn narrow(acc: $acc) -> Self { $narrow(acc) }
+            fn fold_sum(a: $acc, b: $acc) -> $acc { a + b }
+            fn fold_prod(a: $acc, b: $acc) -> $acc { a * b }
+            fn fold_max(a: $acc, b: $acc) -> $acc { a.max(b) }
+            fn fold_min(a: $acc, b: $acc) -> $acc { a.min(b) }
+            fn average(acc: $acc, n: usize) -> $acc { acc / n as $acc }
+            fn round(acc: $acc, wide: bool) -> $acc { $round(acc, wide) }
+        }
+    };
+}
+
+impl_accumulate_float!(f16, f32, |v: f16| v.to_f32(), f16::from_f32);
+impl_accumulate_float!(bf16, f32, |v: bf16| v.to_f32(), bf16::from_f32);
+impl_accumulate_float!(F8E4M3, f32, |v: F8E4M3| v.to_f32(), F8E4M3::from_f32);
+impl_accumulate_float!(F8E5M2, f32, |v: F8E5M2| v.to_f32(), F8E5M2::from_f32);
+// the accumulator is f64 either way. without --accumulate-f64 every fold is rounded to f32,
+// which gives the f32 arithmetic of the batch reduction: an f64 sum, product or quotient of f32
+// values rounded to f32 is the f32 one.
+impl_accumulate_float!(f32, f64, |v: f32| v as f64, |v: f64| v as f32, |v: f64, wide: bool| {
+    if wide {
+        v
+    } else {
+        v as f32 as f64
+    }
+});
+impl_accumulate_float!(f64, f64, |v: f64| v, |v: f64| v);
+
+// the low bits of the wrapping i128 arithmetic are the wrapping arithmetic of $t, so
+// truncating the accumulator gives the same Sum and Prod as the batch reduction
+macro_rules! impl_accumulate_int {
+    ($t:ty) => {
+        impl Accumulate for $t {
+            type Acc = i128;
+            fn widen(self) -> i128 { self as i128 }
+            fn narrow(acc: i128) -> Self { acc as $t }
+            fn fold_sum(a: i128, b: i128) -> i128 { a.wrapping_add(b) }
+            fn fold_prod(a: i128, b: i128) -> i128 { a.wrapping_mul(b) }
+            fn fold_max(a: i128, b: i128) -> i128 { a.max(b) }
+            fn fold_min(a: i128, b: i128) -> i128 { a.min(b) }
+            fn average(acc: i128, n: usize) -> i128 { acc / n as i128 }
+        }
+    };
+}
+
+impl_accumulate_int!(i8);
+impl_accumulate_int!(u8);
+impl_accumulate_int!(i32);
+impl_accumulate_int!(u32);
+impl_accumulate_int!(i64);
+impl_accumulate_int!(u64);
+
+// folds recv into acc. the first buffer of a round initializes the accumulator. wide is
+// --accumulate-f64.
+pub(crate) fn accumulate<T: Accumulate>(
+    acc: &mut [T::Acc],
+    recv: &[T],
+    first: bool,
+    op: ReduceOp,
+    wide: bool,
+) -> Result<(), ()> {
+    if acc.len() != recv.len() {
+        return Err(());
+    }
+    if first {
+        acc.iter_mut().zip(recv).for_each(|(a, r)| *a = r.widen());
+        return Ok(());
+    }
+    let op: fn(T::Acc, T::Acc) -> T::Acc = match op {
+        ReduceOp::Sum | ReduceOp::Avg => T::fold_sum,
+        ReduceOp::Prod => T::fold_prod,
+        ReduceOp::Max => T::fold_max,
+        ReduceOp::Min => T::fold_min,
+    };
+    acc.iter_mut()
+        .zip(recv)
+        .for_each(|(a, r)| *a = T::round(op(*a, r.widen()), wide));
+    Ok(())
+}
+
+// narrows the accumulator of n folded buffers into send_buf
+pub(crate) fn finish<T: Accumulate>(
+    send_buf: &mut [T],
+    acc: &[T::Acc],
+    n: usize,
+    op: ReduceOp,
+    wide: bool,
+) -> Result<(), ()> {
+    if acc.len() != send_buf.len() {
+        return Err(());
+    }
+    for (s, a) in send_buf.iter_mut().zip(acc) {
+        *s = T::narrow(if op == ReduceOp::Avg { T::round(T::average(*a, n), wide) } else { *a });
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     extern crate test;
 
-    fn bench_reduce<T>(b: &mut test::Bencher)
+    fn bench_reduce<T>(b: &mut test::Bencher, op: ReduceOp)
     where
-        T: Float
-            + std::fmt::Debug
-            + std::ops::AddAssign
-            + std::default::Default
-            + std::clone::Clone,
+        T: Element,
     {
         let count = 1024;
         let num_recv = 4;
@@ -163,18 +531,332 @@ mod tests {
                         slice_ref
                     })
                     .collect(),
+                op,
                 Some(&mut work_mem),
             )
         });
     }
 
+    // reduces 1, 2 and 3
+    fn do_reduce<T: Element>(count: usize, offset: usize, op: ReduceOp, expect: f32) {
+        let num_recv = 3;
+        let mut work_mem = WorkingMemory::new(count + offset, num_recv);
+        let recv_bufs = (0..num_recv)
+            .map(|i| {
+                AlignedBox::<[T]>::slice_from_value(
+                    alignment(count + offset),
+                    count + offset,
+                    T::from_f32(i as f32 + 1.0).unwrap(),
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let mut send_buf =
+            AlignedBox::<[T]>::slice_from_default(alignment(count + offset), count + offset)
+                .unwrap();
+        send_buf[offset..]
+            .reduce(
+                &recv_bufs.iter().map(|v| &v[offset..]).collect(),
+                op,
+                Some(&mut work_mem),
+            )
+            .unwrap();
+        for v in &send_buf[offset..] {
+            assert_eq!(*v, T::from_f32(expect).unwrap(), "{}", op);
+        }
+        for v in &send_buf[..offset] {
+            assert_eq!(*v, T::default());
+        }
+    }
+
+    // the servers split a job among the reduce threads, the result must not depend on how
+    fn do_reduce_partitioned<T: Element>(op: ReduceOp) {
+        let (count, num_recv) = (1021, 8);
+        let mut seed = 0x2545f491u32;
+        let recv_bufs = (0..num_recv)
+            .map(|_| {
+                let mut v = AlignedBox::<[T]>::slice_from_default(alignment(count), count).unwrap();
+                for e in v.iter_mut() {
+                    seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                    *e = T::from_f32((seed >> 8) as f32 / (1 << 24) as f32 * 200.0 - 100.0).unwrap();
+                }
+                v
+            })
+            .collect::<Vec<_>>();
+        let reduce = |send_buf: &mut [T], start: usize| {
+            let mut work_mem = WorkingMemory::new(send_buf.len(), num_recv);
+            let len = send_buf.len();
+            send_buf
+                .reduce(
+                    &recv_bufs.iter().map(|v| &v[start..start + len]).collect(),
+                    op,
+                    Some(&mut work_mem),
+                )
+                .unwrap();
+        };
+        let mut expect = vec![T::default(); count];
+        reduce(&mut expect, 0);
+        for nparts in [2, 3, 7, 16] {
+            let mut send_buf = vec![T::default(); count];
+            let part = count.div_ceil(nparts);
+            for (i, chunk) in send_buf.chunks_mut(part).enumerate() {
+                reduce(chunk, i * part);
+            }
+            assert_eq!(send_buf, expect, "{} parts", nparts);
+        }
+    }
+
+    #[test]
+    fn test_reduce_deterministic() {
+        for op in [ReduceOp::Sum, ReduceOp::Avg, ReduceOp::Max] {
+            do_reduce_partitioned::<f32>(op);
+            do_reduce_partitioned::<f64>(op);
+            do_reduce_partitioned::<bf16>(op);
+            do_reduce_partitioned::<F8E4M3>(op);
+        }
+        // the aarch64 f16 kernel requires 128-bit aligned buffers
+        do_reduce_partitioned::<f16>(ReduceOp::Avg);
+    }
+
+    #[test]
+    fn test_reduce_wide_accumulator() {
+        // each of the small values is lost when added to 1.0 one by one, but not their sum
+        let one = [bf16::ONE; 17];
+        let small = [bf16::from_f32(2f32.powi(-9)); 17];
+        let mut recv_bufs = vec![&one[..]];
+        recv_bufs.extend(std::iter::repeat_n(&small[..], 64));
+        let mut send = [bf16::ZERO; 17];
+        send.reduce(&recv_bufs, ReduceOp::Sum, None).unwrap();
+        assert!(send.iter().all(|v| *v == bf16::from_f32(1.125)));
+
+        let one = [1f32; 17];
+        let small = [2f32.powi(-25); 17];
+        let mut recv_bufs = vec![&one[..]];
+        recv_bufs.extend(std::iter::repeat_n(&small[..], 16));
+        let mut work_mem = WorkingMemory::new(17, recv_bufs.len());
+        let mut send = [0f32; 17];
+        send.reduce(&recv_bufs, ReduceOp::Sum, Some(&mut work_mem)).unwrap();
+        assert!(send.iter().all(|v| *v == 1.0));
+        work_mem.accumulate_f64 = true;
+        send.reduce(&recv_bufs, ReduceOp::Sum, Some(&mut work_mem)).unwrap();
+        assert!(send.iter().all(|v| *v == 1.0 + 2f32.powi(-21)));
+        send.reduce(&recv_bufs, ReduceOp::Avg, Some(&mut work_mem)).unwrap();
+        assert!(send.iter().all(|v| *v == ((1.0 + 2f64.powi(-21)) / 17.0) as f32));
+    }
+
+    #[test]
+    fn test_reduce_partial() {
+        for (count, offset) in [(1024, 0), (1021, 0), (1021, 3), (7, 1), (1, 0)] {
+            do_reduce::<f32>(count, offset, ReduceOp::Sum, 6.0);
+            // the aarch64 f16 kernel requires 128-bit aligned buffers
+            do_reduce::<f16>(count, 0, ReduceOp::Sum, 6.0);
+            do_reduce::<bf16>(count, offset, ReduceOp::Sum, 6.0);
+        }
+    }
+
+    #[test]
+    fn test_reduce_op() {
+        for (op, expect) in [
+            (ReduceOp::Sum, 6.0),
+            (ReduceOp::Prod, 6.0),
+            (ReduceOp::Max, 3.0),
+            (ReduceOp::Min, 1.0),
+            (ReduceOp::Avg, 2.0),
+        ] {
+            do_reduce::<f32>(1021, 3, op, expect);
+            do_reduce::<f16>(1021, 0, op, expect);
+            do_reduce::<bf16>(1021, 3, op, expect);
+            do_reduce::<f64>(1021, 3, op, expect);
+            do_reduce::<i8>(1021, 3, op, expect);
+            do_reduce::<u8>(1021, 3, op, expect);
+            do_reduce::<i32>(1021, 3, op, expect);
+            do_reduce::<u32>(1021, 3, op, expect);
+            do_reduce::<i64>(1021, 3, op, expect);
+            do_reduce::<u64>(1021, 3, op, expect);
+            do_reduce::<F8E4M3>(1021, 3, op, expect);
+            do_reduce::<F8E5M2>(1021, 3, op, expect);
+        }
+    }
+
+    #[test]
+    fn test_reduce_int_overflow() {
+        let recv = [100i8; 33];
+        let mut send = [0i8; 33];
+        send.reduce(&vec![&recv[..]; 3], ReduceOp::Sum, None).unwrap();
+        assert!(send.iter().all(|v| *v == 44)); // 300 wraps around
+        send.reduce(&vec![&recv[..]; 3], ReduceOp::Avg, None).unwrap();
+        assert!(send.iter().all(|v| *v == 100));
+
+        let recv = [u64::MAX; 5];
+        let mut send = [0u64; 5];
+        send.reduce(&vec![&recv[..]; 4], ReduceOp::Avg, None).unwrap();
+        assert!(send.iter().all(|v| *v == u64::MAX));
+    }
+
+    #[test]
+    fn test_reduce_f8_saturate() {
+        let recv = [F8E4M3::from_f32(300.0); 16];
+        let mut send = [F8E4M3::default(); 16];
+        let mut work_mem = WorkingMemory::new(16, 2);
+        send.reduce(&vec![&recv[..]; 2], ReduceOp::Sum, Some(&mut work_mem))
+            .unwrap();
+        assert!(send.iter().all(|v| v.to_f32() == 448.0));
+
+        // 1 + 2^-4 * 4 can't be represented with 2 mantissa bits one by one, only the sum can
+        let one = [F8E5M2::from_f32(1.0); 16];
+        let small = [F8E5M2::from_f32(0.0625); 16];
+        let mut send = [F8E5M2::default(); 16];
+        let mut work_mem = WorkingMemory::new(16, 5);
+        send.reduce(
+            &vec![&one[..], &small[..], &small[..], &small[..], &small[..]],
+            ReduceOp::Sum,
+            Some(&mut work_mem),
+        )
+        .unwrap();
+        assert!(send.iter().all(|v| v.to_f32() == 1.25));
+    }
+
+    #[test]
+    fn test_reduce_avg_f16_overflow() {
+        // the sum doesn't fit in f16 (max 65504), the average does
+        let recv = [f16::from_f32(40000.0); 64];
+        let mut send = [f16::default(); 64];
+        let mut work_mem = WorkingMemory::new(64, 4);
+        send.reduce(&vec![&recv[..]; 4], ReduceOp::Avg, Some(&mut work_mem))
+            .unwrap();
+        assert!(send.iter().all(|v| *v == f16::from_f32(40000.0)));
+    }
+
+    #[test]
+    fn test_reduce_max_nan() {
+        let a = [f32::NAN, 1.0, 2.0, f32::NAN, 4.0];
+        let b = [0.0, f32::NAN, 3.0, f32::NAN, -1.0];
+        let mut send = [0.0f32; 5];
+        send.reduce(&vec![&a[..], &b[..]], ReduceOp::Max, None).unwrap();
+        assert_eq!(send[..3], [0.0, 1.0, 3.0]);
+        assert!(send[3].is_nan());
+        assert_eq!(send[4], 4.0);
+
+        let a = a.map(bf16::from_f32);
+        let b = b.map(bf16::from_f32);
+        let mut send = [bf16::default(); 5];
+        send.reduce(&vec![&a[..], &b[..]], ReduceOp::Min, None).unwrap();
+        assert_eq!(send[..3], [0.0, 1.0, 2.0].map(bf16::from_f32));
+        assert!(send[3].is_nan());
+        assert_eq!(send[4], bf16::from_f32(-1.0));
+    }
+
+    // folding the buffers one by one as they arrive must match the batch reduction, with and
+    // without --accumulate-f64. the values are small and positive to fit all the types.
+    fn do_accumulate<T: Element>(op: ReduceOp, wide: bool) {
+        let (count, num_recv) = (1021, 8);
+        let mut seed = 0x2545f491u32;
+        let recv_bufs = (0..num_recv)
+            .map(|_| {
+                let mut v = AlignedBox::<[T]>::slice_from_default(alignment(count), count).unwrap();
+                for e in v.iter_mut() {
+                    seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                    *e = T::from_f32((seed >> 8) as f32 / (1 << 24) as f32 * 4.0).unwrap();
+                }
+                v
+            })
+            .collect::<Vec<_>>();
+        let mut work_mem = WorkingMemory::new(count, num_recv);
+        work_mem.accumulate_f64 = wide;
+        let mut expect = vec![T::default(); count];
+        expect
+            .reduce(&recv_bufs.iter().map(|v| &v[..]).collect(), op, Some(&mut work_mem))
+            .unwrap();
+
+        let mut acc = vec![T::Acc::default(); count];
+        for (i, recv) in recv_bufs.iter().enumerate() {
+            accumulate::<T>(&mut acc, recv, i == 0, op, wide).unwrap();
+        }
+        let mut send_buf = vec![T::default(); count];
+        finish::<T>(&mut send_buf, &acc, num_recv, op, wide).unwrap();
+        assert_eq!(send_buf, expect, "{}, wide: {}", op, wide);
+    }
+
+    #[test]
+    fn test_accumulate() {
+        for wide in [false, true] {
+            for op in [ReduceOp::Sum, ReduceOp::Prod, ReduceOp::Max, ReduceOp::Min, ReduceOp::Avg] {
+                do_accumulate::<f32>(op, wide);
+                do_accumulate::<f64>(op, wide);
+                do_accumulate::<bf16>(op, wide);
+                do_accumulate::<F8E4M3>(op, wide);
+                do_accumulate::<F8E5M2>(op, wide);
+                do_accumulate::<i8>(op, wide);
+                do_accumulate::<u8>(op, wide);
+                do_accumulate::<i32>(op, wide);
+                do_accumulate::<u64>(op, wide);
+            }
+            // f16 is reduced natively on aarch64, except for Avg
+            do_accumulate::<f16>(ReduceOp::Avg, wide);
+        }
+    }
+
+    #[test]
+    fn test_accumulate_wide() {
+        // the values of test_reduce_wide_accumulator, whose sum tells f32 and f64 accumulation
+        // apart
+        let one = [1f32; 17];
+        let small = [2f32.powi(-25); 17];
+        let mut recv_bufs = vec![&one[..]];
+        recv_bufs.extend(std::iter::repeat_n(&small[..], 16));
+        for (wide, op, expect) in [
+            (false, ReduceOp::Sum, 1.0),
+            (true, ReduceOp::Sum, 1.0 + 2f32.powi(-21)),
+            (false, ReduceOp::Avg, 1.0 / 17.0),
+            (true, ReduceOp::Avg, ((1.0 + 2f64.powi(-21)) / 17.0) as f32),
+        ] {
+            let mut work_mem = WorkingMemory::new(17, recv_bufs.len());
+            work_mem.accumulate_f64 = wide;
+            let mut batch = [0f32; 17];
+            batch.reduce(&recv_bufs, op, Some(&mut work_mem)).unwrap();
+
+            let mut acc = [0f64; 17];
+            for (i, recv) in recv_bufs.iter().enumerate() {
+                accumulate::<f32>(&mut acc, recv, i == 0, op, wide).unwrap();
+            }
+            let mut send = [0f32; 17];
+            finish::<f32>(&mut send, &acc, recv_bufs.len(), op, wide).unwrap();
+            assert_eq!(send, batch, "{}, wide: {}", op, wide);
+            assert!(send.iter().all(|v| *v == expect), "{}, wide: {}", op, wide);
+        }
+    }
+
+    #[test]
+    fn test_accumulate_len_mismatch() {
+        let mut acc = [0f64; 4];
+        assert!(accumulate::<f32>(&mut acc, &[1.0; 8], true, ReduceOp::Sum, false).is_err());
+        assert!(finish::<f32>(&mut [0.0; 8], &acc, 1, ReduceOp::Sum, false).is_err());
+    }
+
+    #[test]
+    fn test_reduce_len_mismatch() {
+        let recv = [1.0f32; 8];
+        let mut send = [0.0f32; 4];
+        assert!(send.reduce(&vec![&recv[..]], ReduceOp::Sum, None).is_err());
+    }
+
     #[bench]
     fn bench_f16_reduce(b: &mut test::Bencher) {
-        bench_reduce::<f16>(b);
+        bench_reduce::<f16>(b, ReduceOp::Sum);
     }
 
     #[bench]
     fn bench_f32_reduce(b: &mut test::Bencher) {
-        bench_reduce::<f32>(b);
+        bench_reduce::<f32>(b, ReduceOp::Sum);
+    }
+
+    #[bench]
+    fn bench_bf16_reduce(b: &mut test::Bencher) {
+        bench_reduce::<bf16>(b, ReduceOp::Sum);
+    }
+
+    #[bench]
+    fn bench_f32_reduce_max(b: &mut test::Bencher) {
+        bench_reduce::<f32>(b, ReduceOp::Max);
     }
 }
@@ -72,6 +72,37 @@ pub(super) unsafe fn add_assign_f16_aligned_slice(a: &mut [f16], b: &[f16]) {
     }
 }
 
+// generates the in-place slice kernel of a binary f16 instruction
+macro_rules! assign_f16_aligned_slice {
+    ($name:ident, $inst:literal) => {
+        // SAFETY: a and b must be aligned to 128 bits
+        pub(super) unsafe fn $name(a: &mut [f16], b: &[f16]) {
+            #[target_feature(enable = "fp16")]
+            #[inline]
+            unsafe fn op(a: &mut uint16x8_t, b: &uint16x8_t) {
+                asm!(
+                    concat!($inst, " {a:v}.8h, {a:v}.8h, {b:v}.8h"),
+                    a = inlateout(vreg) *a,
+                    b = in(vreg) *b,
+                    options(pure, nomem, nostack));
+            }
+
+            assert_eq!(a.len(), b.len());
+            assert_eq!(a.len() % 8, 0);
+            for i in (0..a.len()).step_by(8) {
+                let a = unsafe { &mut *(a.as_mut_ptr().add(i) as *mut uint16x8_t) };
+                let b = unsafe { &*(b.as_ptr().add(i) as *const uint16x8_t) };
+                unsafe { op(a, b) };
+            }
+        }
+    };
+}
+
+assign_f16_aligned_slice!(mul_assign_f16_aligned_slice, "fmul");
+// fmaxnm/fminnm return the other operand for a NaN, the same as f16::max/min
+assign_f16_aligned_slice!(max_assign_f16_aligned_slice, "fmaxnm");
+assign_f16_aligned_slice!(min_assign_f16_aligned_slice, "fminnm");
+
 #[target_feature(enable = "fp16")]
 unsafe fn add_f16x8(a: &[f16; 8], b: &[f16; 8]) -> [f16; 8] {
     let mut aa = MaybeUninit::<uint16x8_t>::uninit();
@@ -190,6 +221,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_assign_f16_aligned_slice() {
+        let mut a =
+            AlignedBox::<[f16]>::slice_from_value(alignment(128), 128, f16::from_f32(3.0)).unwrap();
+        let b =
+            AlignedBox::<[f16]>::slice_from_value(alignment(128), 128, f16::from_f32(2.0)).unwrap();
+
+        unsafe { mul_assign_f16_aligned_slice(&mut a, &b) };
+        assert!(a.iter().all(|v| *v == f16::from_f32(6.0)));
+        unsafe { min_assign_f16_aligned_slice(&mut a, &b) };
+        assert!(a.iter().all(|v| *v == f16::from_f32(2.0)));
+        a.fill(f16::NAN);
+        unsafe { max_assign_f16_aligned_slice(&mut a, &b) };
+        assert!(a.iter().all(|v| *v == f16::from_f32(2.0)));
+    }
+
     #[test]
     fn test_add_assign_f16x8() {
         // create two arrays of f16
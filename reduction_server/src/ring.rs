@@ -5,44 +5,45 @@
  */
 
 use std::hint;
-use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
 
 use aligned_box::AlignedBox;
-use half::{bf16, f16};
 use log::{info, trace};
 
+use crate::bootstrap;
 use crate::reduce::{Reduce, WorkingMemory};
 use crate::utils::*;
 
-use crate::nccl_net;
-use crate::nccl_net::Comm;
+use crate::transport::Transport;
 
-struct Task<'a, T> {
+struct Task<'a, T, N: Transport> {
     task_id: usize,
     tasks: Vec<(
         usize,
         Arc<AtomicUsize>,
-        Vec<nccl_net::MemoryHandle<'a>>,
+        Vec<N::MemoryHandle<'a>>,
         Vec<Arc<Mutex<AlignedBox<[T]>>>>,
     )>,
-    comms: &'a Vec<Comm>,
+    net: &'a N,
+    comms: &'a Vec<N::Comm>,
     task_ready: Arc<AtomicUsize>,
     args: &'a Args,
-    req: Option<Vec<Option<nccl_net::Request>>>,
+    req: Option<Vec<Option<N::Request>>>,
     next: usize,
     count: usize,
     reqcount: usize,
     try_count: usize,
     timer: std::time::Instant,
+    deadline: Deadline,
 }
 
-impl<'a, T> Task<'a, T> {
+impl<'a, T, N: Transport> Task<'a, T, N> {
     fn new(
         task_id: usize,
-        comms: &'a Vec<Comm>,
+        net: &'a N,
+        comms: &'a Vec<N::Comm>,
         tasks: Vec<(usize, Arc<AtomicUsize>, Vec<Arc<Mutex<AlignedBox<[T]>>>>)>,
         args: &'a Args,
         task_ready: Arc<AtomicUsize>,
@@ -53,7 +54,7 @@ impl<'a, T> Task<'a, T> {
                 let mhs = comms
                     .iter()
                     .enumerate()
-                    .map(|(i, comm)| nccl_net::reg_mr(comm, &bufs[i].lock().unwrap()).unwrap())
+                    .map(|(i, comm)| net.reg_mr(comm, &bufs[i].lock().unwrap()).unwrap())
                     .collect::<Vec<_>>();
                 (idx, ready, mhs, bufs)
             })
@@ -63,6 +64,7 @@ impl<'a, T> Task<'a, T> {
         Task {
             task_id,
             tasks,
+            net,
             comms,
             task_ready,
             args,
@@ -72,31 +74,38 @@ impl<'a, T> Task<'a, T> {
             reqcount: 0,
             try_count: args.try_count * tasks_len / args.nreq / nring,
             timer: std::time::Instant::now(),
+            deadline: Deadline::from_secs(0),
         }
     }
 
     fn recv(
         &self,
-        comm: &Comm,
-        mh: &nccl_net::MemoryHandle<'a>,
+        comm: &N::Comm,
+        mh: &N::MemoryHandle<'a>,
         buf: &Mutex<AlignedBox<[T]>>,
-    ) -> Option<nccl_net::Request> {
+    ) -> Option<N::Request> {
         let mut req = None;
         while req.is_none() {
-            req = nccl_net::irecv(comm, mh, &mut buf.lock().unwrap(), 0x69).unwrap();
+            req = self
+                .net
+                .irecv(comm, mh, &mut buf.lock().unwrap(), 0x69)
+                .unwrap();
         }
         req
     }
 
     fn send(
         &self,
-        comm: &Comm,
-        mh: &nccl_net::MemoryHandle<'a>,
+        comm: &N::Comm,
+        mh: &N::MemoryHandle<'a>,
         buf: &Mutex<AlignedBox<[T]>>,
-    ) -> Option<nccl_net::Request> {
+    ) -> Option<N::Request> {
         let mut req = None;
         while req.is_none() {
-            req = nccl_net::isend(comm, mh, &buf.lock().unwrap(), 0x69).unwrap();
+            req = self
+                .net
+                .isend(comm, mh, &buf.lock().unwrap(), 0x69)
+                .unwrap();
         }
         req
     }
@@ -111,10 +120,10 @@ impl<'a, T> Task<'a, T> {
         }
 
         let op = if is_recv { Self::recv } else { Self::send };
-        let (opname, ready_value, done_value) = if is_recv {
-            ("recv", 0, self.args.reduce_threads)
+        let (opname, ready_value, done_value, timeout) = if is_recv {
+            ("recv", 0, self.args.reduce_threads, self.args.recv_timeout)
         } else {
-            ("send", self.args.reduce_threads, 0)
+            ("send", self.args.reduce_threads, 0, self.args.send_timeout)
         };
 
         if self.reqcount < self.try_count
@@ -143,6 +152,7 @@ impl<'a, T> Task<'a, T> {
             if self.reqcount == 0 {
                 self.timer = std::time::Instant::now();
             }
+            self.deadline = Deadline::from_secs(timeout);
             self.reqcount += 1;
             self.task_ready.store(
                 (self.task_id + 1) % self.args.nreq,
@@ -155,7 +165,7 @@ impl<'a, T> Task<'a, T> {
 
             for (j, req) in self.req.as_mut().unwrap().iter_mut().enumerate() {
                 if req.is_some() {
-                    let (done, _) = nccl_net::test(req.as_ref().unwrap()).unwrap();
+                    let (done, _) = self.net.test(req.as_ref().unwrap()).unwrap();
                     if done {
                         trace!(
                             "{}  : task_id: {}, idx: {}, i: {}, j: {}, done, count: {}",
@@ -173,6 +183,25 @@ impl<'a, T> Task<'a, T> {
                 }
             }
 
+            if !all_done {
+                if let Some(elapsed) = self.deadline.expired() {
+                    let pending = self
+                        .req
+                        .as_ref()
+                        .unwrap()
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, req)| req.is_some())
+                        .map(|(j, _)| j)
+                        .collect::<Vec<_>>();
+                    let report = format!(
+                        "{} stalled for {:?}: task_id: {}, idx: {}, i: {}, pending rings: {:?}",
+                        opname, elapsed, self.task_id, idx, i, pending
+                    );
+                    timed_out(self.args.timeout_action, report).unwrap_or_else(|e| panic!("{}", e));
+                }
+            }
+
             if all_done {
                 buf_ready.store(done_value, std::sync::atomic::Ordering::Relaxed);
                 self.req = None;
@@ -188,9 +217,10 @@ impl<'a, T> Task<'a, T> {
     }
 }
 
-fn comm_loop<T: Float>(
+fn comm_loop<T: Element, N: Transport>(
+    net: &N,
     args: &Args,
-    comms: Vec<Comm>,
+    comms: Vec<N::Comm>,
     tasks: Vec<Vec<(usize, Arc<AtomicUsize>, Vec<Arc<Mutex<AlignedBox<[T]>>>>)>>,
     is_recv: bool,
 ) {
@@ -198,7 +228,7 @@ fn comm_loop<T: Float>(
     let mut tasks = tasks
         .into_iter()
         .enumerate()
-        .map(|(i, t)| Task::new(i, &comms, t, args, Arc::clone(&task_readys)))
+        .map(|(i, t)| Task::new(i, net, &comms, t, args, Arc::clone(&task_readys)))
         .collect::<Vec<_>>();
 
     let mut done = 0;
@@ -217,7 +247,7 @@ fn comm_loop<T: Float>(
     }
 }
 
-fn reduce_loop<T: Float>(
+fn reduce_loop<T: Element>(
     task_id: usize,
     args: &Args,
     tasks: Vec<(
@@ -229,7 +259,7 @@ fn reduce_loop<T: Float>(
     )>,
 ) {
     let mut count = 0;
-    let mut work_mem = WorkingMemory::new(args.count, 1);
+    let mut work_mem = WorkingMemory::new(args.count, 2);
     let initial: T = T::from_usize(args.ring_rank).unwrap();
     let init = vec![initial; args.count];
     //    trace!("reduce_loop: len(tasks): {}", tasks.len());
@@ -256,7 +286,8 @@ fn reduce_loop<T: Float>(
                     let mut send = send_buf.lock().unwrap();
                     let recv = recv_buf.lock().unwrap();
                     let vecs = vec![init.as_ref(), recv.as_ref()];
-                    send.reduce(&vecs, Some(&mut work_mem)).unwrap();
+                    send.reduce(&vecs, ReduceOp::Sum, Some(&mut work_mem))
+                        .unwrap();
                 });
             trace!(
                 "reduce: task_id: {}, idx: {}, i: {}, done, count: {}",
@@ -285,7 +316,13 @@ fn reduce_loop<T: Float>(
     }
 }
 
-fn do_ring<T: Float + 'static>(args: Args, ch: usize, recvs: Vec<Comm>, sends: Vec<Comm>) {
+fn do_ring<T: Element + 'static, N: Transport>(
+    net: N,
+    args: Args,
+    ch: usize,
+    recvs: Vec<N::Comm>,
+    sends: Vec<N::Comm>,
+) {
     assert!(recvs.len() == sends.len());
 
     let size = args.count * std::mem::size_of::<T>();
@@ -390,8 +427,9 @@ fn do_ring<T: Float + 'static>(args: Args, ch: usize, recvs: Vec<Comm>, sends: V
         });
         let mut tasks = first.chain(second).collect::<Vec<_>>();
         tasks = transpose(tasks);
+        let net = net.clone();
         let args = Arc::clone(&args);
-        std::thread::spawn(move || comm_loop(&args, recvs, tasks, true))
+        std::thread::spawn(move || comm_loop(&net, &args, recvs, tasks, true))
     };
 
     let reduce_ths = (0..args.reduce_threads)
@@ -471,7 +509,7 @@ fn do_ring<T: Float + 'static>(args: Args, ch: usize, recvs: Vec<Comm>, sends: V
         let mut tasks = first.chain(second).collect::<Vec<_>>();
         tasks = transpose(tasks);
         let args = Arc::clone(&args);
-        std::thread::spawn(move || comm_loop(&args, sends, tasks, false))
+        std::thread::spawn(move || comm_loop(&net, &args, sends, tasks, false))
     };
 
     let start = std::time::Instant::now();
@@ -498,30 +536,34 @@ fn do_ring<T: Float + 'static>(args: Args, ch: usize, recvs: Vec<Comm>, sends: V
     //    }
 }
 
-pub(crate) fn ring(args: Args) {
+pub(crate) fn ring<N: Transport>(net: N, args: Args) {
+    let rails = rails(&net, &args);
     let comms = args
         .address
         .split(',')
-        .map(|addr| {
+        .enumerate()
+        .map(|(i, addr)| {
             let port = addr.split(':').last().unwrap().parse::<u16>().unwrap();
 
+            // the channels are striped across the rails
+            let devs = (0..args.nchannel)
+                .map(|c| rails[(i * args.nchannel + c) % rails.len()])
+                .collect::<Vec<_>>();
+
             let recvs = {
-                let args = args.clone();
+                let net = net.clone();
+                let devs = devs.clone();
                 std::thread::spawn(move || {
                     let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
                     let (mut recv, _) = listener.accept().unwrap();
 
-                    (0..args.nchannel)
-                        .map(|_| {
-                            let (lcomm, handle) = nccl_net::listen().unwrap();
-                            // send size of handle
-                            let size = handle.len() as u32;
-                            recv.write_all(&size.to_le_bytes()).unwrap();
-                            // send handle
-                            recv.write_all(&handle).unwrap();
+                    devs.iter()
+                        .map(|dev| {
+                            let (lcomm, handle) = net.listen(*dev).unwrap();
+                            bootstrap::send_handle(&mut recv, &handle).unwrap();
 
                             loop {
-                                let comm = nccl_net::accept(&lcomm).unwrap();
+                                let comm = net.accept(&lcomm).unwrap();
                                 if comm.is_some() {
                                     return comm.unwrap();
                                 }
@@ -532,28 +574,31 @@ pub(crate) fn ring(args: Args) {
             };
 
             let sends = {
+                let net = net.clone();
                 let args = args.clone();
                 let addr = addr.to_string();
                 std::thread::spawn(move || {
+                    let mut deadline = Deadline::from_secs(args.connect_timeout);
                     let mut send = loop {
                         let res = TcpStream::connect(&addr);
                         if res.is_ok() {
                             break res.unwrap();
                         }
+                        if let Some(elapsed) = deadline.expired() {
+                            let report = format!("failed to connect to {} for {:?}", addr, elapsed);
+                            timed_out(args.timeout_action, report)
+                                .unwrap_or_else(|e| panic!("{}", e));
+                        }
                         // sleep 1s
                         std::thread::sleep(std::time::Duration::from_secs(1));
                     };
-                    (0..args.nchannel)
-                        .map(|_| {
-                            let mut buffer = [0u8; 4];
-                            send.read(buffer.as_mut()).unwrap();
-                            let size = u32::from_le_bytes(buffer);
-                            let mut handle = vec![0u8; size as usize];
-                            send.read(handle.as_mut()).unwrap();
+                    devs.iter()
+                        .map(|dev| {
+                            let handle = bootstrap::recv_handle(&mut send).unwrap();
                             info!("received handle: {:?}", handle);
 
                             loop {
-                                let comm = nccl_net::connect(&handle).unwrap();
+                                let comm = net.connect(*dev, &handle).unwrap();
                                 if comm.is_some() {
                                     return comm.unwrap();
                                 }
@@ -576,16 +621,11 @@ pub(crate) fn ring(args: Args) {
         .into_iter()
         .enumerate()
         .map(|(ch, comm)| {
+            let net = net.clone();
             let args = args.clone();
             std::thread::spawn(move || {
                 let (recvs, sends) = comm.into_iter().unzip();
-                if args.data_type == DataType::F32 {
-                    do_ring::<f32>(args, ch, recvs, sends);
-                } else if args.data_type == DataType::F16 {
-                    do_ring::<f16>(args, ch, recvs, sends);
-                } else if args.data_type == DataType::BF16 {
-                    do_ring::<bf16>(args, ch, recvs, sends);
-                }
+                with_data_type!(args.data_type, do_ring::<N>(net, args, ch, recvs, sends));
             })
         })
         .collect::<Vec<_>>();
@@ -596,17 +636,24 @@ pub(crate) fn ring(args: Args) {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::utils::tests::initialize;
+    use crate::transport::loopback::Loopback;
+    use crate::utils::tests::{free_port, init_logger};
     use clap::Parser;
 
     #[test]
     fn test_ring() {
-        initialize();
+        init_logger();
+        let net = Loopback::default();
+        let ports = (0..4)
+            .map(|_| (free_port(), free_port()))
+            .collect::<Vec<_>>();
         (0..4)
             .map(|i| {
+                let net = net.clone();
+                let (port, next) = ports[i];
                 std::thread::spawn(move || {
                     let ring_rank = format!("{}", i + 1);
-                    let address = format!("127.0.0.1:{},127.0.0.1:{}", 9090 + i, 9100 + i);
+                    let address = format!("127.0.0.1:{},127.0.0.1:{}", port, next);
                     let args = Args::parse_from([
                         "--bench",
                         "--nrank",
@@ -615,13 +662,11 @@ mod tests {
                         "2",
                         "--address",
                         &address,
-                        "--nreq",
-                        "1", // when using socket plugin, concurrent recv/send requests doesn't work
                         "--ring-rank",
                         &ring_rank,
                     ]);
                     println!("{:?}", args);
-                    ring(args);
+                    ring(net, args);
                 })
             })
             .collect::<Vec<_>>()
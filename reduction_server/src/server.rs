@@ -4,147 +4,404 @@
  * See LICENSE for license information
  */
 
-use std::collections::HashMap;
-use std::hint;
-use std::io::{Read, Write};
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
 use std::net::{TcpListener, TcpStream};
 use std::sync::atomic::AtomicUsize;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use half::{bf16, f16};
 use log::{error, info, trace, warn};
 
-use crate::reduce::{Reduce, WorkingMemory};
+use crate::bootstrap;
+use crate::reduce::{accumulate, finish, Reduce, WorkingMemory};
 use crate::utils::*;
 
-use crate::nccl_net;
-use crate::nccl_net::{Comm, Request};
+use crate::transport::Transport;
 
 use crate::partitioned_vec::PartitionedVec;
+use crate::pool::{Pool, Task};
+use crate::ready::ReadySet;
 
-fn handle_connection(
-    stream: std::net::TcpStream,
-    idx: usize,
-    rank: &AtomicUsize,
-    rcomm_ch: std::sync::mpsc::Sender<(usize, Comm)>,
-    scomm_ch: std::sync::mpsc::Sender<(usize, Comm)>,
-) {
-    let (lcomm, handle) = nccl_net::listen().unwrap();
+// the rank counter of a communicator being torn down
+const CLOSED: usize = usize::MAX;
 
-    let mut stream = stream;
+// threads of the send and recv pools unless --send-threads/--recv-threads are given. each
+// thread multiplexes the requests of several ranks of all the communicators.
+const DEFAULT_PROGRESS_THREADS: usize = 8;
+
+// waits for all the ranks of the communicator. returns false when the communicator gets
+// torn down before that.
+fn wait_connected(rank: &AtomicUsize, nrank: usize) -> bool {
+    loop {
+        match rank.load(std::sync::atomic::Ordering::Relaxed) {
+            CLOSED => return false,
+            v if v == nrank => return true,
+            _ => std::thread::sleep(std::time::Duration::from_millis(100)),
+        }
+    }
+}
+
+// events of a communicator, sent to serve_comm
+enum Event {
+    Connected(TcpStream, usize), // bootstrap stream of an accepted rank and its rank id
+    Ready(usize),                // the comms of a rank are set up
+    Closed(usize),               // the bootstrap stream of a rank got closed
+    Timeout(String),             // a phase timed out with --timeout-action abort
+    Failed(String),              // a job can't be reduced, e.g. the ranks sent different sizes
+    Aborted(String),             // a result can't be sent, e.g. the send comm of a rank failed
+}
+
+// reports a phase of the communicator that ran past its timeout. returns the report when the
+// communicator has to be aborted.
+fn stalled(args: &Args, report: String) -> Option<String> {
+    let report = format!("communicator 0x{:016x}: {}", args.comm_id, report);
+    match timed_out(args.timeout_action, report) {
+        Ok(()) => None,
+        Err(report) => {
+            error!("{}", report);
+            Some(report)
+        }
+    }
+}
 
-    // send size of handle
-    let size = handle.len() as u32;
-    stream.write_all(&size.to_le_bytes()).unwrap();
-    // send handle
-    stream.write_all(&handle).unwrap();
-
-    let mut buffer = [0u8; 4];
-    stream.read(buffer.as_mut()).unwrap();
-    let size = u32::from_le_bytes(buffer);
-    let mut handle = vec![0u8; size as usize];
-    stream.read(handle.as_mut()).unwrap();
+// sets up the comms of a channel of a rank over its bootstrap stream, through network device dev
+fn connect_comms<N: Transport>(
+    net: &N,
+    stream: &mut TcpStream,
+    dev: usize,
+    rank: &AtomicUsize,
+) -> Result<(N::Comm, N::Comm), String> {
+    let (lcomm, handle) = net.listen(dev).map_err(|e| format!("listen: {}", e))?;
+
+    bootstrap::send_handle(stream, &handle).map_err(|e| e.to_string())?;
+    let handle = bootstrap::recv_handle(stream).map_err(|e| e.to_string())?;
     info!("received handle: {:?}", handle);
 
-    let mut scomm: Option<Comm> = None;
-    let mut rcomm: Option<Comm> = None;
+    let mut scomm: Option<N::Comm> = None;
+    let mut rcomm: Option<N::Comm> = None;
 
     loop {
         if scomm.is_none() {
-            scomm = nccl_net::connect(handle.as_slice()).unwrap();
+            scomm = net
+                .connect(dev, &handle)
+                .map_err(|e| format!("connect: {}", e))?;
         }
         if rcomm.is_none() {
-            rcomm = nccl_net::accept(&lcomm).unwrap();
+            rcomm = net.accept(&lcomm).map_err(|e| format!("accept: {}", e))?;
         }
         if scomm.is_some() && rcomm.is_some() {
             break;
         }
+        if rank.load(std::sync::atomic::Ordering::Relaxed) == CLOSED {
+            return Err("communicator closed".to_string());
+        }
+    }
+
+    Ok((scomm.unwrap(), rcomm.unwrap()))
+}
+
+// devs are the network devices of the channels of the rank, rcomm_chs and scomm_chs lead to their
+// recv and send threads
+fn handle_connection<N: Transport>(
+    net: &N,
+    stream: std::net::TcpStream,
+    idx: usize,
+    rank: &AtomicUsize,
+    devs: Vec<usize>,
+    rcomm_chs: Vec<std::sync::mpsc::Sender<(usize, usize, N::Comm)>>,
+    scomm_chs: Vec<std::sync::mpsc::Sender<(usize, usize, N::Comm)>>,
+    event_ch: std::sync::mpsc::Sender<Event>,
+) {
+    let mut stream = stream;
+
+    // the channels are set up in order, the same way as the client does
+    let comms = devs
+        .iter()
+        .map(|dev| connect_comms(net, &mut stream, *dev, rank))
+        .collect::<Result<Vec<_>, _>>();
+
+    match comms {
+        Ok(comms) => {
+            info!("server connected, nchannel: {}", comms.len());
+            // the send and recv threads are already gone if the communicator is torn down
+            for (channel, (scomm, rcomm)) in comms.into_iter().enumerate() {
+                let _ = rcomm_chs[channel].send((idx, channel, rcomm));
+                let _ = scomm_chs[channel].send((idx, channel, scomm));
+            }
+            let _ = event_ch.send(Event::Ready(idx));
+
+            let mut buffer = [0u8; 4];
+            let ret = stream.read(buffer.as_mut());
+
+            info!("handle_connection: exiting ret {:?}", ret);
+        }
+        Err(e) => warn!("rank({}) failed to connect: {}", idx, e),
+    }
+
+    let _ = event_ch.send(Event::Closed(idx));
+}
+
+// every rank must contribute the same number of bytes to a job.
+// returns the number of elements of the job.
+fn recv_count<T>(lens: &[AtomicUsize]) -> Result<usize, String> {
+    let len = lens[0].load(std::sync::atomic::Ordering::Relaxed);
+    for (idx, v) in lens.iter().enumerate().skip(1) {
+        let v = v.load(std::sync::atomic::Ordering::Relaxed);
+        if v != len {
+            return Err(format!(
+                "size mismatch: rank(0) sent {} bytes, rank({}) sent {} bytes",
+                len, idx, v
+            ));
+        }
+    }
+    if len % std::mem::size_of::<T>() != 0 {
+        return Err(format!(
+            "size {} is not a multiple of the element size {}",
+            len,
+            std::mem::size_of::<T>()
+        ));
+    }
+    Ok(len / std::mem::size_of::<T>())
+}
+
+// no rank is receiving into a staging buffer
+const FREE: usize = usize::MAX;
+
+// a recv buffer of --accumulate-on-arrival, shared by all the ranks of a job. a recv thread
+// claims a free one for the data of a rank, the reduce threads fold it into their accumulators
+// as soon as it's filled, and the last of them to fold it frees it for the next rank.
+struct Staging<'a, T> {
+    buf: PartitionedVec<'a, T>,
+    rank: AtomicUsize, // the rank the data comes from, FREE when it can be claimed
+    seq: AtomicUsize,  // bumped every time the buffer gets filled
+    folded: AtomicUsize, // reduce threads that have folded the current data
+}
+
+impl<'a, T> Staging<'a, T> {
+    fn new(buf: PartitionedVec<'a, T>) -> Self {
+        Staging {
+            buf,
+            rank: AtomicUsize::new(FREE),
+            seq: AtomicUsize::new(0),
+            folded: AtomicUsize::new(0),
+        }
     }
 
-    info!("server connected");
-    rcomm_ch.send((idx, rcomm.unwrap())).unwrap();
-    scomm_ch.send((idx, scomm.unwrap())).unwrap();
+    fn claim(&self, idx: usize) -> bool {
+        self.rank
+            .compare_exchange(
+                FREE,
+                idx,
+                std::sync::atomic::Ordering::Acquire,
+                std::sync::atomic::Ordering::Relaxed,
+            )
+            .is_ok()
+    }
 
-    let ret = stream.read(buffer.as_mut());
+    fn release(&self) {
+        self.rank.store(FREE, std::sync::atomic::Ordering::Release);
+    }
 
-    info!("handle_connection: exiting ret {:?}", ret);
+    fn fill(&self) {
+        self.seq.fetch_add(1, std::sync::atomic::Ordering::Release);
+    }
 
-    rank.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    fn fold(&self, nthreads: usize) {
+        // the last reduce thread to fold the data frees the buffer
+        let folded = self
+            .folded
+            .fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+        if folded + 1 == nthreads {
+            self.folded.store(0, std::sync::atomic::Ordering::Relaxed);
+            self.release();
+        }
+    }
 }
 
-fn reduce_loop<T: Float>(
+fn reduce_loop<T: Element>(
     i: usize,
     args: &Args,
     rank: &AtomicUsize,
+    event_ch: std::sync::mpsc::Sender<Event>,
     mut jobs: Vec<(
-        Arc<AtomicUsize>,
+        Arc<ReadySet>,
+        Arc<ReadySet>,
+        Arc<Vec<AtomicUsize>>,
         Arc<AtomicUsize>,
         Arc<PartitionedVec<T>>,
         Vec<Arc<PartitionedVec<T>>>,
+        Arc<Vec<Staging<T>>>,
     )>,
 ) {
     info!("reduce thread({})", i);
 
-    loop {
-        if rank.load(std::sync::atomic::Ordering::Relaxed) == args.nrank {
-            break;
-        }
-        std::thread::sleep(std::time::Duration::from_millis(100));
+    if !wait_connected(rank, args.nrank) {
+        warn!("reduce thread({}) exit.", i);
+        return;
     }
     info!("reduce thread({}) all ranks get connected!", i);
 
-    let mut mems = (0..jobs.len())
-        .map(|_| WorkingMemory::new(args.count / args.reduce_threads, args.recv_threads))
+    // the data of the ranks is either reduced at once from their recv buffers or folded into an
+    // accumulator as it arrives in the staging buffers
+    let num_recv = if args.accumulate_on_arrival {
+        0
+    } else {
+        args.nrank
+    };
+    let mut mems = jobs
+        .iter()
+        .map(|v| {
+            let len = v.4.parts[i].lock().unwrap().len();
+            let mut mem = WorkingMemory::new(len, num_recv);
+            mem.accumulate_f64 = args.accumulate_f64;
+            mem
+        })
+        .collect::<Vec<_>>();
+    let mut accs = jobs
+        .iter()
+        .map(|v| {
+            let len = if v.6.is_empty() {
+                0
+            } else {
+                v.4.parts[i].lock().unwrap().len()
+            };
+            vec![<T::Acc>::default(); len]
+        })
         .collect::<Vec<_>>();
+    // the fills of each staging buffer this thread has folded
+    let mut seqs = jobs.iter().map(|v| vec![0; v.6.len()]).collect::<Vec<_>>();
+
+    // the data of the current round folded by this thread, per job
+    let mut arrived = vec![0; jobs.len()];
+    // armed once all the data of a job arrived, per job
+    let mut deadlines = jobs.iter().map(|_| None).collect::<Vec<Option<Deadline>>>();
 
+    // the jobs are reduced in whatever order they become ready, so that a slow job doesn't hold
+    // back the others. the send threads still return the results to each rank in order.
+    let mut backoff = Backoff::new();
     loop {
-        for (job_idx, (send_ready, recv_ready, send_buf, recv_bufs)) in jobs.iter_mut().enumerate()
+        if rank.load(std::sync::atomic::Ordering::Relaxed) != args.nrank {
+            warn!("rank != nrank");
+            warn!("reduce thread({}) exit.", i);
+            return;
+        }
+
+        let mut progressed = false;
+        for (
+            job_idx,
+            (send_ready, recv_ready, recv_lens, send_len, send_buf, recv_bufs, staging),
+        ) in jobs.iter_mut().enumerate()
         {
-            trace!("rank({})/job({}) reduce wait recv", i, job_idx);
+            // fold the data that arrived in the staging buffers. the next round of a rank can't
+            // arrive before the current one is reduced, so it all belongs to the current round.
+            for (slot, seq) in staging.iter().zip(seqs[job_idx].iter_mut()) {
+                let v = slot.seq.load(std::sync::atomic::Ordering::Acquire);
+                if v == *seq {
+                    continue;
+                }
+                *seq = v;
+                {
+                    let idx = slot.rank.load(std::sync::atomic::Ordering::Relaxed);
+                    let recv = slot.buf.parts[i].lock().unwrap();
+                    let count = recv_lens[idx].load(std::sync::atomic::Ordering::Relaxed)
+                        / std::mem::size_of::<T>();
+                    let len = count.saturating_sub(i * recv.len()).min(recv.len());
+                    let acc = &mut accs[job_idx][..len];
+                    let first = arrived[job_idx] == 0;
+                    accumulate::<T>(
+                        acc,
+                        &recv[..len],
+                        first,
+                        args.reduce_op,
+                        args.accumulate_f64,
+                    )
+                    .unwrap();
+                }
+                slot.fold(args.reduce_threads);
+                arrived[job_idx] += 1;
+                progressed = true;
+            }
+            // the recv threads report the ranks that stall the job
+            if !staging.is_empty() && arrived[job_idx] < args.nrank {
+                continue;
+            }
 
-            loop {
-                if cfg!(no_spinloop) {
-                    std::thread::sleep(NO_SPINLOOP_INTERVAL);
-                } else {
-                    hint::spin_loop();
+            let sent = send_ready.all_set();
+            let received = recv_ready.all_set();
+            if !(sent && received) {
+                let deadline = &mut deadlines[job_idx];
+                if received && deadline.is_none() {
+                    *deadline = Some(Deadline::from_secs(args.reduce_timeout));
                 }
-                let send_ready = send_ready.load(std::sync::atomic::Ordering::Relaxed);
-                let send_expect = (1 << args.send_threads) - 1;
-                let recv_ready = recv_ready.load(std::sync::atomic::Ordering::Relaxed);
-                let recv_expect = (1 << args.recv_threads) - 1;
-                //            trace!(
-                //                "[reduce] job({})/({}) recv ready: 0b{:016b}, expect: 0b{:016b}",
-                //                job_idx,
-                //                offset,
-                //                ready,
-                //                expect
-                //            );
-                if send_ready == send_expect && recv_ready == recv_expect {
-                    break;
+                if let Some(elapsed) = deadline.as_mut().and_then(|v| v.expired()) {
+                    let report = format!(
+                        "rank({})/job({}) reduce stalled for {:?} waiting for the previous result to be sent, ranks that haven't received it: {:?}",
+                        i, job_idx, elapsed, send_ready.unset()
+                    );
+                    if let Some(report) = stalled(args, report) {
+                        let _ = event_ch.send(Event::Timeout(report));
+                        warn!("reduce thread({}) exit.", i);
+                        return;
+                    }
                 }
-                if rank.load(std::sync::atomic::Ordering::Relaxed) != args.nrank {
-                    warn!("rank != nrank");
+                continue;
+            }
+            deadlines[job_idx] = None;
+            arrived[job_idx] = 0;
+
+            // the other threads would wait for the job forever, the communicator is aborted
+            // instead so that the ranks get the error
+            let count = match recv_count::<T>(recv_lens) {
+                Ok(count) => count,
+                Err(e) => {
+                    let report = format!(
+                        "communicator 0x{:016x}: job({}) {}",
+                        args.comm_id, job_idx, e
+                    );
+                    error!("{}", report);
+                    let _ = event_ch.send(Event::Failed(report));
                     warn!("reduce thread({}) exit.", i);
                     return;
                 }
-            }
+            };
 
-            trace!("rank({})/job({}) reduce start", i, job_idx);
+            trace!(
+                "rank({})/job({}) reduce start, count: {}",
+                i,
+                job_idx,
+                count
+            );
             // start timer for performance measurement
             let start = std::time::Instant::now();
             {
                 let mut send_buf = send_buf.parts[i].lock().unwrap();
-                let recv_buf_guards = recv_bufs
-                    .iter()
-                    .map(|v| v.parts[i].lock().unwrap())
-                    .collect::<Vec<_>>();
-                let recv_bufs = recv_buf_guards
-                    .iter()
-                    .map(|v| v.as_ref())
-                    .collect::<Vec<_>>();
-                send_buf
-                    .reduce(&recv_bufs, Some(&mut mems[job_idx]))
+                // only the first `count` elements of the job are valid
+                let offset = i * send_buf.len();
+                let len = count.saturating_sub(offset).min(send_buf.len());
+                if len > 0 && !staging.is_empty() {
+                    // the ranks are folded in the order they arrived
+                    finish::<T>(
+                        &mut send_buf[..len],
+                        &accs[job_idx][..len],
+                        args.nrank,
+                        args.reduce_op,
+                        args.accumulate_f64,
+                    )
                     .unwrap();
+                } else if len > 0 {
+                    // recv_bufs are indexed by rank id, which fixes the reduction order
+                    let recv_buf_guards = recv_bufs
+                        .iter()
+                        .map(|v| v.parts[i].lock().unwrap())
+                        .collect::<Vec<_>>();
+                    let recv_bufs = recv_buf_guards
+                        .iter()
+                        .map(|v| &v[..len])
+                        .collect::<Vec<_>>();
+                    send_buf[..len]
+                        .reduce(&recv_bufs, args.reduce_op, Some(&mut mems[job_idx]))
+                        .unwrap();
+                }
             }
             // stop timer
             let elapsed = start.elapsed();
@@ -155,333 +412,553 @@ fn reduce_loop<T: Float>(
                 elapsed.as_micros()
             );
 
-            recv_ready.store(0, std::sync::atomic::Ordering::Relaxed);
-            send_ready.store(0, std::sync::atomic::Ordering::Relaxed);
+            send_len.store(count, std::sync::atomic::Ordering::Relaxed);
+            recv_ready.clear_all();
+            send_ready.clear_all();
+            progressed = true;
+        }
+
+        if progressed {
+            backoff.reset();
+        } else {
+            backoff.idle();
         }
     }
 }
 
-fn send_loop<T: Float>(
-    i: usize,
-    args: &Args,
-    rank: &AtomicUsize,
-    sends: Vec<(Vec<Arc<AtomicUsize>>, Arc<PartitionedVec<T>>)>,
-    rx: std::sync::mpsc::Receiver<(usize, Comm)>,
-) {
-    let nrank = args.nrank;
-    let nsends = args.send_threads;
-    info!(
-        "send thread({}) sends.len(): {} waiting all ranks get connected.",
-        i,
-        sends.len(),
-    );
+// a request of a rank in flight
+struct Pending<N: Transport> {
+    job: usize,
+    round: usize,
+    slot: usize, // the buffer of the request
+    req: N::Request,
+    start: std::time::Instant,
+}
 
-    let comms = (0..nrank / nsends)
-        .map(|_| rx.recv().unwrap())
-        .collect::<Vec<_>>();
-    let sends = sends
-        .iter()
-        .map(|v| {
-            (
-                &v.0,
-                comms
-                    .iter()
-                    .map(|(_, comm)| {
-                        let mh = nccl_net::reg_mr(comm, &v.1.lock()).unwrap();
-                        (comm, mh, &v.1)
-                    })
-                    .collect::<Vec<_>>(),
-            )
-        })
-        .collect::<Vec<_>>();
+// a channel of a rank served by a send or recv task. the jobs are striped across the
+// channels: channel c carries jobs c, c + nchannel, ... its requests go through them in order,
+// independently of the other peers of the task. up to --pipeline-depth of them are in flight
+// at a time and they complete in the order they were posted.
+struct Peer<'a, N: Transport> {
+    idx: usize,
+    channel: usize,
+    nchannel: usize,
+    comm: &'a N::Comm,
+    mhs: Vec<Vec<N::MemoryHandle<'a>>>, // per job of the channel, per buffer
+    job: usize,                         // the next job to post a request for
+    round: usize, // the number of times the peer went through the jobs of its channel
+    reqs: VecDeque<Pending<N>>,
+    deadline: Option<Deadline>,
+}
 
-    let size = args.count * {
-        if args.data_type == DataType::F32 {
-            4
-        } else {
-            2
+impl<'a, N: Transport> Peer<'a, N> {
+    fn new(
+        idx: usize,
+        channel: usize,
+        nchannel: usize,
+        comm: &'a N::Comm,
+        mhs: Vec<Vec<N::MemoryHandle<'a>>>,
+    ) -> Self {
+        Peer {
+            idx,
+            channel,
+            nchannel,
+            comm,
+            mhs,
+            job: channel,
+            round: 0,
+            reqs: VecDeque::new(),
+            deadline: None,
         }
-    } as usize;
+    }
 
-    loop {
-        if rank.load(std::sync::atomic::Ordering::Relaxed) == args.nrank {
-            break;
+    // the memory handle of a buffer of the next job
+    fn mh(&self, slot: usize) -> &N::MemoryHandle<'a> {
+        &self.mhs[self.job / self.nchannel][slot]
+    }
+
+    fn post(&mut self, slot: usize, req: N::Request) {
+        self.reqs.push_back(Pending {
+            job: self.job,
+            round: self.round,
+            slot,
+            req,
+            start: std::time::Instant::now(),
+        });
+        self.job += self.nchannel;
+        if self.job >= self.mhs.len() * self.nchannel {
+            self.job = self.channel;
+            self.round += 1;
         }
-        std::thread::sleep(std::time::Duration::from_millis(100));
     }
-    info!(
-        "send thread({}) all ranks get connected!, size: {}",
-        i, size
-    );
 
-    for (idx, (readys, send)) in sends.iter().enumerate().cycle() {
-        for ready in readys.iter() {
-            loop {
-                if cfg!(no_spinloop) {
-                    std::thread::sleep(NO_SPINLOOP_INTERVAL);
-                } else {
-                    hint::spin_loop();
-                }
-                let ready = ready.load(std::sync::atomic::Ordering::Relaxed);
-                //                trace!(
-                //                    "[send] rank({})/job({}) send ready: 0b{:016b}",
-                //                    i,
-                //                    idx,
-                //                    ready,
-                //                );
-                if ready & (1 << i) == 0 {
-                    break;
-                }
-                if rank.load(std::sync::atomic::Ordering::Relaxed) != nrank {
-                    warn!("rank != nrank");
-                    warn!("send thread({}) exit.", i);
-                    return;
-                }
-            }
+    fn complete(&mut self) -> Option<Pending<N>> {
+        self.deadline = None;
+        self.reqs.pop_front()
+    }
+
+    // the job and the round the rank is waiting for: its oldest request, or the next one
+    fn waiting(&self) -> (usize, usize) {
+        self.reqs
+            .front()
+            .map_or((self.job, self.round), |v| (v.job, v.round))
+    }
+}
+
+// the peers of a send or recv task. channel c of rank idx is peer idx * nchannel + c, the
+// peers are assigned to the tasks round robin
+fn assigned_peers(i: usize, npeer: usize, nthreads: usize) -> usize {
+    (0..npeer).filter(|idx| idx % nthreads == i).count()
+}
+
+// the send or recv task of a channel of a rank
+fn peer_thread(idx: usize, channel: usize, nchannel: usize, nthreads: usize) -> usize {
+    (idx * nchannel + channel) % nthreads
+}
+
+// the comms of the peers of a task, handed over by handle_connection as the ranks get
+// connected. the peers borrow their comms, which are freed after them when the task is over.
+struct Peers<N: Transport> {
+    peers: Vec<Peer<'static, N>>,
+    comms: Vec<*mut N::Comm>,
+    npeer: usize,
+    rx: std::sync::mpsc::Receiver<(usize, usize, N::Comm)>,
+}
+
+impl<N: Transport> Peers<N> {
+    fn new(npeer: usize, rx: std::sync::mpsc::Receiver<(usize, usize, N::Comm)>) -> Self {
+        Peers {
+            peers: Vec::with_capacity(npeer),
+            comms: Vec::with_capacity(npeer),
+            npeer,
+            rx,
         }
-        trace!("rank({})/job({}) send start", i, idx);
+    }
 
-        let mut reqs = vec_of_none(send.len());
-        loop {
-            if cfg!(no_spinloop) {
-                std::thread::sleep(NO_SPINLOOP_INTERVAL);
-            } else {
-                hint::spin_loop();
-            }
-            if rank.load(std::sync::atomic::Ordering::Relaxed) != nrank {
-                warn!("rank != nrank");
-                warn!("send thread({}) exit.", i);
-                return;
-            }
+    // makes the peers of the comms that arrived. returns whether all the peers are there, or
+    // None when the communicator is torn down before that, which closes the channel.
+    fn collect(
+        &mut self,
+        mut new_peer: impl FnMut(usize, usize, &'static N::Comm) -> Result<Peer<'static, N>, String>,
+    ) -> Result<Option<bool>, String> {
+        while self.peers.len() < self.npeer {
+            let (idx, channel, comm) = match self.rx.try_recv() {
+                Ok(v) => v,
+                Err(std::sync::mpsc::TryRecvError::Empty) => return Ok(Some(false)),
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => return Ok(None),
+            };
+            let comm = Box::into_raw(Box::new(comm));
+            self.comms.push(comm);
+            // SAFETY: the comm is only freed on drop, after the peer borrowing it
+            self.peers.push(new_peer(idx, channel, unsafe { &*comm })?);
+        }
+        Ok(Some(true))
+    }
+}
 
-            let mut done = true;
-            for (j, (comm, mh, buf)) in send.iter().enumerate() {
-                if reqs[j].is_none() {
-                    reqs[j] = nccl_net::isend(comm, mh, &buf.lock(), 0x69).unwrap();
-                    if reqs[j].is_none() {
-                        done = false;
-                    }
+impl<N: Transport> Drop for Peers<N> {
+    fn drop(&mut self) {
+        self.peers.clear();
+        for comm in self.comms.drain(..) {
+            // SAFETY: made by Box::into_raw in collect, no peer borrows it anymore
+            drop(unsafe { Box::from_raw(comm) });
+        }
+    }
+}
+
+// reports an error of the network to serve_comm, which aborts the communicator so that its
+// ranks get the error instead of waiting for the task. ends the task. a rank that is done may
+// close its comms before its bootstrap stream, failing the receives posted ahead for it, so the
+// recv tasks report Event::Failed, after which the results already reduced still go out, and the
+// send tasks Event::Aborted.
+fn failed(
+    args: &Args,
+    event_ch: &std::sync::mpsc::Sender<Event>,
+    event: fn(String) -> Event,
+    report: String,
+) -> Option<bool> {
+    let report = format!("communicator 0x{:016x}: {}", args.comm_id, report);
+    warn!("{}", report);
+    let _ = event_ch.send(event(report));
+    None
+}
+
+// whether the task can go on once its peers are there. returns None when the communicator is
+// torn down before all its ranks get connected.
+fn all_connected(rank: &AtomicUsize, nrank: usize) -> Option<bool> {
+    match rank.load(std::sync::atomic::Ordering::Relaxed) {
+        CLOSED => None,
+        v => Some(v == nrank),
+    }
+}
+
+// the send side of the peers of a communicator assigned to a thread of the send pool
+struct SendTask<T: Element + 'static, N: Transport> {
+    // dropped first, their memory registrations cover the buffers of the jobs
+    peers: Peers<N>,
+    net: N,
+    i: usize,
+    args: Arc<Args>,
+    rank: Arc<AtomicUsize>,
+    event_ch: std::sync::mpsc::Sender<Event>,
+    sends: Vec<(
+        Vec<Arc<ReadySet>>,
+        Arc<AtomicUsize>,
+        Arc<PartitionedVec<'static, T>>,
+    )>,
+    connected: bool,
+    _alive: std::sync::mpsc::Sender<()>, // serve_comm waits for the tasks to drop it
+}
+
+impl<T: Element + 'static, N: Transport> Task for SendTask<T, N> {
+    fn poll(&mut self) -> Option<bool> {
+        let SendTask {
+            peers,
+            net,
+            i,
+            args,
+            rank,
+            event_ch,
+            sends,
+            connected,
+            ..
+        } = self;
+        let nrank = args.nrank;
+
+        if !*connected {
+            let collected = peers.collect(|idx, channel, comm| {
+                let mhs = sends
+                    .iter()
+                    .skip(channel)
+                    .step_by(args.nchannel)
+                    .map(|v| Ok(vec![net.reg_mr(comm, &v.2.lock())?]))
+                    .collect::<Result<Vec<_>, N::Error>>()
+                    .map_err(|e| format!("rank({}) send reg_mr: {}", idx, e))?;
+                Ok(Peer::<N>::new(idx, channel, args.nchannel, comm, mhs))
+            });
+            let ready = match collected {
+                Ok(Some(true)) => all_connected(rank, nrank),
+                Ok(v) => v,
+                Err(e) => return failed(args, event_ch, Event::Aborted, e),
+            };
+            match ready {
+                Some(true) => {}
+                Some(false) => return Some(false),
+                None => {
+                    warn!("send task({}) exit.", i);
+                    return None;
                 }
             }
+            info!(
+                "send task({}) all ranks get connected!, max size: {}",
+                i,
+                args.count * std::mem::size_of::<T>()
+            );
+            *connected = true;
+        }
 
-            if done {
-                break;
-            }
+        if rank.load(std::sync::atomic::Ordering::Relaxed) != nrank {
+            warn!("rank != nrank");
+            warn!("send task({}) exit.", i);
+            return None;
         }
-        trace!("rank({})/job({}) send requested", i, idx);
-        let start = std::time::Instant::now();
 
-        loop {
-            if cfg!(no_spinloop) {
-                std::thread::sleep(NO_SPINLOOP_INTERVAL);
-            } else {
-                hint::spin_loop();
+        let mut progressed = false;
+        for peer in peers.peers.iter_mut() {
+            // the results are sent as soon as they are reduced, as far as the pipeline allows
+            while peer.reqs.len() < args.pipeline_depth {
+                let (readys, send_len, send) = &sends[peer.job];
+                // the result of the job isn't reduced yet
+                if readys.iter().any(|v| v.is_set(peer.idx)) {
+                    break;
+                }
+                let count = send_len.load(std::sync::atomic::Ordering::Relaxed);
+                let req = match net.isend(peer.comm, peer.mh(0), &send.lock()[..count], 0x69) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        let report = format!("rank({})/job({}) isend: {}", peer.idx, peer.job, e);
+                        return failed(args, event_ch, Event::Aborted, report);
+                    }
+                };
+                let Some(req) = req else {
+                    break;
+                };
+                trace!(
+                    "rank({})/job({}) send requested, count: {}",
+                    peer.idx,
+                    peer.job,
+                    count
+                );
+                peer.post(0, req);
+                progressed = true;
             }
 
-            if rank.load(std::sync::atomic::Ordering::Relaxed) != nrank {
-                warn!("rank != nrank");
-                warn!("send thread({}) exit.", i);
-                return;
+            while let Some(p) = peer.reqs.front() {
+                let done = match net.test(&p.req) {
+                    Ok((done, _)) => done,
+                    Err(e) => {
+                        let report = format!("rank({})/job({}) send: {}", peer.idx, p.job, e);
+                        return failed(args, event_ch, Event::Aborted, report);
+                    }
+                };
+                if !done {
+                    break;
+                }
+                let (readys, send_len, _) = &sends[p.job];
+                let count = send_len.load(std::sync::atomic::Ordering::Relaxed);
+                trace!(
+                    "rank({})/job({}) send latency: {}us, {:.2}Gbps",
+                    peer.idx,
+                    p.job,
+                    p.start.elapsed().as_micros(),
+                    (count * std::mem::size_of::<T>() * 8) as f64 / p.start.elapsed().as_secs_f64()
+                        * 1e-9
+                );
+                for ready in readys.iter() {
+                    ready.set(peer.idx);
+                }
+                peer.complete();
+                progressed = true;
             }
 
-            let mut done = true;
-            for (j, _) in send.iter().enumerate() {
-                if reqs[j].is_some() {
-                    let (d, _) = nccl_net::test(reqs[j].as_ref().unwrap()).unwrap();
-                    if d {
-                        reqs[j] = None;
+            // armed once the result the rank waits for is reduced
+            let (job, _) = peer.waiting();
+            if peer.reqs.is_empty() && sends[job].0.iter().any(|v| v.is_set(peer.idx)) {
+                continue;
+            }
+            if peer.deadline.is_none() {
+                peer.deadline = Some(Deadline::from_secs(args.send_timeout));
+            }
+            if let Some(elapsed) = peer.deadline.as_mut().and_then(|v| v.expired()) {
+                let report = format!(
+                    "rank({})/job({}) send stalled for {:?}, {}",
+                    peer.idx,
+                    job,
+                    elapsed,
+                    if peer.reqs.is_empty() {
+                        "the rank isn't ready to receive"
                     } else {
-                        done = false;
+                        "the rank hasn't received the result"
                     }
+                );
+                if let Some(report) = stalled(args, report) {
+                    let _ = event_ch.send(Event::Timeout(report));
+                    warn!("send task({}) exit.", i);
+                    return None;
                 }
             }
-            if done {
-                break;
-            }
-        }
-
-        for ready in readys.iter() {
-            ready.fetch_add(1 << i, std::sync::atomic::Ordering::Relaxed);
         }
+        Some(progressed)
+    }
 
-        trace!(
-            "rank({})/job({}) send latency: {}us, {:.2}Gbps",
-            i,
-            idx,
-            start.elapsed().as_micros(),
-            (size * 8) as f64 / start.elapsed().as_secs_f64() * 1e-9
-        );
+    // the ranks get the error like the ones of the network
+    fn panicked(&mut self, message: &str) {
+        let report = format!("send task({}) panicked: {}", self.i, message);
+        failed(&self.args, &self.event_ch, Event::Aborted, report);
     }
 }
 
-fn recv_loop<T: Float>(
+// the receive side of the peers of a communicator assigned to a thread of the recv pool
+struct RecvTask<T: Element + 'static, N: Transport> {
+    // dropped first, their memory registrations cover the buffers of the jobs
+    peers: Peers<N>,
+    net: N,
     i: usize,
-    args: &Args,
-    rank: &AtomicUsize,
-    mut recvs: Vec<(
-        Vec<Arc<AtomicUsize>>,
-        Vec<(usize, Option<Arc<PartitionedVec<T>>>)>,
-    )>, // len = reduce-threads
-    rx: std::sync::mpsc::Receiver<(usize, Comm)>,
-) {
-    let nrank = args.nrank;
-    let nrecvs = args.recv_threads;
-    info!(
-        "recv thread: {}, recvs: {:?}",
-        i,
-        recvs
-            .iter()
-            .map(|v| v.1.iter().map(|(j, _)| j).collect::<Vec<_>>())
-            .collect::<Vec<_>>(),
-    );
+    args: Arc<Args>,
+    rank: Arc<AtomicUsize>,
+    event_ch: std::sync::mpsc::Sender<Event>,
+    recvs: Vec<(
+        Vec<Arc<ReadySet>>,
+        Arc<Vec<AtomicUsize>>,
+        Arc<Vec<AtomicUsize>>,
+        Vec<(usize, Arc<PartitionedVec<'static, T>>)>,
+        Arc<Vec<Staging<'static, T>>>,
+    )>, // len = reduce-jobs * nchannel * nchunk
+    // the recv buffers of the ranks of this task, per job
+    bufs: Vec<HashMap<usize, Arc<PartitionedVec<'static, T>>>>,
+    connected: bool,
+    _alive: std::sync::mpsc::Sender<()>, // serve_comm waits for the tasks to drop it
+}
 
-    let comms: HashMap<usize, Comm> = (0..nrank / nrecvs)
-        .map(|_| rx.recv().unwrap())
-        .collect::<HashMap<_, _>>();
-    let mut recvs = recvs
-        .iter_mut()
-        .map(|v| {
-            (
-                &v.0,
-                v.1.iter_mut()
-                    .map(|(idx, buf)| {
-                        let comm = comms.get(idx).unwrap();
-                        let mh = nccl_net::reg_mr(comm, &buf.as_ref().unwrap().lock()).unwrap();
-                        (comm, mh, Option::take(buf).unwrap())
+impl<T: Element + 'static, N: Transport> Task for RecvTask<T, N> {
+    fn poll(&mut self) -> Option<bool> {
+        let RecvTask {
+            peers,
+            net,
+            i,
+            args,
+            rank,
+            event_ch,
+            recvs,
+            bufs,
+            connected,
+            ..
+        } = self;
+        let nrank = args.nrank;
+
+        if !*connected {
+            let collected = peers.collect(|idx, channel, comm| {
+                // the rank's own recv buffer, or all the staging buffers of the job
+                let mhs = bufs
+                    .iter()
+                    .zip(recvs.iter())
+                    .skip(channel)
+                    .step_by(args.nchannel)
+                    .map(|(v, (_, _, _, _, staging))| match v.get(&idx) {
+                        Some(buf) => Ok(vec![net.reg_mr(comm, &buf.lock())?]),
+                        None => staging
+                            .iter()
+                            .map(|v| net.reg_mr(comm, &v.buf.lock()))
+                            .collect(),
                     })
-                    .collect::<Vec<_>>(),
-            )
-        })
-        .collect::<Vec<_>>();
-
-    let size = args.count * {
-        if args.data_type == DataType::F32 {
-            4
-        } else {
-            2
+                    .collect::<Result<Vec<_>, N::Error>>()
+                    .map_err(|e| format!("rank({}) recv reg_mr: {}", idx, e))?;
+                Ok(Peer::<N>::new(idx, channel, args.nchannel, comm, mhs))
+            });
+            let ready = match collected {
+                Ok(Some(true)) => all_connected(rank, nrank),
+                Ok(v) => v,
+                Err(e) => return failed(args, event_ch, Event::Failed, e),
+            };
+            match ready {
+                Some(true) => {}
+                Some(false) => return Some(false),
+                None => {
+                    warn!("recv task({}) exit.", i);
+                    return None;
+                }
+            }
+            info!(
+                "recv task({}) all ranks get connected!, max size: {}",
+                i,
+                args.count * std::mem::size_of::<T>()
+            );
+            *connected = true;
         }
-    } as usize;
 
-    loop {
-        if rank.load(std::sync::atomic::Ordering::Relaxed) == args.nrank {
-            break;
+        if rank.load(std::sync::atomic::Ordering::Relaxed) != nrank {
+            warn!("rank != nrank");
+            warn!("recv task({}) exit.", i);
+            return None;
         }
-        std::thread::sleep(std::time::Duration::from_millis(100));
-    }
-    info!(
-        "recv thread({}) all ranks get connected!, size: {}",
-        i, size
-    );
 
-    loop {
-        for (job_idx, (readys, recv)) in recvs.iter_mut().enumerate() {
-            for ready in readys.iter() {
-                loop {
-                    if cfg!(no_spinloop) {
-                        std::thread::sleep(NO_SPINLOOP_INTERVAL);
-                    } else {
-                        hint::spin_loop();
-                    }
-                    let ready = ready.load(std::sync::atomic::Ordering::Relaxed);
-                    //                    trace!(
-                    //                        "[recv] rank({})/job({}) recv ready: 0b{:016b}",
-                    //                        i,
-                    //                        job_idx,
-                    //                        ready,
-                    //                    );
-                    if ready & (1 << i) == 0 {
+        let mut progressed = false;
+        for peer in peers.peers.iter_mut() {
+            // post requests for the next jobs as far as the pipeline allows
+            while peer.reqs.len() < args.pipeline_depth {
+                let (readys, _, _, _, staging) = &recvs[peer.job];
+                // the previous data of the job isn't reduced yet
+                if readys.iter().any(|v| v.is_set(peer.idx)) {
+                    break;
+                }
+                let (slot, buf) = if staging.is_empty() {
+                    (0, &**bufs[peer.job].get(&peer.idx).unwrap())
+                } else {
+                    // all the staging buffers are in use, wait for one to get folded
+                    let Some(slot) = staging.iter().position(|v| v.claim(peer.idx)) else {
                         break;
+                    };
+                    (slot, &staging[slot].buf)
+                };
+                let req = match net.irecv(peer.comm, peer.mh(slot), &mut buf.lock(), 0x69) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        let report = format!("rank({})/job({}) irecv: {}", peer.idx, peer.job, e);
+                        return failed(args, event_ch, Event::Failed, report);
                     }
-                    if rank.load(std::sync::atomic::Ordering::Relaxed) != nrank {
-                        warn!("rank != nrank");
-                        warn!("recv thread({}) exit.", i);
-                        return;
+                };
+                let Some(req) = req else {
+                    if !staging.is_empty() {
+                        staging[slot].release();
                     }
-                }
+                    break;
+                };
+                trace!("rank({})/job({}) recv requested", peer.idx, peer.job);
+                peer.post(slot, req);
+                progressed = true;
             }
-            trace!("rank({})/job({}) recv start", i, job_idx);
-
-            let mut reqs = vec_of_none(recv.len());
-            loop {
-                if cfg!(no_spinloop) {
-                    std::thread::sleep(NO_SPINLOOP_INTERVAL);
-                } else {
-                    hint::spin_loop();
-                }
-                if rank.load(std::sync::atomic::Ordering::Relaxed) != nrank {
-                    warn!("rank != nrank");
-                    warn!("recv thread({}) exit.", i);
-                    return;
-                }
 
-                let mut done = true;
-                for (j, (comm, mh, buf)) in recv.iter_mut().enumerate() {
-                    if reqs[j].is_none() {
-                        reqs[j] = nccl_net::irecv(comm, mh, &mut buf.lock(), 0x69).unwrap();
-                        if reqs[j].is_none() {
-                            done = false;
-                        }
+            // delivered[rank] of a job is round + 1 once the rank delivered its data of the
+            // round
+            while let Some(p) = peer.reqs.front() {
+                let (done, len) = match net.test(&p.req) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        let report = format!("rank({})/job({}) recv: {}", peer.idx, p.job, e);
+                        return failed(args, event_ch, Event::Failed, report);
                     }
-                }
-
-                if done {
+                };
+                if !done {
                     break;
                 }
-            }
-
-            trace!("rank({})/job({}) recv requested", i, job_idx);
-            let start = std::time::Instant::now();
-
-            loop {
-                if cfg!(no_spinloop) {
-                    std::thread::sleep(NO_SPINLOOP_INTERVAL);
-                } else {
-                    hint::spin_loop();
+                let (readys, lens, delivered, _, staging) = &recvs[p.job];
+                trace!(
+                    "rank({})/job({}) recv latency: {}us, {:.2}Gbps",
+                    peer.idx,
+                    p.job,
+                    p.start.elapsed().as_micros(),
+                    (len * 8) as f64 / p.start.elapsed().as_secs_f64() * 1e-9
+                );
+                lens[peer.idx].store(len, std::sync::atomic::Ordering::Relaxed);
+                delivered[peer.idx].store(p.round + 1, std::sync::atomic::Ordering::Relaxed);
+                if !staging.is_empty() {
+                    staging[p.slot].fill();
                 }
-
-                if rank.load(std::sync::atomic::Ordering::Relaxed) != nrank {
-                    warn!("rank != nrank");
-                    warn!("recv thread({}) exit.", i);
-                    return;
+                for ready in readys.iter() {
+                    ready.set(peer.idx);
                 }
+                peer.complete();
+                progressed = true;
+            }
 
-                let mut done = true;
-                for (j, _) in recv.iter().enumerate() {
-                    if reqs[j].is_some() {
-                        let (d, _) = nccl_net::test(reqs[j].as_ref().unwrap()).unwrap();
-                        if d {
-                            reqs[j] = None;
-                        } else {
-                            done = false;
-                        }
-                    }
+            if peer.reqs.is_empty() {
+                continue;
+            }
+            // armed once any rank delivered its data of the job
+            let (job, round) = peer.waiting();
+            let delivered = &recvs[job].2;
+            let arrived =
+                |idx: usize| delivered[idx].load(std::sync::atomic::Ordering::Relaxed) > round;
+            if peer.deadline.is_none() && (0..nrank).any(arrived) {
+                peer.deadline = Some(Deadline::from_secs(args.recv_timeout));
+            }
+            if let Some(elapsed) = peer.deadline.as_mut().and_then(|v| v.expired()) {
+                let (arrived, missing): (Vec<usize>, Vec<usize>) =
+                    (0..nrank).partition(|&idx| arrived(idx));
+                // the first missing rank reports for all of them
+                if missing.first() != Some(&peer.idx) {
+                    continue;
                 }
-                if done {
-                    break;
+                let report = format!(
+                    "job({}) recv stalled for {:?}, delivered ranks: {:?}, missing ranks: {:?}",
+                    job, elapsed, arrived, missing
+                );
+                if let Some(report) = stalled(args, report) {
+                    let _ = event_ch.send(Event::Timeout(report));
+                    warn!("recv task({}) exit.", i);
+                    return None;
                 }
             }
-
-            for ready in readys.iter() {
-                ready.fetch_add(1 << i, std::sync::atomic::Ordering::Relaxed);
-            }
-
-            trace!(
-                "rank({})/job({}) recv latency: {}us, {:.2}Gbps",
-                i,
-                job_idx,
-                start.elapsed().as_micros(),
-                (size * 8) as f64 / start.elapsed().as_secs_f64() * 1e-9
-            );
         }
+        Some(progressed)
+    }
+
+    // the ranks get the error like the ones of the network
+    fn panicked(&mut self, message: &str) {
+        let report = format!("recv task({}) panicked: {}", self.i, message);
+        failed(&self.args, &self.event_ch, Event::Failed, report);
     }
 }
 
-fn upstream_loop<T: Float>(
+fn upstream_loop<T: Element, N: Transport>(
+    net: &N,
     args: &Args,
+    dev: usize,
     rank: &AtomicUsize,
     mut jobs: Vec<(
+        Arc<ReadySet>,
+        Vec<Arc<ReadySet>>,
         Arc<AtomicUsize>,
-        Vec<Arc<AtomicUsize>>,
         Arc<PartitionedVec<T>>,
     )>,
 ) {
@@ -493,33 +970,39 @@ fn upstream_loop<T: Float>(
         if res.is_ok() {
             break res.unwrap();
         }
+        if rank.load(std::sync::atomic::Ordering::Relaxed) == CLOSED {
+            warn!("upstream thread({}) exit.", 0);
+            return;
+        }
         // sleep 1s
         std::thread::sleep(std::time::Duration::from_secs(1));
     };
 
-    let mut buffer = [0u8; 4];
-    stream.read(buffer.as_mut()).unwrap();
-    let size = u32::from_le_bytes(buffer);
-    let mut handle = vec![0u8; size as usize];
-    stream.read(handle.as_mut()).unwrap();
+    // the upstream server's nrank is the number of its children, which is unknown here.
+    // the jobs of all the channels go upstream in order over a single channel.
+    let hello = bootstrap::Hello {
+        nrank: 0,
+        nchannel: 1,
+        ..bootstrap::Hello::from_args(args)
+    };
+    bootstrap::connect(&mut stream, &hello)
+        .unwrap_or_else(|e| panic!("handshake with upstream {} failed: {}", args.upstream, e));
+
+    let handle = bootstrap::recv_handle(&mut stream).unwrap();
 
-    let (lcomm, lhandle) = nccl_net::listen().unwrap();
+    let (lcomm, lhandle) = net.listen(dev).unwrap();
 
-    // send size of handle
-    let size = lhandle.len() as u32;
-    stream.write_all(&size.to_le_bytes()).unwrap();
-    // send handle
-    stream.write_all(&lhandle).unwrap();
+    bootstrap::send_handle(&mut stream, &lhandle).unwrap();
 
-    let mut scomm: Option<Comm> = None;
-    let mut rcomm: Option<Comm> = None;
+    let mut scomm: Option<N::Comm> = None;
+    let mut rcomm: Option<N::Comm> = None;
 
     loop {
         if scomm.is_none() {
-            scomm = nccl_net::connect(handle.as_slice()).unwrap();
+            scomm = net.connect(dev, &handle).unwrap();
         }
         if rcomm.is_none() {
-            rcomm = nccl_net::accept(&lcomm).unwrap();
+            rcomm = net.accept(&lcomm).unwrap();
         }
         if scomm.is_some() && rcomm.is_some() {
             break;
@@ -531,82 +1014,64 @@ fn upstream_loop<T: Float>(
 
     let mhs = jobs
         .iter()
-        .map(|(_, _, buf)| {
-            let send_mh = nccl_net::reg_mr(&scomm, &buf.lock()).unwrap();
-            let recv_mh = nccl_net::reg_mr(&rcomm, &buf.lock()).unwrap();
+        .map(|(_, _, _, buf)| {
+            let send_mh = net.reg_mr(&scomm, &buf.lock()).unwrap();
+            let recv_mh = net.reg_mr(&rcomm, &buf.lock()).unwrap();
             (send_mh, recv_mh)
         })
         .collect::<Vec<_>>();
 
-    loop {
-        if rank.load(std::sync::atomic::Ordering::Relaxed) == args.nrank {
-            break;
-        }
-        std::thread::sleep(std::time::Duration::from_millis(100));
+    if !wait_connected(rank, args.nrank) {
+        warn!("upstream thread({}) exit.", 0);
+        return;
     }
 
     info!("upstream connected");
     let tag = 0x69;
 
+    // the waits back off like the send and recv tasks, each phase that gets through starts over
+    let mut backoff = Backoff::new();
     loop {
-        for (idx, (send_ready, reduce_readys, buf)) in jobs.iter_mut().enumerate() {
+        for (idx, (send_ready, reduce_readys, send_len, buf)) in jobs.iter_mut().enumerate() {
             for reduce_ready in reduce_readys.iter() {
-                loop {
-                    if cfg!(no_spinloop) {
-                        std::thread::sleep(NO_SPINLOOP_INTERVAL);
-                    } else {
-                        hint::spin_loop();
-                    }
-
-                    let reduce_ready = reduce_ready.load(std::sync::atomic::Ordering::Relaxed);
-                    if reduce_ready == 0 {
-                        break;
-                    }
+                while !reduce_ready.none_set() {
                     if rank.load(std::sync::atomic::Ordering::Relaxed) != nrank {
                         warn!("rank != nrank");
                         warn!("upstream thread({}) exit.", 0);
                         return;
                     }
+                    backoff.idle();
                 }
             }
 
-            loop {
-                if cfg!(no_spinloop) {
-                    std::thread::sleep(NO_SPINLOOP_INTERVAL);
-                } else {
-                    hint::spin_loop();
-                }
-                let send_ready = send_ready.load(std::sync::atomic::Ordering::Relaxed);
-                let send_expect = (1 << args.send_threads) - 1;
-                if send_ready == send_expect {
-                    break;
-                }
+            while !send_ready.all_set() {
                 if rank.load(std::sync::atomic::Ordering::Relaxed) != nrank {
                     warn!("rank != nrank");
                     warn!("upstream thread({}) exit.", 0);
                     return;
                 }
+                backoff.idle();
             }
+            backoff.reset();
 
+            let count = send_len.load(std::sync::atomic::Ordering::Relaxed);
             let (send_mh, recv_mh) = &mhs[idx];
-            let mut srequest: Option<Request> = None;
-            let mut rrequest: Option<Request> = None;
+            let mut srequest: Option<N::Request> = None;
+            let mut rrequest: Option<N::Request> = None;
 
             loop {
-                if cfg!(no_spinloop) {
-                    std::thread::sleep(NO_SPINLOOP_INTERVAL);
-                } else {
-                    hint::spin_loop();
-                }
-
                 if srequest.is_none() {
-                    srequest = nccl_net::isend(&scomm, send_mh, buf.lock().as_ref(), tag).unwrap();
+                    srequest = net
+                        .isend(&scomm, send_mh, &buf.lock()[..count], tag)
+                        .unwrap();
                     if srequest.is_some() {
                         trace!("upstream send  : idx: {} start", idx);
                     }
                 }
                 if rrequest.is_none() {
-                    rrequest = nccl_net::irecv(&rcomm, recv_mh, buf.lock().as_mut(), tag).unwrap();
+                    rrequest = net
+                        .irecv(&rcomm, recv_mh, buf.lock().as_mut(), tag)
+                        .unwrap();
                     if srequest.is_some() {
                         trace!("upstream recv : idx: {} start", idx);
                     }
@@ -614,17 +1079,13 @@ fn upstream_loop<T: Float>(
                 if srequest.is_some() && rrequest.is_some() {
                     break;
                 }
+                backoff.idle();
             }
+            backoff.reset();
 
             loop {
-                if cfg!(no_spinloop) {
-                    std::thread::sleep(NO_SPINLOOP_INTERVAL);
-                } else {
-                    hint::spin_loop();
-                }
-
                 if srequest.is_some() {
-                    match nccl_net::test(&srequest.as_ref().unwrap()) {
+                    match net.test(srequest.as_ref().unwrap()) {
                         Ok((send_done, _)) => {
                             if send_done {
                                 trace!("upstream send  : idx: {} done", idx);
@@ -638,11 +1099,20 @@ fn upstream_loop<T: Float>(
                     }
                 }
                 if rrequest.is_some() {
-                    match nccl_net::test(&rrequest.as_ref().unwrap()) {
-                        Ok((recv_done, _)) => {
+                    match net.test(rrequest.as_ref().unwrap()) {
+                        Ok((recv_done, len)) => {
                             if recv_done {
                                 trace!("upstream recv  : idx: {} done", idx);
                                 rrequest = None;
+                                if len != count * std::mem::size_of::<T>() {
+                                    error!(
+                                        "upstream recv : idx: {} size mismatch: sent {} bytes, received {} bytes",
+                                        idx,
+                                        count * std::mem::size_of::<T>(),
+                                        len
+                                    );
+                                    return;
+                                }
                             }
                         }
                         Err(e) => {
@@ -654,56 +1124,159 @@ fn upstream_loop<T: Float>(
                 if srequest.is_none() && rrequest.is_none() {
                     break;
                 }
+                backoff.idle();
             }
+            backoff.reset();
 
             for reduce_ready in reduce_readys.iter_mut() {
-                reduce_ready.store(
-                    (1 << args.send_threads) - 1,
-                    std::sync::atomic::Ordering::Relaxed,
-                );
+                reduce_ready.set_all();
             }
 
-            send_ready.store(0, std::sync::atomic::Ordering::Relaxed);
+            send_ready.clear_all();
         }
     }
 }
 
-fn do_server<T: Float + 'static>(args: Args) {
-    let mut args = args;
+// a group of ranks reduced together, identified by the communicator id of the handshake
+struct Communicator {
+    nrank: usize,
+    reduce_op: ReduceOp,
+    connected: Vec<bool>,
+    event_ch: std::sync::mpsc::Sender<Event>,
+}
 
-    if args.recv_threads == 0 {
-        args.recv_threads = args.nrank
-    }
+type Communicators = Arc<Mutex<HashMap<u64, Communicator>>>;
 
-    if args.send_threads == 0 {
-        args.send_threads = args.nrank
+// the send and recv threads of the server, shared by all its communicators
+struct Pools {
+    send: Pool,
+    recv: Pool,
+}
+
+// waits for the ranks that are still connected to disconnect as long as some of them have a
+// reduced result that isn't sent yet, up to --send-timeout. sends are the send readys of the
+// jobs, where the flag of a rank is clear from the time its result is reduced until it is sent.
+fn linger(
+    args: &Args,
+    events: &std::sync::mpsc::Receiver<Event>,
+    closed: &mut [bool],
+    sends: &[Vec<Arc<ReadySet>>],
+) {
+    let mut deadline = Deadline::from_secs(args.send_timeout);
+    loop {
+        let waiting = (0..args.nrank)
+            .filter(|&idx| !closed[idx] && sends.iter().flatten().any(|v| !v.is_set(idx)))
+            .collect::<Vec<_>>();
+        if waiting.is_empty() {
+            return;
+        }
+        if let Some(elapsed) = deadline.expired() {
+            warn!(
+                "communicator 0x{:016x}: results still unsent to the ranks {:?} after {:?}",
+                args.comm_id, waiting, elapsed
+            );
+            return;
+        }
+        match events.recv_timeout(std::time::Duration::from_millis(10)) {
+            Ok(Event::Closed(idx)) => closed[idx] = true,
+            // the results can't go out anymore
+            Ok(Event::Timeout(_) | Event::Aborted(_))
+            | Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return,
+            // including the receives of the other ranks that are done
+            _ => {}
+        }
     }
+}
 
-    let listener =
-        TcpListener::bind(format!("{}:{}", args.address, args.port)).expect("failed to bind");
+// allocates the buffers, the threads and the send and recv tasks of a communicator and serves it
+// until one of its ranks gets disconnected. the communicator is then torn down once the results
+// already reduced have reached the other ranks or --send-timeout passes: the bootstrap streams of
+// the other ranks are shut down, the threads and tasks exit closing their comms and memory
+// registrations, and the id becomes free for a fresh set of ranks.
+fn serve_comm<T: Element + 'static, N: Transport>(
+    net: N,
+    args: Args,
+    rails: Arc<Vec<usize>>,
+    comms: Communicators,
+    pools: Arc<Pools>,
+    event_ch: std::sync::mpsc::Sender<Event>,
+    events: std::sync::mpsc::Receiver<Event>,
+) {
+    let mut args = args;
+
+    // the channels of the ranks are split into a task per pool thread, or a task per channel
+    // when there are fewer of them
+    let npeer = args.nrank * args.nchannel;
+    args.recv_threads = pools.recv.len().min(npeer);
+    args.send_threads = pools.send.len().min(npeer);
+
+    // the chunks of a job go through the receive, reduce and send threads as jobs of their own,
+    // so that the first chunks are reduced and sent back while the rest is still arriving.
+    // each channel of the ranks carries --reduce-jobs jobs of its own.
+    let njob = args.reduce_jobs * args.nchannel * args.nchunk;
+    let count = args.count.div_ceil(args.nchunk);
+    // a channel can't have two requests for the same job in flight
+    args.pipeline_depth = args.pipeline_depth.min(njob / args.nchannel);
 
     let rank = Arc::new(AtomicUsize::new(0));
-    let size = args.count * std::mem::size_of::<T>();
+    let size = count * std::mem::size_of::<T>();
 
     let args = Arc::new(args);
+    let mut workers = vec![];
+    // held by the send and recv tasks, the channel gets closed once they are all over
+    let (alive, tasks) = std::sync::mpsc::channel::<()>();
 
     // memory allocation
-    let bufs = (0..args.reduce_jobs)
+    let bufs = (0..njob)
         .map(|_| {
             let sbuf = Arc::new(
-                PartitionedVec::<T>::new(alignment(size), args.count, args.reduce_threads).unwrap(),
+                PartitionedVec::<T>::new(alignment(size), count, args.reduce_threads).unwrap(),
             );
 
-            let rbufs = (0..args.nrank)
+            // with --accumulate-on-arrival the ranks share a few staging buffers instead
+            let nrbuf = if args.accumulate_on_arrival {
+                0
+            } else {
+                args.nrank
+            };
+            let rbufs = (0..nrbuf)
                 .map(|_| {
                     Arc::new(
-                        PartitionedVec::new(alignment(size), args.count, args.reduce_threads)
-                            .unwrap(),
+                        PartitionedVec::new(alignment(size), count, args.reduce_threads).unwrap(),
                     )
                 })
                 .collect::<Vec<_>>();
+            let nstaging = if args.accumulate_on_arrival {
+                args.staging_buffers
+            } else {
+                0
+            };
+            let staging = Arc::new(
+                (0..nstaging)
+                    .map(|_| {
+                        Staging::new(
+                            PartitionedVec::new(alignment(size), count, args.reduce_threads)
+                                .unwrap(),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            );
 
-            (sbuf, rbufs)
+            // actual message sizes of the job, in bytes per rank and in elements for the result
+            let recv_lens = Arc::new(
+                (0..args.nrank)
+                    .map(|_| AtomicUsize::new(0))
+                    .collect::<Vec<_>>(),
+            );
+            let send_len = Arc::new(AtomicUsize::new(0));
+            // rounds of the job each rank has delivered, to report the ranks a stalled job waits for
+            let delivered = Arc::new(
+                (0..args.nrank)
+                    .map(|_| AtomicUsize::new(0))
+                    .collect::<Vec<_>>(),
+            );
+
+            (sbuf, rbufs, recv_lens, send_len, delivered, staging)
         })
         .collect::<Vec<_>>();
 
@@ -713,15 +1286,24 @@ fn do_server<T: Float + 'static>(args: Args) {
             let rank = Arc::clone(&rank);
             let jobs = bufs
                 .iter()
-                .map(|(sbuf, rbufs)| {
-                    let send_ready = Arc::new(AtomicUsize::new((1 << args.send_threads) - 1));
-                    let recv_ready = Arc::new(AtomicUsize::new(0));
+                .map(|(sbuf, rbufs, recv_lens, send_len, _, staging)| {
+                    // a flag per rank
+                    let send_ready = Arc::new(ReadySet::new(args.nrank, true));
+                    let recv_ready = Arc::new(ReadySet::new(args.nrank, false));
 
                     let recv_bufs = rbufs
                         .iter()
                         .map(|rbuf| Arc::clone(rbuf))
                         .collect::<Vec<_>>();
-                    (send_ready, recv_ready, Arc::clone(sbuf), recv_bufs)
+                    (
+                        send_ready,
+                        recv_ready,
+                        Arc::clone(recv_lens),
+                        Arc::clone(send_len),
+                        Arc::clone(sbuf),
+                        recv_bufs,
+                        Arc::clone(staging),
+                    )
                 })
                 .collect::<Vec<_>>();
 
@@ -731,13 +1313,16 @@ fn do_server<T: Float + 'static>(args: Args) {
                 .collect::<Vec<_>>();
 
             let args = Arc::clone(&args);
-            std::thread::spawn(move || reduce_loop(i, &args, &rank, jobs));
+            let event_ch = event_ch.clone();
+            workers.push(std::thread::spawn(move || {
+                reduce_loop(i, &args, &rank, event_ch, jobs)
+            }));
             readys
         })
         .collect::<Vec<_>>();
 
-    // transpose readys[reduce_threads][reduce_jobs] to readys[reduce_jobs][reduce_threads]
-    let (send_readys, recv_readys): (Vec<_>, Vec<_>) = (0..args.reduce_jobs)
+    // transpose readys[reduce_threads][njob] to readys[njob][reduce_threads]
+    let (send_readys, recv_readys): (Vec<_>, Vec<_>) = (0..njob)
         .map(|i| {
             (0..args.reduce_threads)
                 .map(|j| Option::take(&mut readys[j][i]).unwrap())
@@ -754,91 +1339,415 @@ fn do_server<T: Float + 'static>(args: Args) {
             .into_iter()
             .enumerate()
             .map(|(i, send_ready)| {
-                let ready = Arc::new(AtomicUsize::new((1 << args.send_threads) - 1));
-                let (sbuf, _) = &bufs[i];
-                (ready, send_ready, Arc::clone(sbuf))
+                let ready = Arc::new(ReadySet::new(args.nrank, true));
+                let (sbuf, _, _, send_len, _, _) = &bufs[i];
+                (ready, send_ready, Arc::clone(send_len), Arc::clone(sbuf))
             })
             .collect::<Vec<_>>();
 
         let readys = jobs
             .iter()
-            .map(|(ready, _, _)| vec![Arc::clone(ready)])
+            .map(|(ready, _, _, _)| vec![Arc::clone(ready)])
             .collect::<Vec<_>>();
 
+        let net = net.clone();
         let rank = Arc::clone(&rank);
-        std::thread::spawn(move || upstream_loop(&args, &rank, jobs));
+        let dev = rails[0];
+        workers.push(std::thread::spawn(move || {
+            upstream_loop(&net, &args, dev, &rank, jobs)
+        }));
         readys
     };
 
-    // launch send threads
+    // launch send tasks
     let send_chs = (0..args.send_threads)
         .map(|send_idx| {
             let rank = Arc::clone(&rank);
             let sends = bufs
                 .iter()
                 .zip(&send_readys)
-                .map(|((sbuf, _), readys)| {
+                .map(|((sbuf, _, _, send_len, _, _), readys)| {
                     (
                         readys.iter().map(|v| Arc::clone(v)).collect::<Vec<_>>(),
+                        Arc::clone(send_len),
                         Arc::clone(sbuf),
                     )
                 })
                 .collect::<Vec<_>>();
 
             let (tx, rx) = std::sync::mpsc::channel();
+            let net = net.clone();
             let args = Arc::clone(&args);
-            std::thread::spawn(move || send_loop(send_idx, &args, &rank, sends, rx));
+            let event_ch = event_ch.clone();
+            let alive = alive.clone();
+            info!("send task({}) waiting all ranks get connected.", send_idx);
+            pools.send.spawn(move || SendTask {
+                peers: Peers::new(assigned_peers(send_idx, npeer, args.send_threads), rx),
+                net,
+                i: send_idx,
+                args,
+                rank,
+                event_ch,
+                sends,
+                connected: false,
+                _alive: alive,
+            });
             tx
         })
         .collect::<Vec<_>>();
 
-    // launch recv threads
+    // launch recv tasks
     let recv_chs = (0..args.recv_threads)
         .map(|recv_idx| {
             let rank = Arc::clone(&rank);
             let recvs = bufs
                 .iter()
                 .zip(&recv_readys)
-                .map(|((_, rbufs), readys)| {
-                    (
-                        readys.iter().map(|v| Arc::clone(v)).collect::<Vec<_>>(),
-                        rbufs
-                            .iter()
-                            .enumerate()
-                            .filter(|(j, _)| j % args.recv_threads == recv_idx)
-                            .map(|(k, rbuf)| (k, Some(Arc::clone(rbuf))))
-                            .collect::<Vec<_>>(),
-                    )
-                })
+                .enumerate()
+                .map(
+                    |(job, ((_, rbufs, recv_lens, _, delivered, staging), readys))| {
+                        let channel = job % args.nchannel;
+                        (
+                            readys.iter().map(|v| Arc::clone(v)).collect::<Vec<_>>(),
+                            Arc::clone(recv_lens),
+                            Arc::clone(delivered),
+                            rbufs
+                                .iter()
+                                .enumerate()
+                                .filter(|(j, _)| {
+                                    peer_thread(*j, channel, args.nchannel, args.recv_threads)
+                                        == recv_idx
+                                })
+                                .map(|(k, rbuf)| (k, Arc::clone(rbuf)))
+                                .collect::<Vec<_>>(),
+                            Arc::clone(staging),
+                        )
+                    },
+                )
+                .collect::<Vec<_>>();
+            // the recv buffers of the ranks of this task, per job
+            let bufs = recvs
+                .iter()
+                .map(|v| v.3.iter().cloned().collect::<HashMap<_, _>>())
                 .collect::<Vec<_>>();
             let (tx, rx) = std::sync::mpsc::channel();
+            let net = net.clone();
             let args = Arc::clone(&args);
-            std::thread::spawn(move || recv_loop(recv_idx, &args, &rank, recvs, rx));
+            let event_ch = event_ch.clone();
+            let alive = alive.clone();
+            info!(
+                "recv task({}) waiting all ranks get connected, recvs: {:?}",
+                recv_idx,
+                bufs.iter()
+                    .map(|v| v.keys().collect::<Vec<_>>())
+                    .collect::<Vec<_>>(),
+            );
+            pools.recv.spawn(move || RecvTask {
+                peers: Peers::new(assigned_peers(recv_idx, npeer, args.recv_threads), rx),
+                net,
+                i: recv_idx,
+                args,
+                rank,
+                event_ch,
+                recvs,
+                bufs,
+                connected: false,
+                _alive: alive,
+            });
             tx
         })
         .collect::<Vec<_>>();
 
-    let hs = (0..args.nrank)
-        .map(|_| {
-            let (socket, _) = listener.accept().unwrap();
-            let idx = rank.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            let rcomm_ch = recv_chs[idx % recv_chs.len()].clone();
-            let scomm_ch = send_chs[idx % send_chs.len()].clone();
-            let rank = Arc::clone(&rank);
-            std::thread::spawn(move || handle_connection(socket, idx, &rank, rcomm_ch, scomm_ch))
-        })
-        .collect::<Vec<_>>();
-    hs.into_iter().for_each(|h| h.join().unwrap());
+    let mut streams = Vec::with_capacity(args.nrank);
+    let mut hs = Vec::with_capacity(args.nrank);
+    let mut ready = vec![false; args.nrank];
+    let mut deadline = Deadline::from_secs(args.connect_timeout);
+    let mut closed = vec![false; args.nrank];
+    let reason = loop {
+        let event = if ready.iter().all(|v| *v) {
+            events.recv().unwrap()
+        } else {
+            match events.recv_timeout(std::time::Duration::from_millis(100)) {
+                Ok(event) => event,
+                Err(_) => {
+                    if let Some(elapsed) = deadline.expired() {
+                        let (connected, missing): (Vec<usize>, Vec<usize>) =
+                            (0..args.nrank).partition(|&idx| ready[idx]);
+                        let report = format!(
+                            "waited {:?} for the ranks to get connected, connected ranks: {:?}, missing ranks: {:?}",
+                            elapsed, connected, missing
+                        );
+                        if let Some(report) = stalled(&args, report) {
+                            break report;
+                        }
+                    }
+                    continue;
+                }
+            }
+        };
+        match event {
+            Event::Connected(socket, idx) => {
+                streams.push((idx, socket.try_clone().unwrap()));
+                rank.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                // the channels of the ranks are striped across the rails
+                let devs = (0..args.nchannel)
+                    .map(|c| rails[(idx * args.nchannel + c) % rails.len()])
+                    .collect();
+                let (rcomm_chs, scomm_chs) = (0..args.nchannel)
+                    .map(|c| {
+                        (
+                            recv_chs[peer_thread(idx, c, args.nchannel, recv_chs.len())].clone(),
+                            send_chs[peer_thread(idx, c, args.nchannel, send_chs.len())].clone(),
+                        )
+                    })
+                    .unzip();
+                let net = net.clone();
+                let event_ch = event_ch.clone();
+                let rank = Arc::clone(&rank);
+                hs.push(std::thread::spawn(move || {
+                    handle_connection(
+                        &net, socket, idx, &rank, devs, rcomm_chs, scomm_chs, event_ch,
+                    )
+                }));
+            }
+            Event::Ready(idx) => ready[idx] = true,
+            Event::Closed(idx) => {
+                info!(
+                    "rank {} of communicator 0x{:016x} disconnected",
+                    idx, args.comm_id
+                );
+                closed[idx] = true;
+                // a rank that is done disconnects as soon as it has its last result, while the
+                // others may still be receiving theirs
+                if ready.iter().all(|v| *v) {
+                    linger(&args, &events, &mut closed, &send_readys);
+                }
+                break format!("rank {} disconnected", idx);
+            }
+            Event::Failed(report) => {
+                if ready.iter().all(|v| *v) {
+                    linger(&args, &events, &mut closed, &send_readys);
+                }
+                break report;
+            }
+            Event::Timeout(report) | Event::Aborted(report) => break report,
+        }
+    };
+
+    // no more ranks can join once the communicator is unregistered
+    comms.lock().unwrap().remove(&args.comm_id);
+    rank.store(CLOSED, std::sync::atomic::Ordering::Relaxed);
+    // tell the surviving ranks why their allreduce is going to fail instead of letting them
+    // wait for data that will never come
+    for (idx, mut stream) in streams {
+        if !closed[idx] {
+            let _ = bootstrap::send_abort(&mut stream, &reason);
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+    }
+    // ranks accepted in the meantime are turned away by closing their streams
+    drop(events);
+    drop(send_chs);
+    drop(recv_chs);
+
+    drop(alive);
+    let _ = tasks.recv();
+    for h in hs.into_iter().chain(workers) {
+        if h.join().is_err() {
+            warn!("communicator 0x{:016x}: thread panicked", args.comm_id);
+        }
+    }
+    info!("communicator 0x{:016x} closed", args.comm_id);
 }
 
-pub(crate) fn server(args: Args) {
-    if args.data_type == DataType::F32 {
-        do_server::<f32>(args);
-    } else if args.data_type == DataType::F16 {
-        do_server::<f16>(args);
-    } else if args.data_type == DataType::BF16 {
-        do_server::<bf16>(args);
+fn do_server<T: Element + 'static, N: Transport>(net: N, args: Args) {
+    assert!(args.nchannel > 0, "--nchannel must be at least 1");
+
+    assert!(
+        args.reduce_threads > 0 && args.reduce_jobs > 0,
+        "--reduce-threads and --reduce-jobs must be at least 1"
+    );
+
+    assert!(
+        args.nchunk > 0 && args.pipeline_depth > 0,
+        "--nchunk and --pipeline-depth must be at least 1"
+    );
+
+    // the ranks are folded in the order their data arrives
+    assert!(
+        !args.accumulate_on_arrival || !args.deterministic,
+        "--accumulate-on-arrival can't be used in deterministic mode"
+    );
+
+    assert!(
+        !args.accumulate_on_arrival || args.staging_buffers > 0,
+        "--staging-buffers must be at least 1"
+    );
+
+    // the upstream server orders its children by their rank ids
+    assert!(
+        args.upstream.is_empty() || !args.deterministic || args.rank.is_some(),
+        "--rank is required to connect to an upstream server in deterministic mode"
+    );
+
+    // an upstream server would average the averages of its children. the ranks that choose avg
+    // themselves are rejected.
+    assert!(
+        args.upstream.is_empty() || args.reduce_op != ReduceOp::Avg,
+        "avg can't be used with an upstream server"
+    );
+
+    let rails = Arc::new(rails(&net, &args));
+
+    let listener =
+        TcpListener::bind(format!("{}:{}", args.address, args.port)).expect("failed to bind");
+
+    // the first rank of a communicator creates it. a communicator without nrank in the
+    // handshake, like the one of a downstream server, has the server's --nrank ranks.
+    let nrank_of = |hello: &bootstrap::Hello| {
+        if hello.nrank == 0 {
+            args.nrank
+        } else {
+            hello.nrank
+        }
+    };
+
+    // polled, so that the server notices the last communicator getting closed before all its
+    // ranks are connected
+    listener.set_nonblocking(true).unwrap();
+
+    let comms: Communicators = Arc::new(Mutex::new(HashMap::new()));
+    let nthreads = |v: usize| if v == 0 { DEFAULT_PROGRESS_THREADS } else { v };
+    let pools = Arc::new(Pools {
+        send: Pool::new("send", nthreads(args.send_threads)),
+        recv: Pool::new("recv", nthreads(args.recv_threads)),
+    });
+    let (hello_ch, hellos) = std::sync::mpsc::channel();
+    let mut served = vec![];
+    let mut nserved = 0;
+    loop {
+        // the threads of the communicators that are already closed
+        served.retain(|h: &std::thread::JoinHandle<()>| !h.is_finished());
+        if args.ncomm != 0 && nserved >= args.ncomm {
+            let comms = comms.lock().unwrap();
+            if comms.values().all(|c| c.connected.iter().all(|v| *v)) {
+                break;
+            }
+        }
+
+        match listener.accept() {
+            // the Hello is read on a thread of its own, so that a peer that doesn't send it
+            // holds back neither the other peers nor the communicators
+            Ok((mut socket, addr)) => {
+                let args = args.clone();
+                let hello_ch = hello_ch.clone();
+                std::thread::spawn(move || {
+                    socket.set_nonblocking(false).unwrap();
+                    let timeout = Some(std::time::Duration::from_secs(args.connect_timeout))
+                        .filter(|v| !v.is_zero());
+                    socket.set_read_timeout(timeout).unwrap();
+                    match bootstrap::read_hello(&mut socket, &args) {
+                        Ok(hello) => {
+                            socket.set_read_timeout(None).unwrap();
+                            let _ = hello_ch.send((socket, addr, hello));
+                        }
+                        Err(e) => warn!("handshake with {} failed: {}", addr, e),
+                    }
+                });
+                continue;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(e) => panic!("accept: {}", e),
+        }
+        let (mut socket, addr, hello) =
+            match hellos.recv_timeout(std::time::Duration::from_millis(100)) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+
+        // the lock is only held to find the communicator of the peer and to take its rank
+        let mut comms_guard = comms.lock().unwrap();
+        let member = match comms_guard.get(&hello.comm_id) {
+            Some(comm) => hello.validate_member(
+                comm.nrank,
+                comm.reduce_op,
+                &comm.connected,
+                args.deterministic,
+            ),
+            // the first rank chooses the reduce operator of the communicator
+            None => hello.validate_member(
+                nrank_of(&hello),
+                hello.reduce_op.unwrap(),
+                &[],
+                args.deterministic,
+            ),
+        };
+        if let Err(e) = member {
+            drop(comms_guard);
+            let e = bootstrap::reply(&mut socket, &args, &hello, Err(e)).unwrap_err();
+            warn!("handshake with {} failed: {}", addr, e);
+            continue;
+        }
+
+        let comm = comms_guard.entry(hello.comm_id).or_insert_with(|| {
+            let nrank = nrank_of(&hello);
+            let reduce_op = hello.reduce_op.unwrap();
+            info!(
+                "communicator 0x{:016x} of {} ranks created, reduce op: {}",
+                hello.comm_id, nrank, reduce_op
+            );
+            let (event_ch, events) = std::sync::mpsc::channel();
+            let args = Args {
+                nrank,
+                reduce_op,
+                comm_id: hello.comm_id,
+                ..args.clone()
+            };
+            let net = net.clone();
+            let rails = Arc::clone(&rails);
+            let comms = Arc::clone(&comms);
+            let pools = Arc::clone(&pools);
+            let ch = event_ch.clone();
+            nserved += 1;
+            served.push(std::thread::spawn(move || {
+                serve_comm::<T, N>(net, args, rails, comms, pools, ch, events)
+            }));
+            Communicator {
+                nrank,
+                reduce_op,
+                connected: vec![false; nrank],
+                event_ch,
+            }
+        });
+
+        // the receive buffer of a rank is chosen by its rank id, so that the reduction order
+        // doesn't depend on the order the ranks get connected. ranks without an id take the
+        // first free one.
+        let idx = hello
+            .rank
+            .unwrap_or_else(|| comm.connected.iter().position(|v| !v).unwrap());
+        comm.connected[idx] = true;
+        let event_ch = comm.event_ch.clone();
+        drop(comms_guard);
+
+        // a peer that is gone before it gets the reply is noticed by its communicator like any
+        // other rank that disconnects
+        if let Err(e) = bootstrap::reply(&mut socket, &args, &hello, Ok(())) {
+            warn!("handshake with {} failed: {}", addr, e);
+        }
+        info!(
+            "rank {} of communicator 0x{:016x} connected from {}",
+            idx, hello.comm_id, addr
+        );
+        if event_ch.send(Event::Connected(socket, idx)).is_err() {
+            warn!("communicator 0x{:016x} is gone", hello.comm_id);
+        }
     }
+    served.into_iter().for_each(|h| h.join().unwrap());
+}
+
+pub(crate) fn server<N: Transport>(net: N, args: Args) {
+    with_data_type!(args.data_type, do_server::<N>(net, args));
 }
 
 // test
@@ -846,45 +1755,138 @@ pub(crate) fn server(args: Args) {
 mod tests {
     use super::*;
     use crate::client::client;
-    use crate::utils::tests::initialize;
+    use crate::nccl_net::{self, NcclNet};
+    use crate::transport::loopback::{self, Loopback};
+    use crate::transport::shm::Shm;
+    use crate::transport::tcp::{self, Tcp};
+    use crate::transport::uring::Uring;
+    use crate::utils::tests::{free_port, init_logger, initialize};
     use clap::Parser;
+    use half::f16;
 
-    fn do_test(dt: &str) {
-        initialize();
+    fn do_test(dt: &str, count: usize, op: &str) {
+        do_test_with(dt, count, op, &[]);
+    }
+
+    fn do_test_with(dt: &str, count: usize, op: &str, server_opts: &[&str]) {
+        do_test_with_opts(dt, count, op, server_opts, &[]);
+    }
+
+    fn do_test_with_opts(
+        dt: &str,
+        count: usize,
+        op: &str,
+        server_opts: &[&str],
+        client_opts: &[&str],
+    ) {
+        do_test_on(Loopback::default(), dt, count, op, server_opts, client_opts);
+    }
+
+    fn do_test_on<N: Transport>(
+        net: N,
+        dt: &str,
+        count: usize,
+        op: &str,
+        server_opts: &[&str],
+        client_opts: &[&str],
+    ) {
+        let rank_net = |_| net.clone();
+        do_test_on_ranks(
+            net.clone(),
+            rank_net,
+            dt,
+            count,
+            op,
+            server_opts,
+            client_opts,
+        );
+    }
+
+    // rank_net gives the net of the i-th client
+    fn do_test_on_ranks<N: Transport>(
+        net: N,
+        rank_net: impl Fn(usize) -> N,
+        dt: &str,
+        count: usize,
+        op: &str,
+        server_opts: &[&str],
+        client_opts: &[&str],
+    ) {
+        init_logger();
         let nrank = 4;
+        let port = free_port().to_string();
         let server = {
+            let net = net.clone();
+            let port = port.clone();
             let dt = dt.to_string();
+            let op = op.to_string();
+            let server_opts = server_opts
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>();
             std::thread::spawn(move || {
                 let nrank = format!("{}", nrank);
-                let args = Args::parse_from([
-                    "--verbose", // doesn't work without specifying a flag that doesn't take an argument
-                    "--port",
-                    "8080",
-                    "--data-type",
-                    &dt,
-                    "--nrank",
-                    &nrank,
-                    "--nreq",
-                    "1", // when using socket plugin, concurrent recv/send requests doesn't work
-                ]);
-                server(args);
+                // the largest message the server accepts, a power of two that the reduce
+                // threads split evenly
+                let max_count = format!("{}", count.next_power_of_two().max(1024 * 1024));
+                let args = Args::parse_from(
+                    [
+                        "--verbose", // doesn't work without specifying a flag that doesn't take an argument
+                        "--ncomm",
+                        "1",
+                        "--port",
+                        &port,
+                        "--data-type",
+                        &dt,
+                        "--reduce-op",
+                        &op,
+                        "--nrank",
+                        &nrank,
+                        "--count",
+                        &max_count,
+                    ]
+                    .into_iter()
+                    .chain(server_opts.iter().map(|v| v.as_str())),
+                );
+                server(net, args);
             })
         };
         (0..nrank)
-            .map(|_| {
+            .map(|i| {
+                let net = rank_net(i);
+                let address = format!("127.0.0.1:{}", port);
                 let dt = dt.to_string();
+                let op = op.to_string();
+                let client_opts = client_opts
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>();
                 std::thread::spawn(move || {
                     std::thread::sleep(std::time::Duration::from_millis(100));
-                    let args = Args::parse_from([
-                        "--client",
-                        "--address",
-                        "127.0.0.1:8080",
-                        "--data-type",
-                        &dt,
-                        "--nreq",
-                        "1", // when using socket plugin, concurrent recv/send requests doesn't work
-                    ]);
-                    client(args);
+                    // rank ids unrelated to the order the clients get connected
+                    let rank = format!("{}", nrank - 1 - i);
+                    let nrank = format!("{}", nrank);
+                    let count = format!("{}", count);
+                    let args = Args::parse_from(
+                        [
+                            "--client",
+                            "--rank",
+                            &rank,
+                            "--address",
+                            &address,
+                            "--data-type",
+                            &dt,
+                            "--nrank",
+                            &nrank,
+                            "--count",
+                            &count,
+                            "--reduce-op",
+                            &op,
+                        ]
+                        .into_iter()
+                        .chain(client_opts.iter().map(|v| v.as_str())),
+                    );
+                    client(net, args);
                 })
             })
             .collect::<Vec<_>>()
@@ -894,36 +1896,46 @@ mod tests {
     }
 
     fn do_test_upstream(dt: &str) {
-        initialize();
+        init_logger();
+        let net = Loopback::default();
         let nrank = 2;
+        let root_port = free_port().to_string();
         let root = {
+            let net = net.clone();
+            let port = root_port.clone();
             let dt = dt.to_string();
             std::thread::spawn(move || {
                 let nrank = format!("{}", nrank);
                 let args = Args::parse_from([
                     "--verbose", // doesn't work without specifying a flag that doesn't take an argument
+                    "--ncomm",
+                    "1",
                     "--port",
-                    "8080",
+                    &port,
                     "--data-type",
                     &dt,
                     "--nrank",
                     &nrank,
                 ]);
-                server(args);
+                server(net, args);
             })
         };
         (0..nrank)
-            .map(|i| {
-                let port = format!("{}", 8081 + i);
+            .map(|_| {
+                let port = free_port().to_string();
                 let parent = {
+                    let net = net.clone();
+                    let upstream = format!("localhost:{}", root_port);
                     let dt = dt.to_string();
                     let port = port.to_string();
                     std::thread::spawn(move || {
                         let nrank = format!("{}", nrank);
                         let args = Args::parse_from([
                             "--verbose", // doesn't work without specifying a flag that doesn't take an argument
+                            "--ncomm",
+                            "1",
                             "--upstream",
-                            "localhost:8080",
+                            &upstream,
                             "--port",
                             &port,
                             "--data-type",
@@ -931,25 +1943,28 @@ mod tests {
                             "--nrank",
                             &nrank,
                         ]);
-                        server(args);
+                        server(net, args);
                     })
                 };
+                let net = net.clone();
                 let children = (0..nrank).map(move |_| {
+                    let net = net.clone();
                     let dt = dt.to_string();
                     let port = port.to_string();
                     std::thread::spawn(move || {
                         std::thread::sleep(std::time::Duration::from_millis(100));
                         let address = format!("127.0.0.1:{}", port);
+                        let nrank = format!("{}", nrank);
                         let args = Args::parse_from([
                             "--client",
                             "--address",
                             &address,
                             "--data-type",
                             &dt,
-                            "--nreq",
-                            "1", // when using socket plugin, concurrent recv/send requests doesn't work
+                            "--nrank",
+                            &nrank,
                         ]);
-                        client(args);
+                        client(net, args);
                     })
                 });
                 vec![parent].into_iter().chain(children)
@@ -962,6 +1977,45 @@ mod tests {
         root.join().unwrap();
     }
 
+    #[test]
+    fn test_server_reject_mismatch() {
+        init_logger();
+        let net = Loopback::default();
+        let port = free_port();
+        let address = format!("127.0.0.1:{}", port);
+        let server = {
+            let net = net.clone();
+            std::thread::spawn(move || {
+                let port = port.to_string();
+                let args = Args::parse_from([
+                    "--verbose", // doesn't work without specifying a flag that doesn't take an argument
+                    "--ncomm",
+                    "1",
+                    "--port",
+                    &port,
+                    "--data-type",
+                    "f32",
+                ]);
+                server(net, args);
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // a misconfigured client must be rejected without taking a rank slot
+        let args = Args::parse_from(["--client", "--data-type", "f16"]);
+        let mut stream = TcpStream::connect(&address).unwrap();
+        let ret = bootstrap::connect(&mut stream, &bootstrap::Hello::from_args(&args));
+        assert!(
+            matches!(ret, Err(bootstrap::Error::Rejected(_))),
+            "{:?}",
+            ret
+        );
+
+        let args = Args::parse_from(["--client", "--address", &address, "--data-type", "f32"]);
+        client(net, args);
+        server.join().unwrap();
+    }
+
     #[test]
     fn test_server_with_upstream_f32() {
         do_test_upstream("f32");
@@ -969,16 +2023,749 @@ mod tests {
 
     #[test]
     fn test_server_f32() {
-        do_test("f32");
+        do_test("f32", 1024 * 1024, "sum");
     }
 
     #[test]
     fn test_server_f16() {
-        do_test("f16");
+        do_test("f16", 1024 * 1024, "sum");
     }
 
     #[test]
     fn test_server_bf16() {
-        do_test("bf16");
+        do_test("bf16", 1024 * 1024, "sum");
+    }
+
+    #[test]
+    fn test_server_deterministic() {
+        do_test_with("f32", 1024 * 1024, "sum", &["--deterministic"]);
+        do_test_with("bf16", 1000, "avg", &["--deterministic"]);
+    }
+
+    #[test]
+    fn test_server_pipelined() {
+        let nchunk = ["--nchunk", "4"];
+        let server_opts = [&nchunk[..], &["--pipeline-depth", "3"]].concat();
+        do_test_with_opts("f32", 1024 * 1024, "sum", &server_opts, &nchunk);
+        // the last chunks of a short message are empty
+        do_test_with_opts("bf16", 3, "avg", &server_opts, &nchunk);
+    }
+
+    #[test]
+    fn test_server_multi_channel() {
+        let nchannel = ["--nchannel", "2"];
+        do_test_with_opts("f32", 1024 * 1024, "sum", &nchannel, &nchannel);
+        // more channels than the progress threads, with the chunks of the jobs pipelined
+        let opts = [&nchannel[..], &["--nchunk", "2"]].concat();
+        let server_opts = [
+            &opts[..],
+            &[
+                "--recv-threads",
+                "3",
+                "--send-threads",
+                "3",
+                "--pipeline-depth",
+                "2",
+            ],
+        ]
+        .concat();
+        do_test_with_opts("bf16", 1000, "avg", &server_opts, &opts);
+        do_test_with_opts(
+            "i32",
+            1021,
+            "max",
+            &[&nchannel[..], &["--accumulate-on-arrival"]].concat(),
+            &nchannel,
+        );
+    }
+
+    #[test]
+    fn test_server_multi_rail() {
+        let net = Loopback::new(loopback::Options {
+            devices: 2,
+            ..Default::default()
+        });
+        // device 0 listed twice to get a larger share of the channels
+        let opts = ["--nchannel", "4", "--devices", "0,1,0"];
+        let server_net = net.counted();
+        let rank_net = |_| net.clone();
+        do_test_on_ranks(
+            server_net.clone(),
+            rank_net,
+            "f32",
+            1024 * 1024,
+            "sum",
+            &opts,
+            &opts,
+        );
+
+        // channel c of rank idx goes through rails[(idx * nchannel + c) % rails.len()], a listen
+        // and a connect each
+        let rails = [0, 1, 0];
+        let mut expect = [0; 2];
+        for peer in 0..4 * 4 {
+            expect[rails[peer % rails.len()]] += 1;
+        }
+        assert_eq!(expect, [11, 5]);
+        for (dev, n) in expect.into_iter().enumerate() {
+            assert_eq!(server_net.usage(dev), (n, n));
+        }
+    }
+
+    // the socket plugin over several interfaces, e.g. NCCL_SOCKET_IFNAME=lo,lo:1 with an alias of
+    // lo, which the tests can't set up on their own
+    #[test]
+    #[ignore]
+    fn test_server_multi_rail_plugin() {
+        initialize();
+        // all the devices of the plugin with device 0 listed twice
+        let devices = (0..nccl_net::devices().unwrap())
+            .chain([0])
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let opts = ["--nchannel", "4", "--devices", &devices];
+        do_test_on(NcclNet, "f32", 1024 * 1024, "sum", &opts, &opts);
+    }
+
+    #[test]
+    fn test_server_out_of_order() {
+        // several jobs of each rank in flight, which become ready in any order
+        let opts = ["--reduce-jobs", "4", "--pipeline-depth", "4"];
+        do_test_with("f32", 1024 * 1024, "sum", &opts);
+        do_test_with(
+            "i32",
+            1021,
+            "max",
+            &[&opts[..], &["--accumulate-on-arrival"]].concat(),
+        );
+    }
+
+    #[test]
+    fn test_server_delayed() {
+        // the messages of the ranks and channels arrive in a shuffled order, and a later request
+        // of a comm may be done before an earlier one
+        let net = Loopback::new(loopback::Options {
+            max_delay: std::time::Duration::from_millis(2),
+            reorder: true,
+            seed: 1,
+            ..Default::default()
+        });
+        let opts = ["--nchannel", "2", "--nreq", "2", "--nchunk", "2"];
+        let server_opts = [&opts[..], &["--reduce-jobs", "4", "--pipeline-depth", "2"]].concat();
+        do_test_on(net.clone(), "f32", 1024 * 1024, "sum", &server_opts, &opts);
+        let server_opts = [&server_opts[..], &["--accumulate-on-arrival"]].concat();
+        do_test_on(net, "bf16", 1000, "avg", &server_opts, &opts);
+    }
+
+    #[test]
+    fn test_server_shm() {
+        // half of the ranks on the same host, the other half across the network
+        let net = Loopback::default();
+        let rank_net = |i| Shm::new(net.clone(), i % 2 == 0);
+        let opts = ["--nchannel", "2", "--nreq", "2", "--nchunk", "2"];
+        let server_opts = [&opts[..], &["--pipeline-depth", "2"]].concat();
+        let shm = Shm::new(net.clone(), true);
+        do_test_on_ranks(
+            shm.clone(),
+            rank_net,
+            "f32",
+            1024 * 1024,
+            "sum",
+            &server_opts,
+            &opts,
+        );
+        do_test_on_ranks(shm, rank_net, "i32", 1021, "max", &server_opts, &opts);
+    }
+
+    #[test]
+    fn test_server_shm_handle_size() {
+        // with the full size handles of a net plugin, with shared memory and with --no-shm
+        let net = Loopback::new(loopback::Options {
+            handle_size: bootstrap::MAX_HANDLE_SIZE,
+            ..Default::default()
+        });
+        for enabled in [true, false] {
+            let shm = Shm::new(net.clone(), enabled);
+            do_test_on(shm, "f32", 1024, "sum", &[], &[]);
+        }
+    }
+
+    #[test]
+    fn test_server_tcp() {
+        // over sockets, with the larger messages split across several of them
+        let net = Tcp::new(tcp::Options {
+            ifname: "lo".to_string(),
+            nthreads: 2,
+            nsocks_per_thread: 2,
+        })
+        .unwrap();
+        let opts = ["--nchannel", "2", "--nreq", "2", "--nchunk", "2"];
+        let client_opts = [&opts[..], &["--try-count", "10"]].concat();
+        do_test_on(net.clone(), "f32", 1024 * 1024, "sum", &opts, &client_opts);
+        do_test_on(net, "f16", 1021, "max", &opts, &client_opts);
+    }
+
+    #[test]
+    fn test_server_uring() {
+        // over io_uring, with pipelined chunks so that the sends of a comm are batched
+        let net = Uring::new("lo").unwrap();
+        let opts = [
+            "--nchannel",
+            "2",
+            "--nreq",
+            "2",
+            "--nchunk",
+            "4",
+            "--pipeline-depth",
+            "4",
+        ];
+        let client_opts = [&opts[..], &["--try-count", "10"]].concat();
+        do_test_on(net.clone(), "f32", 1024 * 1024, "sum", &opts, &client_opts);
+        do_test_on(net, "i32", 1021, "max", &opts, &client_opts);
+    }
+
+    #[test]
+    fn test_server_accumulate_on_arrival() {
+        do_test_with("f32", 1024 * 1024, "sum", &["--accumulate-on-arrival"]);
+        do_test_with(
+            "bf16",
+            1000,
+            "avg",
+            &["--accumulate-on-arrival", "--staging-buffers", "1"],
+        );
+        do_test_with("i32", 1021, "prod", &["--accumulate-on-arrival"]);
+    }
+
+    #[test]
+    fn test_server_multi_comm() {
+        init_logger();
+        // the communicators share the send and recv threads of the server, down to a single one
+        for threads in ["0", "1"] {
+            do_test_multi_comm(threads);
+        }
+    }
+
+    fn do_test_multi_comm(threads: &str) {
+        let net = Loopback::default();
+        let port = free_port();
+        // two communicators of different sizes share the server
+        let server = {
+            let net = net.clone();
+            let threads = threads.to_string();
+            std::thread::spawn(move || {
+                let port = port.to_string();
+                let args = Args::parse_from([
+                    "--verbose", // doesn't work without specifying a flag that doesn't take an argument
+                    "--ncomm",
+                    "2",
+                    "--port",
+                    &port,
+                    "--nrank",
+                    "4",
+                    "--send-threads",
+                    &threads,
+                    "--recv-threads",
+                    &threads,
+                ]);
+                server(net, args);
+            })
+        };
+        let address = format!("127.0.0.1:{}", port);
+        // each with its own reduce operator
+        [(1, 2, "max"), (2, 3, "sum")]
+            .into_iter()
+            .flat_map(|(comm_id, nrank, op)| {
+                let net = net.clone();
+                let address = address.clone();
+                (0..nrank).map(move |i| {
+                    let net = net.clone();
+                    let address = address.clone();
+                    std::thread::spawn(move || {
+                        std::thread::sleep(std::time::Duration::from_millis(100));
+                        let comm_id = format!("{}", comm_id);
+                        let nrank = format!("{}", nrank);
+                        let rank = format!("{}", i);
+                        let args = Args::parse_from([
+                            "--client",
+                            "--address",
+                            &address,
+                            "--comm-id",
+                            &comm_id,
+                            "--nrank",
+                            &nrank,
+                            "--rank",
+                            &rank,
+                            "--reduce-op",
+                            op,
+                        ]);
+                        client(net, args);
+                    })
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .for_each(|h| h.join().unwrap());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_server_silent_peer() {
+        init_logger();
+        let net = Loopback::default();
+        let port = free_port();
+        let server = {
+            let net = net.clone();
+            std::thread::spawn(move || {
+                let port = port.to_string();
+                let args = Args::parse_from([
+                    "--verbose", // doesn't work without specifying a flag that doesn't take an argument
+                    "--ncomm",
+                    "1",
+                    "--port",
+                    &port,
+                    "--nrank",
+                    "2",
+                ]);
+                server(net, args);
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let address = format!("127.0.0.1:{}", port);
+
+        // a peer that never sends its Hello doesn't keep the ranks from connecting
+        let silent = TcpStream::connect(&address).unwrap();
+        (0..2)
+            .map(|i| {
+                let net = net.clone();
+                let address = address.clone();
+                std::thread::spawn(move || {
+                    let rank = format!("{}", i);
+                    let args = Args::parse_from([
+                        "--client",
+                        "--address",
+                        &address,
+                        "--nrank",
+                        "2",
+                        "--rank",
+                        &rank,
+                    ]);
+                    client(net, args);
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .for_each(|h| h.join().unwrap());
+        server.join().unwrap();
+        drop(silent);
+    }
+
+    #[test]
+    fn test_server_recover() {
+        init_logger();
+        let net = Loopback::default();
+        let port = free_port();
+        let address = format!("127.0.0.1:{}", port);
+        let server = {
+            let net = net.clone();
+            std::thread::spawn(move || {
+                let port = port.to_string();
+                let args = Args::parse_from([
+                    "--verbose", // doesn't work without specifying a flag that doesn't take an argument
+                    "--ncomm",
+                    "2",
+                    "--port",
+                    &port,
+                    "--nrank",
+                    "2",
+                ]);
+                server(net, args);
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // a rank that goes away in the middle of the setup tears the communicator down
+        let args = Args::parse_from(["--client", "--nrank", "2", "--rank", "0"]);
+        let mut stream = TcpStream::connect(&address).unwrap();
+        bootstrap::connect(&mut stream, &bootstrap::Hello::from_args(&args)).unwrap();
+        drop(stream);
+        std::thread::sleep(std::time::Duration::from_secs(2));
+
+        // and a fresh set of ranks can take its place
+        (0..2)
+            .map(|i| {
+                let net = net.clone();
+                let address = address.clone();
+                std::thread::spawn(move || {
+                    let rank = format!("{}", i);
+                    let args = Args::parse_from([
+                        "--client",
+                        "--address",
+                        &address,
+                        "--nrank",
+                        "2",
+                        "--rank",
+                        &rank,
+                    ]);
+                    client(net, args);
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .for_each(|h| h.join().unwrap());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_server_abort() {
+        init_logger();
+        let net = Loopback::default();
+        let port = free_port();
+        let address = format!("127.0.0.1:{}", port);
+        let server = {
+            let net = net.clone();
+            std::thread::spawn(move || {
+                let port = port.to_string();
+                let args = Args::parse_from([
+                    "--verbose", // doesn't work without specifying a flag that doesn't take an argument
+                    "--ncomm",
+                    "1",
+                    "--port",
+                    &port,
+                    "--nrank",
+                    "2",
+                ]);
+                server(net, args);
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let args = Args::parse_from(["--client", "--nrank", "2", "--rank", "0"]);
+        let mut stream = TcpStream::connect(&address).unwrap();
+        bootstrap::connect(&mut stream, &bootstrap::Hello::from_args(&args)).unwrap();
+
+        let c = std::thread::spawn(move || {
+            let args = Args::parse_from([
+                "--client",
+                "--address",
+                &address,
+                "--nrank",
+                "2",
+                "--rank",
+                "1",
+            ]);
+            client(net, args);
+        });
+        std::thread::sleep(std::time::Duration::from_millis(500));
+
+        // the surviving rank gives up instead of waiting for rank 0 forever
+        drop(stream);
+        let err = c.join().unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(msg.contains("allreduce aborted"), "{}", msg);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_server_lagging_rank() {
+        // the other ranks have their results and disconnect while the ones of the lagging rank
+        // are still on their way, which it gets to receive. the comms of the others hang up on
+        // the receives the server has posted ahead for them.
+        let net = Loopback::default();
+        let latency = std::time::Duration::from_millis(1500);
+        do_test_on_ranks(
+            net.clone(),
+            |i| {
+                if i == 0 {
+                    net.lagging(latency)
+                } else {
+                    net.hanging_up()
+                }
+            },
+            "f32",
+            1024,
+            "sum",
+            &[],
+            &["--try-count", "2"],
+        );
+    }
+
+    #[test]
+    fn test_server_lagging_rank_timeout() {
+        init_logger();
+        let net = Loopback::default();
+        let port = free_port();
+        let address = format!("127.0.0.1:{}", port);
+        let server = {
+            let net = net.clone();
+            std::thread::spawn(move || {
+                let port = port.to_string();
+                let args = Args::parse_from([
+                    "--verbose", // doesn't work without specifying a flag that doesn't take an argument
+                    "--ncomm",
+                    "1",
+                    "--port",
+                    &port,
+                    "--nrank",
+                    "2",
+                    "--send-timeout",
+                    "1",
+                ]);
+                server(net, args);
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // the lagging rank is aborted once --send-timeout passes after the other rank is gone
+        let clients = (0..2)
+            .map(|i| {
+                let net = if i == 0 {
+                    net.lagging(std::time::Duration::from_secs(3))
+                } else {
+                    net.clone()
+                };
+                let address = address.clone();
+                std::thread::spawn(move || {
+                    let rank = format!("{}", i);
+                    let args = Args::parse_from([
+                        "--client",
+                        "--address",
+                        &address,
+                        "--nrank",
+                        "2",
+                        "--rank",
+                        &rank,
+                        "--try-count",
+                        "1",
+                    ]);
+                    client(net, args);
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut clients = clients.into_iter();
+        let lagging = clients.next().unwrap();
+        clients.next().unwrap().join().unwrap();
+        let err = lagging.join().unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(msg.contains("allreduce aborted"), "{}", msg);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_server_size_mismatch() {
+        init_logger();
+        let net = Loopback::default();
+        let port = free_port();
+        let address = format!("127.0.0.1:{}", port);
+        let server = {
+            let net = net.clone();
+            std::thread::spawn(move || {
+                let port = port.to_string();
+                let args = Args::parse_from([
+                    "--verbose", // doesn't work without specifying a flag that doesn't take an argument
+                    "--ncomm",
+                    "1",
+                    "--port",
+                    &port,
+                    "--nrank",
+                    "2",
+                    "--count",
+                    "1024",
+                ]);
+                server(net, args);
+            })
+        };
+
+        // the two ranks send different sizes for the same step
+        let clients = [(0, "1024"), (1, "512")]
+            .into_iter()
+            .map(|(rank, count)| {
+                let net = net.clone();
+                let address = address.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                    let rank = format!("{}", rank);
+                    let args = Args::parse_from([
+                        "--client",
+                        "--address",
+                        &address,
+                        "--nrank",
+                        "2",
+                        "--rank",
+                        &rank,
+                        "--count",
+                        count,
+                    ]);
+                    client(net, args);
+                })
+            })
+            .collect::<Vec<_>>();
+        for c in clients {
+            let err = c.join().unwrap_err();
+            let msg = err.downcast_ref::<String>().unwrap();
+            assert!(msg.contains("size mismatch"), "{}", msg);
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_server_transport_error() {
+        init_logger();
+        let net = Loopback::default();
+        let port = free_port();
+        let address = format!("127.0.0.1:{}", port);
+        // the requests of the server fail halfway through the allreduces
+        let server = {
+            let net = net.failing(64);
+            std::thread::spawn(move || {
+                let port = port.to_string();
+                let args = Args::parse_from([
+                    "--verbose", // doesn't work without specifying a flag that doesn't take an argument
+                    "--ncomm",
+                    "1",
+                    "--port",
+                    &port,
+                    "--nrank",
+                    "2",
+                ]);
+                server(net, args);
+            })
+        };
+
+        let clients = (0..2)
+            .map(|rank| {
+                let net = net.clone();
+                let address = address.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                    let rank = format!("{}", rank);
+                    let args = Args::parse_from([
+                        "--client",
+                        "--address",
+                        &address,
+                        "--nrank",
+                        "2",
+                        "--rank",
+                        &rank,
+                    ]);
+                    client(net, args);
+                })
+            })
+            .collect::<Vec<_>>();
+        // the ranks are aborted with the error instead of waiting for the server forever
+        for c in clients {
+            let err = c.join().unwrap_err();
+            let msg = err.downcast_ref::<String>().unwrap();
+            assert!(msg.contains("injected error"), "{}", msg);
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_server_connect_timeout() {
+        init_logger();
+        let net = Loopback::default();
+        let port = free_port();
+        let address = format!("127.0.0.1:{}", port);
+        let server = {
+            let net = net.clone();
+            std::thread::spawn(move || {
+                let port = port.to_string();
+                let args = Args::parse_from([
+                    "--verbose", // doesn't work without specifying a flag that doesn't take an argument
+                    "--ncomm",
+                    "1",
+                    "--port",
+                    &port,
+                    "--nrank",
+                    "2",
+                    "--connect-timeout",
+                    "1",
+                    "--timeout-action",
+                    "abort",
+                ]);
+                server(net, args);
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // rank 0 never shows up
+        let c = std::thread::spawn(move || {
+            let args = Args::parse_from([
+                "--client",
+                "--address",
+                &address,
+                "--nrank",
+                "2",
+                "--rank",
+                "1",
+            ]);
+            client(net, args);
+        });
+        let err = c.join().unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(msg.contains("missing ranks: [0]"), "{}", msg);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_server_f64() {
+        do_test("f64", 1024 * 1024, "sum");
+    }
+
+    #[test]
+    fn test_server_int() {
+        do_test("i32", 1024 * 1024, "sum");
+        do_test("u8", 1024 * 1024 + 3, "max");
+        do_test("i64", 1024 * 1024, "prod");
+    }
+
+    #[test]
+    fn test_server_f8() {
+        do_test("f8e4m3", 1024 * 1024, "sum");
+        do_test("f8e5m2", 1024 * 1024, "avg");
+    }
+
+    #[test]
+    fn test_server_smaller_count() {
+        // odd sizes leave a tail in the last reduce partition
+        do_test("f32", 1000 * 1000 + 1, "sum");
+        do_test("f16", 1000, "sum");
+    }
+
+    #[test]
+    fn test_server_reduce_op() {
+        do_test("f32", 1024 * 1024, "max");
+        do_test("f16", 1024 * 1024, "avg");
+        do_test("bf16", 1024 * 1024, "prod");
+    }
+
+    #[test]
+    fn test_recv_count() {
+        let lens = (0..3).map(|_| AtomicUsize::new(12)).collect::<Vec<_>>();
+        assert_eq!(recv_count::<f32>(&lens), Ok(3));
+        assert!(recv_count::<f32>(&lens[..1]).is_ok());
+
+        lens[2].store(8, std::sync::atomic::Ordering::Relaxed);
+        assert!(recv_count::<f32>(&lens).is_err());
+
+        let lens = (0..2).map(|_| AtomicUsize::new(6)).collect::<Vec<_>>();
+        assert!(recv_count::<f32>(&lens).is_err());
+        assert_eq!(recv_count::<f16>(&lens), Ok(3));
+    }
+
+    #[test]
+    fn test_assigned_peers() {
+        for (nrank, nthreads) in [(8, 8), (6, 4), (130, 8)] {
+            let total = (0..nthreads)
+                .map(|i| assigned_peers(i, nrank, nthreads))
+                .sum::<usize>();
+            assert_eq!(total, nrank);
+        }
+        assert_eq!(assigned_peers(1, 6, 4), 2);
+        assert_eq!(assigned_peers(3, 6, 4), 1);
+        // the channels of a rank are spread over the threads
+        assert_eq!(peer_thread(1, 0, 2, 3), 2);
+        assert_eq!(peer_thread(1, 1, 2, 3), 0);
     }
 }
@@ -0,0 +1,97 @@
+/*
+ * Copyright (c) 2024, the Optcast Authors. All rights reserved.
+ *
+ * See LICENSE for license information
+ */
+
+// the network the server, client and ring modes run on. it follows the net API of the NCCL
+// plugins: a comm pair per channel set up by exchanging a handle over the bootstrap stream, memory
+// registered with a comm, and non-blocking tagged sends and receives polled with test.
+
+#[cfg(test)]
+pub(crate) mod loopback;
+pub(crate) mod shm;
+pub(crate) mod tcp;
+pub(crate) mod uring;
+
+// properties of a network device
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Properties {
+    pub name: String,
+    pub pci_path: String,
+    pub guid: u64,
+    pub ptr_support: i32,    // NCCL_PTR_HOST | NCCL_PTR_CUDA | NCCL_PTR_DMABUF
+    pub reg_is_global: bool, // memory registrations aren't tied to a comm, since v8
+    pub speed: i32,          // port speed in Mbps
+    pub port: i32,
+    pub latency: f32,
+    pub max_comms: i32,
+    pub max_recvs: i32,
+}
+
+// what a peer connects to. net is the handle of the network, at most MAX_HANDLE_SIZE bytes like
+// the handles of the NCCL net plugins, and local what only the peers on the same host can use,
+// empty for the transports without it. the two are sent apart over the bootstrap stream, so that
+// a transport can wrap a net plugin without growing its handles.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Handle {
+    pub net: Vec<u8>,
+    pub local: Vec<u8>,
+}
+
+impl From<Vec<u8>> for Handle {
+    fn from(net: Vec<u8>) -> Self {
+        Handle { net, local: vec![] }
+    }
+}
+
+// a transport is cloned into every thread that drives its comms
+pub(crate) trait Transport: Clone + Send + Sync + 'static {
+    type Error: std::error::Error + Send + 'static;
+    // a listen, send or recv comm
+    type Comm: Send;
+    // a registration of a buffer with a comm, released when dropped
+    type MemoryHandle<'a>: Send
+    where
+        Self: 'a;
+    type Request: Send;
+
+    fn devices(&self) -> Result<usize, Self::Error>;
+
+    fn get_properties(&self, dev: usize) -> Result<Properties, Self::Error>;
+
+    // returns a listen comm and the handle the peer connects to
+    fn listen(&self, dev: usize) -> Result<(Self::Comm, Handle), Self::Error>;
+
+    // connect and accept don't block, they return None until the connection is established
+    fn connect(&self, dev: usize, handle: &Handle) -> Result<Option<Self::Comm>, Self::Error>;
+
+    fn accept(&self, comm: &Self::Comm) -> Result<Option<Self::Comm>, Self::Error>;
+
+    fn reg_mr<'a, T>(
+        &self,
+        comm: &'a Self::Comm,
+        data: &[T],
+    ) -> Result<Self::MemoryHandle<'a>, Self::Error>;
+
+    // isend and irecv return None when the request can't be posted yet, and have to be retried.
+    // the buffer must not be touched until the request is done.
+    fn isend<T>(
+        &self,
+        comm: &Self::Comm,
+        mhandle: &Self::MemoryHandle<'_>,
+        data: &[T],
+        tag: i32,
+    ) -> Result<Option<Self::Request>, Self::Error>;
+
+    fn irecv<T>(
+        &self,
+        comm: &Self::Comm,
+        mhandle: &Self::MemoryHandle<'_>,
+        data: &mut [T],
+        tag: i32,
+    ) -> Result<Option<Self::Request>, Self::Error>;
+
+    // returns whether the request is done and the bytes it transferred
+    fn test(&self, request: &Self::Request) -> Result<(bool, usize), Self::Error>;
+}
@@ -0,0 +1,621 @@
+/*
+ * Copyright (c) 2024, the Optcast Authors. All rights reserved.
+ *
+ * See LICENSE for license information
+ */
+
+// an in-process transport for the tests, which needs neither the plugin nor the network. the comms
+// of an instance live in memory, so the server, the clients and the ring ranks have to run as
+// threads sharing a clone of it. like the socket plugin, the sends and receives of a comm are
+// matched in the order they are posted and the tags are ignored.
+//
+// a message becomes ready after a delay drawn from the seed, the comm and its position on the
+// comm, up to max_delay. without reorder a message is never ready before the ones sent before it
+// on the same comm, with reorder it may overtake them. the comms accepted by a lagging clone add a
+// fixed latency to that.
+//
+// a handle is the id of its listen comm, padded to handle_size bytes to stand in for the fixed
+// size handles of the net plugins.
+
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use nccl_net_sys as ffi;
+
+use super::{Handle, Properties, Transport};
+
+// listen ids are unique across the instances, so that a handle is only valid for the instance
+// that made it
+static NEXT_LISTEN_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Error {
+    InvalidDevice(usize),
+    InvalidHandle,
+    InvalidUsage,
+    Truncated(usize, usize),
+    Injected,
+    Closed,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidDevice(dev) => write!(f, "invalid device: {}", dev),
+            Error::InvalidHandle => write!(f, "invalid handle"),
+            Error::InvalidUsage => write!(f, "invalid usage"),
+            Error::Injected => write!(f, "injected error"),
+            Error::Closed => write!(f, "connection closed"),
+            Error::Truncated(size, len) => write!(
+                f,
+                "message of {} bytes received into a buffer of {} bytes",
+                size, len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Options {
+    pub devices: usize,
+    pub max_delay: Duration,
+    pub reorder: bool,
+    pub seed: u64,
+    pub handle_size: usize, // at least 8
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            devices: 1,
+            max_delay: Duration::ZERO,
+            reorder: false,
+            seed: 0,
+            handle_size: 8,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    opts: Options,
+    // connections waiting to be accepted, by listen id
+    pending: Mutex<HashMap<u64, Pending>>,
+    next_channel_id: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Loopback {
+    inner: Arc<Inner>,
+    // the listens and connects per device made through this instance and its clones
+    usage: Arc<Mutex<HashMap<usize, (usize, usize)>>>,
+    // the requests this instance and its clones test successfully before they fail
+    tests_left: Option<Arc<AtomicU64>>,
+    // added to the delay of the messages sent to the comms accepted by this instance
+    latency: Duration,
+    // whether closing the send comms of this instance fails the receives left on the other end
+    hang_up: bool,
+}
+
+impl Loopback {
+    pub(crate) fn new(opts: Options) -> Self {
+        Loopback {
+            inner: Arc::new(Inner {
+                opts,
+                ..Default::default()
+            }),
+            usage: Default::default(),
+            tests_left: None,
+            latency: Duration::ZERO,
+            hang_up: false,
+        }
+    }
+
+    // a clone whose tests fail with Error::Injected after n of them, sharing the comms of this
+    // instance, to inject an error in one end of the connections
+    pub(crate) fn failing(&self, n: u64) -> Self {
+        Loopback {
+            tests_left: Some(Arc::new(AtomicU64::new(n))),
+            ..self.clone()
+        }
+    }
+
+    // a clone on whose listens every message arrives latency later, sharing the comms of this
+    // instance, to stand in for a peer that is slow to receive
+    pub(crate) fn lagging(&self, latency: Duration) -> Self {
+        Loopback {
+            latency,
+            ..self.clone()
+        }
+    }
+
+    // a clone whose send comms fail the receives still waiting on the other end once they are
+    // closed, like the connections of the socket plugin, sharing the comms of this instance
+    pub(crate) fn hanging_up(&self) -> Self {
+        Loopback {
+            hang_up: true,
+            ..self.clone()
+        }
+    }
+
+    // a clone that counts its listens and connects apart from this instance, so that a test can
+    // tell the devices the server uses from the ones of the clients
+    pub(crate) fn counted(&self) -> Self {
+        Loopback {
+            usage: Default::default(),
+            ..self.clone()
+        }
+    }
+
+    // the listens and connects made on a device
+    pub(crate) fn usage(&self, dev: usize) -> (usize, usize) {
+        self.usage
+            .lock()
+            .unwrap()
+            .get(&dev)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn check_device(&self, dev: usize) -> Result<(), Error> {
+        if dev < self.inner.opts.devices {
+            Ok(())
+        } else {
+            Err(Error::InvalidDevice(dev))
+        }
+    }
+
+    fn delay(&self, channel: u64, seq: u64) -> Duration {
+        let max = self.inner.opts.max_delay.as_nanos() as u64;
+        if max == 0 {
+            return Duration::ZERO;
+        }
+        let v = splitmix64(self.inner.opts.seed ^ splitmix64(channel) ^ seq);
+        Duration::from_nanos(v % (max + 1))
+    }
+}
+
+fn splitmix64(v: u64) -> u64 {
+    let v = v.wrapping_add(0x9e3779b97f4a7c15);
+    let v = (v ^ (v >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let v = (v ^ (v >> 27)).wrapping_mul(0x94d049bb133111eb);
+    v ^ (v >> 31)
+}
+
+#[derive(Debug)]
+struct Message {
+    data: Vec<u8>,
+    ready: Instant,
+}
+
+// the two ends of a comm pair
+#[derive(Debug, Default)]
+struct Channel {
+    id: u64,
+    latency: Duration,
+    hang_up: bool,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // messages not received yet, by their position on the comm
+    messages: HashMap<u64, Message>,
+    sent: u64,
+    posted: u64,
+    last_ready: Option<Instant>,
+    // the send comm is closed
+    closed: bool,
+}
+
+// the connections of a listen comm, on which the messages arrive latency later
+#[derive(Debug, Default)]
+struct Pending {
+    latency: Duration,
+    channels: VecDeque<Arc<Channel>>,
+}
+
+#[derive(Debug)]
+struct Listener {
+    id: u64,
+    inner: Arc<Inner>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.inner.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[derive(Debug)]
+enum CommType {
+    Listen(Listener),
+    Send(Arc<Channel>),
+    Recv(Arc<Channel>),
+}
+
+#[derive(Debug)]
+pub(crate) struct Comm(CommType);
+
+impl Drop for Comm {
+    fn drop(&mut self) {
+        if let CommType::Send(channel) = &self.0 {
+            channel.state.lock().unwrap().closed = true;
+        }
+    }
+}
+
+// the messages are copied, so there is nothing to register
+pub(crate) struct MemoryHandle<'a>(PhantomData<&'a Comm>);
+
+enum Op {
+    Send { ready: Instant, size: usize },
+    // the buffer is only written by test, until which it must not be touched
+    Recv { seq: u64, buf: *mut u8, len: usize },
+}
+
+pub(crate) struct Request {
+    channel: Arc<Channel>,
+    op: Op,
+    received: OnceLock<usize>,
+}
+
+unsafe impl Send for Request {}
+
+impl Transport for Loopback {
+    type Error = Error;
+    type Comm = Comm;
+    type MemoryHandle<'a> = MemoryHandle<'a>;
+    type Request = Request;
+
+    fn devices(&self) -> Result<usize, Error> {
+        Ok(self.inner.opts.devices)
+    }
+
+    fn get_properties(&self, dev: usize) -> Result<Properties, Error> {
+        self.check_device(dev)?;
+        Ok(Properties {
+            name: format!("loopback{}", dev),
+            pci_path: String::new(),
+            guid: dev as u64,
+            ptr_support: ffi::NCCL_PTR_HOST as i32,
+            reg_is_global: true,
+            speed: 100000,
+            port: 0,
+            latency: 0.0,
+            max_comms: 65536,
+            max_recvs: 1,
+        })
+    }
+
+    fn listen(&self, dev: usize) -> Result<(Comm, Handle), Error> {
+        self.check_device(dev)?;
+        let id = NEXT_LISTEN_ID.fetch_add(1, Ordering::Relaxed);
+        self.inner.pending.lock().unwrap().insert(
+            id,
+            Pending {
+                latency: self.latency,
+                ..Default::default()
+            },
+        );
+        let listener = Listener {
+            id,
+            inner: self.inner.clone(),
+        };
+        self.usage.lock().unwrap().entry(dev).or_default().0 += 1;
+        let mut handle = id.to_le_bytes().to_vec();
+        handle.resize(self.inner.opts.handle_size.max(8), 0);
+        Ok((Comm(CommType::Listen(listener)), Handle::from(handle)))
+    }
+
+    fn connect(&self, dev: usize, handle: &Handle) -> Result<Option<Comm>, Error> {
+        self.check_device(dev)?;
+        if handle.net.len() != self.inner.opts.handle_size.max(8) {
+            return Err(Error::InvalidHandle);
+        }
+        let id = u64::from_le_bytes(handle.net[..8].try_into().unwrap());
+        let mut pending = self.inner.pending.lock().unwrap();
+        let pending = pending.get_mut(&id).ok_or(Error::InvalidHandle)?;
+        let channel = Arc::new(Channel {
+            id: self.inner.next_channel_id.fetch_add(1, Ordering::Relaxed),
+            latency: pending.latency,
+            hang_up: self.hang_up,
+            ..Default::default()
+        });
+        pending.channels.push_back(channel.clone());
+        self.usage.lock().unwrap().entry(dev).or_default().1 += 1;
+        Ok(Some(Comm(CommType::Send(channel))))
+    }
+
+    fn accept(&self, comm: &Comm) -> Result<Option<Comm>, Error> {
+        let CommType::Listen(listener) = &comm.0 else {
+            return Err(Error::InvalidUsage);
+        };
+        let mut pending = self.inner.pending.lock().unwrap();
+        let pending = pending.get_mut(&listener.id).ok_or(Error::InvalidHandle)?;
+        Ok(pending
+            .channels
+            .pop_front()
+            .map(|v| Comm(CommType::Recv(v))))
+    }
+
+    fn reg_mr<'a, T>(&self, comm: &'a Comm, _data: &[T]) -> Result<MemoryHandle<'a>, Error> {
+        match comm.0 {
+            CommType::Listen(_) => Err(Error::InvalidUsage),
+            _ => Ok(MemoryHandle(PhantomData)),
+        }
+    }
+
+    fn isend<T>(
+        &self,
+        comm: &Comm,
+        _mhandle: &MemoryHandle,
+        data: &[T],
+        _tag: i32,
+    ) -> Result<Option<Request>, Error> {
+        let CommType::Send(channel) = &comm.0 else {
+            return Err(Error::InvalidUsage);
+        };
+        let size = std::mem::size_of_val(data);
+        let data = unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, size) };
+
+        let mut state = channel.state.lock().unwrap();
+        let seq = state.sent;
+        state.sent += 1;
+        let mut ready = Instant::now() + self.delay(channel.id, seq) + channel.latency;
+        if !self.inner.opts.reorder {
+            ready = state.last_ready.map_or(ready, |v| v.max(ready));
+            state.last_ready = Some(ready);
+        }
+        let message = Message {
+            data: data.to_vec(),
+            ready,
+        };
+        state.messages.insert(seq, message);
+
+        Ok(Some(Request {
+            channel: channel.clone(),
+            op: Op::Send { ready, size },
+            received: OnceLock::new(),
+        }))
+    }
+
+    fn irecv<T>(
+        &self,
+        comm: &Comm,
+        _mhandle: &MemoryHandle,
+        data: &mut [T],
+        _tag: i32,
+    ) -> Result<Option<Request>, Error> {
+        let CommType::Recv(channel) = &comm.0 else {
+            return Err(Error::InvalidUsage);
+        };
+        let mut state = channel.state.lock().unwrap();
+        let seq = state.posted;
+        state.posted += 1;
+        Ok(Some(Request {
+            channel: channel.clone(),
+            op: Op::Recv {
+                seq,
+                buf: data.as_mut_ptr() as *mut u8,
+                len: std::mem::size_of_val(data),
+            },
+            received: OnceLock::new(),
+        }))
+    }
+
+    fn test(&self, request: &Request) -> Result<(bool, usize), Error> {
+        if let Some(left) = &self.tests_left {
+            if left
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| v.checked_sub(1))
+                .is_err()
+            {
+                return Err(Error::Injected);
+            }
+        }
+        let ret = match request.op {
+            Op::Send { ready, size } => (Instant::now() >= ready, size),
+            Op::Recv { seq, buf, len } => match request.received.get() {
+                Some(size) => (true, *size),
+                None => {
+                    let mut state = request.channel.state.lock().unwrap();
+                    match state.messages.get(&seq) {
+                        // the messages sent before the close still arrive
+                        None if request.channel.hang_up && state.closed => {
+                            return Err(Error::Closed)
+                        }
+                        Some(message) if Instant::now() >= message.ready => {
+                            let message = state.messages.remove(&seq).unwrap();
+                            let size = message.data.len();
+                            if size > len {
+                                return Err(Error::Truncated(size, len));
+                            }
+                            unsafe {
+                                std::ptr::copy_nonoverlapping(message.data.as_ptr(), buf, size)
+                            };
+                            request.received.set(size).unwrap();
+                            (true, size)
+                        }
+                        _ => (false, 0),
+                    }
+                }
+            },
+        };
+        // the peer is a thread of this process, which may be waiting for the cpu the caller
+        // spins on
+        if !ret.0 {
+            std::thread::yield_now();
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(net: &Loopback) -> (Comm, Comm) {
+        let (lcomm, handle) = net.listen(0).unwrap();
+        let scomm = net.connect(0, &handle).unwrap().unwrap();
+        let rcomm = net.accept(&lcomm).unwrap().unwrap();
+        assert!(net.accept(&lcomm).unwrap().is_none());
+        (scomm, rcomm)
+    }
+
+    fn wait(net: &Loopback, request: &Request) -> usize {
+        loop {
+            let (done, size) = net.test(request).unwrap();
+            if done {
+                return size;
+            }
+        }
+    }
+
+    #[test]
+    fn test_loopback_send_recv() {
+        let net = Loopback::default();
+        let (scomm, rcomm) = connect(&net);
+        let (smh, rmh) = (
+            net.reg_mr(&scomm, &[0u8]).unwrap(),
+            net.reg_mr(&rcomm, &[0u8]).unwrap(),
+        );
+
+        // a receive posted before the send
+        let mut recv = vec![0f32; 4];
+        let rreq = net.irecv(&rcomm, &rmh, &mut recv, 0).unwrap().unwrap();
+        assert_eq!(net.test(&rreq), Ok((false, 0)));
+        let sreq = net
+            .isend(&scomm, &smh, &[1f32, 2.0, 3.0], 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(wait(&net, &sreq), 12);
+        assert_eq!(wait(&net, &rreq), 12);
+        assert_eq!(net.test(&rreq), Ok((true, 12)));
+        assert_eq!(recv, [1.0, 2.0, 3.0, 0.0]);
+
+        // and one posted after it, into a buffer too small for the message
+        let sreq = net.isend(&scomm, &smh, &[1u8, 2], 0).unwrap().unwrap();
+        let mut recv = [0u8; 1];
+        let rreq = net.irecv(&rcomm, &rmh, &mut recv, 0).unwrap().unwrap();
+        assert_eq!(wait(&net, &sreq), 2);
+        assert_eq!(net.test(&rreq), Err(Error::Truncated(2, 1)));
+
+        assert!(net.isend(&rcomm, &rmh, &[0u8], 0).is_err());
+        assert!(net.irecv(&scomm, &smh, &mut [0u8], 0).is_err());
+    }
+
+    #[test]
+    fn test_loopback_hang_up() {
+        for net in [Loopback::default(), Loopback::default().hanging_up()] {
+            let (scomm, rcomm) = connect(&net);
+            let rmh = net.reg_mr(&rcomm, &[0u8]).unwrap();
+            let sreq = net.isend(&scomm, &rmh, &[1u8], 0).unwrap().unwrap();
+            assert_eq!(wait(&net, &sreq), 1);
+            let (mut first, mut second) = ([0u8; 1], [0u8; 1]);
+            let first = net.irecv(&rcomm, &rmh, &mut first, 0).unwrap().unwrap();
+            let second = net.irecv(&rcomm, &rmh, &mut second, 0).unwrap().unwrap();
+            drop(sreq);
+            drop(scomm);
+
+            // the message sent before the close arrives, the receive after it waits for good
+            // unless the send comm hangs up
+            assert_eq!(wait(&net, &first), 1);
+            let closed = if net.hang_up {
+                Err(Error::Closed)
+            } else {
+                Ok((false, 0))
+            };
+            assert_eq!(net.test(&second), closed);
+        }
+    }
+
+    #[test]
+    fn test_loopback_handle() {
+        let net = Loopback::new(Options {
+            devices: 2,
+            ..Default::default()
+        });
+        let (lcomm, handle) = net.listen(1).unwrap();
+        assert_eq!(net.listen(2).err(), Some(Error::InvalidDevice(2)));
+        assert!(net.accept(&lcomm).unwrap().is_none());
+
+        // a handle of another instance or of a closed listen comm
+        assert_eq!(
+            Loopback::default().connect(0, &handle).err(),
+            Some(Error::InvalidHandle)
+        );
+        assert_eq!(
+            net.connect(0, &Handle::from(handle.net[1..].to_vec()))
+                .err(),
+            Some(Error::InvalidHandle)
+        );
+        drop(lcomm);
+        assert_eq!(net.connect(0, &handle).err(), Some(Error::InvalidHandle));
+
+        // padded like the handles of the net plugins
+        let net = Loopback::new(Options {
+            handle_size: 128,
+            ..Default::default()
+        });
+        let (_lcomm, handle) = net.listen(0).unwrap();
+        assert_eq!(handle.net.len(), 128);
+        assert!(net.connect(0, &handle).unwrap().is_some());
+    }
+
+    fn completion_order(net: &Loopback, n: usize) -> Vec<usize> {
+        let (scomm, rcomm) = connect(net);
+        let (smh, rmh) = (
+            net.reg_mr(&scomm, &[0u8]).unwrap(),
+            net.reg_mr(&rcomm, &[0u8]).unwrap(),
+        );
+        let mut bufs = vec![[0usize; 1]; n];
+        let sreqs = (0..n)
+            .map(|i| net.isend(&scomm, &smh, &[i], 0).unwrap().unwrap())
+            .collect::<Vec<_>>();
+        let mut rreqs = bufs
+            .iter_mut()
+            .map(|buf| Some(net.irecv(&rcomm, &rmh, buf, 0).unwrap().unwrap()))
+            .collect::<Vec<_>>();
+        // polled from the last one, so that a pass sees every message ready before a later one
+        let mut order = Vec::new();
+        while order.len() < n {
+            let mut done = Vec::new();
+            for (i, req) in rreqs.iter_mut().enumerate().rev() {
+                if req.is_some() && net.test(req.as_ref().unwrap()).unwrap().0 {
+                    *req = None;
+                    done.push(i);
+                }
+            }
+            order.extend(done.into_iter().rev());
+        }
+        sreqs.iter().for_each(|req| assert_eq!(wait(net, req), 8));
+        // the messages are matched in order whenever they become ready
+        assert_eq!(
+            bufs.iter().map(|v| v[0]).collect::<Vec<_>>(),
+            (0..n).collect::<Vec<_>>()
+        );
+        order
+    }
+
+    #[test]
+    fn test_loopback_reorder() {
+        let opts = Options {
+            max_delay: Duration::from_millis(200),
+            seed: 7,
+            ..Default::default()
+        };
+        let net = Loopback::new(opts.clone());
+        assert_eq!(completion_order(&net, 16), (0..16).collect::<Vec<_>>());
+
+        let net = Loopback::new(Options {
+            reorder: true,
+            ..opts
+        });
+        assert_ne!(completion_order(&net, 16), (0..16).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,914 @@
+/*
+ * Copyright (c) 2024, the Optcast Authors. All rights reserved.
+ *
+ * See LICENSE for license information
+ */
+
+// a transport that takes the ranks on the same host off the network. it wraps the transport of
+// --net: a listen comm also creates a shared memory segment, whose name and nonce go in the local
+// part of the handle, next to the untouched handle of the network. a peer that can open the segment
+// and finds the nonce in it is on the same host, and connects through a ring buffer in shared
+// memory instead of the network. the others use the network, so the ranks of a communicator may be
+// connected either way.
+//
+// a ring carries the messages of a comm with a header of their tag and size. like the tcp
+// transport, a message is matched with the earliest receive posted with its tag. the data is copied
+// in and out of the ring by test, so a request only progresses while it or another request of its
+// comm is tested.
+
+use std::cell::UnsafeCell;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::hash::BuildHasher;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+
+use super::{Handle, Properties, Transport};
+
+const HANDLE_MAGIC: u32 = 0x4d48_534f; // "OSHM"
+const NAME_SIZE: usize = 64;
+// connections waiting to be accepted per listen comm
+const SLOTS: usize = 64;
+const RING_SIZE: usize = 4 * 1024 * 1024;
+const HEADER_SIZE: usize = 12;
+// requests in flight per comm, isend and irecv return None beyond
+const MAX_REQUESTS: usize = 32;
+
+const SLOT_FREE: u32 = 0;
+const SLOT_WRITING: u32 = 1;
+const SLOT_READY: u32 = 2;
+
+static NEXT_SEGMENT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Error<E> {
+    Net(E),
+    InvalidHandle,
+    InvalidUsage,
+    Truncated(usize, usize),
+    Io(String),
+    Closed,
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Net(e) => write!(f, "{}", e),
+            Error::InvalidHandle => write!(f, "invalid handle"),
+            Error::InvalidUsage => write!(f, "invalid usage"),
+            Error::Truncated(size, len) => write!(
+                f,
+                "message of {} bytes received into a buffer of {} bytes",
+                size, len
+            ),
+            Error::Io(e) => write!(f, "shared memory: {}", e),
+            Error::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl<E: std::error::Error> std::error::Error for Error<E> {}
+
+impl<E> From<std::io::Error> for Error<E> {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e.to_string())
+    }
+}
+
+// a mapping of a shared memory object. the one that created it unlinks it when it's dropped, if
+// nobody did before.
+#[derive(Debug)]
+struct Segment {
+    ptr: *mut u8,
+    size: usize,
+    name: CString,
+    unlink: bool,
+}
+
+unsafe impl Send for Segment {}
+unsafe impl Sync for Segment {}
+
+impl Segment {
+    fn create(name: &str, size: usize) -> std::io::Result<Segment> {
+        Self::map(name, size, true)
+    }
+
+    fn open(name: &str, size: usize) -> std::io::Result<Segment> {
+        Self::map(name, size, false)
+    }
+
+    fn map(name: &str, size: usize, create: bool) -> std::io::Result<Segment> {
+        let name = CString::new(name)?;
+        let flags = match create {
+            true => libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+            false => libc::O_RDWR,
+        };
+        let fd = unsafe { libc::shm_open(name.as_ptr(), flags, 0o600) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let ret = (|| {
+            if create {
+                if unsafe { libc::ftruncate(fd, size as libc::off_t) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            } else {
+                let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+                if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if stat.st_size as usize != size {
+                    return Err(std::io::ErrorKind::InvalidData.into());
+                }
+            }
+            let ptr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    size,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    fd,
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(ptr as *mut u8)
+        })();
+        unsafe { libc::close(fd) };
+        if create && ret.is_err() {
+            unsafe { libc::shm_unlink(name.as_ptr()) };
+        }
+        Ok(Segment {
+            ptr: ret?,
+            size,
+            name,
+            unlink: create,
+        })
+    }
+
+    fn unlink(&mut self) {
+        unsafe { libc::shm_unlink(self.name.as_ptr()) };
+        self.unlink = false;
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.size) };
+        if self.unlink {
+            self.unlink();
+        }
+    }
+}
+
+fn segment_name() -> String {
+    format!(
+        "/optcast-{}-{}",
+        std::process::id(),
+        NEXT_SEGMENT_ID.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+// the segment of a listen comm, where the connectors leave the names of their rings
+#[repr(C)]
+struct ListenArea {
+    nonce: AtomicU64,
+    next: AtomicU64,
+    slots: [Slot; SLOTS],
+}
+
+#[repr(C)]
+struct Slot {
+    state: AtomicU32,
+    len: AtomicU32,
+    name: UnsafeCell<[u8; NAME_SIZE]>,
+}
+
+// the head of the segment of a comm pair, followed by RING_SIZE bytes of data
+#[repr(C, align(64))]
+struct RingArea {
+    head: AtomicU64, // bytes written by the sender
+    _pad: [u8; 56],
+    tail: AtomicU64, // bytes read by the receiver
+    send_closed: AtomicU32,
+    recv_closed: AtomicU32,
+}
+
+const RING_SEGMENT_SIZE: usize = std::mem::size_of::<RingArea>() + RING_SIZE;
+
+#[derive(Debug)]
+struct Ring {
+    segment: Segment,
+}
+
+impl Ring {
+    fn area(&self) -> &RingArea {
+        unsafe { &*(self.segment.ptr as *const RingArea) }
+    }
+
+    fn data(&self) -> *mut u8 {
+        unsafe { self.segment.ptr.add(std::mem::size_of::<RingArea>()) }
+    }
+
+    fn space(&self) -> usize {
+        let area = self.area();
+        let head = area.head.load(Ordering::Relaxed);
+        RING_SIZE - (head - area.tail.load(Ordering::Acquire)) as usize
+    }
+
+    fn available(&self) -> usize {
+        let area = self.area();
+        (area.head.load(Ordering::Acquire) - area.tail.load(Ordering::Relaxed)) as usize
+    }
+
+    // copies len bytes between the ring at pos and buf, across the end of the ring
+    fn copy(&self, pos: u64, buf: *mut u8, len: usize, into_ring: bool) {
+        let off = (pos % RING_SIZE as u64) as usize;
+        let first = len.min(RING_SIZE - off);
+        for (ring, buf, len) in [
+            (off, buf, first),
+            (0, unsafe { buf.add(first) }, len - first),
+        ] {
+            let ring = unsafe { self.data().add(ring) };
+            match into_ring {
+                true => unsafe { std::ptr::copy_nonoverlapping(buf, ring, len) },
+                false => unsafe { std::ptr::copy_nonoverlapping(ring, buf, len) },
+            }
+        }
+    }
+
+    // writes as much of data as fits, returns the bytes written
+    fn write(&self, data: &[u8]) -> usize {
+        let len = data.len().min(self.space());
+        let head = self.area().head.load(Ordering::Relaxed);
+        self.copy(head, data.as_ptr() as *mut u8, len, true);
+        self.area().head.store(head + len as u64, Ordering::Release);
+        len
+    }
+
+    // reads up to len bytes into buf, or drops them without buf, returns the bytes read
+    fn read(&self, buf: Option<*mut u8>, len: usize) -> usize {
+        let len = len.min(self.available());
+        let tail = self.area().tail.load(Ordering::Relaxed);
+        if let Some(buf) = buf {
+            self.copy(tail, buf, len, false);
+        }
+        self.area().tail.store(tail + len as u64, Ordering::Release);
+        len
+    }
+
+    fn peek_header(&self) -> Option<(i32, usize)> {
+        if self.available() < HEADER_SIZE {
+            return None;
+        }
+        let mut header = [0u8; HEADER_SIZE];
+        let tail = self.area().tail.load(Ordering::Relaxed);
+        self.copy(tail, header.as_mut_ptr(), HEADER_SIZE, false);
+        Some((
+            i32::from_le_bytes(header[..4].try_into().unwrap()),
+            u64::from_le_bytes(header[4..].try_into().unwrap()) as usize,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Failure {
+    Truncated(usize), // the length of the buffer
+    Closed,
+}
+
+// the state of a request, updated by whichever test progresses its comm
+#[derive(Debug, Default)]
+pub(crate) struct RequestState {
+    done: bool,
+    size: usize,
+    failure: Option<Failure>,
+}
+
+#[derive(Debug)]
+struct SendOp {
+    data: *const u8,
+    len: usize,
+    header: Option<[u8; HEADER_SIZE]>,
+    sent: usize,
+    state: Arc<Mutex<RequestState>>,
+}
+
+#[derive(Debug)]
+struct RecvOp {
+    tag: i32,
+    buf: *mut u8,
+    len: usize,
+    state: Arc<Mutex<RequestState>>,
+}
+
+#[derive(Debug, Default)]
+struct Ops {
+    sends: VecDeque<SendOp>,
+    posted: VecDeque<RecvOp>,
+    // the receive of the message being read, its size and the bytes read
+    current: Option<(RecvOp, usize, usize)>,
+    closed: bool,
+}
+
+unsafe impl Send for Ops {}
+
+impl Ops {
+    fn inflight(&self) -> usize {
+        self.sends.len() + self.posted.len() + self.current.is_some() as usize
+    }
+
+    // fails the requests in flight once the peer is gone
+    fn close(&mut self) {
+        self.closed = true;
+        let states = self
+            .sends
+            .drain(..)
+            .map(|op| op.state)
+            .chain(self.posted.drain(..).map(|op| op.state))
+            .chain(self.current.take().map(|(op, _, _)| op.state));
+        for state in states {
+            state.lock().unwrap().failure = Some(Failure::Closed);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Conn {
+    ring: Ring,
+    send: bool,
+    ops: Mutex<Ops>,
+}
+
+impl Conn {
+    fn new(ring: Ring, send: bool) -> Self {
+        Conn {
+            ring,
+            send,
+            ops: Mutex::new(Ops::default()),
+        }
+    }
+
+    fn progress(&self) {
+        let mut ops = self.ops.lock().unwrap();
+        if ops.closed {
+            return;
+        }
+        let area = self.ring.area();
+        if self.send {
+            if area.recv_closed.load(Ordering::Acquire) != 0 {
+                ops.close();
+                return;
+            }
+            while let Some(op) = ops.sends.front_mut() {
+                if let Some(header) = op.header {
+                    if self.ring.space() < HEADER_SIZE {
+                        return;
+                    }
+                    self.ring.write(&header);
+                    op.header = None;
+                }
+                let data = unsafe { std::slice::from_raw_parts(op.data, op.len) };
+                op.sent += self.ring.write(&data[op.sent..]);
+                if op.sent < op.len {
+                    return;
+                }
+                let op = ops.sends.pop_front().unwrap();
+                let mut state = op.state.lock().unwrap();
+                state.done = true;
+                state.size = op.len;
+            }
+        } else {
+            loop {
+                if ops.current.is_none() {
+                    let Some((tag, size)) = self.ring.peek_header() else {
+                        // the sender only closes its end after its last message
+                        if self.ring.available() == 0
+                            && area.send_closed.load(Ordering::Acquire) != 0
+                        {
+                            ops.close();
+                        }
+                        return;
+                    };
+                    let Some(idx) = ops.posted.iter().position(|op| op.tag == tag) else {
+                        return;
+                    };
+                    self.ring.read(None, HEADER_SIZE);
+                    let op = ops.posted.remove(idx).unwrap();
+                    ops.current = Some((op, size, 0));
+                }
+                let (op, size, read) = ops.current.as_mut().unwrap();
+                // a truncated message is dropped, its receive fails
+                let buf = (*size <= op.len).then(|| unsafe { op.buf.add(*read) });
+                *read += self.ring.read(buf, *size - *read);
+                if *read < *size {
+                    return;
+                }
+                let (op, size, _) = ops.current.take().unwrap();
+                let mut state = op.state.lock().unwrap();
+                state.done = true;
+                state.size = size;
+                if size > op.len {
+                    state.failure = Some(Failure::Truncated(op.len));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Shm<N> {
+    net: N,
+    enabled: bool,
+}
+
+impl<N: Clone> Clone for Shm<N> {
+    fn clone(&self) -> Self {
+        Shm {
+            net: self.net.clone(),
+            enabled: self.enabled,
+        }
+    }
+}
+
+impl<N: Transport> Shm<N> {
+    // without enabled, the comms all go through net, but the handles still work with the peers
+    // that have it
+    pub(crate) fn new(net: N, enabled: bool) -> Self {
+        Shm { net, enabled }
+    }
+
+    // connects through the ring of a new segment, None if the listen segment isn't reachable
+    // from here
+    fn connect_shm(&self, name: &str, nonce: u64) -> std::io::Result<Option<Conn>> {
+        let listen = match Segment::open(name, std::mem::size_of::<ListenArea>()) {
+            Ok(segment) => segment,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let area = unsafe { &*(listen.ptr as *const ListenArea) };
+        if area.nonce.load(Ordering::Acquire) != nonce {
+            return Ok(None);
+        }
+        let ring_name = segment_name();
+        let ring = Ring {
+            segment: Segment::create(&ring_name, RING_SEGMENT_SIZE)?,
+        };
+        let start = area.next.fetch_add(1, Ordering::Relaxed) as usize;
+        let Some(slot) = (0..SLOTS)
+            .map(|i| &area.slots[(start + i) % SLOTS])
+            .find(|slot| {
+                slot.state
+                    .compare_exchange(
+                        SLOT_FREE,
+                        SLOT_WRITING,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok()
+            })
+        else {
+            warn!("shm: {} has too many pending connections", name);
+            return Ok(None);
+        };
+        let slot_name = slot.name.get() as *mut u8;
+        unsafe { std::ptr::copy_nonoverlapping(ring_name.as_ptr(), slot_name, ring_name.len()) };
+        slot.len.store(ring_name.len() as u32, Ordering::Relaxed);
+        slot.state.store(SLOT_READY, Ordering::Release);
+        Ok(Some(Conn::new(ring, true)))
+    }
+}
+
+// the local part of a handle is the nonce and the name of the listen segment, empty without one
+fn encode_local(nonce: u64, name: &str) -> Vec<u8> {
+    let mut local = Vec::with_capacity(12 + name.len());
+    local.extend_from_slice(&HANDLE_MAGIC.to_le_bytes());
+    local.extend_from_slice(&nonce.to_le_bytes());
+    local.extend_from_slice(name.as_bytes());
+    local
+}
+
+fn decode_local(local: &[u8]) -> Option<(u64, &str)> {
+    if local.len() < 12 || local[..4] != HANDLE_MAGIC.to_le_bytes() {
+        return None;
+    }
+    let nonce = u64::from_le_bytes(local[4..12].try_into().unwrap());
+    let name = std::str::from_utf8(&local[12..]).ok()?;
+    Some((nonce, name))
+}
+
+#[derive(Debug)]
+pub(crate) struct Listener {
+    segment: Segment,
+}
+
+impl Listener {
+    fn area(&self) -> &ListenArea {
+        unsafe { &*(self.segment.ptr as *const ListenArea) }
+    }
+
+    fn accept(&self) -> Option<Conn> {
+        for slot in &self.area().slots {
+            if slot.state.load(Ordering::Acquire) != SLOT_READY {
+                continue;
+            }
+            let len = (slot.len.load(Ordering::Relaxed) as usize).min(NAME_SIZE);
+            let name = unsafe { &(&*slot.name.get())[..len] };
+            let name = String::from_utf8_lossy(name).into_owned();
+            slot.state.store(SLOT_FREE, Ordering::Release);
+            match Segment::open(&name, RING_SEGMENT_SIZE) {
+                Ok(mut segment) => {
+                    // the segment stays mapped by the two ends
+                    segment.unlink();
+                    return Some(Conn::new(Ring { segment }, false));
+                }
+                Err(e) => warn!("shm: failed to open {}: {}", name, e),
+            }
+        }
+        None
+    }
+}
+
+pub(crate) enum Comm<N: Transport> {
+    Listen(N::Comm, Option<Listener>),
+    Send(Arc<Conn>),
+    Recv(Arc<Conn>),
+    Net(N::Comm),
+}
+
+// the requests in flight keep the ring mapped, but the peer sees the end closed with the comm
+impl<N: Transport> Drop for Comm<N> {
+    fn drop(&mut self) {
+        match self {
+            Comm::Send(conn) => conn.ring.area().send_closed.store(1, Ordering::Release),
+            Comm::Recv(conn) => conn.ring.area().recv_closed.store(1, Ordering::Release),
+            _ => (),
+        }
+    }
+}
+
+pub(crate) enum MemoryHandle<'a, N: Transport + 'a> {
+    // the data is copied through the ring, so there is nothing to register
+    Shm(PhantomData<&'a ()>),
+    Net(N::MemoryHandle<'a>),
+}
+
+pub(crate) enum Request<N: Transport> {
+    Shm(Arc<Conn>, Arc<Mutex<RequestState>>),
+    Net(N::Request),
+}
+
+impl<N: Transport> Transport for Shm<N> {
+    type Error = Error<N::Error>;
+    type Comm = Comm<N>;
+    type MemoryHandle<'a>
+        = MemoryHandle<'a, N>
+    where
+        Self: 'a;
+    type Request = Request<N>;
+
+    fn devices(&self) -> Result<usize, Self::Error> {
+        self.net.devices().map_err(Error::Net)
+    }
+
+    fn get_properties(&self, dev: usize) -> Result<Properties, Self::Error> {
+        self.net.get_properties(dev).map_err(Error::Net)
+    }
+
+    fn listen(&self, dev: usize) -> Result<(Self::Comm, Handle), Self::Error> {
+        let (comm, handle) = self.net.listen(dev).map_err(Error::Net)?;
+        if !self.enabled {
+            return Ok((Comm::Listen(comm, None), handle));
+        }
+        let name = segment_name();
+        let segment = Segment::create(&name, std::mem::size_of::<ListenArea>())?;
+        let nonce = RandomState::new().hash_one(&name) | 1;
+        let listener = Listener { segment };
+        listener.area().nonce.store(nonce, Ordering::Release);
+        let handle = Handle {
+            local: encode_local(nonce, &name),
+            ..handle
+        };
+        Ok((Comm::Listen(comm, Some(listener)), handle))
+    }
+
+    fn connect(&self, dev: usize, handle: &Handle) -> Result<Option<Self::Comm>, Self::Error> {
+        if self.enabled && !handle.local.is_empty() {
+            let (nonce, name) = decode_local(&handle.local).ok_or(Error::InvalidHandle)?;
+            match self.connect_shm(name, nonce) {
+                Ok(Some(conn)) => {
+                    info!("shm: connected to {}", name);
+                    return Ok(Some(Comm::Send(Arc::new(conn))));
+                }
+                Ok(None) => (),
+                Err(e) => warn!("shm: failed to connect to {}: {}", name, e),
+            }
+        }
+        let handle = Handle::from(handle.net.clone());
+        let comm = self.net.connect(dev, &handle).map_err(Error::Net)?;
+        Ok(comm.map(Comm::Net))
+    }
+
+    fn accept(&self, comm: &Self::Comm) -> Result<Option<Self::Comm>, Self::Error> {
+        let Comm::Listen(comm, listener) = comm else {
+            return Err(Error::InvalidUsage);
+        };
+        if let Some(conn) = listener.as_ref().and_then(|v| v.accept()) {
+            return Ok(Some(Comm::Recv(Arc::new(conn))));
+        }
+        let comm = self.net.accept(comm).map_err(Error::Net)?;
+        Ok(comm.map(Comm::Net))
+    }
+
+    fn reg_mr<'a, T>(
+        &self,
+        comm: &'a Self::Comm,
+        data: &[T],
+    ) -> Result<Self::MemoryHandle<'a>, Self::Error> {
+        match comm {
+            Comm::Listen(..) => Err(Error::InvalidUsage),
+            Comm::Send(_) | Comm::Recv(_) => Ok(MemoryHandle::Shm(PhantomData)),
+            Comm::Net(comm) => Ok(MemoryHandle::Net(
+                self.net.reg_mr(comm, data).map_err(Error::Net)?,
+            )),
+        }
+    }
+
+    fn isend<T>(
+        &self,
+        comm: &Self::Comm,
+        mhandle: &Self::MemoryHandle<'_>,
+        data: &[T],
+        tag: i32,
+    ) -> Result<Option<Self::Request>, Self::Error> {
+        let conn = match (comm, mhandle) {
+            (Comm::Send(conn), MemoryHandle::Shm(_)) => conn,
+            (Comm::Net(comm), MemoryHandle::Net(mhandle)) => {
+                let req = self.net.isend(comm, mhandle, data, tag);
+                return Ok(req.map_err(Error::Net)?.map(Request::Net));
+            }
+            _ => return Err(Error::InvalidUsage),
+        };
+        let len = std::mem::size_of_val(data);
+        let mut header = [0u8; HEADER_SIZE];
+        header[..4].copy_from_slice(&tag.to_le_bytes());
+        header[4..].copy_from_slice(&(len as u64).to_le_bytes());
+        let state = Arc::new(Mutex::new(RequestState::default()));
+        {
+            let mut ops = conn.ops.lock().unwrap();
+            if ops.closed {
+                return Err(Error::Closed);
+            }
+            if ops.inflight() >= MAX_REQUESTS {
+                return Ok(None);
+            }
+            ops.sends.push_back(SendOp {
+                data: data.as_ptr() as *const u8,
+                len,
+                header: Some(header),
+                sent: 0,
+                state: state.clone(),
+            });
+        }
+        conn.progress();
+        Ok(Some(Request::Shm(conn.clone(), state)))
+    }
+
+    fn irecv<T>(
+        &self,
+        comm: &Self::Comm,
+        mhandle: &Self::MemoryHandle<'_>,
+        data: &mut [T],
+        tag: i32,
+    ) -> Result<Option<Self::Request>, Self::Error> {
+        let conn = match (comm, mhandle) {
+            (Comm::Recv(conn), MemoryHandle::Shm(_)) => conn,
+            (Comm::Net(comm), MemoryHandle::Net(mhandle)) => {
+                let req = self.net.irecv(comm, mhandle, data, tag);
+                return Ok(req.map_err(Error::Net)?.map(Request::Net));
+            }
+            _ => return Err(Error::InvalidUsage),
+        };
+        let state = Arc::new(Mutex::new(RequestState::default()));
+        {
+            let mut ops = conn.ops.lock().unwrap();
+            if ops.closed {
+                return Err(Error::Closed);
+            }
+            if ops.inflight() >= MAX_REQUESTS {
+                return Ok(None);
+            }
+            ops.posted.push_back(RecvOp {
+                tag,
+                buf: data.as_mut_ptr() as *mut u8,
+                len: std::mem::size_of_val(data),
+                state: state.clone(),
+            });
+        }
+        conn.progress();
+        Ok(Some(Request::Shm(conn.clone(), state)))
+    }
+
+    fn test(&self, request: &Self::Request) -> Result<(bool, usize), Self::Error> {
+        let (conn, state) = match request {
+            Request::Shm(conn, state) => (conn, state),
+            Request::Net(request) => return self.net.test(request).map_err(Error::Net),
+        };
+        conn.progress();
+        let state = state.lock().unwrap();
+        match state.failure {
+            Some(Failure::Truncated(len)) => Err(Error::Truncated(state.size, len)),
+            Some(Failure::Closed) => Err(Error::Closed),
+            None => Ok((state.done, state.size)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bootstrap::{self, MAX_HANDLE_SIZE};
+    use crate::transport::loopback::{self, Loopback};
+
+    type Net = Shm<Loopback>;
+
+    fn connect(net: &Net, peer: &Net) -> (Comm<Loopback>, Comm<Loopback>) {
+        let (lcomm, handle) = net.listen(0).unwrap();
+        let scomm = peer.connect(0, &handle).unwrap().unwrap();
+        let rcomm = loop {
+            if let Some(comm) = net.accept(&lcomm).unwrap() {
+                break comm;
+            }
+        };
+        assert!(net.accept(&lcomm).unwrap().is_none());
+        (scomm, rcomm)
+    }
+
+    fn wait(net: &Net, req: &Request<Loopback>) -> Result<usize, Error<loopback::Error>> {
+        loop {
+            let (done, size) = net.test(req)?;
+            if done {
+                return Ok(size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shm_select() {
+        let net = Loopback::default();
+        let (shm, noshm) = (Shm::new(net.clone(), true), Shm::new(net, false));
+        // the ring is only used when both ends have it
+        for (a, b, local) in [
+            (&shm, &shm, true),
+            (&shm, &noshm, false),
+            (&noshm, &shm, false),
+            (&noshm, &noshm, false),
+        ] {
+            let (scomm, rcomm) = connect(a, b);
+            assert_eq!(matches!(scomm, Comm::Send(_)), local);
+            assert_eq!(matches!(rcomm, Comm::Recv(_)), local);
+            let (smh, rmh) = (
+                b.reg_mr(&scomm, &[0u8]).unwrap(),
+                a.reg_mr(&rcomm, &[0u8]).unwrap(),
+            );
+            let mut buf = [0u32; 4];
+            let rreq = a.irecv(&rcomm, &rmh, &mut buf, 0).unwrap().unwrap();
+            let sreq = b.isend(&scomm, &smh, &[1u32, 2, 3], 0).unwrap().unwrap();
+            assert_eq!(wait(b, &sreq), Ok(12));
+            assert_eq!(wait(a, &rreq), Ok(12));
+            assert_eq!(buf, [1, 2, 3, 0]);
+        }
+
+        // a segment of another listen comm, or of nobody
+        let (lcomm, handle) = shm.listen(0).unwrap();
+        let (nonce, name) = decode_local(&handle.local).unwrap();
+        let other = Handle {
+            local: encode_local(nonce ^ 2, name),
+            ..handle.clone()
+        };
+        assert!(matches!(shm.connect(0, &other), Ok(Some(Comm::Net(_)))));
+        let other = Handle {
+            local: encode_local(nonce, "/optcast-none"),
+            ..handle.clone()
+        };
+        assert!(matches!(shm.connect(0, &other), Ok(Some(Comm::Net(_)))));
+        assert!(matches!(shm.accept(&lcomm), Ok(Some(Comm::Net(_)))));
+        let other = Handle {
+            local: handle.local[1..].to_vec(),
+            ..handle
+        };
+        assert!(matches!(shm.connect(0, &other), Err(Error::InvalidHandle)));
+    }
+
+    #[test]
+    fn test_shm_handle_size() {
+        // the handle of the network is passed through as is, so that the handles of a net plugin
+        // still fit the bootstrap protocol
+        let net = Loopback::new(loopback::Options {
+            handle_size: MAX_HANDLE_SIZE,
+            ..Default::default()
+        });
+        for enabled in [true, false] {
+            let shm = Shm::new(net.clone(), enabled);
+            let (_lcomm, handle) = shm.listen(0).unwrap();
+            assert_eq!(handle.net.len(), MAX_HANDLE_SIZE);
+            assert_eq!(handle.local.is_empty(), !enabled);
+            let mut buf = vec![];
+            bootstrap::send_handle(&mut buf, &handle).unwrap();
+            let handle = bootstrap::recv_handle(&mut buf.as_slice()).unwrap();
+            let comm = shm.connect(0, &handle).unwrap().unwrap();
+            assert_eq!(matches!(comm, Comm::Send(_)), enabled);
+        }
+    }
+
+    #[test]
+    fn test_shm_ring() {
+        let net = Shm::new(Loopback::default(), true);
+        let (scomm, rcomm) = connect(&net, &net);
+        let (smh, rmh) = (
+            net.reg_mr(&scomm, &[0u8]).unwrap(),
+            net.reg_mr(&rcomm, &[0u8]).unwrap(),
+        );
+        // messages larger than the ring, wrapping around it, and waiting for a receive of their
+        // tag
+        let data = (0..3)
+            .map(|i| {
+                (0..RING_SIZE + 12345)
+                    .map(|v| (v * 3 + i) as u8)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let sreqs = [(0, 7), (1, 8), (2, 7)]
+            .iter()
+            .map(|(i, tag)| net.isend(&scomm, &smh, &data[*i], *tag).unwrap().unwrap())
+            .collect::<Vec<_>>();
+        let mut bufs = vec![vec![0u8; RING_SIZE + 12345]; 3];
+        let mut it = bufs.iter_mut();
+        let (b8, b7, b7_) = (it.next().unwrap(), it.next().unwrap(), it.next().unwrap());
+        let r8 = net.irecv(&rcomm, &rmh, b8, 8).unwrap().unwrap();
+        for _ in 0..100 {
+            assert_eq!(net.test(&r8), Ok((false, 0)));
+        }
+        let r7 = net.irecv(&rcomm, &rmh, b7, 7).unwrap().unwrap();
+        let r7_ = net.irecv(&rcomm, &rmh, b7_, 7).unwrap().unwrap();
+        let reqs = [&r8, &r7, &r7_]
+            .into_iter()
+            .chain(&sreqs)
+            .collect::<Vec<_>>();
+        let mut done = vec![false; reqs.len()];
+        while done.iter().any(|v| !v) {
+            for (req, done) in reqs.iter().zip(done.iter_mut()) {
+                if !*done {
+                    *done = net.test(req).unwrap().0;
+                }
+            }
+        }
+        assert_eq!(bufs, [data[1].clone(), data[0].clone(), data[2].clone()]);
+    }
+
+    #[test]
+    fn test_shm_errors() {
+        let net = Shm::new(Loopback::default(), true);
+        let (scomm, rcomm) = connect(&net, &net);
+        let (smh, rmh) = (
+            net.reg_mr(&scomm, &[0u8]).unwrap(),
+            net.reg_mr(&rcomm, &[0u8]).unwrap(),
+        );
+        assert!(net.isend(&rcomm, &rmh, &[0u8], 0).is_err());
+        assert!(net.irecv(&scomm, &smh, &mut [0u8], 0).is_err());
+
+        // a truncated message fails its receive only
+        let mut buf = [0u8; 4];
+        let sreq = net.isend(&scomm, &smh, &[1u8; 8], 0).unwrap().unwrap();
+        let rreq = net.irecv(&rcomm, &rmh, &mut buf, 0).unwrap().unwrap();
+        assert_eq!(wait(&net, &sreq), Ok(8));
+        assert_eq!(wait(&net, &rreq), Err(Error::Truncated(8, 4)));
+        let sreq = net.isend(&scomm, &smh, &[2u8; 3], 0).unwrap().unwrap();
+        let rreq = net.irecv(&rcomm, &rmh, &mut buf, 0).unwrap().unwrap();
+        assert_eq!(wait(&net, &sreq), Ok(3));
+        assert_eq!(wait(&net, &rreq), Ok(3));
+        assert_eq!(buf, [2, 2, 2, 0]);
+
+        // at most MAX_REQUESTS in flight
+        let mut bufs = [[0u8; 1]; MAX_REQUESTS];
+        let reqs = bufs
+            .iter_mut()
+            .map(|buf| net.irecv(&rcomm, &rmh, buf, 0).unwrap().unwrap())
+            .collect::<Vec<_>>();
+        assert!(net.irecv(&rcomm, &rmh, &mut buf, 0).unwrap().is_none());
+
+        // and a closed peer fails them
+        drop(scomm);
+        assert_eq!(wait(&net, &reqs[0]), Err(Error::Closed));
+        assert_eq!(
+            net.irecv(&rcomm, &rmh, &mut buf, 0).err(),
+            Some(Error::Closed)
+        );
+    }
+}
@@ -0,0 +1,989 @@
+/*
+ * Copyright (c) 2024, the Optcast Authors. All rights reserved.
+ *
+ * See LICENSE for license information
+ */
+
+// a transport over plain TCP sockets, for the clusters where building the net plugin isn't worth
+// it. it works like the socket plugin: a comm pair is a set of sockets, driven by a few threads
+// per comm that own nsocks_per_thread sockets each. a message is split into parts of at least
+// MIN_CHUNK bytes, one per socket, and the first socket carries its header with the tag and the
+// size. the receiver matches a message with the earliest posted receive of the same tag, and stops
+// reading until there is one.
+//
+// the devices are the network interfaces, picked with the syntax of NCCL_SOCKET_IFNAME.
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CStr;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::{info, warn};
+use nccl_net_sys as ffi;
+
+use super::{Handle, Properties, Transport};
+use crate::utils::Args;
+
+// a message is split into parts of at least MIN_CHUNK bytes over the sockets of a comm
+const MIN_CHUNK: usize = 64 * 1024;
+const MAX_SOCKETS: usize = 64;
+// requests in flight per comm, isend and irecv return None beyond
+const MAX_REQUESTS: usize = 32;
+
+const HELLO_MAGIC: u32 = 0x5043_544f; // "OTCP"
+const HELLO_SIZE: usize = 20;
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+const HEADER_SIZE: usize = 12;
+
+// comm ids tell apart the sockets of the comms connected to the same listen comm
+static NEXT_COMM_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Error {
+    NoDevice(String),
+    InvalidOptions(String),
+    InvalidDevice(usize),
+    InvalidHandle,
+    InvalidUsage,
+    Truncated(usize, usize),
+    Io(String),
+    Closed,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NoDevice(ifname) => write!(f, "no interface matches {:?}", ifname),
+            Error::InvalidOptions(reason) => write!(f, "invalid options: {}", reason),
+            Error::InvalidDevice(dev) => write!(f, "invalid device: {}", dev),
+            Error::InvalidHandle => write!(f, "invalid handle"),
+            Error::InvalidUsage => write!(f, "invalid usage"),
+            Error::Truncated(size, len) => write!(
+                f,
+                "message of {} bytes received into a buffer of {} bytes",
+                size, len
+            ),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Options {
+    pub ifname: String,
+    pub nthreads: usize,
+    pub nsocks_per_thread: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            ifname: String::new(),
+            nthreads: 2,
+            nsocks_per_thread: 1,
+        }
+    }
+}
+
+impl Options {
+    pub(crate) fn from_args(args: &Args) -> Self {
+        Options {
+            ifname: args.socket_ifname.clone(),
+            nthreads: args.socket_nthreads,
+            nsocks_per_thread: args.nsocks_perthread,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Device {
+    pub name: String,
+    pub addr: IpAddr,
+}
+
+impl Device {
+    pub(super) fn properties(&self, dev: usize, reg_is_global: bool) -> Properties {
+        Properties {
+            name: self.name.clone(),
+            pci_path: String::new(),
+            guid: dev as u64,
+            ptr_support: ffi::NCCL_PTR_HOST as i32,
+            reg_is_global,
+            speed: speed(&self.name),
+            port: 0,
+            latency: 0.0,
+            max_comms: 65536,
+            max_recvs: 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    opts: Options,
+    devices: Vec<Device>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Tcp {
+    inner: Arc<Inner>,
+}
+
+impl Tcp {
+    pub(crate) fn new(opts: Options) -> Result<Self, Error> {
+        let nsocks = opts.nthreads * opts.nsocks_per_thread;
+        if nsocks == 0 || nsocks > MAX_SOCKETS {
+            return Err(Error::InvalidOptions(format!(
+                "{} threads of {} sockets, a comm needs 1 to {} sockets",
+                opts.nthreads, opts.nsocks_per_thread, MAX_SOCKETS
+            )));
+        }
+        let devices = select_devices(&opts.ifname)?;
+        for (dev, device) in devices.iter().enumerate() {
+            info!(
+                "tcp: dev: {}, name: {}, addr: {}",
+                dev, device.name, device.addr
+            );
+        }
+        Ok(Tcp {
+            inner: Arc::new(Inner { opts, devices }),
+        })
+    }
+
+    fn device(&self, dev: usize) -> Result<&Device, Error> {
+        self.inner.devices.get(dev).ok_or(Error::InvalidDevice(dev))
+    }
+}
+
+// whether an interface matches a list of NCCL_SOCKET_IFNAME: comma separated prefixes, exact
+// names with a leading '=', and names to exclude with a leading '^'
+fn matches_ifname(spec: &str, name: &str) -> bool {
+    let (exclude, spec) = match spec.strip_prefix('^') {
+        Some(spec) => (true, spec),
+        None => (false, spec),
+    };
+    let (exact, spec) = match spec.strip_prefix('=') {
+        Some(spec) => (true, spec),
+        None => (false, spec),
+    };
+    let found = spec.split(',').filter(|v| !v.is_empty()).any(|v| {
+        if exact {
+            name == v
+        } else {
+            name.starts_with(v)
+        }
+    });
+    found != exclude
+}
+
+// the interfaces that are up and match ifname, with their first IPv4 address or else their first
+// IPv6 one. link-local IPv6 addresses need a scope, so they aren't used.
+fn interfaces(ifname: &str) -> Vec<Device> {
+    let mut devices: Vec<Device> = Vec::new();
+    let mut ifap = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        warn!("getifaddrs: {}", std::io::Error::last_os_error());
+        return devices;
+    }
+    let mut ifa = ifap;
+    while !ifa.is_null() {
+        let v = unsafe { &*ifa };
+        ifa = v.ifa_next;
+        if v.ifa_addr.is_null() || v.ifa_flags & libc::IFF_UP as u32 == 0 {
+            continue;
+        }
+        let name = unsafe { CStr::from_ptr(v.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        if !matches_ifname(ifname, &name) {
+            continue;
+        }
+        let addr = match unsafe { (*v.ifa_addr).sa_family } as i32 {
+            libc::AF_INET => {
+                let sin = unsafe { &*(v.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(v.ifa_addr as *const libc::sockaddr_in6) };
+                let addr = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                if addr.is_unicast_link_local() {
+                    continue;
+                }
+                IpAddr::V6(addr)
+            }
+            _ => continue,
+        };
+        match devices.iter_mut().find(|d| d.name == name) {
+            Some(d) if d.addr.is_ipv6() && addr.is_ipv4() => d.addr = addr,
+            Some(_) => (),
+            None => devices.push(Device { name, addr }),
+        }
+    }
+    unsafe { libc::freeifaddrs(ifap) };
+    devices
+}
+
+// the devices of --socket-ifname
+pub(super) fn select_devices(ifname: &str) -> Result<Vec<Device>, Error> {
+    let devices = match ifname {
+        // like NCCL, the loopback interface is only used when there is nothing else
+        "" => Some(interfaces("^docker,lo"))
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| interfaces("lo")),
+        ifname => interfaces(ifname),
+    };
+    if devices.is_empty() {
+        return Err(Error::NoDevice(ifname.to_string()));
+    }
+    Ok(devices)
+}
+
+// the port speed the kernel reports, or 10Gbps like the socket plugin when it doesn't
+fn speed(name: &str) -> i32 {
+    std::fs::read_to_string(format!("/sys/class/net/{}/speed", name))
+        .ok()
+        .and_then(|v| v.trim().parse::<i32>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(10000)
+}
+
+// the number of parts a message of size bytes is split into over nsocks sockets, and their size.
+// only the last part may be shorter, and a message without data still has an empty first part.
+fn split(size: usize, nsocks: usize) -> (usize, usize) {
+    let chunk = size
+        .div_ceil(size.div_ceil(MIN_CHUNK).clamp(1, nsocks))
+        .max(1);
+    (size.div_ceil(chunk).max(1), chunk)
+}
+
+fn part(size: usize, chunk: usize, idx: usize) -> std::ops::Range<usize> {
+    (idx * chunk).min(size)..((idx + 1) * chunk).min(size)
+}
+
+// the state of a request, shared with the threads that transfer its parts
+#[derive(Debug)]
+struct RequestState {
+    // parts not transferred yet, plus one until a receive is matched with a message
+    pending: AtomicUsize,
+    size: AtomicUsize,
+    error: Mutex<Option<Error>>,
+}
+
+impl RequestState {
+    fn new(pending: usize, size: usize) -> Self {
+        RequestState {
+            pending: AtomicUsize::new(pending),
+            size: AtomicUsize::new(size),
+            error: Mutex::new(None),
+        }
+    }
+
+    fn done(&self) {
+        self.pending.fetch_sub(1, Ordering::Release);
+    }
+
+    fn fail(&self, e: Error) {
+        self.error.lock().unwrap().get_or_insert(e);
+    }
+}
+
+// a receive waiting for a message, or matched with one
+#[derive(Debug, Clone)]
+struct Posted {
+    tag: i32,
+    buf: *mut u8,
+    len: usize,
+    state: Arc<RequestState>,
+}
+
+unsafe impl Send for Posted {}
+
+#[derive(Debug, Default)]
+struct Matching {
+    posted: VecDeque<Posted>,
+    // messages matched by the thread of the first socket, by their position on the comm, with
+    // the number of the other threads that haven't taken them yet
+    matched: HashMap<u64, (Posted, usize)>,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    nsocks: usize,
+    nthreads: usize,
+    // set once a socket fails, the requests in flight never complete then
+    broken: AtomicBool,
+    inflight: AtomicUsize,
+    matching: Mutex<Matching>,
+    cond: Condvar,
+}
+
+impl Shared {
+    fn close(&self, broken: bool) {
+        if broken {
+            self.broken.store(true, Ordering::Release);
+        }
+        self.matching.lock().unwrap().closed = true;
+        self.cond.notify_all();
+    }
+
+    // matches message seq with the earliest receive posted with its tag
+    fn match_message(&self, seq: u64, tag: i32, size: usize) -> Option<Posted> {
+        let mut matching = self.matching.lock().unwrap();
+        loop {
+            if matching.closed {
+                return None;
+            }
+            if let Some(idx) = matching.posted.iter().position(|p| p.tag == tag) {
+                let posted = matching.posted.remove(idx).unwrap();
+                let (nparts, _) = split(size, self.nsocks);
+                posted.state.size.store(size, Ordering::Relaxed);
+                posted
+                    .state
+                    .pending
+                    .fetch_add(nparts - 1, Ordering::Relaxed);
+                if size > posted.len {
+                    posted.state.fail(Error::Truncated(size, posted.len));
+                }
+                if self.nthreads > 1 {
+                    matching
+                        .matched
+                        .insert(seq, (posted.clone(), self.nthreads - 1));
+                    self.cond.notify_all();
+                }
+                return Some(posted);
+            }
+            matching = self.cond.wait(matching).unwrap();
+        }
+    }
+
+    fn wait_matched(&self, seq: u64) -> Option<Posted> {
+        let mut matching = self.matching.lock().unwrap();
+        loop {
+            if matching.closed {
+                return None;
+            }
+            if let Some((posted, users)) = matching.matched.get_mut(&seq) {
+                *users -= 1;
+                let posted = posted.clone();
+                if *users == 0 {
+                    matching.matched.remove(&seq);
+                }
+                return Some(posted);
+            }
+            matching = self.cond.wait(matching).unwrap();
+        }
+    }
+}
+
+// a part of a message to send. socket is the index among the sockets of the thread.
+struct Part {
+    socket: usize,
+    header: Option<[u8; HEADER_SIZE]>,
+    data: *const u8,
+    len: usize,
+    state: Arc<RequestState>,
+}
+
+unsafe impl Send for Part {}
+
+fn send_loop(mut socks: Vec<TcpStream>, parts: mpsc::Receiver<Part>, shared: Arc<Shared>) {
+    for part in parts {
+        if shared.broken.load(Ordering::Acquire) {
+            part.state.fail(Error::Closed);
+            continue;
+        }
+        let sock = &mut socks[part.socket];
+        let data = unsafe { std::slice::from_raw_parts(part.data, part.len) };
+        let ret = match part.header {
+            Some(header) => sock.write_all(&header),
+            None => Ok(()),
+        }
+        .and_then(|_| sock.write_all(data));
+        match ret {
+            Ok(_) => part.state.done(),
+            Err(e) => {
+                part.state.fail(e.into());
+                shared.close(true);
+            }
+        }
+    }
+}
+
+// thread idx reads the parts of the sockets idx, idx + nthreads, ... of every message in turn.
+// thread 0 reads the headers from the first socket and matches the messages for the others.
+fn recv_loop(idx: usize, mut socks: Vec<TcpStream>, shared: Arc<Shared>) {
+    let mut header = [0u8; HEADER_SIZE];
+    for seq in 0.. {
+        let posted = if idx == 0 {
+            if let Err(e) = socks[0].read_exact(&mut header) {
+                let closed = shared.matching.lock().unwrap().closed;
+                if !closed && e.kind() != std::io::ErrorKind::UnexpectedEof {
+                    warn!("tcp: failed to receive a header: {}", e);
+                }
+                shared.close(true);
+                return;
+            }
+            let tag = i32::from_le_bytes(header[..4].try_into().unwrap());
+            let size = u64::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+            shared.match_message(seq, tag, size)
+        } else {
+            shared.wait_matched(seq)
+        };
+        let Some(posted) = posted else {
+            return;
+        };
+        let size = posted.state.size.load(Ordering::Relaxed);
+        let (nparts, chunk) = split(size, shared.nsocks);
+        for i in (idx..nparts).step_by(shared.nthreads) {
+            let range = part(size, chunk, i);
+            let sock = &mut socks[i / shared.nthreads];
+            let ret = if size <= posted.len {
+                let buf = unsafe {
+                    std::slice::from_raw_parts_mut(posted.buf.add(range.start), range.len())
+                };
+                sock.read_exact(buf)
+            } else {
+                // a truncated message is drained, the request fails when it is tested
+                std::io::copy(&mut sock.take(range.len() as u64), &mut std::io::sink()).and_then(
+                    |n| match n as usize == range.len() {
+                        true => Ok(()),
+                        false => Err(std::io::ErrorKind::UnexpectedEof.into()),
+                    },
+                )
+            };
+            match ret {
+                Ok(_) => posted.state.done(),
+                Err(e) => {
+                    posted.state.fail(e.into());
+                    shared.close(true);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// the sockets of a send or recv comm and the threads driving them
+#[derive(Debug)]
+struct Connection {
+    socks: Vec<TcpStream>,
+    parts: Vec<mpsc::Sender<Part>>,
+    threads: Vec<JoinHandle<()>>,
+    shared: Arc<Shared>,
+}
+
+impl Connection {
+    fn new(socks: Vec<TcpStream>, nthreads: usize, send: bool) -> Result<Self, Error> {
+        let nthreads = nthreads.min(socks.len());
+        let shared = Arc::new(Shared {
+            nsocks: socks.len(),
+            nthreads,
+            broken: AtomicBool::new(false),
+            inflight: AtomicUsize::new(0),
+            matching: Mutex::new(Matching::default()),
+            cond: Condvar::new(),
+        });
+        let mut parts = Vec::new();
+        let mut threads = Vec::new();
+        for idx in 0..nthreads {
+            let owned = socks
+                .iter()
+                .skip(idx)
+                .step_by(nthreads)
+                .map(|v| v.try_clone())
+                .collect::<Result<Vec<_>, _>>()?;
+            let shared = shared.clone();
+            if send {
+                let (tx, rx) = mpsc::channel();
+                parts.push(tx);
+                threads.push(std::thread::spawn(move || send_loop(owned, rx, shared)));
+            } else {
+                threads.push(std::thread::spawn(move || recv_loop(idx, owned, shared)));
+            }
+        }
+        Ok(Connection {
+            socks,
+            parts,
+            threads,
+            shared,
+        })
+    }
+}
+
+impl Drop for Connection {
+    // the sockets are shut down first, so that the threads give up the parts in flight and no
+    // longer touch the buffers of the requests once the comm is gone
+    fn drop(&mut self) {
+        for sock in &self.socks {
+            let _ = sock.shutdown(Shutdown::Both);
+        }
+        self.shared.close(false);
+        self.parts.clear();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+// sockets of the comms being connected, by the address and the comm id of the peer
+type Pending = HashMap<(IpAddr, u64), Vec<Option<TcpStream>>>;
+
+#[derive(Debug)]
+struct Listener {
+    sock: TcpListener,
+    pending: Mutex<Pending>,
+}
+
+#[derive(Debug)]
+enum CommType {
+    Listen(Listener),
+    Send(Connection),
+    Recv(Connection),
+}
+
+#[derive(Debug)]
+pub(crate) struct Comm(CommType);
+
+// the data is read and written in place, so there is nothing to register
+pub(crate) struct MemoryHandle<'a>(PhantomData<&'a Comm>);
+
+pub(crate) struct Request {
+    state: Arc<RequestState>,
+    shared: Arc<Shared>,
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        self.shared.inflight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn hello(id: u64, idx: usize, nsocks: usize) -> [u8; HELLO_SIZE] {
+    let mut buf = [0u8; HELLO_SIZE];
+    buf[..4].copy_from_slice(&HELLO_MAGIC.to_le_bytes());
+    buf[4..12].copy_from_slice(&id.to_le_bytes());
+    buf[12..16].copy_from_slice(&(idx as u32).to_le_bytes());
+    buf[16..].copy_from_slice(&(nsocks as u32).to_le_bytes());
+    buf
+}
+
+// returns the comm id, the index of the socket and the number of sockets of the comm
+fn read_hello(sock: &mut TcpStream) -> Result<(u64, usize, usize), Error> {
+    let mut buf = [0u8; HELLO_SIZE];
+    sock.set_read_timeout(Some(HELLO_TIMEOUT))?;
+    sock.read_exact(&mut buf)?;
+    sock.set_read_timeout(None)?;
+    let magic = u32::from_le_bytes(buf[..4].try_into().unwrap());
+    let id = u64::from_le_bytes(buf[4..12].try_into().unwrap());
+    let idx = u32::from_le_bytes(buf[12..16].try_into().unwrap()) as usize;
+    let nsocks = u32::from_le_bytes(buf[16..].try_into().unwrap()) as usize;
+    if magic != HELLO_MAGIC || nsocks == 0 || nsocks > MAX_SOCKETS || idx >= nsocks {
+        return Err(Error::InvalidHandle);
+    }
+    Ok((id, idx, nsocks))
+}
+
+impl Transport for Tcp {
+    type Error = Error;
+    type Comm = Comm;
+    type MemoryHandle<'a> = MemoryHandle<'a>;
+    type Request = Request;
+
+    fn devices(&self) -> Result<usize, Error> {
+        Ok(self.inner.devices.len())
+    }
+
+    fn get_properties(&self, dev: usize) -> Result<Properties, Error> {
+        Ok(self.device(dev)?.properties(dev, true))
+    }
+
+    // the handle is the address the listen comm is bound to
+    fn listen(&self, dev: usize) -> Result<(Comm, Handle), Error> {
+        let device = self.device(dev)?;
+        let sock = TcpListener::bind((device.addr, 0))?;
+        sock.set_nonblocking(true)?;
+        let handle = Handle::from(sock.local_addr()?.to_string().into_bytes());
+        let listener = Listener {
+            sock,
+            pending: Mutex::new(HashMap::new()),
+        };
+        Ok((Comm(CommType::Listen(listener)), handle))
+    }
+
+    fn connect(&self, dev: usize, handle: &Handle) -> Result<Option<Comm>, Error> {
+        self.device(dev)?;
+        let addr = std::str::from_utf8(&handle.net)
+            .ok()
+            .and_then(|v| v.parse::<SocketAddr>().ok())
+            .ok_or(Error::InvalidHandle)?;
+        let id = (std::process::id() as u64) << 32 | NEXT_COMM_ID.fetch_add(1, Ordering::Relaxed);
+        let opts = &self.inner.opts;
+        let nsocks = opts.nthreads * opts.nsocks_per_thread;
+        let socks = (0..nsocks)
+            .map(|idx| {
+                let mut sock = TcpStream::connect(addr)?;
+                sock.set_nodelay(true)?;
+                sock.write_all(&hello(id, idx, nsocks))?;
+                Ok(sock)
+            })
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+        let conn = Connection::new(socks, opts.nthreads, true)?;
+        Ok(Some(Comm(CommType::Send(conn))))
+    }
+
+    fn accept(&self, comm: &Comm) -> Result<Option<Comm>, Error> {
+        let CommType::Listen(listener) = &comm.0 else {
+            return Err(Error::InvalidUsage);
+        };
+        let mut pending = listener.pending.lock().unwrap();
+        loop {
+            let (mut sock, peer) = match listener.sock.accept() {
+                Ok(v) => v,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            sock.set_nonblocking(false)?;
+            sock.set_nodelay(true)?;
+            let (id, idx, nsocks) = match read_hello(&mut sock) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("tcp: dropped a connection from {}: {}", peer, e);
+                    continue;
+                }
+            };
+            let key = (peer.ip(), id);
+            let socks = pending
+                .entry(key)
+                .or_insert_with(|| (0..nsocks).map(|_| None).collect());
+            if socks.len() != nsocks || socks[idx].is_some() {
+                warn!("tcp: dropped a connection from {}: invalid hello", peer);
+                continue;
+            }
+            socks[idx] = Some(sock);
+            if socks.iter().all(|v| v.is_some()) {
+                let socks = pending
+                    .remove(&key)
+                    .unwrap()
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                let conn = Connection::new(socks, self.inner.opts.nthreads, false)?;
+                return Ok(Some(Comm(CommType::Recv(conn))));
+            }
+        }
+    }
+
+    fn reg_mr<'a, T>(&self, comm: &'a Comm, _data: &[T]) -> Result<MemoryHandle<'a>, Error> {
+        match comm.0 {
+            CommType::Listen(_) => Err(Error::InvalidUsage),
+            _ => Ok(MemoryHandle(PhantomData)),
+        }
+    }
+
+    fn isend<T>(
+        &self,
+        comm: &Comm,
+        _mhandle: &MemoryHandle,
+        data: &[T],
+        tag: i32,
+    ) -> Result<Option<Request>, Error> {
+        let CommType::Send(conn) = &comm.0 else {
+            return Err(Error::InvalidUsage);
+        };
+        let shared = &conn.shared;
+        if shared.broken.load(Ordering::Acquire) {
+            return Err(Error::Closed);
+        }
+        if shared.inflight.load(Ordering::Relaxed) >= MAX_REQUESTS {
+            return Ok(None);
+        }
+        let size = std::mem::size_of_val(data);
+        let (nparts, chunk) = split(size, shared.nsocks);
+        let state = Arc::new(RequestState::new(nparts, size));
+        let mut header = [0u8; HEADER_SIZE];
+        header[..4].copy_from_slice(&tag.to_le_bytes());
+        header[4..].copy_from_slice(&(size as u64).to_le_bytes());
+        for i in 0..nparts {
+            let range = part(size, chunk, i);
+            let part = Part {
+                socket: i / shared.nthreads,
+                header: (i == 0).then_some(header),
+                data: unsafe { (data.as_ptr() as *const u8).add(range.start) },
+                len: range.len(),
+                state: state.clone(),
+            };
+            conn.parts[i % shared.nthreads]
+                .send(part)
+                .map_err(|_| Error::Closed)?;
+        }
+        shared.inflight.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(Request {
+            state,
+            shared: shared.clone(),
+        }))
+    }
+
+    fn irecv<T>(
+        &self,
+        comm: &Comm,
+        _mhandle: &MemoryHandle,
+        data: &mut [T],
+        tag: i32,
+    ) -> Result<Option<Request>, Error> {
+        let CommType::Recv(conn) = &comm.0 else {
+            return Err(Error::InvalidUsage);
+        };
+        let shared = &conn.shared;
+        if shared.broken.load(Ordering::Acquire) {
+            return Err(Error::Closed);
+        }
+        if shared.inflight.load(Ordering::Relaxed) >= MAX_REQUESTS {
+            return Ok(None);
+        }
+        let state = Arc::new(RequestState::new(1, 0));
+        let posted = Posted {
+            tag,
+            buf: data.as_mut_ptr() as *mut u8,
+            len: std::mem::size_of_val(data),
+            state: state.clone(),
+        };
+        shared.matching.lock().unwrap().posted.push_back(posted);
+        shared.cond.notify_all();
+        shared.inflight.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(Request {
+            state,
+            shared: shared.clone(),
+        }))
+    }
+
+    fn test(&self, request: &Request) -> Result<(bool, usize), Error> {
+        let state = &request.state;
+        if let Some(e) = state.error.lock().unwrap().clone() {
+            return Err(e);
+        }
+        if state.pending.load(Ordering::Acquire) == 0 {
+            return Ok((true, state.size.load(Ordering::Relaxed)));
+        }
+        if request.shared.broken.load(Ordering::Acquire) {
+            return Err(Error::Closed);
+        }
+        Ok((false, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(nthreads: usize, nsocks_per_thread: usize) -> Tcp {
+        Tcp::new(Options {
+            ifname: "lo".to_string(),
+            nthreads,
+            nsocks_per_thread,
+        })
+        .unwrap()
+    }
+
+    fn connect(net: &Tcp) -> (Comm, Comm) {
+        let (lcomm, handle) = net.listen(0).unwrap();
+        let scomm = net.connect(0, &handle).unwrap().unwrap();
+        let rcomm = loop {
+            if let Some(comm) = net.accept(&lcomm).unwrap() {
+                break comm;
+            }
+        };
+        (scomm, rcomm)
+    }
+
+    fn wait(net: &Tcp, req: &Request) -> Result<usize, Error> {
+        loop {
+            let (done, size) = net.test(req)?;
+            if done {
+                return Ok(size);
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_tcp_split() {
+        assert_eq!(split(0, 4), (1, 1));
+        assert_eq!(split(100, 4), (1, 100));
+        assert_eq!(split(MIN_CHUNK + 1, 4), (2, MIN_CHUNK / 2 + 1));
+        assert_eq!(split(MIN_CHUNK * 16, 4), (4, MIN_CHUNK * 4));
+        assert_eq!(split(MIN_CHUNK * 16 + 1, 1), (1, MIN_CHUNK * 16 + 1));
+        for size in [0, 1, MIN_CHUNK - 1, 3 * MIN_CHUNK + 5, 1 << 24] {
+            for nsocks in [1, 3, 8] {
+                let (nparts, chunk) = split(size, nsocks);
+                assert!(nparts <= nsocks);
+                let parts = (0..nparts)
+                    .map(|i| part(size, chunk, i))
+                    .collect::<Vec<_>>();
+                assert_eq!(parts.iter().map(|v| v.len()).sum::<usize>(), size);
+                assert!(parts.iter().skip(1).all(|v| !v.is_empty()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_tcp_ifname() {
+        assert!(matches_ifname("eth", "eth0"));
+        assert!(!matches_ifname("eth", "ib0"));
+        assert!(matches_ifname("ib,eth", "eth1"));
+        assert!(!matches_ifname("=eth", "eth0"));
+        assert!(matches_ifname("=eth0,eth1", "eth1"));
+        assert!(!matches_ifname("^docker,lo", "lo"));
+        assert!(matches_ifname("^docker,lo", "eth0"));
+        assert!(!matches_ifname("^=eth0", "eth0"));
+        assert!(matches_ifname("^=eth0", "eth01"));
+    }
+
+    #[test]
+    fn test_tcp_send_recv() {
+        for (nthreads, nsocks_per_thread) in [(1, 1), (2, 1), (2, 3)] {
+            let net = tcp(nthreads, nsocks_per_thread);
+            let (scomm, rcomm) = connect(&net);
+            let (smh, rmh) = (
+                net.reg_mr(&scomm, &[0u8]).unwrap(),
+                net.reg_mr(&rcomm, &[0u8]).unwrap(),
+            );
+            for size in [0, 7, MIN_CHUNK * 5 + 3, 1 << 22] {
+                let data = (0..size).map(|i| (i * 7 + size) as u8).collect::<Vec<_>>();
+                let mut buf = vec![0u8; size + 16];
+                let rreq = net.irecv(&rcomm, &rmh, &mut buf, 1).unwrap().unwrap();
+                let sreq = net.isend(&scomm, &smh, &data, 1).unwrap().unwrap();
+                assert_eq!(wait(&net, &sreq), Ok(size));
+                assert_eq!(wait(&net, &rreq), Ok(size));
+                assert_eq!(&buf[..size], &data[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tcp_tags() {
+        let net = tcp(2, 2);
+        let (scomm, rcomm) = connect(&net);
+        let (smh, rmh) = (
+            net.reg_mr(&scomm, &[0u8]).unwrap(),
+            net.reg_mr(&rcomm, &[0u8]).unwrap(),
+        );
+        let data = [
+            vec![1u32; 1 << 16],
+            vec![2u32; 1 << 16],
+            vec![3u32; 1 << 16],
+        ];
+        let sreqs = [(0, 5), (1, 6), (2, 5)]
+            .iter()
+            .map(|(i, tag)| net.isend(&scomm, &smh, &data[*i], *tag).unwrap().unwrap())
+            .collect::<Vec<_>>();
+        // a message waits for a receive of its tag, and the receives of a tag are matched in order
+        let mut bufs = vec![vec![0u32; 1 << 16]; 3];
+        let mut it = bufs.iter_mut();
+        let (b6, b5, b5_) = (it.next().unwrap(), it.next().unwrap(), it.next().unwrap());
+        let r6 = net.irecv(&rcomm, &rmh, b6, 6).unwrap().unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(net.test(&r6), Ok((false, 0)));
+        let r5 = net.irecv(&rcomm, &rmh, b5, 5).unwrap().unwrap();
+        let r5_ = net.irecv(&rcomm, &rmh, b5_, 5).unwrap().unwrap();
+        for req in [&r6, &r5, &r5_] {
+            assert_eq!(wait(&net, req), Ok(1 << 18));
+        }
+        for req in &sreqs {
+            assert_eq!(wait(&net, req), Ok(1 << 18));
+        }
+        drop((r6, r5, r5_));
+        assert_eq!(bufs[0][0], 2);
+        assert_eq!(bufs[1][0], 1);
+        assert_eq!(bufs[2][0], 3);
+    }
+
+    #[test]
+    fn test_tcp_errors() {
+        let net = tcp(2, 1);
+        assert_eq!(net.listen(1).err(), Some(Error::InvalidDevice(1)));
+        assert_eq!(net.connect(0, &Handle::from(b"garbage".to_vec())).err(), Some(Error::InvalidHandle));
+        assert!(matches!(
+            Tcp::new(Options {
+                nthreads: 0,
+                ..Default::default()
+            }),
+            Err(Error::InvalidOptions(_))
+        ));
+        assert!(matches!(
+            Tcp::new(Options {
+                ifname: "=nonexistent".to_string(),
+                ..Default::default()
+            }),
+            Err(Error::NoDevice(_))
+        ));
+
+        let (scomm, rcomm) = connect(&net);
+        let (smh, rmh) = (
+            net.reg_mr(&scomm, &[0u8]).unwrap(),
+            net.reg_mr(&rcomm, &[0u8]).unwrap(),
+        );
+        assert!(net.isend(&rcomm, &rmh, &[0u8], 0).is_err());
+        assert!(net.irecv(&scomm, &smh, &mut [0u8], 0).is_err());
+
+        // a truncated message fails its receive but leaves the comm usable
+        let data = vec![1u8; MIN_CHUNK * 3];
+        let mut buf = vec![0u8; MIN_CHUNK];
+        let sreq = net.isend(&scomm, &smh, &data, 0).unwrap().unwrap();
+        let rreq = net.irecv(&rcomm, &rmh, &mut buf, 0).unwrap().unwrap();
+        assert_eq!(
+            wait(&net, &rreq),
+            Err(Error::Truncated(MIN_CHUNK * 3, MIN_CHUNK))
+        );
+        assert_eq!(wait(&net, &sreq), Ok(MIN_CHUNK * 3));
+        let sreq = net.isend(&scomm, &smh, &data[..10], 0).unwrap().unwrap();
+        let rreq = net.irecv(&rcomm, &rmh, &mut buf, 0).unwrap().unwrap();
+        assert_eq!(wait(&net, &rreq), Ok(10));
+        assert_eq!(wait(&net, &sreq), Ok(10));
+
+        // so does a closed peer, for the receives in flight and the ones after
+        let rreq = net.irecv(&rcomm, &rmh, &mut buf, 0).unwrap().unwrap();
+        drop(sreq);
+        drop(scomm);
+        assert_eq!(wait(&net, &rreq), Err(Error::Closed));
+        assert_eq!(
+            net.irecv(&rcomm, &rmh, &mut buf, 0).err(),
+            Some(Error::Closed)
+        );
+    }
+
+    #[test]
+    fn test_tcp_max_requests() {
+        let net = tcp(1, 1);
+        let (scomm, rcomm) = connect(&net);
+        let rmh = net.reg_mr(&rcomm, &[0u8]).unwrap();
+        let mut bufs = [[0u8; 1]; MAX_REQUESTS + 1];
+        let mut it = bufs.iter_mut();
+        let mut reqs = (0..MAX_REQUESTS)
+            .map(|_| {
+                net.irecv(&rcomm, &rmh, it.next().unwrap(), 0)
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let last = it.next().unwrap();
+        assert!(net.irecv(&rcomm, &rmh, last, 0).unwrap().is_none());
+        reqs.pop();
+        assert!(net.irecv(&rcomm, &rmh, last, 0).unwrap().is_some());
+        drop(scomm);
+    }
+}
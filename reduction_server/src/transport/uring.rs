@@ -0,0 +1,1321 @@
+/*
+ * Copyright (c) 2024, the Optcast Authors. All rights reserved.
+ *
+ * See LICENSE for license information
+ */
+
+// a transport over TCP sockets driven by io_uring, for the links where the syscalls of the socket
+// plugin and of the tcp transport become the bottleneck. a comm is a socket with a ring of its
+// own. isend and irecv only queue their operations, and test submits whatever is queued with a
+// single io_uring_enter and reaps the completions from the shared memory of the ring, so polling a
+// comm whose requests are in flight costs no syscall at all.
+//
+// the sends of a comm go in batches: the header and the data of every queued message are linked
+// into one chain, which keeps them in order on the socket, and the next batch is submitted once
+// the chain is done. the buffers registered with reg_mr are registered with the ring, so that the
+// data of the messages in them is sent with SEND_ZC from the fixed buffer, without pinning the
+// pages again nor copying them. receives can't use fixed buffers on a socket, they are RECV with
+// MSG_WAITALL so that a message completes in one go.
+//
+// like the tcp transport, a message is matched with the earliest receive posted with its tag, and
+// the devices are the interfaces of --socket-ifname.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{info, warn};
+
+use super::tcp::{select_devices, Device, Error};
+use super::{Handle, Properties, Transport};
+
+const ENTRIES: u32 = 128;
+// requests in flight per comm, isend and irecv return None beyond. a send takes at most two
+// entries of the submission queue and three of the completion queue.
+const MAX_REQUESTS: usize = 32;
+// buffers registered per comm
+const MAX_BUFFERS: usize = 1024;
+// smaller messages are copied, the notification of a zero copy send costs more than the copy
+const ZC_MIN: usize = 16 * 1024;
+// a truncated message is drained in parts of this size
+const DRAIN_SIZE: usize = 64 * 1024;
+
+const HELLO_MAGIC: u32 = 0x4752_554f; // "OURG"
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+const HEADER_SIZE: usize = 12;
+
+// the parts of linux/io_uring.h that libc doesn't have
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x800_0000;
+const IORING_OFF_SQES: i64 = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+const IOSQE_IO_LINK: u8 = 1 << 2;
+const IORING_CQE_F_MORE: u32 = 1 << 1;
+const IORING_RECVSEND_FIXED_BUF: u16 = 1 << 2;
+const IORING_ASYNC_CANCEL_ALL: u32 = 1 << 0;
+const IORING_ASYNC_CANCEL_FD: u32 = 1 << 1;
+const IORING_RSRC_REGISTER_SPARSE: u32 = 1 << 0;
+const IO_URING_OP_SUPPORTED: u16 = 1 << 0;
+
+const IORING_OP_ASYNC_CANCEL: u8 = 14;
+const IORING_OP_SEND: u8 = 26;
+const IORING_OP_RECV: u8 = 27;
+const IORING_OP_SEND_ZC: u8 = 47;
+
+const IORING_REGISTER_PROBE: u32 = 8;
+const IORING_REGISTER_BUFFERS2: u32 = 15;
+const IORING_REGISTER_BUFFERS_UPDATE: u32 = 16;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    file_index: u32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct RsrcRegister {
+    nr: u32,
+    flags: u32,
+    resv2: u64,
+    data: u64,
+    tags: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct RsrcUpdate2 {
+    offset: u32,
+    resv: u32,
+    data: u64,
+    tags: u64,
+    nr: u32,
+    resv2: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct ProbeOp {
+    op: u8,
+    resv: u8,
+    flags: u16,
+    resv2: u32,
+}
+
+#[repr(C)]
+struct Probe {
+    last_op: u8,
+    ops_len: u8,
+    resv: u16,
+    resv2: [u32; 3],
+    ops: [ProbeOp; 256],
+}
+
+impl Sqe {
+    fn new(opcode: u8, fd: RawFd, addr: *const u8, len: usize) -> Self {
+        Sqe {
+            opcode,
+            fd,
+            addr: addr as u64,
+            len: len as u32,
+            ..Default::default()
+        }
+    }
+}
+
+fn last_os_error(what: &str) -> std::io::Error {
+    let e = std::io::Error::last_os_error();
+    std::io::Error::new(e.kind(), format!("{}: {}", what, e))
+}
+
+#[derive(Debug)]
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: i64) -> std::io::Result<Mmap> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(last_os_error("mmap"));
+        }
+        Ok(Mmap {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+// an io_uring instance. the submission queue is filled by push and handed to the kernel by
+// submit, the completion queue is read by pop without entering the kernel.
+#[derive(Debug)]
+struct Ring {
+    // the mappings go before the fd, they are unmapped first
+    sq_ring: Mmap,
+    cq_ring: Mmap,
+    sqes: Mmap,
+    fd: OwnedFd,
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+    sq_entries: u32,
+    // the tail of the submission queue, and the entries pushed since the last submit
+    tail: u32,
+    queued: u32,
+}
+
+impl Ring {
+    fn new(entries: u32) -> std::io::Result<Ring> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(last_os_error("io_uring_setup"));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let (sq_off, cq_off) = (params.sq_off, params.cq_off);
+        let sq_len = sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = cq_off.cqes as usize + params.cq_entries as usize * 16;
+        let sq_ring = Mmap::new(fd.as_raw_fd(), sq_len, IORING_OFF_SQ_RING)?;
+        let cq_ring = Mmap::new(fd.as_raw_fd(), cq_len, IORING_OFF_CQ_RING)?;
+        let sqes = Mmap::new(
+            fd.as_raw_fd(),
+            params.sq_entries as usize * 64,
+            IORING_OFF_SQES,
+        )?;
+        // the entries are used in the order of the queue
+        let array = sq_ring.at::<u32>(sq_off.array);
+        for i in 0..params.sq_entries {
+            unsafe { *array.add(i as usize) = i };
+        }
+        let tail = unsafe { (*sq_ring.at::<AtomicU32>(sq_off.tail)).load(Ordering::Acquire) };
+        Ok(Ring {
+            sq_ring,
+            cq_ring,
+            sqes,
+            fd,
+            sq_off,
+            cq_off,
+            sq_entries: params.sq_entries,
+            tail,
+            queued: 0,
+        })
+    }
+
+    fn sq(&self, offset: u32) -> &AtomicU32 {
+        unsafe { &*self.sq_ring.at::<AtomicU32>(offset) }
+    }
+
+    fn cq(&self, offset: u32) -> &AtomicU32 {
+        unsafe { &*self.cq_ring.at::<AtomicU32>(offset) }
+    }
+
+    fn space(&self) -> u32 {
+        let head = self.sq(self.sq_off.head).load(Ordering::Acquire);
+        self.sq_entries - self.tail.wrapping_sub(head)
+    }
+
+    fn push(&mut self, sqe: Sqe) {
+        // the callers keep their entries in flight below the size of the queues
+        assert!(self.space() > 0, "io_uring submission queue overflow");
+        let mask = unsafe { *self.sq_ring.at::<u32>(self.sq_off.ring_mask) };
+        unsafe { *self.sqes.at::<Sqe>(0).add((self.tail & mask) as usize) = sqe };
+        self.tail = self.tail.wrapping_add(1);
+        self.sq(self.sq_off.tail)
+            .store(self.tail, Ordering::Release);
+        self.queued += 1;
+    }
+
+    // submits the queued entries and waits for min_complete completions
+    fn submit(&mut self, min_complete: u32) -> std::io::Result<()> {
+        if self.queued == 0 && min_complete == 0 {
+            return Ok(());
+        }
+        let flags = match min_complete {
+            0 => 0,
+            _ => IORING_ENTER_GETEVENTS,
+        };
+        loop {
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    self.queued,
+                    min_complete,
+                    flags,
+                    std::ptr::null::<libc::sigset_t>(),
+                    0,
+                )
+            };
+            if ret >= 0 {
+                self.queued -= ret as u32;
+                return Ok(());
+            }
+            let e = std::io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // the completion queue is full, the entries are submitted after the next pops
+                Some(libc::EBUSY) | Some(libc::EAGAIN) => return Ok(()),
+                _ => return Err(last_os_error("io_uring_enter")),
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Cqe> {
+        let head = self.cq(self.cq_off.head).load(Ordering::Relaxed);
+        if head == self.cq(self.cq_off.tail).load(Ordering::Acquire) {
+            return None;
+        }
+        let mask = unsafe { *self.cq_ring.at::<u32>(self.cq_off.ring_mask) };
+        let cqe = unsafe {
+            *self
+                .cq_ring
+                .at::<Cqe>(self.cq_off.cqes)
+                .add((head & mask) as usize)
+        };
+        self.cq(self.cq_off.head)
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(cqe)
+    }
+
+    fn register<T>(&self, opcode: u32, arg: &T, nr: usize) -> std::io::Result<i64> {
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.fd.as_raw_fd(),
+                opcode,
+                arg as *const T,
+                nr as u32,
+            )
+        };
+        if ret < 0 {
+            return Err(last_os_error("io_uring_register"));
+        }
+        Ok(ret)
+    }
+
+    // sets the buffer at idx in the table of registered buffers, or clears it when len is 0
+    fn update_buffer(&self, idx: usize, addr: usize, len: usize) -> std::io::Result<()> {
+        let iov = libc::iovec {
+            iov_base: addr as *mut libc::c_void,
+            iov_len: len,
+        };
+        let update = RsrcUpdate2 {
+            offset: idx as u32,
+            data: &iov as *const libc::iovec as u64,
+            nr: 1,
+            ..Default::default()
+        };
+        let size = std::mem::size_of::<RsrcUpdate2>();
+        self.register(IORING_REGISTER_BUFFERS_UPDATE, &update, size)
+            .map(|_| ())
+    }
+
+    fn supports(&self, opcode: u8) -> bool {
+        let mut probe = Box::new(Probe {
+            last_op: 0,
+            ops_len: 0,
+            resv: 0,
+            resv2: [0; 3],
+            ops: [ProbeOp::default(); 256],
+        });
+        if self
+            .register(IORING_REGISTER_PROBE, probe.as_mut(), 256)
+            .is_err()
+        {
+            return false;
+        }
+        opcode <= probe.last_op && probe.ops[opcode as usize].flags & IO_URING_OP_SUPPORTED != 0
+    }
+}
+
+// the state of a request, updated by whichever test progresses its comm
+#[derive(Debug, Default)]
+struct RequestState {
+    // completions not reaped yet
+    pending: usize,
+    done: bool,
+    size: usize,
+    error: Option<Error>,
+}
+
+type State = Arc<Mutex<RequestState>>;
+
+fn fail(state: &State, e: Error) {
+    state.lock().unwrap().error.get_or_insert(e);
+}
+
+// the error of a completion that transferred res bytes out of len
+fn error(res: i32, len: usize) -> Option<Error> {
+    match res {
+        _ if res >= 0 && res as usize == len => None,
+        // a short transfer is the peer closing the connection, and a cancelled operation was
+        // linked to a failed one
+        0.. => Some(Error::Closed),
+        _ if [libc::ECANCELED, libc::EPIPE, libc::ECONNRESET].contains(&-res) => {
+            Some(Error::Closed)
+        }
+        _ => Some(std::io::Error::from_raw_os_error(-res).into()),
+    }
+}
+
+#[derive(Debug)]
+struct SendOp {
+    header: Box<[u8; HEADER_SIZE]>,
+    data: *const u8,
+    len: usize,
+    // the index of the registered buffer the data is in
+    fixed: Option<u16>,
+    state: State,
+}
+
+#[derive(Debug)]
+struct RecvOp {
+    tag: i32,
+    buf: *mut u8,
+    len: usize,
+    state: State,
+}
+
+// an operation submitted to the ring, by its user_data
+#[derive(Debug)]
+enum Op {
+    // the header or the data of a message, notif is set once a zero copy send waits for the kernel
+    // to release its buffer
+    Send {
+        _header: Option<Box<[u8; HEADER_SIZE]>>,
+        len: usize,
+        notif: bool,
+        state: State,
+    },
+    // a header read into Io::header
+    Header,
+    Recv(RecvOp, usize),
+    // the rest of a truncated message still to read after this part
+    Drain(RecvOp, usize, usize),
+    Cancel,
+}
+
+#[derive(Debug)]
+struct Io {
+    ring: Ring,
+    ops: HashMap<u64, Op>,
+    next: u64,
+    // the buffers registered with the ring by their index, empty if the kernel doesn't support it
+    buffers: Vec<Option<(usize, usize)>>,
+    requests: usize,
+    sends: VecDeque<SendOp>,
+    // the entries of the batch of sends in flight
+    sending: usize,
+    posted: VecDeque<RecvOp>,
+    // the entries of the receives in flight
+    reading: usize,
+    header: Box<[u8; HEADER_SIZE]>,
+    // the tag and the size of the message whose header was read, until it's matched
+    message: Option<(i32, usize)>,
+    scratch: Vec<u8>,
+    // set once the socket fails or the peer is gone
+    broken: bool,
+    // set once the comm is dropped
+    closed: bool,
+}
+
+unsafe impl Send for Io {}
+
+impl Io {
+    fn push(&mut self, mut sqe: Sqe, op: Op) {
+        sqe.user_data = self.next;
+        self.ring.push(sqe);
+        self.ops.insert(self.next, op);
+        self.next += 1;
+    }
+
+    // fails the requests that aren't in flight once the socket is unusable
+    fn close(&mut self) {
+        self.broken = true;
+        let states = self
+            .sends
+            .drain(..)
+            .map(|op| op.state)
+            .chain(self.posted.drain(..).map(|op| op.state));
+        for state in states {
+            fail(&state, Error::Closed);
+        }
+    }
+
+    // submits the queued messages in one chain, once the previous one is done
+    fn prepare_sends(&mut self, fd: RawFd) {
+        if self.sending > 0 || self.broken || self.closed {
+            return;
+        }
+        while let Some(op) = self.sends.pop_front() {
+            let flags = libc::MSG_WAITALL | libc::MSG_NOSIGNAL;
+            let mut sqe = Sqe::new(IORING_OP_SEND, fd, op.header.as_ptr(), HEADER_SIZE);
+            sqe.op_flags = flags as u32;
+            sqe.flags = IOSQE_IO_LINK;
+            let mut state = op.state.lock().unwrap();
+            state.pending = 1;
+            let header = Op::Send {
+                _header: Some(op.header),
+                len: HEADER_SIZE,
+                notif: false,
+                state: op.state.clone(),
+            };
+            self.push(sqe, header);
+            if op.len > 0 {
+                let mut sqe = Sqe::new(IORING_OP_SEND, fd, op.data, op.len);
+                sqe.op_flags = flags as u32;
+                sqe.flags = IOSQE_IO_LINK;
+                if let Some(idx) = op.fixed {
+                    sqe.opcode = IORING_OP_SEND_ZC;
+                    sqe.ioprio = IORING_RECVSEND_FIXED_BUF;
+                    sqe.buf_index = idx;
+                }
+                state.pending += 1;
+                let data = Op::Send {
+                    _header: None,
+                    len: op.len,
+                    notif: false,
+                    state: op.state.clone(),
+                };
+                self.push(sqe, data);
+                self.sending += 1;
+            }
+            self.sending += 1;
+        }
+        // the chain ends with the batch
+        if self.sending > 0 {
+            let mask = unsafe { *self.ring.sq_ring.at::<u32>(self.ring.sq_off.ring_mask) };
+            let last = self.ring.tail.wrapping_sub(1) & mask;
+            unsafe { (*self.ring.sqes.at::<Sqe>(0).add(last as usize)).flags &= !IOSQE_IO_LINK };
+        }
+    }
+
+    // reads the next header, or the message whose header was read once a receive matches it
+    fn prepare_recv(&mut self, fd: RawFd) {
+        if self.reading > 0 || self.broken || self.closed {
+            return;
+        }
+        let header = |io: &mut Io| {
+            let ptr = io.header.as_mut_ptr();
+            let mut sqe = Sqe::new(IORING_OP_RECV, fd, ptr, HEADER_SIZE);
+            sqe.op_flags = libc::MSG_WAITALL as u32;
+            io.push(sqe, Op::Header);
+            io.reading += 1;
+        };
+        let Some((tag, size)) = self.message else {
+            return header(self);
+        };
+        let Some(idx) = self.posted.iter().position(|op| op.tag == tag) else {
+            return;
+        };
+        let op = self.posted.remove(idx).unwrap();
+        self.message = None;
+        if size == 0 {
+            let mut state = op.state.lock().unwrap();
+            state.done = true;
+            state.size = 0;
+            drop(state);
+            return header(self);
+        }
+        if size > op.len {
+            op.state.lock().unwrap().size = size;
+            return self.drain(fd, op, size);
+        }
+        // the next header is read right after the data
+        let mut sqe = Sqe::new(IORING_OP_RECV, fd, op.buf, size);
+        sqe.op_flags = libc::MSG_WAITALL as u32;
+        sqe.flags = IOSQE_IO_LINK;
+        self.push(sqe, Op::Recv(op, size));
+        self.reading += 1;
+        header(self);
+    }
+
+    // reads left bytes of a truncated message into the scratch buffer
+    fn drain(&mut self, fd: RawFd, op: RecvOp, left: usize) {
+        self.scratch.resize(DRAIN_SIZE, 0);
+        let len = left.min(DRAIN_SIZE);
+        let mut sqe = Sqe::new(IORING_OP_RECV, fd, self.scratch.as_mut_ptr(), len);
+        sqe.op_flags = libc::MSG_WAITALL as u32;
+        self.push(sqe, Op::Drain(op, len, left - len));
+        self.reading += 1;
+    }
+
+    fn complete(&mut self, fd: RawFd, cqe: Cqe) {
+        let Some(op) = self.ops.remove(&cqe.user_data) else {
+            return;
+        };
+        match op {
+            Op::Send {
+                _header,
+                len,
+                notif,
+                state,
+            } => {
+                if !notif {
+                    self.sending -= 1;
+                    if let Some(e) = error(cqe.res, len) {
+                        fail(&state, e);
+                        self.close();
+                    }
+                    // the buffer of a zero copy send is released later
+                    if cqe.flags & IORING_CQE_F_MORE != 0 {
+                        let op = Op::Send {
+                            _header,
+                            len,
+                            notif: true,
+                            state,
+                        };
+                        self.ops.insert(cqe.user_data, op);
+                        return;
+                    }
+                }
+                let mut state = state.lock().unwrap();
+                state.pending -= 1;
+                state.done = state.pending == 0;
+            }
+            Op::Header => {
+                self.reading -= 1;
+                match error(cqe.res, HEADER_SIZE) {
+                    None => {
+                        let tag = i32::from_le_bytes(self.header[..4].try_into().unwrap());
+                        let size = u64::from_le_bytes(self.header[4..].try_into().unwrap());
+                        self.message = Some((tag, size as usize));
+                    }
+                    Some(e) => {
+                        // the peer closes the socket after its last message
+                        if cqe.res != 0 && cqe.res != -libc::ECANCELED && !self.closed {
+                            warn!("uring: failed to receive a header: {}", e);
+                        }
+                        self.close();
+                    }
+                }
+            }
+            Op::Recv(op, size) => {
+                self.reading -= 1;
+                match error(cqe.res, size) {
+                    None => {
+                        let mut state = op.state.lock().unwrap();
+                        state.done = true;
+                        state.size = size;
+                    }
+                    Some(e) => {
+                        fail(&op.state, e);
+                        self.close();
+                    }
+                }
+            }
+            Op::Drain(op, len, left) => {
+                self.reading -= 1;
+                if let Some(e) = error(cqe.res, len) {
+                    fail(&op.state, e);
+                    self.close();
+                } else if left > 0 {
+                    self.drain(fd, op, left);
+                } else {
+                    let size = op.state.lock().unwrap().size;
+                    fail(&op.state, Error::Truncated(size, op.len));
+                }
+            }
+            Op::Cancel => (),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Conn {
+    sock: TcpStream,
+    io: Mutex<Io>,
+}
+
+impl Conn {
+    fn new(sock: TcpStream) -> Result<Self, Error> {
+        sock.set_nodelay(true)?;
+        let ring = Ring::new(ENTRIES)?;
+        // a sparse table of buffers, filled by reg_mr
+        let table = RsrcRegister {
+            nr: MAX_BUFFERS as u32,
+            flags: IORING_RSRC_REGISTER_SPARSE,
+            ..Default::default()
+        };
+        let size = std::mem::size_of::<RsrcRegister>();
+        let buffers = match ring.register(IORING_REGISTER_BUFFERS2, &table, size) {
+            Ok(_) => vec![None; MAX_BUFFERS],
+            Err(e) => {
+                info!("uring: buffers can't be registered: {}", e);
+                Vec::new()
+            }
+        };
+        Ok(Conn {
+            sock,
+            io: Mutex::new(Io {
+                ring,
+                ops: HashMap::new(),
+                next: 0,
+                buffers,
+                requests: 0,
+                sends: VecDeque::new(),
+                sending: 0,
+                posted: VecDeque::new(),
+                reading: 0,
+                header: Box::new([0; HEADER_SIZE]),
+                message: None,
+                scratch: Vec::new(),
+                broken: false,
+                closed: false,
+            }),
+        })
+    }
+
+    // submits the queued operations and reaps the completions, until the completions don't make
+    // anything else ready to submit
+    fn progress(&self, io: &mut Io) -> Result<(), Error> {
+        let fd = self.sock.as_raw_fd();
+        loop {
+            io.prepare_sends(fd);
+            io.prepare_recv(fd);
+            if let Err(e) = io.ring.submit(0) {
+                io.close();
+                return Err(e.into());
+            }
+            let mut reaped = false;
+            while let Some(cqe) = io.ring.pop() {
+                io.complete(fd, cqe);
+                reaped = true;
+            }
+            if !reaped {
+                return Ok(());
+            }
+        }
+    }
+
+    // registers the buffer with the ring, returns its index in the table
+    fn register(&self, addr: usize, len: usize) -> Result<u16, std::io::Error> {
+        let mut io = self.io.lock().unwrap();
+        let Some(idx) = io.buffers.iter().position(|v| v.is_none()) else {
+            return Err(std::io::Error::other("the buffer table is full"));
+        };
+        io.ring.update_buffer(idx, addr, len)?;
+        io.buffers[idx] = Some((addr, len));
+        Ok(idx as u16)
+    }
+
+    // the operations in flight keep their own reference to the buffer
+    fn unregister(&self, idx: u16) {
+        let mut io = self.io.lock().unwrap();
+        io.buffers[idx as usize] = None;
+        if let Err(e) = io.ring.update_buffer(idx as usize, 0, 0) {
+            warn!("uring: failed to unregister a buffer: {}", e);
+        }
+    }
+
+    // the socket is shut down first, so that the operations in flight complete and no longer touch
+    // the buffers of the requests once the comm is gone. the zero copy sends that wait for the
+    // release of their buffer only read the pinned pages, they are left to the ring.
+    fn close(&self) {
+        let _ = self.sock.shutdown(Shutdown::Both);
+        let fd = self.sock.as_raw_fd();
+        let mut io = self.io.lock().unwrap();
+        io.closed = true;
+        let mut sqe = Sqe::new(IORING_OP_ASYNC_CANCEL, fd, std::ptr::null(), 0);
+        sqe.op_flags = IORING_ASYNC_CANCEL_ALL | IORING_ASYNC_CANCEL_FD;
+        io.push(sqe, Op::Cancel);
+        loop {
+            while let Some(cqe) = io.ring.pop() {
+                io.complete(fd, cqe);
+            }
+            let inflight = io.ops.values().any(|op| match op {
+                Op::Send { notif, .. } => !notif,
+                Op::Cancel => false,
+                _ => true,
+            });
+            if !inflight {
+                break;
+            }
+            if let Err(e) = io.ring.submit(1) {
+                warn!("uring: failed to wait for the operations in flight: {}", e);
+                break;
+            }
+        }
+        io.close();
+    }
+}
+
+#[derive(Debug)]
+enum CommType {
+    Listen(TcpListener),
+    Send(Arc<Conn>),
+    Recv(Arc<Conn>),
+}
+
+#[derive(Debug)]
+pub(crate) struct Comm(CommType);
+
+// the requests in flight keep the ring, but the socket is closed with the comm
+impl Drop for Comm {
+    fn drop(&mut self) {
+        if let CommType::Send(conn) | CommType::Recv(conn) = &self.0 {
+            conn.close();
+        }
+    }
+}
+
+impl Comm {
+    fn conn(&self) -> Result<&Arc<Conn>, Error> {
+        match &self.0 {
+            CommType::Send(conn) | CommType::Recv(conn) => Ok(conn),
+            CommType::Listen(_) => Err(Error::InvalidUsage),
+        }
+    }
+}
+
+// a buffer registered with the ring of a comm, or not if the table is full or the kernel doesn't
+// support it. the data is sent from it without copy.
+pub(crate) struct MemoryHandle<'a> {
+    conn: &'a Conn,
+    buf: Option<(u16, usize, usize)>,
+}
+
+impl Drop for MemoryHandle<'_> {
+    fn drop(&mut self) {
+        if let Some((idx, _, _)) = self.buf {
+            self.conn.unregister(idx);
+        }
+    }
+}
+
+impl MemoryHandle<'_> {
+    // the index of the buffer holding the data, if it's registered with conn
+    fn fixed(&self, conn: &Conn, data: *const u8, len: usize) -> Option<u16> {
+        let (idx, addr, size) = self.buf?;
+        let data = data as usize;
+        (std::ptr::eq(self.conn, conn) && data >= addr && data + len <= addr + size).then_some(idx)
+    }
+}
+
+pub(crate) struct Request {
+    conn: Arc<Conn>,
+    state: State,
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        self.conn.io.lock().unwrap().requests -= 1;
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    devices: Vec<Device>,
+    zc: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Uring {
+    inner: Arc<Inner>,
+}
+
+impl Uring {
+    pub(crate) fn new(ifname: &str) -> Result<Self, Error> {
+        let devices = select_devices(ifname)?;
+        // fails early where io_uring is missing or disabled
+        let ring = Ring::new(1)?;
+        let zc = ring.supports(IORING_OP_SEND_ZC);
+        for (dev, device) in devices.iter().enumerate() {
+            info!(
+                "uring: dev: {}, name: {}, addr: {}",
+                dev, device.name, device.addr
+            );
+        }
+        info!("uring: zero copy sends: {}", zc);
+        Ok(Uring {
+            inner: Arc::new(Inner { devices, zc }),
+        })
+    }
+
+    fn device(&self, dev: usize) -> Result<&Device, Error> {
+        self.inner.devices.get(dev).ok_or(Error::InvalidDevice(dev))
+    }
+
+    // a failure to submit breaks the comm, the request reports it when it's tested
+    fn request(&self, conn: &Arc<Conn>, io: &mut Io, state: State) -> Request {
+        io.requests += 1;
+        if let Err(e) = conn.progress(io) {
+            warn!("uring: {}", e);
+        }
+        Request {
+            conn: conn.clone(),
+            state,
+        }
+    }
+}
+
+fn read_hello(sock: &mut TcpStream) -> Result<(), Error> {
+    let mut buf = [0u8; 4];
+    sock.set_read_timeout(Some(HELLO_TIMEOUT))?;
+    sock.read_exact(&mut buf)?;
+    sock.set_read_timeout(None)?;
+    if u32::from_le_bytes(buf) != HELLO_MAGIC {
+        return Err(Error::InvalidHandle);
+    }
+    Ok(())
+}
+
+impl Transport for Uring {
+    type Error = Error;
+    type Comm = Comm;
+    type MemoryHandle<'a> = MemoryHandle<'a>;
+    type Request = Request;
+
+    fn devices(&self) -> Result<usize, Error> {
+        Ok(self.inner.devices.len())
+    }
+
+    // the buffers are registered with the ring of a comm
+    fn get_properties(&self, dev: usize) -> Result<Properties, Error> {
+        Ok(self.device(dev)?.properties(dev, false))
+    }
+
+    // the handle is the address the listen comm is bound to
+    fn listen(&self, dev: usize) -> Result<(Comm, Handle), Error> {
+        let device = self.device(dev)?;
+        let sock = TcpListener::bind((device.addr, 0))?;
+        sock.set_nonblocking(true)?;
+        let handle = Handle::from(sock.local_addr()?.to_string().into_bytes());
+        Ok((Comm(CommType::Listen(sock)), handle))
+    }
+
+    fn connect(&self, dev: usize, handle: &Handle) -> Result<Option<Comm>, Error> {
+        self.device(dev)?;
+        let addr = std::str::from_utf8(&handle.net)
+            .ok()
+            .and_then(|v| v.parse::<SocketAddr>().ok())
+            .ok_or(Error::InvalidHandle)?;
+        let mut sock = TcpStream::connect(addr)?;
+        sock.write_all(&HELLO_MAGIC.to_le_bytes())?;
+        let conn = Conn::new(sock)?;
+        Ok(Some(Comm(CommType::Send(Arc::new(conn)))))
+    }
+
+    fn accept(&self, comm: &Comm) -> Result<Option<Comm>, Error> {
+        let CommType::Listen(listener) = &comm.0 else {
+            return Err(Error::InvalidUsage);
+        };
+        loop {
+            let (mut sock, peer) = match listener.accept() {
+                Ok(v) => v,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            sock.set_nonblocking(false)?;
+            if let Err(e) = read_hello(&mut sock) {
+                warn!("uring: dropped a connection from {}: {}", peer, e);
+                continue;
+            }
+            let conn = Conn::new(sock)?;
+            return Ok(Some(Comm(CommType::Recv(Arc::new(conn)))));
+        }
+    }
+
+    fn reg_mr<'a, T>(&self, comm: &'a Comm, data: &[T]) -> Result<MemoryHandle<'a>, Error> {
+        let conn = comm.conn()?;
+        let (addr, len) = (data.as_ptr() as usize, std::mem::size_of_val(data));
+        let mut mhandle = MemoryHandle { conn, buf: None };
+        // only the data that is sent benefits from the registration
+        if !self.inner.zc || !matches!(comm.0, CommType::Send(_)) || len == 0 {
+            return Ok(mhandle);
+        }
+        if conn.io.lock().unwrap().buffers.is_empty() {
+            return Ok(mhandle);
+        }
+        match conn.register(addr, len) {
+            Ok(idx) => mhandle.buf = Some((idx, addr, len)),
+            Err(e) => warn!("uring: failed to register a buffer of {} bytes: {}", len, e),
+        }
+        Ok(mhandle)
+    }
+
+    fn isend<T>(
+        &self,
+        comm: &Comm,
+        mhandle: &MemoryHandle,
+        data: &[T],
+        tag: i32,
+    ) -> Result<Option<Request>, Error> {
+        let CommType::Send(conn) = &comm.0 else {
+            return Err(Error::InvalidUsage);
+        };
+        let mut io = conn.io.lock().unwrap();
+        if io.broken {
+            return Err(Error::Closed);
+        }
+        if io.requests >= MAX_REQUESTS {
+            return Ok(None);
+        }
+        let len = std::mem::size_of_val(data);
+        let data = data.as_ptr() as *const u8;
+        let mut header = Box::new([0u8; HEADER_SIZE]);
+        header[..4].copy_from_slice(&tag.to_le_bytes());
+        header[4..].copy_from_slice(&(len as u64).to_le_bytes());
+        let state = Arc::new(Mutex::new(RequestState {
+            size: len,
+            ..Default::default()
+        }));
+        io.sends.push_back(SendOp {
+            header,
+            data,
+            len,
+            fixed: mhandle.fixed(conn, data, len).filter(|_| len >= ZC_MIN),
+            state: state.clone(),
+        });
+        Ok(Some(self.request(conn, &mut io, state)))
+    }
+
+    fn irecv<T>(
+        &self,
+        comm: &Comm,
+        _mhandle: &MemoryHandle,
+        data: &mut [T],
+        tag: i32,
+    ) -> Result<Option<Request>, Error> {
+        let CommType::Recv(conn) = &comm.0 else {
+            return Err(Error::InvalidUsage);
+        };
+        let mut io = conn.io.lock().unwrap();
+        if io.broken {
+            return Err(Error::Closed);
+        }
+        if io.requests >= MAX_REQUESTS {
+            return Ok(None);
+        }
+        let state = State::default();
+        io.posted.push_back(RecvOp {
+            tag,
+            buf: data.as_mut_ptr() as *mut u8,
+            len: std::mem::size_of_val(data),
+            state: state.clone(),
+        });
+        Ok(Some(self.request(conn, &mut io, state)))
+    }
+
+    fn test(&self, request: &Request) -> Result<(bool, usize), Error> {
+        let mut io = request.conn.io.lock().unwrap();
+        let ret = request.conn.progress(&mut io);
+        let state = request.state.lock().unwrap();
+        if let Some(e) = state.error.clone() {
+            return Err(e);
+        }
+        if state.done {
+            return Ok((true, state.size));
+        }
+        ret?;
+        if io.broken {
+            return Err(Error::Closed);
+        }
+        Ok((false, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uring() -> Uring {
+        Uring::new("lo").unwrap()
+    }
+
+    fn connect(net: &Uring) -> (Comm, Comm) {
+        let (lcomm, handle) = net.listen(0).unwrap();
+        let scomm = net.connect(0, &handle).unwrap().unwrap();
+        let rcomm = loop {
+            if let Some(comm) = net.accept(&lcomm).unwrap() {
+                break comm;
+            }
+        };
+        (scomm, rcomm)
+    }
+
+    fn wait(net: &Uring, req: &Request) -> Result<usize, Error> {
+        wait_all(net, &[req]).pop().unwrap()
+    }
+
+    // a comm only progresses while its requests are tested, so the sends that don't fit in the
+    // socket buffers wait for their receives to be tested too
+    fn wait_all(net: &Uring, reqs: &[&Request]) -> Vec<Result<usize, Error>> {
+        let mut results = vec![None; reqs.len()];
+        while results.iter().any(|v| v.is_none()) {
+            for (req, result) in reqs.iter().zip(results.iter_mut()) {
+                if result.is_none() {
+                    *result = match net.test(req) {
+                        Ok((false, _)) => None,
+                        Ok((true, size)) => Some(Ok(size)),
+                        Err(e) => Some(Err(e)),
+                    };
+                }
+            }
+            std::thread::yield_now();
+        }
+        results.into_iter().flatten().collect()
+    }
+
+    #[test]
+    fn test_uring_send_recv() {
+        let net = uring();
+        let (scomm, rcomm) = connect(&net);
+        let rmh = net.reg_mr(&rcomm, &[0u8]).unwrap();
+        for size in [0, 7, ZC_MIN + 3, 1 << 22] {
+            let data = (0..size).map(|i| (i * 7 + size) as u8).collect::<Vec<_>>();
+            // the data is sent from a registered buffer or not
+            for registered in [false, true] {
+                let smh = match registered {
+                    true => net.reg_mr(&scomm, &data).unwrap(),
+                    false => net.reg_mr(&scomm, &[0u8]).unwrap(),
+                };
+                if registered && net.inner.zc && size > 0 {
+                    assert!(smh.buf.is_some());
+                }
+                let mut buf = vec![0u8; size + 16];
+                let rreq = net.irecv(&rcomm, &rmh, &mut buf, 1).unwrap().unwrap();
+                let sreq = net.isend(&scomm, &smh, &data, 1).unwrap().unwrap();
+                assert_eq!(wait_all(&net, &[&sreq, &rreq]), [Ok(size), Ok(size)]);
+                assert_eq!(&buf[..size], &data[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_uring_batch() {
+        let net = uring();
+        let (scomm, rcomm) = connect(&net);
+        let data = (0..MAX_REQUESTS)
+            .map(|i| vec![i as u32; (i + 1) * 4096])
+            .collect::<Vec<_>>();
+        let mut bufs = data.iter().map(|v| vec![0u32; v.len()]).collect::<Vec<_>>();
+        let mhs = data
+            .iter()
+            .map(|v| net.reg_mr(&scomm, v).unwrap())
+            .collect::<Vec<_>>();
+        let rmh = net.reg_mr(&rcomm, &[0u8]).unwrap();
+        // the sends queued while a batch is in flight go in the next one, in order
+        let sreqs = data
+            .iter()
+            .zip(&mhs)
+            .map(|(v, mh)| net.isend(&scomm, mh, v, 0).unwrap().unwrap())
+            .collect::<Vec<_>>();
+        let rreqs = bufs
+            .iter_mut()
+            .map(|v| net.irecv(&rcomm, &rmh, v, 0).unwrap().unwrap())
+            .collect::<Vec<_>>();
+        let results = wait_all(&net, &sreqs.iter().chain(&rreqs).collect::<Vec<_>>());
+        for (i, result) in results.iter().enumerate() {
+            assert_eq!(*result, Ok((i % MAX_REQUESTS + 1) * 4096 * 4));
+        }
+        drop(rreqs);
+        assert_eq!(bufs, data);
+    }
+
+    #[test]
+    fn test_uring_tags() {
+        let net = uring();
+        let (scomm, rcomm) = connect(&net);
+        let (smh, rmh) = (
+            net.reg_mr(&scomm, &[0u8]).unwrap(),
+            net.reg_mr(&rcomm, &[0u8]).unwrap(),
+        );
+        let data = [
+            vec![1u32; 1 << 16],
+            vec![2u32; 1 << 16],
+            vec![3u32; 1 << 16],
+        ];
+        let sreqs = [(0, 5), (1, 6), (2, 5)]
+            .iter()
+            .map(|(i, tag)| net.isend(&scomm, &smh, &data[*i], *tag).unwrap().unwrap())
+            .collect::<Vec<_>>();
+        // a message waits for a receive of its tag, and the receives of a tag are matched in order
+        let mut bufs = vec![vec![0u32; 1 << 16]; 3];
+        let mut it = bufs.iter_mut();
+        let (b6, b5, b5_) = (it.next().unwrap(), it.next().unwrap(), it.next().unwrap());
+        let r6 = net.irecv(&rcomm, &rmh, b6, 6).unwrap().unwrap();
+        for _ in 0..100 {
+            assert_eq!(net.test(&r6), Ok((false, 0)));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let r5 = net.irecv(&rcomm, &rmh, b5, 5).unwrap().unwrap();
+        let r5_ = net.irecv(&rcomm, &rmh, b5_, 5).unwrap().unwrap();
+        let results = wait_all(&net, &[&r6, &r5, &r5_, &sreqs[0], &sreqs[1], &sreqs[2]]);
+        assert!(results.iter().all(|v| *v == Ok(1 << 18)));
+        drop((r6, r5, r5_));
+        assert_eq!(bufs[0][0], 2);
+        assert_eq!(bufs[1][0], 1);
+        assert_eq!(bufs[2][0], 3);
+    }
+
+    #[test]
+    fn test_uring_errors() {
+        let net = uring();
+        assert_eq!(net.listen(1).err(), Some(Error::InvalidDevice(1)));
+        assert_eq!(net.connect(0, &Handle::from(b"garbage".to_vec())).err(), Some(Error::InvalidHandle));
+        assert!(matches!(
+            Uring::new("=nonexistent"),
+            Err(Error::NoDevice(_))
+        ));
+
+        let (lcomm, handle) = net.listen(0).unwrap();
+        assert!(net.reg_mr(&lcomm, &[0u8]).is_err());
+        // a peer that isn't a uring transport is dropped
+        let mut sock = TcpStream::connect(std::str::from_utf8(&handle.net).unwrap()).unwrap();
+        sock.write_all(b"OTCP").unwrap();
+        let scomm = net.connect(0, &handle).unwrap().unwrap();
+        let rcomm = loop {
+            if let Some(comm) = net.accept(&lcomm).unwrap() {
+                break comm;
+            }
+        };
+        let (smh, rmh) = (
+            net.reg_mr(&scomm, &[0u8]).unwrap(),
+            net.reg_mr(&rcomm, &[0u8]).unwrap(),
+        );
+        assert!(net.isend(&rcomm, &rmh, &[0u8], 0).is_err());
+        assert!(net.irecv(&scomm, &smh, &mut [0u8], 0).is_err());
+
+        // a truncated message fails its receive but leaves the comm usable
+        let data = vec![1u8; DRAIN_SIZE * 3];
+        let mut buf = vec![0u8; DRAIN_SIZE];
+        let sreq = net.isend(&scomm, &smh, &data, 0).unwrap().unwrap();
+        let rreq = net.irecv(&rcomm, &rmh, &mut buf, 0).unwrap().unwrap();
+        assert_eq!(
+            wait_all(&net, &[&rreq, &sreq]),
+            [
+                Err(Error::Truncated(DRAIN_SIZE * 3, DRAIN_SIZE)),
+                Ok(DRAIN_SIZE * 3)
+            ]
+        );
+        let sreq = net.isend(&scomm, &smh, &data[..10], 0).unwrap().unwrap();
+        let rreq = net.irecv(&rcomm, &rmh, &mut buf, 0).unwrap().unwrap();
+        assert_eq!(wait_all(&net, &[&rreq, &sreq]), [Ok(10), Ok(10)]);
+
+        // so does a closed peer, for the receives in flight and the ones after
+        let rreq = net.irecv(&rcomm, &rmh, &mut buf, 0).unwrap().unwrap();
+        drop(sreq);
+        drop(smh);
+        drop(scomm);
+        assert_eq!(wait(&net, &rreq), Err(Error::Closed));
+        assert_eq!(
+            net.irecv(&rcomm, &rmh, &mut buf, 0).err(),
+            Some(Error::Closed)
+        );
+    }
+
+    #[test]
+    fn test_uring_max_requests() {
+        let net = uring();
+        let (scomm, rcomm) = connect(&net);
+        let rmh = net.reg_mr(&rcomm, &[0u8]).unwrap();
+        let mut bufs = [[0u8; 1]; MAX_REQUESTS + 1];
+        let mut it = bufs.iter_mut();
+        let mut reqs = (0..MAX_REQUESTS)
+            .map(|_| {
+                net.irecv(&rcomm, &rmh, it.next().unwrap(), 0)
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let last = it.next().unwrap();
+        assert!(net.irecv(&rcomm, &rmh, last, 0).unwrap().is_none());
+        reqs.pop();
+        assert!(net.irecv(&rcomm, &rmh, last, 0).unwrap().is_some());
+        drop(scomm);
+    }
+}
@@ -5,15 +5,26 @@
  */
 
 use std::fmt::Debug;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use clap::{Parser, ValueEnum};
+use float8::{F8E4M3, F8E5M2};
 use half::{bf16, f16};
-use log::info;
+use log::{info, warn};
 use num_traits::FromPrimitive;
 
+use nccl_net_sys as ffi;
+
+use crate::reduce::Accumulate;
+use crate::transport::Transport;
+
 pub(crate) const NO_SPINLOOP_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
 
+// idle polls a progress thread spins, then yields, before it starts to sleep
+const BACKOFF_SPINS: u32 = 1 << 10;
+const BACKOFF_YIELDS: u32 = 1 << 14;
+const BACKOFF_SLEEP: Duration = Duration::from_micros(50);
+
 pub(crate) fn transpose<T>(v: Vec<Vec<T>>) -> Vec<Vec<T>> {
     assert!(!v.is_empty());
     let len = v[0].len();
@@ -33,6 +44,155 @@ pub(crate) enum DataType {
     F32,
     F16,
     BF16,
+    F64,
+    I8,
+    U8,
+    I32,
+    U32,
+    I64,
+    U64,
+    #[value(name = "f8e4m3")]
+    F8E4M3,
+    #[value(name = "f8e5m2")]
+    F8E5M2,
+}
+
+// ncclBfloat16 is only defined in nccl.h when CUDA bf16 types exist
+const NCCL_BFLOAT16: u32 = 9;
+// FP8 types were added in NCCL 2.24, after the nccl.h we build against
+const NCCL_FLOAT8E4M3: u32 = 10;
+const NCCL_FLOAT8E5M2: u32 = 11;
+
+impl DataType {
+    pub(crate) fn to_nccl(self) -> u32 {
+        match self {
+            DataType::F32 => ffi::ncclDataType_t::ncclFloat32,
+            DataType::F16 => ffi::ncclDataType_t::ncclFloat16,
+            DataType::BF16 => NCCL_BFLOAT16,
+            DataType::F64 => ffi::ncclDataType_t::ncclFloat64,
+            DataType::I8 => ffi::ncclDataType_t::ncclInt8,
+            DataType::U8 => ffi::ncclDataType_t::ncclUint8,
+            DataType::I32 => ffi::ncclDataType_t::ncclInt32,
+            DataType::U32 => ffi::ncclDataType_t::ncclUint32,
+            DataType::I64 => ffi::ncclDataType_t::ncclInt64,
+            DataType::U64 => ffi::ncclDataType_t::ncclUint64,
+            DataType::F8E4M3 => NCCL_FLOAT8E4M3,
+            DataType::F8E5M2 => NCCL_FLOAT8E5M2,
+        }
+    }
+
+    pub(crate) fn from_nccl(v: u32) -> Option<Self> {
+        match v {
+            ffi::ncclDataType_t::ncclFloat32 => Some(DataType::F32),
+            ffi::ncclDataType_t::ncclFloat16 => Some(DataType::F16),
+            NCCL_BFLOAT16 => Some(DataType::BF16),
+            ffi::ncclDataType_t::ncclFloat64 => Some(DataType::F64),
+            ffi::ncclDataType_t::ncclInt8 => Some(DataType::I8),
+            ffi::ncclDataType_t::ncclUint8 => Some(DataType::U8),
+            ffi::ncclDataType_t::ncclInt32 => Some(DataType::I32),
+            ffi::ncclDataType_t::ncclUint32 => Some(DataType::U32),
+            ffi::ncclDataType_t::ncclInt64 => Some(DataType::I64),
+            ffi::ncclDataType_t::ncclUint64 => Some(DataType::U64),
+            NCCL_FLOAT8E4M3 => Some(DataType::F8E4M3),
+            NCCL_FLOAT8E5M2 => Some(DataType::F8E5M2),
+            _ => None,
+        }
+    }
+
+    // element size in bytes
+    pub(crate) fn size(self) -> usize {
+        match self {
+            DataType::I8 | DataType::U8 | DataType::F8E4M3 | DataType::F8E5M2 => 1,
+            DataType::F16 | DataType::BF16 => 2,
+            DataType::F32 | DataType::I32 | DataType::U32 => 4,
+            DataType::F64 | DataType::I64 | DataType::U64 => 8,
+        }
+    }
+}
+
+// calls the generic function $f with the element type of $data_type
+// the element type comes first when the function takes more type parameters:
+// with_data_type!(data_type, f::<N>(..)) calls f::<f32, N>(..) and so on
+macro_rules! with_data_type {
+    ($data_type:expr, $f:ident($($arg:expr),*)) => {
+        with_data_type!($data_type, $f::<>($($arg),*))
+    };
+    ($data_type:expr, $f:ident::<$($g:ty),*>($($arg:expr),*)) => {
+        match $data_type {
+            DataType::F32 => $f::<f32, $($g),*>($($arg),*),
+            DataType::F16 => $f::<half::f16, $($g),*>($($arg),*),
+            DataType::BF16 => $f::<half::bf16, $($g),*>($($arg),*),
+            DataType::F64 => $f::<f64, $($g),*>($($arg),*),
+            DataType::I8 => $f::<i8, $($g),*>($($arg),*),
+            DataType::U8 => $f::<u8, $($g),*>($($arg),*),
+            DataType::I32 => $f::<i32, $($g),*>($($arg),*),
+            DataType::U32 => $f::<u32, $($g),*>($($arg),*),
+            DataType::I64 => $f::<i64, $($g),*>($($arg),*),
+            DataType::U64 => $f::<u64, $($g),*>($($arg),*),
+            DataType::F8E4M3 => $f::<float8::F8E4M3, $($g),*>($($arg),*),
+            DataType::F8E5M2 => $f::<float8::F8E5M2, $($g),*>($($arg),*),
+        }
+    };
+}
+pub(crate) use with_data_type;
+
+impl std::fmt::Display for DataType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ReduceOp {
+    Sum,
+    Prod,
+    Max,
+    Min,
+    Avg,
+}
+
+impl ReduceOp {
+    pub(crate) fn to_nccl(self) -> u32 {
+        match self {
+            ReduceOp::Sum => ffi::ncclRedOp_t::ncclSum,
+            ReduceOp::Prod => ffi::ncclRedOp_t::ncclProd,
+            ReduceOp::Max => ffi::ncclRedOp_t::ncclMax,
+            ReduceOp::Min => ffi::ncclRedOp_t::ncclMin,
+            ReduceOp::Avg => ffi::ncclRedOp_t::ncclAvg,
+        }
+    }
+
+    pub(crate) fn from_nccl(v: u32) -> Option<Self> {
+        match v {
+            ffi::ncclRedOp_t::ncclSum => Some(ReduceOp::Sum),
+            ffi::ncclRedOp_t::ncclProd => Some(ReduceOp::Prod),
+            ffi::ncclRedOp_t::ncclMax => Some(ReduceOp::Max),
+            ffi::ncclRedOp_t::ncclMin => Some(ReduceOp::Min),
+            ffi::ncclRedOp_t::ncclAvg => Some(ReduceOp::Avg),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ReduceOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+// what to do when a phase waits for the other ranks longer than its timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum TimeoutAction {
+    Abort, // tear the communicator down
+    Warn,  // report and keep waiting
+}
+
+// the network the modes run on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Net {
+    Plugin, // the net plugin of --net-plugin
+    Tcp,    // the built-in transport over TCP sockets
+    Uring,  // the built-in transport over TCP sockets driven by io_uring
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -55,7 +215,11 @@ pub(crate) struct Args {
     #[arg(long, default_value = "")]
     pub upstream: String,
 
-    #[arg(long, default_value = "1048576")]
+    #[arg(
+        long,
+        default_value = "1048576",
+        help = "elements per message (server: maximum accepted)"
+    )]
     pub count: usize,
 
     #[arg(long, default_value = "100")]
@@ -67,50 +231,261 @@ pub(crate) struct Args {
     #[arg(long, default_value = "2")]
     pub reduce_jobs: usize,
 
-    #[arg(long, default_value = "0")] // 0: = nrank
+    #[arg(long, default_value = "0")] // 0: = 8, shared by all the communicators
     pub recv_threads: usize,
 
-    #[arg(long, default_value = "0")] // 0: = nrank
+    #[arg(long, default_value = "0")] // 0: = 8, shared by all the communicators
     pub send_threads: usize,
 
-    #[arg(long, default_value = "1")]
+    #[arg(
+        long,
+        default_value = "1",
+        help = "comm pairs per rank and server, the server stripes its jobs across them"
+    )]
     pub nchannel: usize,
 
+    #[arg(
+        long,
+        default_value = "",
+        help = "network devices to stripe the channels across, comma separated ids (default: all the devices)"
+    )]
+    pub devices: String,
+
+    #[arg(
+        long,
+        default_value = "",
+        help = "net plugin to load, a path or a name like ofi for libnccl-net-ofi.so (default: $NCCL_NET_PLUGIN or libnccl-net.so)"
+    )]
+    pub net_plugin: String,
+
+    #[arg(long, default_value = "plugin")]
+    pub net: Net,
+
+    #[arg(
+        long,
+        default_value = "",
+        help = "tcp, uring: interfaces to use, like NCCL_SOCKET_IFNAME (default: all but docker and lo, or lo)"
+    )]
+    pub socket_ifname: String,
+
+    #[arg(
+        long,
+        default_value = "2",
+        help = "tcp: threads per comm, like NCCL_SOCKET_NTHREADS"
+    )]
+    pub socket_nthreads: usize,
+
+    #[arg(
+        long,
+        default_value = "1",
+        help = "tcp: sockets per thread, like NCCL_NSOCKS_PERTHREAD"
+    )]
+    pub nsocks_perthread: usize,
+
+    #[arg(
+        long,
+        help = "don't use shared memory with the ranks and servers on the same host"
+    )]
+    pub no_shm: bool,
+
+    #[arg(long, help = "print the properties of the network devices and exit")]
+    pub list_devices: bool,
+
     #[arg(long, default_value = "1")]
     pub nreq: usize,
 
+    #[arg(
+        long,
+        default_value = "1",
+        help = "requests a message is split into, so that its first chunks are reduced and sent back while the rest is arriving"
+    )]
+    pub nchunk: usize,
+
+    #[arg(
+        long,
+        default_value = "1",
+        help = "server: requests in flight per rank and direction"
+    )]
+    pub pipeline_depth: usize,
+
     #[arg(long, default_value = "1")]
     pub nrank: usize,
 
     #[arg(long, default_value = "f32")]
     pub data_type: DataType,
 
+    #[arg(
+        long,
+        default_value = "sum",
+        help = "server: the default of the communicators, whose first rank chooses their operator"
+    )]
+    pub reduce_op: ReduceOp,
+
+    #[arg(
+        long,
+        help = "rank id sent to the servers, which reduce the ranks in the order of their ids"
+    )]
+    pub rank: Option<usize>,
+
+    #[arg(
+        long,
+        help = "server: require every rank to send its rank id, for a bitwise reproducible reduction"
+    )]
+    pub deterministic: bool,
+
+    #[arg(long, help = "accumulate f32 reductions in f64 and round once")]
+    pub accumulate_f64: bool,
+
+    #[arg(
+        long,
+        help = "server: fold each rank's data into the result as it arrives, through a few staging buffers"
+    )]
+    pub accumulate_on_arrival: bool,
+
+    #[arg(
+        long,
+        default_value = "2",
+        help = "server: staging buffers per job with --accumulate-on-arrival"
+    )]
+    pub staging_buffers: usize,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "communicator id sent to the servers, ranks with the same id are reduced together"
+    )]
+    pub comm_id: u64,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "server: exit after serving this many communicators (0: never exit)"
+    )]
+    pub ncomm: usize,
+
+    #[arg(
+        long,
+        default_value = "60",
+        help = "seconds to wait for all the ranks to get connected (0: no timeout)"
+    )]
+    pub connect_timeout: u64,
+
+    #[arg(
+        long,
+        default_value = "60",
+        help = "seconds to wait for the rest of a job once a rank delivered its data (0: no timeout)"
+    )]
+    pub recv_timeout: u64,
+
+    #[arg(
+        long,
+        default_value = "60",
+        help = "seconds to wait for a job to get reduced once all its data arrived (0: no timeout)"
+    )]
+    pub reduce_timeout: u64,
+
+    #[arg(
+        long,
+        default_value = "60",
+        help = "seconds to wait for the result of a job to reach all the ranks (0: no timeout)"
+    )]
+    pub send_timeout: u64,
+
+    #[arg(
+        long,
+        default_value = "warn",
+        help = "what to do when a timeout expires"
+    )]
+    pub timeout_action: TimeoutAction,
+
     #[arg(long, default_value = "0")]
     pub ring_rank: usize,
 }
 
-pub(crate) trait Float:
-    num_traits::Float + FromPrimitive + Default + Sync + Send + std::fmt::Debug + std::ops::AddAssign
+// element types the server can reduce
+pub(crate) trait Element:
+    Copy + FromPrimitive + Default + PartialEq + Sync + Send + std::fmt::Debug + Accumulate
 {
 }
 
-impl Float for f32 {}
-impl Float for f16 {}
-impl Float for bf16 {}
+impl Element for f32 {}
+impl Element for f16 {}
+impl Element for bf16 {}
+impl Element for f64 {}
+impl Element for i8 {}
+impl Element for u8 {}
+impl Element for i32 {}
+impl Element for u32 {}
+impl Element for i64 {}
+impl Element for u64 {}
+impl Element for F8E4M3 {}
+impl Element for F8E5M2 {}
 
+// buffers are aligned to their size rounded up to a page, which has to be a power of two
 pub(crate) fn alignment(size: usize) -> usize {
     let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
-    (size + page - 1) & !(page - 1)
+    ((size + page - 1) & !(page - 1)).next_power_of_two()
+}
+
+// parses --devices into the rails the channels are striped across. a device may be listed
+// several times to give it a larger share of the channels.
+pub(crate) fn parse_devices(spec: &str, ndev: usize) -> Result<Vec<usize>, String> {
+    if ndev == 0 {
+        return Err("no network devices found".to_string());
+    }
+    if spec.is_empty() {
+        return Ok((0..ndev).collect());
+    }
+    spec.split(',')
+        .map(|v| match v.trim().parse::<usize>() {
+            Ok(dev) if dev < ndev => Ok(dev),
+            Ok(dev) => Err(format!(
+                "device {} out of range, {} devices found",
+                dev, ndev
+            )),
+            Err(e) => Err(format!("invalid device {:?}: {}", v, e)),
+        })
+        .collect()
+}
+
+// the rails of --devices. channel n of a process goes through rails[n % rails.len()].
+pub(crate) fn rails<N: Transport>(net: &N, args: &Args) -> Vec<usize> {
+    let ndev = net.devices().unwrap_or_else(|e| panic!("devices: {}", e));
+    let rails = parse_devices(&args.devices, ndev).unwrap_or_else(|e| panic!("--devices: {}", e));
+    for dev in rails.iter().collect::<std::collections::BTreeSet<_>>() {
+        let props = net.get_properties(*dev).unwrap();
+        info!(
+            "rail: dev: {}, name: {}, speed: {}Mbps",
+            dev, props.name, props.speed
+        );
+    }
+    rails
+}
+
+// name describes the net, like "socket, version: v8"
+pub(crate) fn print_devices<N: Transport>(net: &N, name: &str) {
+    let ndev = net.devices().unwrap_or_else(|e| panic!("devices: {}", e));
+    println!("net: {}, devices: {}", name, ndev);
+    for dev in 0..ndev {
+        let props = net.get_properties(dev).unwrap();
+        println!(
+            "dev: {}, name: {}, pci: {}, guid: 0x{:x}, speed: {}Mbps, port: {}, ptrSupport: 0x{:x}, maxComms: {}, maxRecvs: {}",
+            dev,
+            props.name,
+            props.pci_path,
+            props.guid,
+            props.speed,
+            props.port,
+            props.ptr_support,
+            props.max_comms,
+            props.max_recvs
+        );
+    }
 }
 
 pub(crate) fn print_stat(args: &Args, elapsed: &Duration) {
     let nsplit = args.address.split(",").count();
-    let size = args.count
-        * if args.data_type == DataType::F32 {
-            4
-        } else {
-            2
-        };
+    let size = args.count * args.data_type.size();
     let size = if args.ring_rank > 0 {
         info!(
             "type: ring, nchannel: {}, nsplit: {}, nreq: {}, nrank: {}, reduce_ths: {}, count: {}, try_count: {} #",
@@ -119,8 +494,8 @@ pub(crate) fn print_stat(args: &Args, elapsed: &Duration) {
         args.nrank * size
     } else {
         info!(
-            "type: agg, nchannel: {}, nsplit: {}, nreq: {}, count: {}, try_count: {} #",
-            args.nchannel, nsplit, args.nreq, args.count, args.try_count
+            "type: agg, nchannel: {}, nsplit: {}, nreq: {}, nchunk: {}, count: {}, try_count: {} #",
+            args.nchannel, nsplit, args.nreq, args.nchunk, args.count, args.try_count
         );
         nsplit * size
     };
@@ -136,22 +511,145 @@ pub(crate) fn print_stat(args: &Args, elapsed: &Duration) {
     );
 }
 
-pub(crate) fn vec_of_none<T>(n: usize) -> Vec<Option<T>> {
-    std::iter::repeat_with(|| None).take(n).collect()
+// how a progress thread waits when a poll of its requests made no progress. it keeps spinning
+// while the data is flowing for the latency, then gives the core away when it stays idle.
+pub(crate) struct Backoff {
+    idle: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new() -> Self {
+        Backoff { idle: 0 }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.idle = 0;
+    }
+
+    pub(crate) fn idle(&mut self) {
+        if cfg!(no_spinloop) {
+            std::thread::sleep(NO_SPINLOOP_INTERVAL);
+        } else if self.idle < BACKOFF_SPINS {
+            std::hint::spin_loop();
+        } else if self.idle < BACKOFF_YIELDS {
+            std::thread::yield_now();
+        } else {
+            std::thread::sleep(BACKOFF_SLEEP);
+        }
+        self.idle = self.idle.saturating_add(1);
+    }
+}
+
+// the deadline of a phase that waits for other ranks. it is re-armed when it expires, so that
+// a phase that keeps being stuck gets reported once per timeout.
+pub(crate) struct Deadline {
+    timeout: Duration,
+    start: Instant,
+}
+
+impl Deadline {
+    // a zero timeout never expires
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Deadline {
+            timeout,
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn from_secs(secs: u64) -> Self {
+        Self::new(Duration::from_secs(secs))
+    }
+
+    // returns how long the phase has been waiting when the timeout expired
+    pub(crate) fn expired(&mut self) -> Option<Duration> {
+        let elapsed = self.start.elapsed();
+        if self.timeout.is_zero() || elapsed < self.timeout {
+            return None;
+        }
+        self.start = Instant::now();
+        Some(elapsed)
+    }
+}
+
+// reports a phase that ran past its timeout. returns the report as an error when the
+// communicator has to be aborted.
+pub(crate) fn timed_out(action: TimeoutAction, report: String) -> Result<(), String> {
+    match action {
+        TimeoutAction::Warn => {
+            warn!("{}", report);
+            Ok(())
+        }
+        TimeoutAction::Abort => Err(report),
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
+    use super::*;
     use crate::nccl_net;
-    use std::sync::Once;
+    use std::collections::BTreeSet;
+    use std::net::TcpListener;
+    use std::sync::{Mutex, Once};
 
     static INIT: Once = Once::new();
+    static PORTS: Mutex<BTreeSet<u16>> = Mutex::new(BTreeSet::new());
+
+    pub(crate) fn init_logger() {
+        let _ = env_logger::try_init();
+    }
 
     pub(crate) fn initialize() {
         INIT.call_once(|| {
-            env_logger::init();
+            init_logger();
             std::env::set_var("NCCL_PLUGIN_P2P", "socket");
-            nccl_net::init();
+            nccl_net::load("").unwrap();
+            nccl_net::init().unwrap();
         });
     }
+
+    // a free port for the bootstrap of a test, never handed out twice so that the tests can run
+    // in parallel
+    pub(crate) fn free_port() -> u16 {
+        loop {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            if PORTS.lock().unwrap().insert(port) {
+                return port;
+            }
+        }
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut deadline = Deadline::new(Duration::from_millis(50));
+        assert!(deadline.expired().is_none());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(deadline.expired().unwrap() >= Duration::from_millis(50));
+        // re-armed
+        assert!(deadline.expired().is_none());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(deadline.expired().is_some());
+
+        let mut never = Deadline::from_secs(0);
+        assert!(never.expired().is_none());
+    }
+
+    #[test]
+    fn test_parse_devices() {
+        assert_eq!(parse_devices("", 3), Ok(vec![0, 1, 2]));
+        assert_eq!(parse_devices("2,0", 3), Ok(vec![2, 0]));
+        assert_eq!(parse_devices("1, 1", 2), Ok(vec![1, 1]));
+        assert!(parse_devices("3", 3).is_err());
+        assert!(parse_devices("0,x", 3).is_err());
+        assert!(parse_devices("", 0).is_err());
+    }
+
+    #[test]
+    fn test_timed_out() {
+        assert!(timed_out(TimeoutAction::Warn, "stuck".to_string()).is_ok());
+        assert_eq!(
+            timed_out(TimeoutAction::Abort, "stuck".to_string()),
+            Err("stuck".to_string())
+        );
+    }
 }
@@ -0,0 +1,143 @@
+import argparse
+import math
+import os
+import re
+import socket
+import statistics
+import subprocess
+import sys
+
+# runs the bench mode of the reduction server against a client on this host once per network, and
+# checks that a network keeps up with the baseline. the client and the bench side run with
+# --no-shm, so that the messages go through the network stack.
+
+SERVER_CMD = "reduction_server/target/release/optcast-reduction-server"
+OPTCAST_PLUGIN = "nccl_plugin/src/.libs/libnccl-net.so"
+
+
+def get_shared_dir():
+    return os.path.dirname(os.path.dirname(os.path.realpath(__file__)))
+
+
+def free_port():
+    with socket.socket() as s:
+        s.bind(("127.0.0.1", 0))
+        return s.getsockname()[1]
+
+
+def net_args(args, net):
+    if net == "plugin":
+        # the socket plugin of the optcast plugin
+        return ["--net", "plugin", "--net-plugin", args.net_plugin], {
+            "NCCL_PLUGIN_P2P": "socket",
+            "NCCL_SOCKET_IFNAME": args.socket_ifname,
+        }
+    return ["--net", net, "--socket-ifname", args.socket_ifname], {}
+
+
+def bench(args, net):
+    port = str(free_port())
+    opts, env = net_args(args, net)
+    opts += [
+        "--no-shm",
+        "--count",
+        str(args.count),
+        "--try-count",
+        str(args.try_count),
+        "--nreq",
+        str(args.nreq),
+        "--nchannel",
+        str(args.nchannel),
+        "--data-type",
+        args.data_type,
+    ]
+    env = {**os.environ, "RUST_LOG": "info", **env}
+    server = subprocess.Popen(
+        [args.server_cmd, "--bench", "--port", port] + opts,
+        stdout=subprocess.DEVNULL,
+        stderr=subprocess.DEVNULL,
+        env=env,
+    )
+    try:
+        client = subprocess.run(
+            [args.server_cmd, "--client", "--address", f"127.0.0.1:{port}"] + opts,
+            capture_output=True,
+            text=True,
+            env=env,
+            timeout=args.timeout,
+        )
+        server.wait(timeout=args.timeout)
+    finally:
+        server.kill()
+    r = re.search(r"bandwidth: (?P<bw>[\d.]+)Gbps", client.stdout)
+    if client.returncode != 0 or not r:
+        print(client.stdout + client.stderr, file=sys.stderr)
+        raise RuntimeError(f"bench over {net} failed")
+    return float(r.group("bw"))
+
+
+def arguments():
+    parser = argparse.ArgumentParser()
+    parser.add_argument("--net", default="uring", choices=["plugin", "tcp", "uring"])
+    parser.add_argument(
+        "--baseline", default="plugin", choices=["plugin", "tcp", "uring"]
+    )
+    parser.add_argument(
+        "--tolerance",
+        default=1.0,
+        type=float,
+        help="the fraction of the bandwidth of the baseline --net must reach, up to the noise of the runs",
+    )
+    parser.add_argument("--runs", default=10, type=int)
+    parser.add_argument("--count", default=4 * 1024 * 1024, type=int)
+    parser.add_argument("--try-count", default=100, type=int)
+    parser.add_argument("--nreq", default=4, type=int)
+    parser.add_argument("--nchannel", default=1, type=int)
+    parser.add_argument("--data-type", default="f32")
+    parser.add_argument("--socket-ifname", default="lo")
+    parser.add_argument("--timeout", default=300, type=int)
+    parser.add_argument("--shared-dir", default=get_shared_dir())
+    parser.add_argument("--server-cmd")
+    parser.add_argument("--net-plugin")
+    args = parser.parse_args()
+    if args.runs < 2:
+        parser.error("the noise of the runs needs --runs 2 or more")
+    return args
+
+
+def main():
+    args = arguments()
+    if not args.server_cmd:
+        args.server_cmd = f"{args.shared_dir}/{SERVER_CMD}"
+    if not args.net_plugin:
+        args.net_plugin = f"{args.shared_dir}/{OPTCAST_PLUGIN}"
+
+    # the runs alternate between the networks, so that both see the same drift of the host
+    runs = {args.baseline: [], args.net: []}
+    for _ in range(args.runs):
+        for net in runs:
+            runs[net].append(bench(args, net))
+
+    # the relative standard error of a median, about 1.25 times the one of a mean
+    medians, errors = {}, {}
+    for net, v in runs.items():
+        medians[net] = statistics.median(v)
+        errors[net] = 1.25 * statistics.stdev(v) / medians[net] / math.sqrt(len(v))
+        print(
+            f"{net}: median: {medians[net]:.2f}Gbps, min: {min(v):.2f}Gbps, max: {max(v):.2f}Gbps, "
+            f"runs: {', '.join(f'{x:.2f}' for x in v)}"
+        )
+
+    # the noise of the ratio is two standard errors, from the spread of the runs of both networks
+    ratio = medians[args.net] / medians[args.baseline]
+    noise = 2 * ratio * math.hypot(errors[args.baseline], errors[args.net])
+    print(f"{args.net} / {args.baseline}: {ratio:.2f} +- {noise:.2f}")
+    if ratio + noise < args.tolerance:
+        print(
+            f"{args.net} is below {args.tolerance:.2f} of {args.baseline} beyond the noise of the runs"
+        )
+        sys.exit(1)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,147 @@
+import argparse
+import math
+import os
+import re
+import socket
+import statistics
+import subprocess
+import sys
+
+# runs a reduction server with --nrank clients on this host, once per server binary, and checks
+# that a server keeps up with the baseline. the clients run with --no-shm, so that the messages
+# go through the network stack and the send and recv threads of the server have to keep up with
+# all the ranks.
+
+SERVER_CMD = "reduction_server/target/release/optcast-reduction-server"
+
+
+def get_shared_dir():
+    return os.path.dirname(os.path.dirname(os.path.realpath(__file__)))
+
+
+def free_port():
+    with socket.socket() as s:
+        s.bind(("127.0.0.1", 0))
+        return s.getsockname()[1]
+
+
+def bench(args, cmd, server_opts):
+    port = str(free_port())
+    opts = [
+        "--net",
+        args.net,
+        "--socket-ifname",
+        args.socket_ifname,
+        "--no-shm",
+        "--count",
+        str(args.count),
+        "--try-count",
+        str(args.try_count),
+        "--nreq",
+        str(args.nreq),
+        "--nrank",
+        str(args.nrank),
+        "--data-type",
+        args.data_type,
+    ]
+    env = {**os.environ, "RUST_LOG": "info"}
+    server = subprocess.Popen(
+        [cmd, "--port", port, "--ncomm", "1"] + opts + server_opts,
+        stdout=subprocess.DEVNULL,
+        stderr=subprocess.DEVNULL,
+        env=env,
+    )
+    try:
+        clients = [
+            subprocess.Popen(
+                [cmd, "--client", "--address", f"127.0.0.1:{port}", "--rank", str(i)]
+                + opts,
+                stdout=subprocess.PIPE,
+                stderr=subprocess.PIPE,
+                text=True,
+                env=env,
+            )
+            for i in range(args.nrank)
+        ]
+        outs = [c.communicate(timeout=args.timeout) for c in clients]
+        server.wait(timeout=args.timeout)
+    finally:
+        server.kill()
+        for c in clients:
+            c.kill()
+
+    # the ranks wait for each other, the slowest one is the bandwidth of the allreduce
+    bws = []
+    for c, (stdout, stderr) in zip(clients, outs):
+        r = re.search(r"bandwidth: (?P<bw>[\d.]+)Gbps", stdout + stderr)
+        if c.returncode != 0 or not r:
+            print(stdout + stderr, file=sys.stderr)
+            raise RuntimeError(f"bench of {cmd} failed")
+        bws.append(float(r.group("bw")))
+    return min(bws)
+
+
+def arguments():
+    parser = argparse.ArgumentParser()
+    parser.add_argument("--baseline-cmd", required=True)
+    parser.add_argument("--server-cmd")
+    parser.add_argument(
+        "--baseline-opts", default="", help="server options of the baseline"
+    )
+    parser.add_argument("--server-opts", default="", help="server options")
+    parser.add_argument(
+        "--tolerance",
+        default=1.0,
+        type=float,
+        help="the fraction of the bandwidth of the baseline the server must reach, up to the noise of the runs",
+    )
+    parser.add_argument("--runs", default=10, type=int)
+    parser.add_argument("--nrank", default=8, type=int)
+    parser.add_argument("--net", default="tcp", choices=["tcp", "uring"])
+    parser.add_argument("--count", default=1024 * 1024, type=int)
+    parser.add_argument("--try-count", default=100, type=int)
+    parser.add_argument("--nreq", default=4, type=int)
+    parser.add_argument("--data-type", default="f32")
+    parser.add_argument("--socket-ifname", default="lo")
+    parser.add_argument("--timeout", default=300, type=int)
+    parser.add_argument("--shared-dir", default=get_shared_dir())
+    args = parser.parse_args()
+    if args.runs < 2:
+        parser.error("the noise of the runs needs --runs 2 or more")
+    return args
+
+
+def main():
+    args = arguments()
+    if not args.server_cmd:
+        args.server_cmd = f"{args.shared_dir}/{SERVER_CMD}"
+
+    # the runs alternate between the servers, so that both see the same drift of the host
+    runs = {"baseline": [], "server": []}
+    for _ in range(args.runs):
+        runs["baseline"].append(bench(args, args.baseline_cmd, args.baseline_opts.split()))
+        runs["server"].append(bench(args, args.server_cmd, args.server_opts.split()))
+
+    # the relative standard error of a median, about 1.25 times the one of a mean
+    medians, errors = {}, {}
+    for name, v in runs.items():
+        medians[name] = statistics.median(v)
+        errors[name] = 1.25 * statistics.stdev(v) / medians[name] / math.sqrt(len(v))
+        print(
+            f"{name}: median: {medians[name]:.2f}Gbps, min: {min(v):.2f}Gbps, max: {max(v):.2f}Gbps, "
+            f"runs: {', '.join(f'{x:.2f}' for x in v)}"
+        )
+
+    # the noise of the ratio is two standard errors, from the spread of the runs of both servers
+    ratio = medians["server"] / medians["baseline"]
+    noise = 2 * ratio * math.hypot(errors["baseline"], errors["server"])
+    print(f"server / baseline: {ratio:.2f} +- {noise:.2f}")
+    if ratio + noise < args.tolerance:
+        print(
+            f"the server is below {args.tolerance:.2f} of the baseline beyond the noise of the runs"
+        )
+        sys.exit(1)
+
+
+if __name__ == "__main__":
+    main()
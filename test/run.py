@@ -18,6 +18,22 @@ SERVER_CMD = "reduction_server/target/release/optcast-reduction-server"
 CLIENT_CMD = "test/nccl-tests/build/all_reduce_perf"
 OPTCAST_PLUGIN_DIR = "nccl_plugin/src/.libs"
 
+# data type: (element size, nccl-tests data type name)
+DATA_TYPES = {
+    "f32": (4, "float"),
+    "f16": (2, "half"),
+    "bf16": (2, "bfloat16"),
+    "f64": (8, "double"),
+    "i8": (1, "int8"),
+    "u8": (1, "uint8"),
+    "i32": (4, "int32"),
+    "u32": (4, "uint32"),
+    "i64": (8, "int64"),
+    "u64": (8, "uint64"),
+    "f8e4m3": (1, "f8e4m3"),
+    "f8e5m2": (1, "f8e5m2"),
+}
+
 
 def show_stats(prefix, v):
     avg = sum(v) / len(v)
@@ -417,7 +433,7 @@ async def server(args):
     port = server["port"]
     count = (
         parse_chunksize(args.chunksize)
-        // (4 if args.data_type == "f32" else 2)
+        // DATA_TYPES[args.data_type][0]
         // args.nsplit
     )
 
@@ -434,7 +450,12 @@ async def server(args):
         f"{server_cmd} --port {port} --nrank {args.nrank}"
         + f" --reduce-jobs {args.num_jobs} --reduce-threads {args.num_threads} --recv-threads {args.num_recvs} --send-threads {args.num_sends}"
         + f" --count {count}"
+        + " --ncomm 1"  # exit with the benchmark
         + f" --data-type {args.data_type}"
+        + f" --reduce-op {args.reduce_op}"
+        + (" --deterministic" if args.deterministic else "")
+        + (" --accumulate-f64" if args.accumulate_f64 else "")
+        + f" --nchunk {args.nchunk} --pipeline-depth {args.pipeline_depth}"
     )
     # print(f"[{platform.node()}] server:", cmd, file=sys.stderr)
 
@@ -475,11 +496,13 @@ async def client(args):
 
         nreq = 4
         try_count = 1000
-        count = parse_chunksize(args.chunksize) // (
-            4 if args.data_type == "f32" else 2
-        )
+        count = parse_chunksize(args.chunksize) // DATA_TYPES[args.data_type][0]
         if args.type == "optcast":
-            cmd = f"{client_cmd} -c -a {args.reduction_servers} --count {count} --try-count {try_count} --nreq {nreq}"
+            if args.nrank == 0:
+                with open(args.config) as f:
+                    config = yaml.load(f, Loader=yaml.FullLoader)
+                args.nrank = len(config["clients"])
+            cmd = f"{client_cmd} -c -a {args.reduction_servers} --count {count} --try-count {try_count} --nreq {nreq} --nrank {args.nrank} --data-type {args.data_type} --reduce-op {args.reduce_op} --rank {rank} --nchunk {args.nchunk}"
         elif args.type == "ring":
             with open(args.config) as f:
                 config = yaml.load(f, Loader=yaml.FullLoader)
@@ -492,9 +515,9 @@ async def client(args):
             )
             cmd = f"{client_cmd} -a {addrs} --reduce-threads 2 --count {count} --try-count {try_count} --nrank {args.nrank} --ring-rank {rank+1} --nreq {nreq}"
     else:
-        dt = "float" if args.data_type == "f32" else "half"
+        dt = DATA_TYPES[args.data_type][1]
         client_cmd = f"{args.shared_dir}/{CLIENT_CMD}"
-        cmd = f"{client_cmd} -d {dt} -e {args.size} -b {args.size} {args.nccl_test_options}"
+        cmd = f"{client_cmd} -d {dt} -o {args.reduce_op} -e {args.size} -b {args.size} {args.nccl_test_options}"
 
         os.environ["NCCL_DEBUG"] = "TRACE" if rank == 0 else "INFO"
         os.environ["NCCL_P2P_DISABLE"] = "1"
@@ -510,6 +533,7 @@ async def client(args):
             chunksize = parse_chunksize(args.chunksize) // 2
             os.environ["NCCL_COLLNET_CHUNKSIZE"] = str(chunksize)
             os.environ["OPTCAST_SPLIT"] = str(args.nsplit)
+            os.environ["OPTCAST_DATA_TYPE"] = args.data_type
         elif args.type == "sharp":
             os.environ["NCCL_COLLNET_ENABLE"] = "1"
 
@@ -689,7 +713,32 @@ def arguments():
         "--type", choices=["optcast", "sharp", "nccl", "ring"], default="optcast"
     )
     parser.add_argument("--nccl-test-options", default="-c 1 -n 1 -w 1")
-    parser.add_argument("--data-type", default="f32", choices=["f32", "f16"])
+    parser.add_argument("--data-type", default="f32", choices=DATA_TYPES.keys())
+    parser.add_argument(
+        "--reduce-op", default="sum", choices=["sum", "prod", "max", "min", "avg"]
+    )
+    parser.add_argument(
+        "--deterministic",
+        action="store_true",
+        help="reduce in the order of the rank ids on the servers",
+    )
+    parser.add_argument(
+        "--accumulate-f64",
+        action="store_true",
+        help="accumulate f32 reductions in f64 on the servers",
+    )
+    parser.add_argument(
+        "--nchunk",
+        default=1,
+        type=int,
+        help="requests a message is split into, pipelined through the servers",
+    )
+    parser.add_argument(
+        "--pipeline-depth",
+        default=1,
+        type=int,
+        help="requests in flight per rank on the servers",
+    )
     parser.add_argument("--shared-dir", default=get_shared_dir())
     parser.add_argument("--log-dir", default="log")
     parser.add_argument("--python", default="python3")